@@ -0,0 +1,12 @@
+#include <stdio.h>
+
+// Large enough to span several pages of memory
+int buffer[4096];
+
+int main() {
+    for (int i = 0; i < 4096; i++) {
+        buffer[i] = i * i;
+    }
+    printf("buffer[4095] = %d\n", buffer[4095]);
+    return 0;
+}
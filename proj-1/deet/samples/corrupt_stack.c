@@ -0,0 +1,17 @@
+#include <stdio.h>
+
+// Points this frame's saved %rbp and return address back into itself, so that walking the stack
+// from inside it never gets any further up
+
+void corrupt(void) {
+    long *frame = __builtin_frame_address(0);
+    frame[0] = (long)frame;
+    frame[1] = (long)&&inside;
+inside:
+    printf("Corrupted the stack\n");
+}
+
+int main() {
+    corrupt();
+    return 0;
+}
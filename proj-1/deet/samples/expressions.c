@@ -0,0 +1,18 @@
+#include <stdio.h>
+
+int arr[5] = {10, 20, 30, 40, 50};
+
+int sum(int *values, int n) {
+    int total = 0;
+    for (int i = 0; i < n; i++) {
+        total += values[i];
+    }
+    return total;
+}
+
+int main() {
+    int n = 5;
+    int *p = &arr[1];
+    printf("sum = %d\n", sum(arr, n));
+    return 0;
+}
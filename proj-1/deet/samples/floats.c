@@ -0,0 +1,11 @@
+#include <stdio.h>
+
+double half(double x) {
+    return x / 2;
+}
+
+int main() {
+    // The argument is passed in %xmm0
+    printf("half = %f\n", half(2.5));
+    return 0;
+}
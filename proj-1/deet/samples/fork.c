@@ -0,0 +1,14 @@
+#include <stdio.h>
+#include <sys/wait.h>
+#include <unistd.h>
+
+int main() {
+    pid_t pid = fork();
+    if (pid == 0) {
+        printf("Hello from the child!\n");
+        return 0;
+    }
+    waitpid(pid, NULL, 0);
+    printf("Hello from the parent!\n");
+    return 0;
+}
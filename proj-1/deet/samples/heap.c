@@ -0,0 +1,20 @@
+#include <stdio.h>
+#include <stdlib.h>
+
+void add(int *total, int n) {
+    int doubled = n * 2;
+    *total += doubled;
+}
+
+int main() {
+    int *total = malloc(sizeof(int));
+    *total = 0;
+    for (int i = 1; i <= 3; i++) {
+        add(total, i);
+    }
+    // Writes the same value back, which watchpoints shouldn't stop for
+    *total = *total;
+    printf("total = %d\n", *total);
+    free(total);
+    return 0;
+}
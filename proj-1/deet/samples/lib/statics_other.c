@@ -0,0 +1,9 @@
+#include <stdio.h>
+
+static void report(int n) {
+    printf("report(%d) from statics_other.c\n", n);
+}
+
+void report_other(int n) {
+    report(n);
+}
@@ -0,0 +1,22 @@
+#include <stdio.h>
+
+// Built with -O2 -fomit-frame-pointer, so %rbp isn't a frame pointer here and only the call frame
+// information says where each function's return address is
+
+__attribute__((noinline, noclone)) int leaf(int n) {
+    volatile int scaled = n * 3;
+    return scaled + 1;
+}
+
+__attribute__((noinline, noclone)) int middle(int n) {
+    int total = 0;
+    for (int i = 0; i < n; i++) {
+        total += leaf(i);
+    }
+    return total * 2;
+}
+
+int main(int argc, char *argv[]) {
+    printf("result = %d\n", middle(argc + 2));
+    return 0;
+}
@@ -0,0 +1,13 @@
+#include <stdio.h>
+
+int depth(int n) {
+    if (n > 0) {
+        depth(n - 1);
+    }
+    return n;
+}
+
+int main() {
+    printf("depth = %d\n", depth(3));
+    return 0;
+}
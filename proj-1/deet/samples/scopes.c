@@ -0,0 +1,15 @@
+#include <stdio.h>
+
+int main() {
+    int total = 0;
+    for (int i = 0; i < 3; i++) {
+        int square = i * i;
+        total += square;
+    }
+    {
+        int total = 100;
+        printf("inner: %d\n", total);
+    }
+    printf("sum of squares: %d\n", total);
+    return 0;
+}
@@ -0,0 +1,18 @@
+#include <stdio.h>
+
+unsigned long spin(unsigned long iterations) {
+    volatile unsigned long total = 0;
+    for (unsigned long i = 0; i < iterations; i++) {
+        total += i;
+    }
+    return total;
+}
+
+int main() {
+    unsigned long total = 0;
+    while (1) {
+        total += spin(100000000);
+    }
+    printf("%lu\n", total);
+    return 0;
+}
@@ -0,0 +1,18 @@
+#include <stdio.h>
+
+int square(int n) {
+    return n * n;
+}
+
+int sum_squares(int n) {
+    int total = 0;
+    for (int i = 1; i <= n; i++) {
+        total += square(i);
+    }
+    return total;
+}
+
+int main() {
+    printf("sum = %d\n", sum_squares(3));
+    return 0;
+}
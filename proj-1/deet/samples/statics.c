@@ -0,0 +1,13 @@
+#include <stdio.h>
+
+void report_other(int n);
+
+static void report(int n) {
+    printf("report(%d) from statics.c\n", n);
+}
+
+int main() {
+    report(1);
+    report_other(2);
+    report(3);
+}
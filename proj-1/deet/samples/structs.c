@@ -0,0 +1,19 @@
+#include <stdio.h>
+
+struct point {
+    int x;
+    int y;
+};
+
+int primes[4] = {2, 3, 5, 7};
+
+void show(struct point *p) {
+    printf("(%d, %d)\n", p->x, p->y);
+}
+
+int main() {
+    struct point origin = {3, -4};
+    int *first = &primes[0];
+    show(&origin);
+    return *first - 2;
+}
@@ -0,0 +1,34 @@
+#include <pthread.h>
+#include <stdio.h>
+
+// Two workers that keep calling tick() at the same time, so that one often hits a breakpoint there
+// while the other is stopped on it too
+pthread_barrier_t barrier;
+int total[2];
+
+void tick(int id, int i) {
+    total[id - 1] += i;
+}
+
+void *worker(void *arg) {
+    int id = *(int *)arg;
+    pthread_barrier_wait(&barrier);
+    for (int i = 1; i <= 10; i++) {
+        tick(id, i);
+    }
+    return NULL;
+}
+
+int main() {
+    pthread_t threads[2];
+    int ids[2] = {1, 2};
+    pthread_barrier_init(&barrier, NULL, 2);
+    for (int i = 0; i < 2; i++) {
+        pthread_create(&threads[i], NULL, worker, &ids[i]);
+    }
+    for (int i = 0; i < 2; i++) {
+        pthread_join(threads[i], NULL);
+    }
+    printf("Totals: %d %d\n", total[0], total[1]);
+    return 0;
+}
@@ -0,0 +1,17 @@
+#include <pthread.h>
+#include <stdio.h>
+
+void *worker(void *arg) {
+    int id = *(int *)arg;
+    printf("Hello from worker %d\n", id);
+    return NULL;
+}
+
+int main() {
+    pthread_t thread;
+    int id = 7;
+    pthread_create(&thread, NULL, worker, &id);
+    pthread_join(thread, NULL);
+    printf("Worker finished\n");
+    return 0;
+}
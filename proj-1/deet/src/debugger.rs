@@ -1,11 +1,146 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::time::{Duration, Instant, SystemTime};
 
-use crate::debugger_command::DebuggerCommand;
-use crate::dwarf_data::{DwarfData, Error as DwarfError};
-use crate::inferior::{Inferior, Status};
+use crate::debugger_command::{self, DebuggerCommand};
+use crate::disassembler;
+use crate::dwarf_data::{DwarfData, Error as DwarfError, Location, Type};
+use crate::expression::{Error as ExprError, Scope, Value};
+use crate::fp_registers;
+use crate::inferior::{self, Inferior, Status, SyscallStop};
+use crate::output::{Output, Style};
+use crate::syscalls;
+use crate::values::{self, Format};
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
+use serde_json::json;
+
+/// Most macro invocations to expand before reading another line from the prompt, which stops
+/// macros that invoke themselves from running forever.
+const MAX_MACRO_EXPANSIONS: usize = 1000;
+
+/// Pid of the inferior while it is running (and we are waiting on it), or 0 otherwise.
+static RUNNING_INFERIOR: AtomicI32 = AtomicI32::new(0);
+
+/// SIGINT handler that interrupts the running inferior, so that ctrl+c returns to the prompt.
+///
+/// When ctrl+c is pressed in the terminal, the inferior is in the foreground process group and gets
+/// the SIGINT itself, so it is only forwarded when it was sent to the debugger directly (e.g. with
+/// `kill`), which user-sent signals show with a non-positive si_code.
+pub extern "C" fn interrupt_inferior(
+    _signal: libc::c_int,
+    info: *mut libc::siginfo_t,
+    _context: *mut libc::c_void,
+) {
+    let pid = RUNNING_INFERIOR.load(Ordering::SeqCst);
+    let sent_by_user = info.is_null() || unsafe { (*info).si_code } <= 0;
+    if pid != 0 && sent_by_user {
+        unsafe {
+            libc::kill(pid, libc::SIGINT);
+        }
+    }
+}
+
+/// A breakpoint set with `break`.
+struct BreakPoint {
+    /// Number the breakpoint is reported by
+    number: usize,
+    /// Where the breakpoint was asked for (as given to `break`), so that it can be resolved again
+    /// when the debugging symbols are reloaded
+    target: String,
+    /// Every address the target resolved to, e.g. one per `static` function of the same name
+    addrs: Vec<usize>,
+    /// Times the inferior has stopped at the breakpoint since it was last started
+    hits: usize,
+    /// What happens when the breakpoint is hit
+    action: Action,
+}
+
+/// What a breakpoint does when it is hit.
+#[derive(Clone, PartialEq)]
+enum Action {
+    /// Stops the inferior (`break`)
+    Stop,
+    /// Prints the format (see `Debugger::format_trace`) and carries on running (`dprintf`)
+    Trace(String),
+    /// Logs the call to the function the breakpoint is in, and its return, and carries on running
+    /// (`trace <function>`)
+    TraceCalls,
+}
+
+/// A call to a function whose calls are traced that hasn't returned yet.
+struct TracedCall {
+    /// Number of the breakpoint tracing the function
+    number: usize,
+    function: String,
+    /// Where the function starts, to look it up again once it returns
+    function_address: usize,
+    /// Where the call returns to, which has a breakpoint to catch it doing so
+    return_addr: usize,
+    /// The stack pointer from before the call, which it is back to once the call has returned
+    stack_pointer: usize,
+}
+
+/// A watchpoint set with `watch -l`, on memory found once when it was set. It only lasts for the
+/// run of the inferior it was set in, since the memory may be elsewhere next time.
+struct WatchPoint {
+    /// Number the watchpoint is reported by, counted along with the breakpoints
+    number: usize,
+    /// The expression given to `watch -l`
+    expression: String,
+    addr: usize,
+    len: usize,
+    /// Type of the memory, or None if the expression was just an address (in which case the
+    /// memory is shown as a word)
+    dtype: Option<Type>,
+    /// What the memory held when last looked at
+    value: Vec<u8>,
+    /// Times the memory has changed
+    hits: usize,
+}
+
+/// A catchpoint set with `catch syscall`.
+struct CatchPoint {
+    /// Number the catchpoint is reported by, counted along with the breakpoints
+    number: usize,
+    /// The syscalls it stops at, on the way in and out, or any syscall if this is empty
+    syscalls: Vec<u64>,
+    /// Times the inferior has stopped at the catchpoint since it was last started
+    hits: usize,
+}
+
+/// A file that stops are being recorded to (see `record`).
+struct Recording {
+    path: String,
+    file: fs::File,
+    /// Number of stops (and exits) recorded so far
+    stops: usize,
+}
+
+/// A recording being replayed (see `replay`).
+struct Replay {
+    /// The recorded stops and exits, as `stop` and `exited` events with the registers added
+    stops: Vec<serde_json::Value>,
+    /// Index of the next one to show
+    next: usize,
+}
+
+/// Why the inferior stopped, as far as the user is concerned.
+enum StopReason {
+    /// The current thread hit breakpoints at this address
+    BreakPoint(usize),
+    /// The current thread changed watched memory. Holds what `watch_point_changes` found.
+    WatchPoint(Vec<serde_json::Value>),
+    /// The current thread entered or left a syscall that is being caught
+    Syscall(SyscallStop),
+    /// The current thread finished a step, next, finish or until
+    Step,
+    /// The inferior received a signal
+    Signal(nix::sys::signal::Signal),
+}
 
 pub struct Debugger {
     target: String,
@@ -13,23 +148,50 @@ pub struct Debugger {
     readline: Editor<()>,
     inferior: Option<Inferior>,
     debug_data: DwarfData,
+    /// When the target was last modified as of loading `debug_data`, to tell when it is rebuilt
+    target_modified: Option<SystemTime>,
+    /// The instruction byte each breakpoint replaced, keyed by its address, as `Inferior` needs
     break_points: HashMap<usize, u8>,
+    /// The breakpoints in the order they were set
+    break_point_list: Vec<BreakPoint>,
+    /// Traced calls in progress, outermost first
+    traced_calls: Vec<TracedCall>,
+    /// The watchpoints, in the order they were set (which is the order of the debug registers
+    /// they use)
+    watch_points: Vec<WatchPoint>,
+    /// The catchpoints, in the order they were set
+    catch_points: Vec<CatchPoint>,
+    /// Whether to switch to the new process when the inferior forks (instead of the parent)
+    follow_fork: bool,
+    /// Whether to single-step the inferior so that we can report how many instructions it ran
+    count_instructions: bool,
+    /// Lines read from command files that haven't been run yet, which take priority over readline
+    script: VecDeque<String>,
+    /// User-defined macros (see `define`): the lines each one runs, keyed by name
+    macros: HashMap<String, Vec<String>>,
+    /// Macros expanded since the last line was read from the prompt
+    macro_expansions: usize,
+    /// Where stops are being recorded, if they are
+    recording: Option<Recording>,
+    /// The recording being replayed, if any
+    replay: Option<Replay>,
+    /// How to color and page output
+    output: Output,
 }
 
 impl Debugger {
     /// Initializes the debugger.
-    pub fn new(target: &str) -> Debugger {
-        let debug_data = match DwarfData::from_file(target) {
-            Ok(val) => val,
-            Err(DwarfError::ErrorOpeningFile) => {
-                println!("Could not open file {}", target);
-                std::process::exit(1);
-            }
-            Err(DwarfError::DwarfFormatError(err)) => {
-                println!("Could not debugging symbols from {}: {:?}", target, err);
-                std::process::exit(1);
-            }
-        };
+    pub fn new(
+        target: &str,
+        follow_fork: bool,
+        count_instructions: bool,
+        output: Output,
+    ) -> Debugger {
+        let target_modified = modified_time(target);
+        let debug_data = read_debug_data(target).unwrap_or_else(|message| {
+            output.error(&message);
+            std::process::exit(1);
+        });
 
         let history_path = format!("{}/.deet_history", std::env::var("HOME").unwrap());
         let mut readline = Editor::<()>::new();
@@ -42,110 +204,1984 @@ impl Debugger {
             readline,
             inferior: None,
             debug_data,
+            target_modified,
             break_points: HashMap::new(),
+            break_point_list: Vec::new(),
+            traced_calls: Vec::new(),
+            watch_points: Vec::new(),
+            catch_points: Vec::new(),
+            follow_fork,
+            count_instructions,
+            script: VecDeque::new(),
+            macros: HashMap::new(),
+            macro_expansions: 0,
+            recording: None,
+            replay: None,
+            output,
         }
     }
 
+    /// Queues the commands in the file at `path` to run before any others (and before prompting
+    /// for more). Blank lines and lines starting with `#` are skipped.
+    pub fn source(&mut self, path: &str) -> Result<(), std::io::Error> {
+        let contents = fs::read_to_string(path)?;
+        for line in contents.lines().rev() {
+            self.script.push_front(line.to_string());
+        }
+        Ok(())
+    }
+
     pub fn run(&mut self) {
         loop {
             match self.get_next_command() {
                 DebuggerCommand::Run(args) => {
                     if self.inferior.is_some() {
-                        self.get_inferior_as_mut()
-                            .kill()
-                            .expect("Error killing inferior");
+                        self.kill_inferior().expect("Error killing inferior");
+                    }
+                    self.reload_if_changed();
+                    for break_point in &mut self.break_point_list {
+                        break_point.hits = 0;
                     }
-                    if let Some(inferior) =
-                        Inferior::new(&self.target, &args, &mut self.break_points)
-                    {
+                    for catch_point in &mut self.catch_points {
+                        catch_point.hits = 0;
+                    }
+                    self.forget_traced_calls();
+                    for watch_point in self.watch_points.drain(..) {
+                        self.output.line(&format!(
+                            "Deleted watchpoint {} (-location {}), which was for the last run",
+                            watch_point.number, watch_point.expression
+                        ));
+                    }
+                    if let Some(mut inferior) = Inferior::new(&self.target, &args) {
+                        inferior.catch_syscalls(self.caught_syscalls());
+                        self.relocate(&inferior);
+                        inferior
+                            .insert_break_points(&mut self.break_points)
+                            .expect("Error setting breakpoint");
                         // Create the inferior
                         self.inferior = Some(inferior);
                         // You may use self.inferior.as_mut().unwrap() to get a mutable reference
                         // to the Inferior object
                         self.run_inferior();
                     } else {
-                        println!("Error starting subprocess");
+                        self.output.error("Error starting subprocess");
                     }
                 }
                 DebuggerCommand::Quit => {
                     if self.inferior.is_some() {
-                        self.get_inferior_as_mut()
-                            .kill()
-                            .expect("Error killing inferior");
+                        self.kill_inferior().expect("Error killing inferior");
                     }
                     return;
                 }
                 DebuggerCommand::Continue => {
                     if self.inferior.is_none() {
-                        println!("No inferior is running");
+                        self.output.error("No inferior is running");
                     } else {
                         self.run_inferior();
                     }
                 }
-                DebuggerCommand::Backtrace => {
-                    self.get_inferior_as_ref()
-                        .print_backtrace(&self.debug_data)
-                        .expect("Error backtracing");
-                }
-                DebuggerCommand::Break(target) => {
-                    let addr = if let Some(address) = target.strip_prefix('*') {
-                        if let Some(avalible) = parse_address(address) {
-                            avalible
-                        } else {
-                            println!("Error address");
-                            continue;
+                command @ (DebuggerCommand::Step | DebuggerCommand::Next) => {
+                    let over_calls = matches!(command, DebuggerCommand::Next);
+                    if self.inferior.is_none() {
+                        self.output.error("No inferior is running");
+                    } else {
+                        if let Err(err) = self.step_line(over_calls) {
+                            self.output
+                                .error(&format!("Error stepping inferior: {}", err));
+                        }
+                    }
+                }
+                DebuggerCommand::Finish => {
+                    if self.inferior.is_none() {
+                        self.output.error("No inferior is running");
+                    } else if let Err(err) = self.finish() {
+                        self.output
+                            .error(&format!("Error running inferior: {}", err));
+                    }
+                }
+                command @ (DebuggerCommand::Until(_) | DebuggerCommand::Advance(_)) => {
+                    let (target, this_frame) = match &command {
+                        DebuggerCommand::Until(target) => (target, true),
+                        DebuggerCommand::Advance(target) => (target, false),
+                        _ => unreachable!(),
+                    };
+                    if self.inferior.is_none() {
+                        self.output.error("No inferior is running");
+                    } else if let Err(err) = self.run_until(target, this_frame) {
+                        self.output
+                            .error(&format!("Error running inferior: {}", err));
+                    }
+                }
+                DebuggerCommand::Kill => {
+                    if self.inferior.is_none() {
+                        self.output.error("No inferior is running");
+                    } else {
+                        if let Err(err) = self.kill_inferior() {
+                            self.output
+                                .error(&format!("Error killing inferior: {}", err));
                         }
-                    } else if let Ok(line_number) = target.parse::<usize>() {
-                        if let Some(address) = self.debug_data.get_addr_for_line(None, line_number)
-                        {
-                            address
-                        } else {
-                            println!("Incorrect line number");
-                            continue;
+                        self.inferior = None;
+                    }
+                }
+                DebuggerCommand::Detach => {
+                    if self.inferior.is_none() {
+                        self.output.error("No inferior is running");
+                    } else {
+                        let inferior = self.inferior.as_mut().unwrap();
+                        self.output
+                            .line(&format!("Detaching from inferior (pid {})", inferior.pid()));
+                        if let Err(err) = inferior.detach(&self.break_points) {
+                            self.output
+                                .error(&format!("Error detaching from inferior: {}", err));
                         }
-                    } else if let Some(address) =
-                        self.debug_data.get_addr_for_function(None, &target)
-                    {
-                        address
+                        self.inferior = None;
+                    }
+                }
+                DebuggerCommand::Backtrace => {
+                    if self.inferior.is_none() {
+                        self.output.error("No inferior is running");
+                    } else if let Err(err) = self.print_backtrace() {
+                        self.output
+                            .error(&format!("Error reading inferior stack: {}", err));
+                    }
+                }
+                DebuggerCommand::Print(expression, format) => {
+                    if self.inferior.is_none() {
+                        self.output.error("No inferior is running");
+                    } else if let Err(err) = self.print_expression(&expression, format) {
+                        self.output.error(&err.to_string());
+                    }
+                }
+                DebuggerCommand::ListMixed => {
+                    if self.inferior.is_none() {
+                        self.output.error("No inferior is running");
+                    } else if let Err(err) = self.list_mixed() {
+                        self.output.error(&err.to_string());
+                    }
+                }
+                DebuggerCommand::List(file) => {
+                    if let Err(err) = self.list_file(&file) {
+                        self.output.error(&err);
+                    }
+                }
+                DebuggerCommand::InfoSources => {
+                    let files = self.debug_data.source_files();
+                    if files.is_empty() {
+                        self.output.line("No source files.");
+                    }
+                    self.output.print_paged(&files);
+                }
+                DebuggerCommand::InfoThreads => {
+                    if self.inferior.is_none() {
+                        self.output.error("No inferior is running");
+                    } else {
+                        self.print_threads();
+                    }
+                }
+                DebuggerCommand::InfoLocals => {
+                    if self.inferior.is_none() {
+                        self.output.error("No inferior is running");
+                    } else if let Err(err) = self.print_locals() {
+                        self.output
+                            .error(&format!("Error reading inferior registers: {}", err));
+                    }
+                }
+                DebuggerCommand::InfoFloatRegisters => {
+                    if self.inferior.is_none() {
+                        self.output.error("No inferior is running");
+                    } else if let Err(err) = self.print_float_registers() {
+                        self.output
+                            .error(&format!("Error reading inferior registers: {}", err));
+                    }
+                }
+                DebuggerCommand::Thread(id) => {
+                    if self.inferior.is_none() {
+                        self.output.error("No inferior is running");
+                    } else {
+                        self.select_thread(id);
+                    }
+                }
+                DebuggerCommand::Return(value) => {
+                    if self.inferior.is_none() {
+                        self.output.error("No inferior is running");
+                    } else {
+                        self.force_return(value.as_deref());
+                    }
+                }
+                DebuggerCommand::Source(path) => {
+                    if let Err(err) = self.source(&path) {
+                        self.output
+                            .error(&format!("Could not read {}: {}", path, err));
+                    }
+                }
+                DebuggerCommand::Define(name) => self.define_macro(name),
+                DebuggerCommand::Break(target) => self.add_break_point(target, Action::Stop),
+                DebuggerCommand::Tracepoint(target, format) => {
+                    self.add_break_point(target, Action::Trace(format))
+                }
+                DebuggerCommand::TraceCalls(function) => {
+                    self.add_break_point(function, Action::TraceCalls)
+                }
+                DebuggerCommand::WatchLocation(expression) => {
+                    if self.inferior.is_none() {
+                        self.output.error("No inferior is running");
                     } else {
-                        println!("Function name not found");
+                        self.watch_location(expression);
+                    }
+                }
+                DebuggerCommand::CatchSyscall(names) => self.catch_syscalls(&names),
+                DebuggerCommand::Record(path) => self.record(path),
+                DebuggerCommand::Replay(path) => self.replay(path),
+            }
+        }
+    }
+
+    /// Kills the inferior, saying so.
+    fn kill_inferior(&mut self) -> Result<(), std::io::Error> {
+        let inferior = self.inferior.as_mut().unwrap();
+        self.output.line(&format!(
+            "Killing running inferior (pid {})",
+            inferior.pid()
+        ));
+        inferior.kill()
+    }
+
+    /// Sets a breakpoint at `target` that does `action` when it is hit.
+    fn add_break_point(&mut self, target: String, action: Action) {
+        // A line offset only means something from where we are now, so remember the line it
+        // comes to instead
+        let target = if target.starts_with(['+', '-']) {
+            match self.relative_line(&target) {
+                Ok(line) => line,
+                Err(message) => {
+                    self.output.error(message);
+                    return;
+                }
+            }
+        } else {
+            target
+        };
+        let addrs = match self.resolve_break_point(&target, &action) {
+            Ok(addrs) => addrs,
+            Err(message) => {
+                self.output.error(message);
+                return;
+            }
+        };
+        let number = self.next_number();
+        if self.output.json() {
+            self.output.event(json!({
+                "event": "breakpoint",
+                "number": number,
+                "tracepoint": action != Action::Stop,
+                "target": target,
+                "addresses": addrs,
+            }));
+        } else {
+            self.output.line(&format!(
+                "Set {} {} at {}",
+                match action {
+                    Action::Stop => "break point",
+                    Action::Trace(_) => "tracepoint",
+                    Action::TraceCalls => "call trace",
+                },
+                number,
+                describe_addrs(&addrs)
+            ));
+        }
+        for &addr in &addrs {
+            if self.break_points.contains_key(&addr) {
+                continue;
+            }
+            // Otherwise the breakpoint only goes in when the inferior is next started
+            let orig_byte = match &mut self.inferior {
+                Some(inferior) => match inferior.insert_break_point(addr) {
+                    Ok(orig_byte) => orig_byte,
+                    Err(err) => {
+                        self.output
+                            .error(&format!("Error setting break point: {}", err));
                         continue;
-                    };
+                    }
+                },
+                None => 0,
+            };
+            self.break_points.insert(addr, orig_byte);
+        }
+        self.break_point_list.push(BreakPoint {
+            number,
+            target,
+            addrs,
+            hits: 0,
+            action,
+        });
+    }
+
+    /// Returns the number to give the next breakpoint, watchpoint or catchpoint.
+    fn next_number(&self) -> usize {
+        let last_break_point = self.break_point_list.last().map(|point| point.number);
+        let last_watch_point = self.watch_points.last().map(|point| point.number);
+        let last_catch_point = self.catch_points.last().map(|point| point.number);
+        last_break_point
+            .max(last_watch_point)
+            .max(last_catch_point)
+            .map_or(0, |number| number + 1)
+    }
+
+    /// Sets a catchpoint that stops the inferior whenever it enters or leaves one of the syscalls
+    /// called `names` (which may also be numbers), or any syscall if there are no names.
+    fn catch_syscalls(&mut self, names: &[String]) {
+        let mut syscalls = Vec::new();
+        for name in names {
+            match syscalls::number(name) {
+                Some(number) => syscalls.push(number),
+                None => {
+                    self.output.error(&format!("Unknown syscall {}", name));
+                    return;
+                }
+            }
+        }
+        let number = self.next_number();
+        let names: Vec<String> = syscalls.iter().map(|&nr| syscalls::name(nr)).collect();
+        if self.output.json() {
+            self.output.event(json!({
+                "event": "catchpoint",
+                "number": number,
+                "syscalls": names,
+            }));
+        } else {
+            let which = match names.len() {
+                0 => "any syscall".to_string(),
+                1 => format!("syscall {}", names[0]),
+                _ => format!("syscalls {}", names.join(", ")),
+            };
+            self.output
+                .line(&format!("Set catchpoint {} on {}", number, which));
+        }
+        self.catch_points.push(CatchPoint {
+            number,
+            syscalls,
+            hits: 0,
+        });
+        let caught = self.caught_syscalls();
+        if let Some(inferior) = self.inferior.as_mut() {
+            inferior.catch_syscalls(caught);
+        }
+    }
 
-                    println!("Set break point {} at {:#x}", self.break_points.len(), addr);
-                    self.break_points.insert(addr, 0);
+    /// Returns the syscalls the catchpoints stop at, in the form `Inferior::catch_syscalls` takes.
+    fn caught_syscalls(&self) -> Option<Vec<u64>> {
+        if self.catch_points.is_empty() {
+            None
+        } else if self
+            .catch_points
+            .iter()
+            .any(|catch_point| catch_point.syscalls.is_empty())
+        {
+            Some(Vec::new())
+        } else {
+            Some(
+                self.catch_points
+                    .iter()
+                    .flat_map(|catch_point| catch_point.syscalls.iter().copied())
+                    .collect(),
+            )
+        }
+    }
+
+    /// Sets a watchpoint on the memory `expression` refers to where the current thread is stopped
+    /// (or at the address it comes to, if it is just a number). The memory is worked out now, so
+    /// the watchpoint keeps watching it whatever frame the inferior goes on to run in, e.g. to
+    /// watch something on the heap through a pointer that goes out of scope.
+    fn watch_location(&mut self, expression: String) {
+        let (addr, len, dtype) = match self.evaluate_location(&expression) {
+            Ok(location) => location,
+            Err(err) => {
+                self.output.error(&err.to_string());
+                return;
+            }
+        };
+        if self.watch_points.len() == inferior::MAX_WATCH_POINTS {
+            self.output.error(&format!(
+                "Can't set more than {} watchpoints",
+                inferior::MAX_WATCH_POINTS
+            ));
+            return;
+        }
+        // The debug registers can only watch aligned words and parts of them
+        if ![1, 2, 4, 8].contains(&len) || addr % len != 0 {
+            self.output.error(&format!(
+                "Can't watch {} bytes at {:#x} (only 1, 2, 4 or 8 bytes aligned to their size)",
+                len, addr
+            ));
+            return;
+        }
+        let value = match self.get_inferior_as_ref().read_memory(addr, len) {
+            Ok(value) => value,
+            Err(_) => {
+                self.output
+                    .error(&format!("Cannot access memory at address {:#x}", addr));
+                return;
+            }
+        };
+        let number = self.next_number();
+        self.watch_points.push(WatchPoint {
+            number,
+            expression,
+            addr,
+            len,
+            dtype,
+            value,
+            hits: 0,
+        });
+        if let Err(err) = self.set_watch_points() {
+            self.output
+                .error(&format!("Error setting watchpoint: {}", err));
+            self.watch_points.pop();
+            return;
+        }
+        let expression = &self.watch_points.last().unwrap().expression;
+        if self.output.json() {
+            self.output.event(json!({
+                "event": "watchpoint",
+                "number": number,
+                "expression": expression,
+                "address": addr,
+                "size": len,
+            }));
+        } else {
+            self.output.line(&format!(
+                "Set watchpoint {} on -location {} ({} bytes at {:#x})",
+                number, expression, len, addr
+            ));
+        }
+    }
+
+    /// Evaluates `expression` where the current thread is stopped, returning the address, size
+    /// and type of the memory it refers to. A plain number is taken as the address of a word.
+    fn evaluate_location(
+        &self,
+        expression: &str,
+    ) -> Result<(usize, usize, Option<Type>), ExprError> {
+        let inferior = self.get_inferior_as_ref();
+        let rip = inferior.instruction_pointer()?;
+        let frame_pointer = inferior.frame_pointer()?;
+        let scope = Scope {
+            debug_data: &self.debug_data,
+            lookup: |name: &str| self.lookup_variable(rip, frame_pointer, name),
+            read_memory: |addr, len| inferior.read_memory(addr, len),
+        };
+        match scope.evaluate(expression)? {
+            Value::Object { addr, dtype } => {
+                let size = self
+                    .debug_data
+                    .strip_aliases(dtype)
+                    .map_or(dtype.size, |dtype| dtype.size);
+                Ok((addr, size, Some(dtype.clone())))
+            }
+            Value::Int(addr) => Ok((addr as usize, std::mem::size_of::<usize>(), None)),
+        }
+    }
+
+    /// Points the debug registers of every thread at the watched memory.
+    fn set_watch_points(&self) -> Result<(), nix::Error> {
+        let watched: Vec<(usize, usize)> = self
+            .watch_points
+            .iter()
+            .map(|watch_point| (watch_point.addr, watch_point.len))
+            .collect();
+        self.get_inferior_as_ref().set_watch_points(&watched)
+    }
+
+    /// Finds the watchpoints the current thread triggered in stopping, and counts a hit for each
+    /// one whose memory it changed, returning what it changed from and to. Returns None if the
+    /// thread didn't stop for a watchpoint at all, and no changes if it only wrote what the
+    /// memory already held.
+    fn watch_point_changes(&mut self) -> Option<Vec<serde_json::Value>> {
+        if self.watch_points.is_empty() {
+            return None;
+        }
+        let indexes = match self.get_inferior_as_ref().watch_point_hits() {
+            Ok(indexes) if !indexes.is_empty() => indexes,
+            _ => return None,
+        };
+        let mut changes = Vec::new();
+        for index in indexes {
+            let watch_point = match self.watch_points.get(index) {
+                Some(watch_point) => watch_point,
+                None => continue,
+            };
+            let value = match self
+                .get_inferior_as_ref()
+                .read_memory(watch_point.addr, watch_point.len)
+            {
+                Ok(value) if value != watch_point.value => value,
+                _ => continue,
+            };
+            let old = self.format_watched(watch_point, &watch_point.value);
+            let new = self.format_watched(watch_point, &value);
+            let watch_point = &mut self.watch_points[index];
+            watch_point.value = value;
+            watch_point.hits += 1;
+            changes.push(json!({
+                "number": watch_point.number,
+                "expression": watch_point.expression,
+                "hits": watch_point.hits,
+                "old": old,
+                "new": new,
+            }));
+        }
+        Some(changes)
+    }
+
+    /// Formats `bytes` as a value of the memory `watch_point` watches.
+    fn format_watched(&self, watch_point: &WatchPoint, bytes: &[u8]) -> String {
+        let dtype = match &watch_point.dtype {
+            Some(dtype) => dtype,
+            None => {
+                let word = bytes
+                    .iter()
+                    .rev()
+                    .fold(0, |word, &byte| word << 8 | byte as u64);
+                return format!("{:#x}", word);
+            }
+        };
+        // Anything outside the watched memory (e.g. what a pointer points to) is read as it is now
+        let read_memory = |addr: usize, len: usize| match addr.checked_sub(watch_point.addr) {
+            Some(offset) if offset + len <= bytes.len() => Ok(bytes[offset..offset + len].to_vec()),
+            _ => self.get_inferior_as_ref().read_memory(addr, len),
+        };
+        values::format_value(
+            &self.debug_data,
+            dtype,
+            watch_point.addr,
+            Format::Natural,
+            &read_memory,
+        )
+        .unwrap_or_else(|err| format!("<error reading memory: {}>", err))
+    }
+
+    /// Says that a watchpoint's memory changed, given as one of the changes
+    /// `watch_point_changes` found.
+    fn print_watch_hit(&self, hit: &serde_json::Value) {
+        let hits = hit["hits"].as_u64().unwrap_or(0);
+        self.output.line(&format!(
+            "Watchpoint {} (-location {}), hit {} time{}",
+            hit["number"],
+            hit["expression"].as_str().unwrap_or("?"),
+            hits,
+            if hits == 1 { "" } else { "s" }
+        ));
+        self.output.line(&format!(
+            "Old value = {}",
+            hit["old"].as_str().unwrap_or("?")
+        ));
+        self.output.line(&format!(
+            "New value = {}",
+            hit["new"].as_str().unwrap_or("?")
+        ));
+    }
+
+    /// Says that the inferior stopped at the catchpoints in `hits` (as `{number, hits}` objects)
+    /// entering or leaving `syscall`, as `syscall_json` describes it.
+    fn print_syscall_hit(&self, syscall: &serde_json::Value, hits: &[serde_json::Value]) {
+        let name = syscall["name"].as_str().unwrap_or("?");
+        let what = match syscall["args"].as_array() {
+            Some(args) => {
+                let args: Vec<u64> = args.iter().map(|arg| arg.as_u64().unwrap_or(0)).collect();
+                let number = syscall["number"].as_u64().unwrap_or(0);
+                let args = syscalls::format_args(number, &args);
+                format!("call to syscall {}({})", name, args.join(", "))
+            }
+            None => format!(
+                "returned from syscall {} = {}",
+                name,
+                syscalls::format_return(syscall["return"].as_i64().unwrap_or(0) as u64)
+            ),
+        };
+        for hit in hits {
+            let count = hit["hits"].as_u64().unwrap_or(0);
+            self.output.line(&format!(
+                "Catchpoint {} ({}), hit {} time{}",
+                hit["number"],
+                what,
+                count,
+                if count == 1 { "" } else { "s" }
+            ));
+        }
+    }
+
+    /// Turns an offset from the line the current thread is stopped at, like `+3` or `-2`, into the
+    /// `file:line` it comes to, or returns a message saying why it can't be broken at.
+    fn relative_line(&self, offset: &str) -> Result<String, &'static str> {
+        let offset: isize = offset
+            .parse()
+            .map_err(|_| "Expected a line offset like +3")?;
+        let inferior = self.inferior.as_ref().ok_or("No inferior is running")?;
+        let pc = inferior
+            .stop_address(&self.break_points)
+            .map_err(|_| "Error reading inferior registers")?;
+        let line = self
+            .debug_data
+            .get_line_from_addr(pc)
+            .ok_or("No line info here")?;
+        // As with until, the symbols may only know the file by its name
+        let file = Path::new(&line.file)
+            .file_name()
+            .ok_or("No line info here")?
+            .to_string_lossy();
+        let number = line
+            .number
+            .checked_add_signed(offset)
+            .filter(|&number| number > 0)
+            .ok_or("Incorrect line number")?;
+        // Lines without code would otherwise resolve to the next line that has some
+        let has_code = self
+            .debug_data
+            .get_addr_for_line(Some(&file), number)
+            .and_then(|addr| self.debug_data.get_line_from_addr(addr))
+            .is_some_and(|line| line.number == number);
+        if !has_code {
+            return Err("No code at that line");
+        }
+        Ok(format!("{}:{}", file, number))
+    }
+
+    /// Returns the addresses to break at for `target`, given as `*<address>`, a line number
+    /// (optionally as `file:line`) or a function name (which may name several functions), or a
+    /// message saying why there are none. Calls can only be traced for a function name, and are
+    /// caught past the prologue, where the arguments can be read.
+    fn resolve_break_point(
+        &self,
+        target: &str,
+        action: &Action,
+    ) -> Result<Vec<usize>, &'static str> {
+        if *action == Action::TraceCalls {
+            let addrs = self.debug_data.get_addrs_for_function(None, target);
+            if addrs.is_empty() {
+                return Err("Function name not found");
+            }
+            return Ok(addrs
+                .into_iter()
+                .map(|addr| self.debug_data.get_prologue_end(addr).unwrap_or(addr))
+                .collect());
+        }
+        let file_line = target
+            .rsplit_once(':')
+            .and_then(|(file, line)| Some((file, line.parse::<usize>().ok()?)));
+        if let Some(address) = target.strip_prefix('*') {
+            parse_address(address)
+                .map(|addr| vec![addr])
+                .ok_or("Error address")
+        } else if let Some((file, line_number)) = file_line {
+            self.debug_data
+                .get_addr_for_line(Some(file), line_number)
+                .map(|addr| vec![addr])
+                .ok_or("Incorrect line number")
+        } else if let Ok(line_number) = target.parse::<usize>() {
+            self.debug_data
+                .get_addr_for_line(None, line_number)
+                .map(|addr| vec![addr])
+                .ok_or("Incorrect line number")
+        } else {
+            let addrs = self.debug_data.get_addrs_for_function(None, target);
+            if addrs.is_empty() {
+                Err("Function name not found")
+            } else {
+                Ok(addrs)
+            }
+        }
+    }
+
+    /// Reloads the debugging symbols if the target has been modified since they were loaded (e.g.
+    /// it was recompiled), and moves each breakpoint to wherever its line or function now is.
+    /// Must only be called while no inferior is running, since breakpoints are reset.
+    fn reload_if_changed(&mut self) {
+        let modified = modified_time(&self.target);
+        if modified == self.target_modified {
+            return;
+        }
+        // If the new target can't be read, keep using the old symbols; starting it will likely
+        // fail anyway
+        let debug_data = match read_debug_data(&self.target) {
+            Ok(debug_data) => debug_data,
+            Err(message) => {
+                self.output.error(&message);
+                return;
+            }
+        };
+        self.output.line(&format!(
+            "{} has changed, reloading debugging symbols",
+            self.output.paint(&self.target, Style::Location)
+        ));
+        self.debug_data = debug_data;
+        self.target_modified = modified;
+        self.resolve_break_points_again(true);
+    }
+
+    /// Moves the symbols to where the program was loaded in the newly started `inferior`, which
+    /// for a position-independent executable changes from run to run, and the breakpoints along
+    /// with them. Must be called before the breakpoints are inserted.
+    fn relocate(&mut self, inferior: &Inferior) {
+        let load_offset = match inferior.entry_point() {
+            Ok(entry_point) => entry_point.wrapping_sub(self.debug_data.entry_point()),
+            Err(err) => {
+                self.output.error(&format!(
+                    "Error finding where the program was loaded: {}",
+                    err
+                ));
+                return;
+            }
+        };
+        if load_offset == self.debug_data.load_offset() {
+            return;
+        }
+        self.debug_data.relocate(load_offset);
+        self.resolve_break_points_again(false);
+    }
+
+    /// Resolves the target of every breakpoint again, after the symbols have changed, deleting the
+    /// ones that no longer resolve. If `report_moves`, says where each of the others is now.
+    /// Must only be called while no inferior is running, since breakpoints are reset.
+    fn resolve_break_points_again(&mut self, report_moves: bool) {
+        self.break_points.clear();
+        let break_points = std::mem::take(&mut self.break_point_list);
+        for mut break_point in break_points {
+            let (number, target) = (break_point.number, &break_point.target);
+            match self.resolve_break_point(target, &break_point.action) {
+                Ok(addrs) => {
+                    if report_moves {
+                        self.output.line(&format!(
+                            "Moved break point {} ({}) to {}",
+                            number,
+                            target,
+                            describe_addrs(&addrs)
+                        ));
+                    }
+                    for &addr in &addrs {
+                        self.break_points.insert(addr, 0);
+                    }
+                    break_point.addrs = addrs;
+                    self.break_point_list.push(break_point);
                 }
+                Err(message) => self.output.line(&format!(
+                    "Deleted break point {} ({}): {}",
+                    number, target, message
+                )),
             }
         }
     }
 
     fn run_inferior(&mut self) {
-        let status = self
-            .inferior
-            .as_mut()
-            .unwrap()
-            .wake_up(&self.break_points)
-            .expect("Error getting inferior status");
+        let start = Instant::now();
+        let mut instructions = 0;
+        let previous_thread = self.get_inferior_as_ref().current_thread().id;
+        let mut watch_point_changes = Vec::new();
+        let status = loop {
+            let inferior = self.inferior.as_mut().unwrap();
+            RUNNING_INFERIOR.store(inferior.pid().as_raw(), Ordering::SeqCst);
+            let status = if self.count_instructions {
+                inferior
+                    .wake_up_counting(&self.break_points)
+                    .map(|(status, count)| {
+                        instructions += count;
+                        status
+                    })
+            } else {
+                inferior.wake_up(&self.break_points)
+            };
+            RUNNING_INFERIOR.store(0, Ordering::SeqCst);
+            if let Some(status) =
+                self.handle_process_event(status.expect("Error getting inferior status"))
+            {
+                if let Status::Stopped(nix::sys::signal::Signal::SIGTRAP, _) = status {
+                    if self.run_tracepoints() {
+                        continue;
+                    }
+                    match self.watch_point_changes() {
+                        // Writing what the memory already held isn't worth stopping for
+                        Some(changes) if changes.is_empty() => continue,
+                        Some(changes) => watch_point_changes = changes,
+                        None => {}
+                    }
+                }
+                break status;
+            }
+        };
 
         match status {
             Status::Stopped(signal, rip) => {
-                println!("Child stopped (signal {signal})");
-                let line = self.debug_data.get_line_from_addr(rip).unwrap();
-                println!("Stopped at {}", line);
+                let reason = if watch_point_changes.is_empty() {
+                    self.stop_reason(signal, false)
+                } else {
+                    StopReason::WatchPoint(watch_point_changes)
+                };
+                self.report_stop_reason(&reason, rip);
+                self.print_run_time(start.elapsed(), instructions);
+                let inferior = self.get_inferior_as_ref();
+                let thread = inferior.current_thread();
+                // JSON stop events always say which thread stopped
+                if thread.id != previous_thread && !self.output.json() {
+                    self.output.line(&format!(
+                        "[Switching to thread {} (LWP {})]",
+                        thread.id, thread.tid
+                    ));
+                }
+                self.print_stop_location(rip);
+            }
+            status @ (Status::Exited(_) | Status::Signaled(_)) => {
+                self.report_exit(&status);
+                self.print_run_time(start.elapsed(), instructions);
+                self.inferior = None;
+            }
+            Status::Forked(_) | Status::Execed(_) | Status::NewThread(..) => unreachable!(),
+        }
+    }
+
+    /// Says that the inferior exited, with `status`.
+    fn report_exit(&mut self, status: &Status) {
+        let event = match *status {
+            Status::Exited(exit_code) => json!({ "event": "exited", "status": exit_code }),
+            Status::Signaled(signal) => json!({ "event": "exited", "signal": signal.to_string() }),
+            _ => unreachable!(),
+        };
+        self.print_exit(&event);
+        self.record_event(event, None);
+    }
+
+    /// Says that the inferior exited, as the `exited` event `event` says.
+    fn print_exit(&self, event: &serde_json::Value) {
+        if self.output.json() {
+            self.output.event(event.clone());
+            return;
+        }
+        match &event["status"] {
+            serde_json::Value::Null => self.output.line(&format!(
+                "Child exited (signal {})",
+                event["signal"].as_str().unwrap_or("?")
+            )),
+            exit_code => self
+                .output
+                .line(&format!("Child exited (status: {exit_code})")),
+        }
+    }
+
+    /// Deals with the inferior forking, execing or starting a thread, none of which stop it as far
+    /// as the user is concerned. Returns any other status for the caller to report.
+    fn handle_process_event(&mut self, status: Status) -> Option<Status> {
+        let watching = !self.watch_points.is_empty();
+        let inferior = self.inferior.as_mut().unwrap();
+        match status {
+            Status::Forked(new_pid) => {
+                if self.follow_fork {
+                    self.output.line(&format!(
+                        "Attaching after fork to child process {}",
+                        new_pid
+                    ));
+                } else {
+                    self.output.line(&format!(
+                        "Detaching after fork from child process {}",
+                        new_pid
+                    ));
+                }
+                inferior
+                    .handle_fork(new_pid, self.follow_fork, &self.break_points)
+                    .expect("Error handling fork");
+                // Debug registers aren't inherited, so a followed child needs its watchpoints set
+                if self.follow_fork && watching {
+                    if let Err(err) = self.set_watch_points() {
+                        self.output
+                            .error(&format!("Error setting watchpoints: {}", err));
+                    }
+                }
+            }
+            Status::Execed(_rip) => {
+                self.output.line(&format!(
+                    "Process {} is executing a new program",
+                    inferior.pid()
+                ));
+            }
+            Status::NewThread(id, tid) => {
+                self.output
+                    .line(&format!("[New thread {} (LWP {})]", id, tid));
+                // Nor are they inherited by new threads
+                if watching {
+                    if let Err(err) = self.set_watch_points() {
+                        self.output
+                            .error(&format!("Error setting watchpoints: {}", err));
+                    }
+                }
+            }
+            status => return Some(status),
+        }
+        None
+    }
+
+    /// If the current thread stopped by hitting tracepoints, counts the hits and prints their
+    /// messages. Returns whether the inferior should carry on running, i.e. there are no ordinary
+    /// breakpoints at the same place.
+    fn run_tracepoints(&mut self) -> bool {
+        let addr = match self
+            .get_inferior_as_ref()
+            .break_point_hit(&self.break_points)
+        {
+            Ok(Some(addr)) => addr,
+            _ => return false,
+        };
+        let mut only_tracepoints = true;
+        let mut messages = Vec::new();
+        let mut call_traces = Vec::new();
+        for break_point in &mut self.break_point_list {
+            if !break_point.addrs.contains(&addr) {
+                continue;
+            }
+            match &break_point.action {
+                Action::Trace(format) => {
+                    break_point.hits += 1;
+                    messages.push((break_point.number, format.clone()));
+                }
+                Action::TraceCalls => {
+                    break_point.hits += 1;
+                    call_traces.push(break_point.number);
+                }
+                Action::Stop => only_tracepoints = false,
+            }
+        }
+        for (number, format) in messages {
+            let message = self.format_trace(&format);
+            self.print_trace(number, &message);
+        }
+        let traced = self.trace_return(addr).and_then(|()| {
+            call_traces
+                .iter()
+                .try_for_each(|&number| self.trace_call(addr, number))
+        });
+        if let Err(err) = traced {
+            self.output.error(&format!("Error tracing calls: {}", err));
+        }
+        only_tracepoints
+    }
+
+    /// Prints a message from tracepoint `number`, which in JSON mode is sent as a `trace` event.
+    fn print_trace(&self, number: usize, message: &str) {
+        if self.output.json() {
+            self.output.event(json!({
+                "event": "trace",
+                "tracepoint": number,
+                "message": message,
+            }));
+        } else {
+            self.output.line(message);
+        }
+    }
+
+    /// Logs a call to the function whose call trace `number` the current thread hit at `addr`,
+    /// with its arguments, indented by how many traced calls it is inside. Puts a breakpoint where
+    /// the call returns to, so that `trace_return` can log the return.
+    fn trace_call(&mut self, addr: usize, number: usize) -> Result<(), nix::Error> {
+        let func = match self.debug_data.get_function_containing(addr) {
+            Some(func) => func,
+            None => return Ok(()),
+        };
+        let inferior = self.get_inferior_as_ref();
+        let (return_addr, _, stack_pointer) =
+            inferior.caller_frame(func.address, &self.break_points)?;
+        let frame_pointer = inferior.frame_pointer()?;
+        let args: Vec<String> = func
+            .variables
+            .iter()
+            .filter(|var| var.parameter)
+            .map(|var| {
+                let value = self.read_variable(addr, frame_pointer, &var.name);
+                format!("{}={}", var.name, value)
+            })
+            .collect();
+        let function = func.name.clone();
+        let function_address = func.address;
+        let message = format!(
+            "{}-> {}({})",
+            "  ".repeat(self.traced_calls.len()),
+            function,
+            args.join(", ")
+        );
+        self.print_trace(number, &message);
+        if !self.break_points.contains_key(&return_addr) {
+            let orig_byte = self.get_inferior_as_mut().insert_break_point(return_addr)?;
+            self.break_points.insert(return_addr, orig_byte);
+        }
+        self.traced_calls.push(TracedCall {
+            number,
+            function,
+            function_address,
+            return_addr,
+            stack_pointer,
+        });
+        Ok(())
+    }
+
+    /// If the current thread has just returned from a traced call by hitting the breakpoint at
+    /// `addr`, logs the return and the value returned. Calls made inside it that never returned
+    /// (e.g. because of a longjmp) are forgotten.
+    fn trace_return(&mut self, addr: usize) -> Result<(), nix::Error> {
+        let stack_pointer = self.get_inferior_as_ref().stack_pointer()?;
+        // Recursive calls return to the same place, but each with the stack where it was
+        let depth = match self
+            .traced_calls
+            .iter()
+            .rposition(|call| call.return_addr == addr && call.stack_pointer == stack_pointer)
+        {
+            Some(depth) => depth,
+            None => return Ok(()),
+        };
+        let call = self.traced_calls.drain(depth..).next().unwrap();
+        // Nothing is shown for functions returning void
+        let value = match self
+            .debug_data
+            .get_function_containing(call.function_address)
+            .and_then(|func| self.debug_data.get_return_type(func))
+        {
+            Some(dtype) => {
+                let rax = self.get_inferior_as_ref().return_value()?;
+                let value = values::format_return_value(&self.debug_data, dtype, rax);
+                format!(
+                    " = {}",
+                    value.unwrap_or_else(|| format!("<{}>", dtype.name))
+                )
+            }
+            None => String::new(),
+        };
+        let message = format!("{}<- {}{}", "  ".repeat(depth), call.function, value);
+        self.print_trace(call.number, &message);
+        Ok(())
+    }
+
+    /// Forgets the traced calls in progress, along with the breakpoints catching their returns,
+    /// before the inferior is started again.
+    fn forget_traced_calls(&mut self) {
+        self.traced_calls.clear();
+        let break_point_list = &self.break_point_list;
+        self.break_points.retain(|addr, _| {
+            break_point_list
+                .iter()
+                .any(|break_point| break_point.addrs.contains(addr))
+        });
+    }
+
+    /// Fills in a tracepoint's format: each `{expression}` is replaced by its value, or the error
+    /// evaluating it, and `{{` and `}}` stand for braces.
+    fn format_trace(&self, format: &str) -> String {
+        let mut message = String::new();
+        let mut rest = format;
+        while let Some(brace) = rest.find(['{', '}']) {
+            message.push_str(&rest[..brace]);
+            let (open, after) = rest[brace..].split_at(1);
+            if after.starts_with(open) {
+                message.push_str(open);
+                rest = &after[1..];
+                continue;
+            }
+            let end = match after.find('}') {
+                Some(end) if open == "{" => end,
+                // An unmatched brace is printed as it is
+                _ => {
+                    message.push_str(open);
+                    rest = after;
+                    continue;
+                }
+            };
+            match self.evaluate_expression(&after[..end], Format::Natural) {
+                Ok(value) => message.push_str(&value),
+                Err(err) => message.push_str(&format!("<{}>", err)),
+            }
+            rest = &after[end + 1..];
+        }
+        message.push_str(rest);
+        message
+    }
+
+    /// Works out why the current thread stopped with `signal`. Traps that aren't breakpoints are
+    /// the end of a step if `stepping`, and reported as signals otherwise.
+    fn stop_reason(&self, signal: nix::sys::signal::Signal, stepping: bool) -> StopReason {
+        if signal != nix::sys::signal::Signal::SIGTRAP {
+            return StopReason::Signal(signal);
+        }
+        if let Ok(Some(syscall)) = self.get_inferior_as_ref().syscall_stop() {
+            return StopReason::Syscall(syscall);
+        }
+        let hit = self
+            .get_inferior_as_ref()
+            .break_point_hit(&self.break_points);
+        match hit {
+            // Tracepoints don't stop the inferior, so only count if something else is there too
+            Ok(Some(addr))
+                if self.break_point_list.iter().any(|break_point| {
+                    break_point.addrs.contains(&addr) && break_point.action == Action::Stop
+                }) =>
+            {
+                StopReason::BreakPoint(addr)
+            }
+            _ if stepping => StopReason::Step,
+            _ => StopReason::Signal(signal),
+        }
+    }
+
+    /// Says why the inferior stopped at `rip`. For breakpoints, this counts the hit and says which
+    /// breakpoint it was and how many times it has been hit; tracepoints are left to
+    /// `run_tracepoints`.
+    fn report_stop_reason(&mut self, reason: &StopReason, rip: usize) {
+        let mut hit = Vec::new();
+        let mut syscall = serde_json::Value::Null;
+        match *reason {
+            StopReason::BreakPoint(addr) => {
+                let location = self.describe_stop(addr);
+                for break_point in &mut self.break_point_list {
+                    if !break_point.addrs.contains(&addr) || break_point.action != Action::Stop {
+                        continue;
+                    }
+                    break_point.hits += 1;
+                    hit.push(json!({ "number": break_point.number, "hits": break_point.hits }));
+                    if self.output.json() {
+                        continue;
+                    }
+                    self.output.line(&describe_hit(
+                        break_point.number,
+                        &location,
+                        break_point.hits,
+                    ));
+                }
+            }
+            StopReason::Syscall(ref stop) => {
+                syscall = syscall_json(stop);
+                let number = syscall["number"].as_u64().unwrap_or(0);
+                for catch_point in &mut self.catch_points {
+                    if !catch_point.syscalls.is_empty() && !catch_point.syscalls.contains(&number) {
+                        continue;
+                    }
+                    catch_point.hits += 1;
+                    hit.push(json!({ "number": catch_point.number, "hits": catch_point.hits }));
+                }
+                if !self.output.json() {
+                    self.print_syscall_hit(&syscall, &hit);
+                }
+            }
+            _ if self.output.json() => {}
+            StopReason::WatchPoint(ref changes) => {
+                for change in changes {
+                    self.print_watch_hit(change);
+                }
+            }
+            StopReason::Step => self
+                .output
+                .line(&format!("Stepped to {}", self.describe_stop(rip))),
+            StopReason::Signal(signal) => self.output.line(&format!("Received signal {}", signal)),
+        }
+        // Past a breakpoint's int3, rip is one byte on from where the inferior stopped
+        let pc = match *reason {
+            StopReason::BreakPoint(addr) => addr,
+            _ => rip,
+        };
+        let mut event = self.location_event("stop", pc);
+        match *reason {
+            StopReason::BreakPoint(_) => {
+                event["reason"] = json!("breakpoint");
+                event["breakpoints"] = json!(hit);
+            }
+            StopReason::WatchPoint(ref changes) => {
+                event["reason"] = json!("watchpoint");
+                event["watchpoints"] = json!(changes);
+            }
+            StopReason::Syscall(_) => {
+                event["reason"] = json!("syscall");
+                event["syscall"] = syscall;
+                event["catchpoints"] = json!(hit);
+            }
+            StopReason::Step => event["reason"] = json!("step"),
+            StopReason::Signal(signal) => {
+                event["reason"] = json!("signal");
+                event["signal"] = json!(signal.to_string());
+            }
+        }
+        if self.output.json() {
+            self.output.event(event.clone());
+        }
+        self.record_event(event, Some(rip));
+    }
+
+    /// Starts recording stops to the file at `path` (replacing whatever it held), or stops
+    /// recording if there is no path. Each stop or exit is written as a line holding its JSON
+    /// event (as `--json-rpc` would print it) with the current thread's registers added, which
+    /// `replay` reads back.
+    fn record(&mut self, path: Option<String>) {
+        if let Some(recording) = self.recording.take() {
+            self.output.line(&format!(
+                "Recorded {} stop{} to {}",
+                recording.stops,
+                if recording.stops == 1 { "" } else { "s" },
+                recording.path
+            ));
+        } else if path.is_none() {
+            self.output
+                .error("Not recording (use record <file> to start)");
+        }
+        let path = match path {
+            Some(path) => path,
+            None => return,
+        };
+        match fs::File::create(&path) {
+            Ok(file) => {
+                self.output.line(&format!("Recording stops to {}", path));
+                self.recording = Some(Recording {
+                    path,
+                    file,
+                    stops: 0,
+                });
+            }
+            Err(err) => self
+                .output
+                .error(&format!("Could not create {}: {}", path, err)),
+        }
+    }
+
+    /// Adds the stop or exit `event` to the recording, if one is being made. Stops at `rip` are
+    /// recorded along with it and the current thread's registers.
+    fn record_event(&mut self, mut event: serde_json::Value, rip: Option<usize>) {
+        if self.recording.is_none() {
+            return;
+        }
+        if let Some(rip) = rip {
+            event["rip"] = json!(rip);
+            if let Ok(regs) = self.get_inferior_as_ref().registers() {
+                event["registers"] = registers_json(&regs);
+            }
+        }
+        let recording = self.recording.as_mut().unwrap();
+        match writeln!(recording.file, "{}", event) {
+            Ok(()) => recording.stops += 1,
+            Err(err) => {
+                let message = format!(
+                    "Error writing to {}, stopped recording: {}",
+                    recording.path, err
+                );
+                self.recording = None;
+                self.output.error(&message);
+            }
+        }
+    }
+
+    /// Loads the recording in the file at `path` and shows its first stop, or shows the next stop
+    /// of the loaded recording if there is no path. Stops are shown as they were when they
+    /// happened, along with the registers recorded with them, without running the inferior.
+    fn replay(&mut self, path: Option<String>) {
+        if let Some(path) = path {
+            let contents = match fs::read_to_string(&path) {
+                Ok(contents) => contents,
+                Err(err) => {
+                    self.output
+                        .error(&format!("Could not read {}: {}", path, err));
+                    return;
+                }
+            };
+            let stops: Result<Vec<serde_json::Value>, _> = contents
+                .lines()
+                .filter(|line| !line.trim().is_empty())
+                .map(serde_json::from_str)
+                .collect();
+            match stops {
+                Ok(stops) if !stops.is_empty() => self.replay = Some(Replay { stops, next: 0 }),
+                Ok(_) => {
+                    self.output
+                        .error(&format!("{} has no recorded stops", path));
+                    return;
+                }
+                Err(err) => {
+                    self.output
+                        .error(&format!("{} is not a recording: {}", path, err));
+                    return;
+                }
+            }
+        }
+        let replay = match &mut self.replay {
+            Some(replay) => replay,
+            None => {
+                self.output
+                    .error("No recording to replay (use replay <file> to load one)");
+                return;
+            }
+        };
+        let event = match replay.stops.get(replay.next) {
+            Some(event) => event.clone(),
+            None => {
+                self.replay = None;
+                self.output.line("End of recording");
+                return;
+            }
+        };
+        replay.next += 1;
+        let (number, count) = (replay.next, replay.stops.len());
+        if self.output.json() {
+            let mut event = event;
+            event["replay"] = json!(number);
+            self.output.event(event);
+            return;
+        }
+        self.output
+            .line(&format!("Recorded stop {} of {}", number, count));
+        if event["event"] == "exited" {
+            self.print_exit(&event);
+            return;
+        }
+        let address = |field: &str| event[field].as_u64().unwrap_or(0) as usize;
+        let (pc, rip) = (address("pc"), address("rip"));
+        match event["reason"].as_str() {
+            Some("breakpoint") => {
+                let location = self.describe_stop(pc);
+                for hit in event["breakpoints"].as_array().into_iter().flatten() {
+                    self.output.line(&describe_hit(
+                        hit["number"].as_u64().unwrap_or(0) as usize,
+                        &location,
+                        hit["hits"].as_u64().unwrap_or(0) as usize,
+                    ));
+                }
+            }
+            Some("watchpoint") => {
+                for change in event["watchpoints"].as_array().into_iter().flatten() {
+                    self.print_watch_hit(change);
+                }
+            }
+            Some("syscall") => {
+                let hits = event["catchpoints"].as_array().cloned().unwrap_or_default();
+                self.print_syscall_hit(&event["syscall"], &hits);
+            }
+            Some("step") => self
+                .output
+                .line(&format!("Stepped to {}", self.describe_stop(rip))),
+            Some("signal") => self.output.line(&format!(
+                "Received signal {}",
+                event["signal"].as_str().unwrap_or("?")
+            )),
+            _ => {}
+        }
+        self.print_stop_location(rip);
+        self.print_recorded_registers(&event["registers"]);
+    }
+
+    /// Shows the registers recorded with a stop (see `registers_json`), three to a line.
+    fn print_recorded_registers(&mut self, registers: &serde_json::Value) {
+        let values: Vec<String> = RECORDED_REGISTERS
+            .iter()
+            .filter_map(|name| Some(format!("{:<7}{:#018x}", name, registers[name].as_u64()?)))
+            .collect();
+        for row in values.chunks(3) {
+            self.output.line(&row.join("    "));
+        }
+    }
+
+    /// Starts a JSON event of kind `event` about the current thread being at `rip`, saying where
+    /// that is as precisely as the debug info allows.
+    fn location_event(&self, event: &str, rip: usize) -> serde_json::Value {
+        let line = self.debug_data.get_line_from_addr(rip);
+        json!({
+            "event": event,
+            "thread": self.get_inferior_as_ref().current_thread().id,
+            "pc": rip,
+            "function": self.debug_data.get_function_from_addr(rip),
+            "file": line.as_ref().map(|line| &line.file),
+            "line": line.as_ref().map(|line| line.number),
+        })
+    }
+
+    /// Describes where the inferior stopped at `addr` as "function (file:line)", or as precisely as
+    /// the debug info allows.
+    fn describe_stop(&self, addr: usize) -> String {
+        match (
+            self.debug_data.get_function_from_addr(addr),
+            self.debug_data.get_line_from_addr(addr),
+        ) {
+            (Some(func), Some(line)) => format!("{} ({})", func, line),
+            _ => self.describe_location(addr),
+        }
+    }
+
+    /// Reports where the inferior is stopped, along with the source code of that line. JSON stop
+    /// events say where already, so this prints nothing in JSON mode.
+    fn print_stop_location(&self, rip: usize) {
+        if self.output.json() {
+            return;
+        }
+        // The inferior may be stopped somewhere without debug info, e.g. inside libc
+        let line = match self.debug_data.get_line_from_addr(rip) {
+            Some(line) => line,
+            None => {
+                match self.debug_data.get_function_from_addr(rip) {
+                    Some(func) => self
+                        .output
+                        .line(&format!("Stopped at {:#x} in {}", rip, func)),
+                    None => self.output.line(&format!("Stopped at {:#x}", rip)),
+                }
+                return;
+            }
+        };
+        self.output.line(&format!(
+            "Stopped at {}",
+            self.output.paint(&line.to_string(), Style::Location)
+        ));
+
+        if let Some(code) = source_line(&line.file, line.number) {
+            // print source code of the line
+            self.output
+                .line(&self.output.paint(&code, Style::CurrentLine));
+        }
+    }
+
+    /// Prints the source of the function the current thread is in, each line followed by the
+    /// instructions compiled from it, in the order they are in memory, and marks the instruction
+    /// the thread is stopped at. Long functions are shown a page at a time.
+    fn list_mixed(&self) -> Result<(), String> {
+        let inferior = self.get_inferior_as_ref();
+        let stop_address = inferior
+            .stop_address(&self.break_points)
+            .map_err(|err| format!("Error reading inferior registers: {}", err))?;
+        let func = self
+            .debug_data
+            .get_function_containing(stop_address)
+            .ok_or("No debugging information for the current function")?;
+        let mut code = inferior
+            .read_memory(func.address, func.text_length)
+            .map_err(|err| format!("Error reading inferior memory: {}", err))?;
+        // Breakpoints are int3 instructions in memory; show what they replaced
+        for (&addr, &orig_byte) in &self.break_points {
+            if addr >= func.address && addr < func.address + func.text_length {
+                code[addr - func.address] = orig_byte;
+            }
+        }
+
+        let mut lines = Vec::new();
+        let mut current_line = None;
+        for instruction in disassembler::disassemble(&code, func.address)? {
+            let line = self.debug_data.get_line_from_addr(instruction.address);
+            if let Some(line) = line.filter(|line| Some(line.number) != current_line) {
+                let code = source_line(&line.file, line.number).unwrap_or_default();
+                lines.push(format!("{}\t{}", line.number, code));
+                current_line = Some(line.number);
+            }
+            let location = format!(
+                "{:#x} <+{}>",
+                instruction.address,
+                instruction.address - func.address
+            );
+            if instruction.address == stop_address {
+                lines.push(format!(
+                    "=> {}:\t{}",
+                    self.output.paint(&location, Style::Address),
+                    self.output.paint(&instruction.text, Style::CurrentLine)
+                ));
+            } else {
+                lines.push(format!(
+                    "   {}:\t{}",
+                    self.output.paint(&location, Style::Address),
+                    instruction.text
+                ));
+            }
+        }
+        self.output.print_paged(&lines);
+        Ok(())
+    }
+
+    /// Prints the whole of the source file called `file` (one of the target's compilation units),
+    /// with line numbers, a page at a time.
+    fn list_file(&self, file: &str) -> Result<(), String> {
+        let path = self
+            .debug_data
+            .find_source_file(file)
+            .ok_or_else(|| format!("No source file named {}.", file))?;
+        let source =
+            fs::read_to_string(&path).map_err(|err| format!("Could not read {}: {}", path, err))?;
+        let lines: Vec<String> = source
+            .lines()
+            .enumerate()
+            .map(|(i, code)| format!("{}\t{}", i + 1, code))
+            .collect();
+        self.output.print_paged(&lines);
+        Ok(())
+    }
+
+    /// Prints the current thread's stack, innermost frame first, a page at a time if it is long.
+    fn print_backtrace(&self) -> Result<(), nix::Error> {
+        let frames = self
+            .get_inferior_as_ref()
+            .backtrace(&self.debug_data, &self.break_points)?;
+        let lines: Vec<String> = frames
+            .iter()
+            .map(|frame| match (&frame.function, &frame.line) {
+                (Some(function), Some(line)) => format!(
+                    "{} {}",
+                    self.output.paint(function, Style::Function),
+                    self.output.paint(&line.to_string(), Style::Location)
+                ),
+                // The function is ours, but its line table is missing
+                (Some(function), None) => {
+                    format!("{} ??", self.output.paint(function, Style::Function))
+                }
+                (None, _) => format!(
+                    "{} in ??",
+                    self.output
+                        .paint(&format!("{:#x}", frame.address), Style::Address)
+                ),
+            })
+            .collect();
+        self.output.print_paged(&lines);
+        Ok(())
+    }
+
+    /// Makes the function the current thread is in return to its caller right away, with the
+    /// value of `value` (evaluated in the returning function) as its return value if given.
+    fn force_return(&mut self, value: Option<&str>) {
+        let inferior = self.get_inferior_as_ref();
+        let rip = match inferior.instruction_pointer() {
+            Ok(rip) => rip,
+            Err(err) => {
+                self.output
+                    .error(&format!("Error reading inferior registers: {}", err));
+                return;
+            }
+        };
+        let (func, function_start) = match self.debug_data.get_function_containing(rip) {
+            Some(func) => (func.name.clone(), func.address),
+            None => {
+                self.output.error(&format!(
+                    "Can't return from {:#x}: no debug info for this function",
+                    rip
+                ));
+                return;
+            }
+        };
+        if func == "main" {
+            self.output
+                .error("Can't return from main; use kill to end the program");
+            return;
+        }
+        let return_value = match value {
+            Some(expression) => match self.evaluate_int(expression) {
+                Ok(value) => Some(value as u64),
+                Err(err) => {
+                    self.output.error(&err.to_string());
+                    return;
+                }
+            },
+            None => None,
+        };
+
+        let break_points = &self.break_points;
+        let inferior = self.inferior.as_mut().unwrap();
+        match inferior.force_return(function_start, return_value, break_points) {
+            Ok(rip) if self.output.json() => {
+                let mut event = self.location_event("stop", rip);
+                event["reason"] = json!("return");
+                event["returned_from"] = json!(func);
+                self.output.event(event);
+            }
+            Ok(rip) => {
+                self.output.line(&format!("Returned from {}", func));
+                self.print_stop_location(rip);
+            }
+            Err(err) => self
+                .output
+                .error(&format!("Error returning from {}: {}", func, err)),
+        }
+    }
+
+    /// Runs the current thread until it reaches a different source line. Calls into code without
+    /// line info (such as libc) are always run until they return, rather than being stepped
+    /// through instruction by instruction; with `over_calls`, so are calls into our own functions.
+    fn step_line(&mut self, over_calls: bool) -> Result<(), nix::Error> {
+        let inferior = self.inferior.as_mut().unwrap();
+        let start = match self
+            .debug_data
+            .get_line_from_addr(inferior.stop_address(&self.break_points)?)
+        {
+            Some(line) => line,
+            None => {
+                self.output.error("No line info here; use continue instead");
+                return Ok(());
+            }
+        };
+        let status = loop {
+            let inferior = self.inferior.as_mut().unwrap();
+            let from = inferior.stop_address(&self.break_points)?;
+            let stack_pointer = inferior.stack_pointer()?;
+            let status = inferior.step_instruction(&self.break_points)?;
+            let pc = match status {
+                Status::Stopped(_, _) => inferior.stop_address(&self.break_points)?,
+                status => break status,
+            };
+            let line = self.debug_data.get_line_from_addr(pc);
+            // A call pushes the address of the instruction after it, which is at most 7 bytes on
+            // from the call
+            let return_addr = inferior.read_word(inferior.stack_pointer()?)?;
+            let called = inferior.stack_pointer()? + 8 == stack_pointer
+                && return_addr > from
+                && return_addr - from <= 7;
+            if called && (line.is_none() || over_calls) {
+                match self.finish_call(return_addr, stack_pointer)? {
+                    Status::Stopped(_, rip) if rip == return_addr => {}
+                    // Something else (e.g. a breakpoint) stopped the inferior first
+                    status => break status,
+                }
+            } else if line.is_none() {
+                // Left our code without calling anything, e.g. by returning from main, so there
+                // is no line to stop at
+                self.run_inferior();
+                return Ok(());
+            }
+            let pc = self
+                .get_inferior_as_ref()
+                .stop_address(&self.break_points)?;
+            match self.debug_data.get_line_from_addr(pc) {
+                Some(line) if line.file == start.file && line.number == start.number => {}
+                _ => break Status::Stopped(nix::sys::signal::Signal::SIGTRAP, pc),
+            }
+        };
+        self.report_status(status);
+        Ok(())
+    }
+
+    /// Runs the current thread until the function it is in returns to its caller, then shows the
+    /// value it returned.
+    fn finish(&mut self) -> Result<(), nix::Error> {
+        let inferior = self.get_inferior_as_ref();
+        let pc = inferior.stop_address(&self.break_points)?;
+        let func = match self.debug_data.get_function_containing(pc) {
+            Some(func) => func.clone(),
+            None => {
+                self.output.error(&format!(
+                    "Can't finish {:#x}: no debug info for this function",
+                    pc
+                ));
+                return Ok(());
+            }
+        };
+        if func.name == "main" {
+            self.output.error("Can't finish main; use continue instead");
+            return Ok(());
+        }
+        let (return_addr, _, caller_rsp) =
+            inferior.caller_frame(func.address, &self.break_points)?;
+        self.output
+            .line(&format!("Run till exit from {}", func.name));
+        let status = self.finish_call(return_addr, caller_rsp)?;
+        let returned = matches!(status, Status::Stopped(_, rip) if rip == return_addr);
+        self.report_status(status);
+        if !returned {
+            // Something else (e.g. a breakpoint) stopped the inferior first
+            return Ok(());
+        }
+        // Nothing is shown for functions returning void
+        if let Some(dtype) = self.debug_data.get_return_type(&func) {
+            let rax = self.get_inferior_as_ref().return_value()?;
+            match values::format_return_value(&self.debug_data, dtype, rax) {
+                Some(value) => self.output.line(&format!("Value returned is {}", value)),
+                None => self.output.line(&format!(
+                    "Can't show the {} returned by {}",
+                    dtype.name, func.name
+                )),
+            }
+        }
+        Ok(())
+    }
 
-                let path = &line.file;
-                let line_number = line.number - 1;
-                if let Some(code) = fs::read_to_string(path).unwrap().lines().nth(line_number) {
-                    println!("{}", code); // print source code of the line
+    /// Runs the current thread until it reaches line `target` of the current source file, with a
+    /// temporary breakpoint that is removed once it stops. With `this_frame`, the line is only
+    /// stopped at in the current frame, so recursive calls that reach it first run on.
+    fn run_until(&mut self, target: &str, this_frame: bool) -> Result<(), nix::Error> {
+        let line_number = match target.parse::<usize>() {
+            Ok(line_number) => line_number,
+            Err(_) => {
+                self.output
+                    .error(&format!("Expected a line number, got {}", target));
+                return Ok(());
+            }
+        };
+        let pc = self
+            .get_inferior_as_ref()
+            .stop_address(&self.break_points)?;
+        // The line info has the file's full path, which the symbols may only know relative to
+        // where it was compiled
+        let file = self.debug_data.get_line_from_addr(pc).and_then(|line| {
+            let name = Path::new(&line.file).file_name()?;
+            Some(name.to_string_lossy().into_owned())
+        });
+        let addr = match self
+            .debug_data
+            .get_addr_for_line(file.as_deref(), line_number)
+        {
+            Some(addr) => addr,
+            None => {
+                self.output.error("Incorrect line number");
+                return Ok(());
+            }
+        };
+        let frame = self.frame_address()?;
+        loop {
+            let inferior = self.inferior.as_mut().unwrap();
+            RUNNING_INFERIOR.store(inferior.pid().as_raw(), Ordering::SeqCst);
+            let status = inferior.run_to(addr, &self.break_points);
+            RUNNING_INFERIOR.store(0, Ordering::SeqCst);
+            match self.handle_process_event(status?) {
+                Some(Status::Stopped(_, rip))
+                    if rip == addr && this_frame && self.frame_address()? < frame =>
+                {
+                    // Move off the line, or the temporary breakpoint would stop us right here again
+                    let inferior = self.inferior.as_mut().unwrap();
+                    match inferior.step_instruction(&self.break_points)? {
+                        Status::Stopped(_, _) => {}
+                        status => {
+                            self.report_status(status);
+                            return Ok(());
+                        }
+                    }
+                }
+                Some(Status::Stopped(nix::sys::signal::Signal::SIGTRAP, rip))
+                    if rip != addr && self.run_tracepoints() => {}
+                Some(status) => {
+                    self.report_status(status);
+                    return Ok(());
                 }
+                None => {}
+            }
+        }
+    }
+
+    /// Returns the address of the current thread's innermost frame: the stack pointer from before
+    /// the call into it. Frames deeper down the stack have lower addresses. Returns None in code
+    /// without debug info.
+    fn frame_address(&self) -> Result<Option<usize>, nix::Error> {
+        let inferior = self.get_inferior_as_ref();
+        let pc = inferior.stop_address(&self.break_points)?;
+        match self.debug_data.get_function_containing(pc) {
+            Some(func) => {
+                let (_, _, caller_rsp) = inferior.caller_frame(func.address, &self.break_points)?;
+                Ok(Some(caller_rsp))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Reports how the inferior came to stop after stepping or finishing a function.
+    fn report_status(&mut self, status: Status) {
+        match status {
+            Status::Stopped(signal, rip) => {
+                let reason = self.stop_reason(signal, true);
+                self.report_stop_reason(&reason, rip);
+                self.print_stop_location(rip);
+            }
+            status @ (Status::Exited(_) | Status::Signaled(_)) => {
+                self.report_exit(&status);
+                self.inferior = None;
+            }
+            Status::Forked(_) | Status::Execed(_) | Status::NewThread(..) => unreachable!(),
+        }
+    }
+
+    /// Runs the inferior until the call that pushed `return_addr` returns. `stack_pointer` is the
+    /// stack pointer from before the call, which tells the return of this call apart from the
+    /// returns of any recursive calls it makes.
+    fn finish_call(
+        &mut self,
+        return_addr: usize,
+        stack_pointer: usize,
+    ) -> Result<Status, nix::Error> {
+        loop {
+            let inferior = self.inferior.as_mut().unwrap();
+            RUNNING_INFERIOR.store(inferior.pid().as_raw(), Ordering::SeqCst);
+            let status = inferior.run_to(return_addr, &self.break_points);
+            RUNNING_INFERIOR.store(0, Ordering::SeqCst);
+            match self.handle_process_event(status?) {
+                Some(Status::Stopped(_, rip))
+                    if rip == return_addr
+                        && self.get_inferior_as_ref().stack_pointer()? < stack_pointer => {}
+                Some(status) => return Ok(status),
+                None => {}
+            }
+        }
+    }
+
+    /// Lists the threads of the inferior and where each one is, marking the current thread.
+    fn print_threads(&self) {
+        let inferior = self.get_inferior_as_ref();
+        let current = inferior.current_thread().id;
+        for thread in inferior.threads() {
+            let location = match inferior.thread_instruction_pointer(thread.tid) {
+                Ok(rip) => self.describe_location(rip),
+                Err(err) => format!("<error reading registers: {}>", err),
+            };
+            let marker = if thread.id == current { '*' } else { ' ' };
+            self.output.line(&format!(
+                "{} {} (LWP {}) {}",
+                marker, thread.id, thread.tid, location
+            ));
+        }
+    }
+
+    /// Makes thread number `id` the one whose registers and stack are shown, or reports which
+    /// thread that is if no id is given.
+    fn select_thread(&mut self, id: Option<usize>) {
+        let inferior = self.get_inferior_as_mut();
+        let message = match id {
+            None => "Current thread is",
+            Some(id) if inferior.select_thread(id) => "Switching to thread",
+            Some(id) => {
+                self.output.error(&format!("Invalid thread ID: {}", id));
+                return;
             }
-            Status::Exited(exit_code) => {
-                println!("Child exited (status: {exit_code})");
+        };
+        let inferior = self.get_inferior_as_ref();
+        let thread = inferior.current_thread();
+        self.output
+            .line(&format!("[{} {} (LWP {})]", message, thread.id, thread.tid));
+        if let Ok(rip) = inferior.instruction_pointer() {
+            self.output.line(&self.describe_location(rip));
+        }
+    }
+
+    /// Describes where `rip` is, as precisely as the debug info allows.
+    fn describe_location(&self, rip: usize) -> String {
+        match (
+            self.debug_data.get_function_from_addr(rip),
+            self.debug_data.get_line_from_addr(rip),
+        ) {
+            (Some(func), Some(line)) => format!("{} {}", func, line),
+            (Some(func), None) => format!("{:#x} in {}", rip, func),
+            (None, _) => format!("{:#x} in ??", rip),
+        }
+    }
+
+    /// Reports how long the inferior ran for since it was last resumed.
+    fn print_run_time(&self, elapsed: Duration, instructions: u64) {
+        if self.count_instructions {
+            self.output.line(&format!(
+                "Ran for {:?} ({} instructions)",
+                elapsed, instructions
+            ));
+        } else {
+            self.output.line(&format!("Ran for {:?}", elapsed));
+        }
+    }
+
+    /// Returns the address and type of the variable `name` as seen from `rip`, in the frame whose
+    /// frame pointer is `frame_pointer`.
+    fn lookup_variable(
+        &self,
+        rip: usize,
+        frame_pointer: usize,
+        name: &str,
+    ) -> Option<(usize, &Type)> {
+        let var = self.debug_data.get_variable(rip, name)?;
+        let addr = match var.location {
+            Location::Address(addr) => addr,
+            // Frame-relative locations are given relative to the canonical frame address, which
+            // sits just above the saved %rbp and the return address
+            Location::FramePointerOffset(offset) => (frame_pointer as isize + 16 + offset) as usize,
+        };
+        Some((addr, &var.entity_type))
+    }
+
+    /// Prints the value of each local variable in scope where the inferior is stopped.
+    fn print_locals(&self) -> Result<(), nix::Error> {
+        let inferior = self.get_inferior_as_ref();
+        let rip = inferior.instruction_pointer()?;
+        let frame_pointer = inferior.frame_pointer()?;
+        let locals = self.debug_data.get_locals(rip);
+        if locals.is_empty() {
+            self.output.line("No locals.");
+        }
+        for var in locals {
+            let value = self.read_variable(rip, frame_pointer, &var.name);
+            self.print_value(&var.name, &value);
+        }
+        Ok(())
+    }
+
+    /// Prints every floating-point register of the current thread.
+    fn print_float_registers(&self) -> Result<(), nix::Error> {
+        let regs = self.get_inferior_as_ref().fp_registers()?;
+        for name in fp_registers::names() {
+            if let Some(value) = fp_registers::format(&regs, &name) {
+                self.print_value(&name, &value);
             }
-            Status::Signaled(signal) => {
-                println!("Child exited (signal {signal})");
+        }
+        Ok(())
+    }
+
+    /// Formats the value of the variable `name` as seen from `rip`, in the frame whose frame
+    /// pointer is `frame_pointer`, or says why it can't be read.
+    fn read_variable(&self, rip: usize, frame_pointer: usize, name: &str) -> String {
+        let inferior = self.get_inferior_as_ref();
+        let read_memory = |addr, len| inferior.read_memory(addr, len);
+        match self.lookup_variable(rip, frame_pointer, name) {
+            Some((addr, dtype)) => {
+                values::format_value(&self.debug_data, dtype, addr, Format::Natural, &read_memory)
+                    .unwrap_or_else(|err| format!("<error reading memory: {}>", err))
             }
+            None => "<unavailable>".to_string(),
+        }
+    }
+
+    /// Prints the value of `expression`, which in JSON mode is sent as a `value` event.
+    fn print_value(&self, expression: &str, value: &str) {
+        if self.output.json() {
+            self.output.event(json!({
+                "event": "value",
+                "expression": expression,
+                "value": value,
+            }));
+        } else {
+            self.output.line(&format!("{} = {}", expression, value));
+        }
+    }
+
+    /// Evaluates `expression` where the inferior is stopped, as an integer.
+    fn evaluate_int(&self, expression: &str) -> Result<i64, ExprError> {
+        let inferior = self.get_inferior_as_ref();
+        let rip = inferior.instruction_pointer()?;
+        let frame_pointer = inferior.frame_pointer()?;
+        let scope = Scope {
+            debug_data: &self.debug_data,
+            lookup: |name: &str| self.lookup_variable(rip, frame_pointer, name),
+            read_memory: |addr, len| inferior.read_memory(addr, len),
+        };
+        scope.to_int(&scope.evaluate(expression)?)
+    }
+
+    /// Evaluates `expression` where the inferior is stopped and prints the result.
+    fn print_expression(&self, expression: &str, format: Format) -> Result<(), ExprError> {
+        let value = self.evaluate_expression(expression, format)?;
+        self.print_value(expression, &value);
+        Ok(())
+    }
+
+    /// Evaluates `expression` where the current thread is stopped, and formats its value.
+    fn evaluate_expression(&self, expression: &str, format: Format) -> Result<String, ExprError> {
+        let inferior = self.get_inferior_as_ref();
+        // Floating-point registers, like $xmm0, are shown whole rather than evaluated
+        if let Some(name) = expression.trim().strip_prefix('$') {
+            let regs = inferior.fp_registers()?;
+            return fp_registers::format(&regs, name)
+                .ok_or_else(|| ExprError::NoSymbol(expression.trim().to_string()));
         }
+        let rip = inferior.instruction_pointer()?;
+        let frame_pointer = inferior.frame_pointer()?;
+        let scope = Scope {
+            debug_data: &self.debug_data,
+            lookup: |name: &str| self.lookup_variable(rip, frame_pointer, name),
+            read_memory: |addr, len| inferior.read_memory(addr, len),
+        };
+        let value = match scope.evaluate(expression)? {
+            Value::Int(number) if format == Format::Hex => format!("{:#x}", number),
+            Value::Int(number) => number.to_string(),
+            Value::Object { addr, dtype } => {
+                values::format_value(&self.debug_data, dtype, addr, format, &scope.read_memory)?
+            }
+        };
+        Ok(value)
     }
 
     fn get_inferior_as_mut(&mut self) -> &mut Inferior {
@@ -161,17 +2197,63 @@ impl Debugger {
     ///
     /// You don't need to read, understand, or modify this function.
     fn get_next_command(&mut self) -> DebuggerCommand {
+        loop {
+            let line = match self.read_line("(deet) ") {
+                Some(line) => line,
+                // User pressed ctrl+d, which is the equivalent of "quit" for our purposes
+                None => return DebuggerCommand::Quit,
+            };
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            if let Some(cmd) = DebuggerCommand::from_tokens(&tokens) {
+                return cmd;
+            }
+            match self.macros.get(tokens[0]) {
+                Some(body) if self.macro_expansions < MAX_MACRO_EXPANSIONS => {
+                    self.macro_expansions += 1;
+                    // Everything after the name is the macro's argument
+                    let arg = tokens[1..].join(" ");
+                    for line in body.iter().rev() {
+                        self.script.push_front(line.replace("$arg0", &arg));
+                    }
+                }
+                Some(_) => {
+                    self.output.error(&format!(
+                        "Too many macro expansions (does {} invoke itself?)",
+                        tokens[0]
+                    ));
+                    self.script.clear();
+                }
+                None => self.output.error("Unrecognized command."),
+            }
+        }
+    }
+
+    /// Returns the next non-blank line of input, or None once there is no more. Lines from command
+    /// files (and macros) come first, echoed after `prompt` so that the output reads like a
+    /// session; then lines typed at `prompt`.
+    fn read_line(&mut self, prompt: &str) -> Option<String> {
+        while let Some(line) = self.script.pop_front() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if !self.output.json() {
+                self.output.line(&format!("{}{}", prompt, line));
+            }
+            return Some(line.to_string());
+        }
+        self.macro_expansions = 0;
+        if self.output.json() {
+            return self.read_json_request();
+        }
         loop {
             // Print prompt and get next line of user input
-            match self.readline.readline("(deet) ") {
+            match self.readline.readline(prompt) {
                 Err(ReadlineError::Interrupted) => {
                     // User pressed ctrl+c. We're going to ignore it
-                    println!("Type \"quit\" to exit");
-                }
-                Err(ReadlineError::Eof) => {
-                    // User pressed ctrl+d, which is the equivalent of "quit" for our purposes
-                    return DebuggerCommand::Quit;
+                    self.output.line("Type \"quit\" to exit");
                 }
+                Err(ReadlineError::Eof) => return None,
                 Err(err) => {
                     panic!("Unexpected I/O error: {:?}", err);
                 }
@@ -181,21 +2263,114 @@ impl Debugger {
                     }
                     self.readline.add_history_entry(line.as_str());
                     if let Err(err) = self.readline.save_history(&self.history_path) {
-                        println!(
+                        self.output.error(&format!(
                             "Warning: failed to save history file at {}: {}",
                             self.history_path, err
-                        );
-                    }
-                    let tokens: Vec<&str> = line.split_whitespace().collect();
-                    if let Some(cmd) = DebuggerCommand::from_tokens(&tokens) {
-                        return cmd;
-                    } else {
-                        println!("Unrecognized command.");
+                        ));
                     }
+                    return Some(line);
+                }
+            }
+        }
+    }
+
+    /// Reads the next request from stdin in JSON mode and returns the command line it stands for, or
+    /// None once there are no more. Requests that can't be understood are answered with an error.
+    fn read_json_request(&self) -> Option<String> {
+        // Tells the client we're waiting for it, as the prompt does
+        self.output.event(json!({ "event": "ready" }));
+        loop {
+            let mut request = String::new();
+            match io::stdin().lock().read_line(&mut request) {
+                Ok(0) => return None,
+                Ok(_) => {}
+                Err(err) => panic!("Unexpected I/O error: {:?}", err),
+            }
+            if request.trim().is_empty() {
+                continue;
+            }
+            match debugger_command::line_from_json(&request) {
+                Ok(line) => return Some(line),
+                Err(message) => {
+                    self.output.error(&message);
+                    self.output.event(json!({ "event": "ready" }));
                 }
             }
         }
     }
+
+    /// Reads the lines of the macro `name` up to `end`, and saves them to run whenever `name` is
+    /// entered as a command. `$arg0` in the lines is replaced with whatever follows the name.
+    fn define_macro(&mut self, name: String) {
+        // Built-in commands are looked up first, so a macro with the same name could never run
+        // (some only count as commands with an argument)
+        if DebuggerCommand::from_tokens(&[&name]).is_some()
+            || DebuggerCommand::from_tokens(&[&name, "1"]).is_some()
+        {
+            self.output.error(&format!(
+                "{} is a built-in command and can't be redefined",
+                name
+            ));
+            return;
+        }
+        if self.script.is_empty() {
+            self.output.line(&format!(
+                "Type commands for {}, one per line. End with a line saying just \"end\".",
+                name
+            ));
+        }
+        let mut body = Vec::new();
+        loop {
+            match self.read_line(">") {
+                Some(line) if line.trim() == "end" => break,
+                Some(line) => body.push(line.trim().to_string()),
+                None => {
+                    self.output
+                        .line(&format!("Definition of {} abandoned", name));
+                    return;
+                }
+            }
+        }
+        self.macros.insert(name, body);
+    }
+}
+
+/// Loads the debugging symbols from the executable at `target`, or returns a message saying why they
+/// couldn't be loaded.
+fn read_debug_data(target: &str) -> Result<DwarfData, String> {
+    match DwarfData::from_file(target) {
+        Ok(val) => Ok(val),
+        Err(DwarfError::ErrorOpeningFile) => Err(format!("Could not open file {}", target)),
+        Err(DwarfError::DwarfFormatError(err)) => Err(format!(
+            "Could not debugging symbols from {}: {:?}",
+            target, err
+        )),
+    }
+}
+
+/// Returns when the file at `path` was last modified, or None if that can't be told.
+fn modified_time(path: &str) -> Option<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// Describes where a breakpoint was set: its address, or how many locations it has and where.
+fn describe_addrs(addrs: &[usize]) -> String {
+    let addrs_text: Vec<String> = addrs.iter().map(|addr| format!("{:#x}", addr)).collect();
+    match addrs.len() {
+        1 => addrs_text[0].clone(),
+        count => format!("{} locations: {}", count, addrs_text.join(", ")),
+    }
+}
+
+/// Returns line `number` (counting from 1) of the source file at `path`, if it can be read.
+fn source_line(path: &str, number: usize) -> Option<String> {
+    let source = fs::read_to_string(path).ok()?;
+    source
+        .lines()
+        .nth(number.checked_sub(1)?)
+        .map(str::to_string)
 }
 
 fn parse_address(addr: &str) -> Option<usize> {
@@ -206,3 +2381,61 @@ fn parse_address(addr: &str) -> Option<usize> {
     };
     usize::from_str_radix(addr_without_0x, 16).ok()
 }
+
+/// Says that breakpoint `number` was hit at `location`, for the `hits`th time.
+fn describe_hit(number: usize, location: &str, hits: usize) -> String {
+    format!(
+        "Breakpoint {} at {}, hit {} time{}",
+        number,
+        location,
+        hits,
+        if hits == 1 { "" } else { "s" }
+    )
+}
+
+/// Describes a syscall stop as a JSON object with the syscall's name and number, and either the
+/// arguments it was called with (`args`) or the value it returned (`return`).
+fn syscall_json(stop: &SyscallStop) -> serde_json::Value {
+    match *stop {
+        SyscallStop::Entry(number, ref args) => json!({
+            "name": syscalls::name(number),
+            "number": number,
+            "args": args[..syscalls::arg_count(number).min(args.len())],
+        }),
+        SyscallStop::Exit(number, value) => json!({
+            "name": syscalls::name(number),
+            "number": number,
+            "return": value as i64,
+        }),
+    }
+}
+
+/// The registers `registers_json` records, in the order replays show them
+const RECORDED_REGISTERS: [&str; 18] = [
+    "rip", "rsp", "rbp", "rax", "rbx", "rcx", "rdx", "rsi", "rdi", "r8", "r9", "r10", "r11", "r12",
+    "r13", "r14", "r15", "eflags",
+];
+
+/// Returns the general-purpose registers in `regs` as a JSON object keyed by register name.
+fn registers_json(regs: &libc::user_regs_struct) -> serde_json::Value {
+    json!({
+        "rip": regs.rip,
+        "rsp": regs.rsp,
+        "rbp": regs.rbp,
+        "rax": regs.rax,
+        "rbx": regs.rbx,
+        "rcx": regs.rcx,
+        "rdx": regs.rdx,
+        "rsi": regs.rsi,
+        "rdi": regs.rdi,
+        "r8": regs.r8,
+        "r9": regs.r9,
+        "r10": regs.r10,
+        "r11": regs.r11,
+        "r12": regs.r12,
+        "r13": regs.r13,
+        "r14": regs.r14,
+        "r15": regs.r15,
+        "eflags": regs.eflags,
+    })
+}
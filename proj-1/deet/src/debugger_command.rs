@@ -1,15 +1,80 @@
+use crate::values::Format;
+use serde_json::Value;
+
 pub enum DebuggerCommand {
     Quit,
     Run(Vec<String>),
     Continue,
+    /// Runs the current thread to the next source line, entering functions it calls
+    Step,
+    /// Runs the current thread to the next source line, running functions it calls to completion
+    Next,
+    /// Runs the current thread until the function it is in returns, and shows the return value
+    Finish,
+    /// Runs the current thread until it reaches the given line in the current frame (not in calls
+    /// it makes), using a temporary breakpoint
+    Until(String),
+    /// Like `Until`, but stops at the line in whatever frame gets there first
+    Advance(String),
+    /// Kills the inferior, keeping the breakpoints for the next run
+    Kill,
+    /// Lets the inferior carry on running without the debugger
+    Detach,
     Backtrace,
     Break(String),
+    /// Sets a breakpoint that prints the given format (with the values of the expressions in `{}`
+    /// filled in) and carries on running instead of stopping
+    Tracepoint(String, String),
+    /// Logs every call to the given function, with its arguments, and every return, with the
+    /// value returned, carrying on running instead of stopping
+    TraceCalls(String),
+    /// Stops the inferior whenever the memory the given expression refers to (worked out once,
+    /// when the watchpoint is set) changes, whatever code changes it (`watch -l`)
+    WatchLocation(String),
+    /// Stops the inferior whenever it enters or leaves one of the given syscalls (names or
+    /// numbers), or any syscall if none are given (`catch syscall`)
+    CatchSyscall(Vec<String>),
+    Print(String, Format),
+    /// Lists the source of the current function, each line followed by the instructions compiled
+    /// from it
+    ListMixed,
+    /// Lists the source file with the given name, which may be a full path or just a file name
+    List(String),
+    InfoThreads,
+    /// Prints the local variables in scope where the current thread is stopped
+    InfoLocals,
+    /// Lists the source files the target was compiled from
+    InfoSources,
+    /// Prints the floating-point registers of the current thread (`info registers float`)
+    InfoFloatRegisters,
+    /// Selects the thread with the given number, or shows the current thread if there is none
+    Thread(Option<usize>),
+    /// Runs the commands in the given file
+    Source(String),
+    /// Makes the current function return right away, with the value of the given expression if
+    /// there is one
+    Return(Option<String>),
+    /// Defines a macro with the given name from the lines that follow, up to `end`
+    Define(String),
+    /// Starts recording every stop to the given file, or stops recording if there is no file
+    Record(Option<String>),
+    /// Loads the recording in the given file and shows its first stop, or shows the next stop of
+    /// the loaded recording if there is no file
+    Replay(Option<String>),
 }
 
 impl DebuggerCommand {
     pub fn from_tokens(tokens: &[&str]) -> Option<DebuggerCommand> {
-        match tokens[0] {
-            "q" | "quit" => Some(DebuggerCommand::Quit),
+        // Commands may carry a format suffix, as in `print/x`
+        let (command, suffix) = match tokens[0].split_once('/') {
+            Some((command, suffix)) => (command, Some(suffix)),
+            None => (tokens[0], None),
+        };
+        if suffix.is_some() && !["p", "print", "list", "disassemble"].contains(&command) {
+            return None;
+        }
+        match command {
+            "q" | "quit" | "exit" => Some(DebuggerCommand::Quit),
             "r" | "run" => {
                 let args = tokens[1..].to_vec();
                 Some(DebuggerCommand::Run(
@@ -17,10 +82,115 @@ impl DebuggerCommand {
                 ))
             }
             "c" | "cont" | "continue" => Some(DebuggerCommand::Continue),
-            "bt" | "back" | "backtrace" => Some(DebuggerCommand::Backtrace),
-            "b" | "break" => Some(DebuggerCommand::Break(tokens[1].to_string())),
+            "s" | "step" => Some(DebuggerCommand::Step),
+            "n" | "next" => Some(DebuggerCommand::Next),
+            "fin" | "finish" | "step-out" => Some(DebuggerCommand::Finish),
+            "u" | "until" if tokens.len() > 1 => {
+                Some(DebuggerCommand::Until(tokens[1].to_string()))
+            }
+            "advance" if tokens.len() > 1 => Some(DebuggerCommand::Advance(tokens[1].to_string())),
+            "k" | "kill" => Some(DebuggerCommand::Kill),
+            "detach" => Some(DebuggerCommand::Detach),
+            "bt" | "back" | "backtrace" | "where" => Some(DebuggerCommand::Backtrace),
+            "b" | "break" if tokens.len() > 1 => {
+                Some(DebuggerCommand::Break(tokens[1].to_string()))
+            }
+            "dprintf" | "trace" if tokens.len() > 2 => {
+                let format = tokens[2..].join(" ");
+                // The format may be quoted, as in gdb
+                let format = match format.strip_prefix('"').and_then(|f| f.strip_suffix('"')) {
+                    Some(unquoted) => unquoted.to_string(),
+                    None => format,
+                };
+                Some(DebuggerCommand::Tracepoint(tokens[1].to_string(), format))
+            }
+            "trace" if tokens.len() == 2 => {
+                Some(DebuggerCommand::TraceCalls(tokens[1].to_string()))
+            }
+            "watch" if tokens.len() > 2 && ["-l", "-location"].contains(&tokens[1]) => {
+                Some(DebuggerCommand::WatchLocation(tokens[2..].join(" ")))
+            }
+            "catch" if tokens.len() > 1 && tokens[1] == "syscall" => Some(
+                DebuggerCommand::CatchSyscall(tokens[2..].iter().map(|s| s.to_string()).collect()),
+            ),
+            "p" | "print" if tokens.len() > 1 => {
+                let format = match suffix {
+                    None => Format::Natural,
+                    Some("x") => Format::Hex,
+                    Some(_) => return None,
+                };
+                Some(DebuggerCommand::Print(tokens[1..].join(" "), format))
+            }
+            // As in gdb, which also accepts the `/m` separately
+            "list" | "disassemble" => match (suffix, tokens.get(1)) {
+                (Some("m"), None) | (None, Some(&"/m")) => Some(DebuggerCommand::ListMixed),
+                (None, Some(file)) if command == "list" => {
+                    Some(DebuggerCommand::List(file.to_string()))
+                }
+                _ => None,
+            },
+            "i" | "info" if tokens.len() > 1 && "threads".starts_with(tokens[1]) => {
+                Some(DebuggerCommand::InfoThreads)
+            }
+            "i" | "info" if tokens.len() > 1 && "locals".starts_with(tokens[1]) => {
+                Some(DebuggerCommand::InfoLocals)
+            }
+            "i" | "info" if tokens.len() > 1 && "sources".starts_with(tokens[1]) => {
+                Some(DebuggerCommand::InfoSources)
+            }
+            "i" | "info"
+                if tokens.len() == 3
+                    && "registers".starts_with(tokens[1])
+                    && tokens[2] == "float" =>
+            {
+                Some(DebuggerCommand::InfoFloatRegisters)
+            }
+            "t" | "thread" => match tokens.get(1) {
+                Some(id) => id.parse().ok().map(|id| DebuggerCommand::Thread(Some(id))),
+                None => Some(DebuggerCommand::Thread(None)),
+            },
+            "source" if tokens.len() > 1 => Some(DebuggerCommand::Source(tokens[1..].join(" "))),
+            "ret" | "return" => Some(DebuggerCommand::Return(if tokens.len() > 1 {
+                Some(tokens[1..].join(" "))
+            } else {
+                None
+            })),
+            "define" if tokens.len() == 2 => Some(DebuggerCommand::Define(tokens[1].to_string())),
+            "record" => Some(DebuggerCommand::Record(
+                Some(tokens[1..].join(" ")).filter(|file| !file.is_empty()),
+            )),
+            "replay" => Some(DebuggerCommand::Replay(
+                Some(tokens[1..].join(" ")).filter(|file| !file.is_empty()),
+            )),
             // Default case:
             _ => None,
         }
     }
 }
+
+/// Turns a request sent with `--json-rpc`, such as `{"cmd": "break", "args": ["main"]}`, into the
+/// command line it stands for, to be parsed like a typed one. Arguments may be strings or numbers,
+/// and may be left out.
+pub fn line_from_json(request: &str) -> Result<String, String> {
+    let request: Value =
+        serde_json::from_str(request).map_err(|err| format!("Invalid request: {}", err))?;
+    let command = match request.get("cmd") {
+        Some(Value::String(command)) if !command.trim().is_empty() => command.trim(),
+        _ => return Err("Invalid request: cmd must be a command name".to_string()),
+    };
+    let mut line = command.to_string();
+    let args = match request.get("args") {
+        Some(Value::Array(args)) => args.as_slice(),
+        None | Some(Value::Null) => &[],
+        Some(_) => return Err("Invalid request: args must be a list".to_string()),
+    };
+    for arg in args {
+        line.push(' ');
+        match arg {
+            Value::String(arg) => line.push_str(arg),
+            Value::Number(number) => line.push_str(&number.to_string()),
+            _ => return Err("Invalid request: args must be strings or numbers".to_string()),
+        }
+    }
+    Ok(line)
+}
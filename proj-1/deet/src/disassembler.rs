@@ -0,0 +1,52 @@
+//! Disassembles the inferior's machine code using objdump from binutils, which is installed
+//! alongside the compiler that builds the programs deet debugs.
+
+use std::fs;
+use std::process::Command;
+
+/// A machine instruction decoded by `disassemble`.
+pub struct Instruction {
+    pub address: usize,
+    /// The instruction in AT&T syntax (as gdb shows it), e.g. `mov    %rsp,%rbp`
+    pub text: String,
+}
+
+/// Disassembles `code`, which was read from `start` in the inferior's memory.
+pub fn disassemble(code: &[u8], start: usize) -> Result<Vec<Instruction>, String> {
+    // objdump only reads files
+    let path = std::env::temp_dir().join(format!("deet-code-{}.bin", std::process::id()));
+    fs::write(&path, code).map_err(|err| format!("Could not save code to disassemble: {}", err))?;
+    let output = Command::new("objdump")
+        .args(["-D", "-b", "binary", "-m", "i386:x86-64"])
+        .arg(format!("--adjust-vma={:#x}", start))
+        .arg(&path)
+        .output();
+    let _ = fs::remove_file(&path);
+    let output = output.map_err(|err| format!("Could not run objdump: {}", err))?;
+    if !output.status.success() {
+        return Err(format!(
+            "objdump failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(parse_objdump(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Picks the instructions out of objdump's output, where they are lines of the address, the bytes
+/// and the instruction separated by tabs. The bytes of long instructions carry on over a line
+/// without an instruction, which is skipped along with the headings.
+fn parse_objdump(output: &str) -> Vec<Instruction> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(3, '\t');
+            let address = fields.next()?.trim().strip_suffix(':')?;
+            let address = usize::from_str_radix(address, 16).ok()?;
+            let _bytes = fields.next()?;
+            Some(Instruction {
+                address,
+                text: fields.next()?.trim().to_string(),
+            })
+        })
+        .collect()
+}
@@ -1,7 +1,10 @@
 use crate::gimli_wrapper;
 use addr2line::Context;
-use object::Object;
+use gimli::UnwindSection;
+use object::{Object, ObjectSection};
+use std::collections::HashMap;
 use std::convert::TryInto;
+use std::path::Path;
 use std::{fmt, fs};
 
 #[derive(Debug)]
@@ -12,9 +15,31 @@ pub enum Error {
 
 pub struct DwarfData {
     files: Vec<File>,
+    types: HashMap<usize, Type>,
     addr2line: Context<addr2line::gimli::EndianRcSlice<addr2line::gimli::RunTimeEndian>>,
+    /// Entry point of the program, as linked
+    entry_point: usize,
+    /// How far the program was loaded from the addresses it was linked at (see `relocate`)
+    load_offset: usize,
+    /// Call frame information, for finding each frame's caller (see `unwind_rule`)
+    frame_info: FrameInfo,
 }
 
+/// The contents of the `.eh_frame` and `.debug_frame` sections, and what their pointers can be
+/// relative to. Either section may be empty.
+struct FrameInfo {
+    eh_frame: Vec<u8>,
+    debug_frame: Vec<u8>,
+    eh_frame_address: u64,
+    text_address: u64,
+    endian: gimli::RunTimeEndian,
+}
+
+// DWARF numbers of the x86-64 registers the unwinder needs, from the System V ABI
+const RBP: gimli::Register = gimli::Register(6);
+const RSP: gimli::Register = gimli::Register(7);
+const RETURN_ADDRESS: gimli::Register = gimli::Register(16);
+
 impl fmt::Debug for DwarfData {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "DwarfData {{files: {:?}}}", self.files)
@@ -38,19 +63,90 @@ impl DwarfData {
         } else {
             gimli::RunTimeEndian::Big
         };
+        let (files, types) = gimli_wrapper::load_file(&object, endian)?;
+        let section_address = |name| {
+            object
+                .section_by_name(name)
+                .map_or(0, |section| section.address())
+        };
+        let frame_info = FrameInfo {
+            eh_frame: section_data(&object, ".eh_frame"),
+            debug_frame: section_data(&object, ".debug_frame"),
+            eh_frame_address: section_address(".eh_frame"),
+            text_address: section_address(".text"),
+            endian,
+        };
         Ok(DwarfData {
-            files: gimli_wrapper::load_file(&object, endian)?,
+            files,
+            types,
             addr2line: Context::new(&object).or_else(|e| Err(gimli_wrapper::Error::from(e)))?,
+            entry_point: object.entry() as usize,
+            load_offset: 0,
+            frame_info,
         })
     }
 
+    /// Returns the entry point of the program as linked, which the entry point it is loaded with
+    /// can be compared against to find its load offset.
+    pub fn entry_point(&self) -> usize {
+        self.entry_point
+    }
+
+    /// Returns the offset the addresses in the symbols are relocated by (see `relocate`).
+    pub fn load_offset(&self) -> usize {
+        self.load_offset
+    }
+
+    /// Moves every address in the symbols to where the program is loaded, `load_offset` bytes
+    /// from the addresses it was linked at. A position-independent executable is loaded at a
+    /// different base each run, while other executables are loaded where they were linked (at an
+    /// offset of 0). Addresses given to and returned from `DwarfData` are runtime addresses from
+    /// then on.
+    pub fn relocate(&mut self, load_offset: usize) {
+        let delta = load_offset.wrapping_sub(self.load_offset);
+        let relocate_variable = |var: &mut Variable| {
+            if let Location::Address(addr) = &mut var.location {
+                *addr = addr.wrapping_add(delta);
+            }
+            if let Some((start, end)) = &mut var.scope {
+                *start = start.wrapping_add(delta);
+                *end = end.wrapping_add(delta);
+            }
+        };
+        for file in &mut self.files {
+            file.global_variables.iter_mut().for_each(relocate_variable);
+            for func in &mut file.functions {
+                func.address = func.address.wrapping_add(delta);
+                func.variables.iter_mut().for_each(relocate_variable);
+            }
+            for line in &mut file.lines {
+                line.address = line.address.wrapping_add(delta);
+            }
+        }
+        self.load_offset = load_offset;
+    }
+
     #[allow(dead_code)]
     fn get_target_file(&self, file: &str) -> Option<&File> {
         self.files.iter().find(|f| {
-            f.name == file || (!file.contains("/") && f.name.ends_with(&format!("/{}", file)))
+            f.name == file
+                || f.path() == file
+                || (!file.contains("/") && f.name.ends_with(&format!("/{}", file)))
         })
     }
 
+    /// Returns the paths of the source files of the compilation units, in the order they were
+    /// compiled.
+    pub fn source_files(&self) -> Vec<String> {
+        self.files.iter().map(File::path).collect()
+    }
+
+    /// Returns the path of the source file called `file` (given as in a `file:line` breakpoint),
+    /// if it is one of the compilation units.
+    pub fn find_source_file(&self, file: &str) -> Option<String> {
+        self.get_target_file(file).map(File::path)
+    }
+
     #[allow(dead_code)]
     pub fn get_addr_for_line(&self, file: Option<&str>, line_number: usize) -> Option<usize> {
         let target_file = match file {
@@ -87,11 +183,30 @@ impl DwarfData {
         }
     }
 
+    /// Returns the address of every function named `func_name`, e.g. `static` functions of the
+    /// same name in different files, in the order the files were compiled.
+    pub fn get_addrs_for_function(&self, file: Option<&str>, func_name: &str) -> Vec<usize> {
+        let files = match file {
+            Some(filename) => match self.get_target_file(filename) {
+                Some(file) => std::slice::from_ref(file),
+                None => return Vec::new(),
+            },
+            None => &self.files[..],
+        };
+        let mut addrs = Vec::new();
+        for func in files.iter().flat_map(|file| &file.functions) {
+            if func.name == func_name && !addrs.contains(&func.address) {
+                addrs.push(func.address);
+            }
+        }
+        addrs
+    }
+
     #[allow(dead_code)]
     pub fn get_line_from_addr(&self, curr_addr: usize) -> Option<Line> {
         let location = self
             .addr2line
-            .find_location(curr_addr.try_into().unwrap())
+            .find_location(curr_addr.wrapping_sub(self.load_offset).try_into().unwrap())
             .ok()??;
         Some(Line {
             file: location.file?.to_string(),
@@ -104,13 +219,157 @@ impl DwarfData {
     pub fn get_function_from_addr(&self, curr_addr: usize) -> Option<String> {
         let frame = self
             .addr2line
-            .find_frames(curr_addr.try_into().unwrap())
+            .find_frames(curr_addr.wrapping_sub(self.load_offset).try_into().unwrap())
             .ok()?
             .next()
             .ok()??;
         Some(frame.function?.raw_name().ok()?.to_string())
     }
 
+    /// Returns the function whose code contains `addr`.
+    pub fn get_function_containing(&self, addr: usize) -> Option<&Function> {
+        self.files
+            .iter()
+            .flat_map(|file| file.functions.iter())
+            .find(|func| func.address <= addr && addr < func.address + func.text_length)
+    }
+
+    /// Returns how to find the caller of the frame executing `pc`, according to the call frame
+    /// information in `.eh_frame` (or `.debug_frame`). This works even in functions that don't
+    /// keep %rbp pointing at their frame. Returns None if `pc` isn't covered, or if its rule is
+    /// one the unwinder doesn't handle (like a DWARF expression).
+    pub fn unwind_rule(&self, pc: usize) -> Option<UnwindRule> {
+        let info = &self.frame_info;
+        let pc = pc.wrapping_sub(self.load_offset) as u64;
+        let bases = gimli::BaseAddresses::default()
+            .set_eh_frame(info.eh_frame_address)
+            .set_text(info.text_address);
+        let mut ctx = gimli::UninitializedUnwindContext::new();
+        let row = gimli::EhFrame::new(&info.eh_frame, info.endian)
+            .unwind_info_for_address(&bases, &mut ctx, pc, gimli::EhFrame::cie_from_offset)
+            .or_else(|_| {
+                gimli::DebugFrame::new(&info.debug_frame, info.endian).unwind_info_for_address(
+                    &bases,
+                    &mut ctx,
+                    pc,
+                    gimli::DebugFrame::cie_from_offset,
+                )
+            })
+            .ok()?;
+        let (cfa_register, cfa_offset) = match *row.cfa() {
+            gimli::CfaRule::RegisterAndOffset { register, offset } if register == RSP => {
+                (FrameRegister::Rsp, offset)
+            }
+            gimli::CfaRule::RegisterAndOffset { register, offset } if register == RBP => {
+                (FrameRegister::Rbp, offset)
+            }
+            _ => return None,
+        };
+        let return_address_offset = match row.register(RETURN_ADDRESS) {
+            gimli::RegisterRule::Offset(offset) => offset,
+            _ => return None,
+        };
+        let saved_rbp_offset = match row.register(RBP) {
+            gimli::RegisterRule::Offset(offset) => Some(offset),
+            _ => None,
+        };
+        Some(UnwindRule {
+            cfa_register,
+            cfa_offset,
+            return_address_offset,
+            saved_rbp_offset,
+        })
+    }
+
+    /// Looks up a type by the offset other DWARF entries use to refer to it.
+    pub fn get_type(&self, offset: usize) -> Option<&Type> {
+        self.types.get(&offset)
+    }
+
+    /// Returns the type `func` returns, or None if it returns void.
+    pub fn get_return_type(&self, func: &Function) -> Option<&Type> {
+        func.return_type.and_then(|offset| self.get_type(offset))
+    }
+
+    /// Follows typedefs and const/volatile qualifiers down to the underlying type.
+    pub fn strip_aliases<'a>(&'a self, mut dtype: &'a Type) -> Option<&'a Type> {
+        // Bound the walk in case of malformed (cyclic) debug info
+        for _ in 0..16 {
+            match dtype.kind {
+                TypeKind::Alias(target) => dtype = self.get_type(target?)?,
+                _ => return Some(dtype),
+            }
+        }
+        None
+    }
+
+    /// Returns the address just past the prologue of the function starting at `func_addr`: where
+    /// the line after its first one starts, by which point its parameters are in their stack
+    /// slots. Returns None if it has no such line.
+    pub fn get_prologue_end(&self, func_addr: usize) -> Option<usize> {
+        let (file, func) = self.files.iter().find_map(|file| {
+            let func = file
+                .functions
+                .iter()
+                .find(|func| func.address == func_addr)?;
+            Some((file, func))
+        })?;
+        file.lines
+            .iter()
+            .map(|line| line.address)
+            .filter(|&addr| addr > func.address && addr < func.address + func.text_length)
+            .min()
+    }
+
+    /// Returns the local variables (including parameters) of the function containing `curr_addr`
+    /// that are in scope there, in declaration order. A variable shadowed by one of the same name in
+    /// an inner block is left out.
+    pub fn get_locals(&self, curr_addr: usize) -> Vec<&Variable> {
+        let func = match self
+            .files
+            .iter()
+            .flat_map(|file| file.functions.iter())
+            .find(|func| func.address <= curr_addr && curr_addr < func.address + func.text_length)
+        {
+            Some(func) => func,
+            None => return Vec::new(),
+        };
+        let in_scope: Vec<&Variable> = func
+            .variables
+            .iter()
+            .filter(|var| match var.scope {
+                Some((start, end)) => start <= curr_addr && curr_addr < end,
+                None => true,
+            })
+            .collect();
+        // Blocks nest, so the narrowest scope is the innermost one
+        let width = |var: &Variable| var.scope.map_or(usize::MAX, |(start, end)| end - start);
+        in_scope
+            .iter()
+            .filter(|var| {
+                !in_scope
+                    .iter()
+                    .any(|other| other.name == var.name && width(other) < width(var))
+            })
+            .copied()
+            .collect()
+    }
+
+    /// Finds the variable called `name` that is visible from `curr_addr`: a local in scope there if
+    /// there is one, otherwise a global.
+    pub fn get_variable(&self, curr_addr: usize, name: &str) -> Option<&Variable> {
+        let local = self
+            .get_locals(curr_addr)
+            .into_iter()
+            .find(|var| var.name == name);
+        local.or_else(|| {
+            self.files
+                .iter()
+                .flat_map(|file| file.global_variables.iter())
+                .find(|var| var.name == name)
+        })
+    }
+
     #[allow(dead_code)]
     pub fn print(&self) {
         for file in &self.files {
@@ -148,21 +407,83 @@ impl DwarfData {
     }
 }
 
+/// Returns the contents of the section called `name`, or nothing if there is no such section.
+fn section_data(object: &object::File, name: &str) -> Vec<u8> {
+    object
+        .section_data_by_name(name)
+        .map_or_else(Vec::new, |data| data.into_owned())
+}
+
+/// How to find the caller of a frame: its canonical frame address (CFA), which is the caller's
+/// %rsp, is `cfa_register` plus `cfa_offset`, and the return address and saved registers are
+/// stored at offsets from the CFA.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UnwindRule {
+    pub cfa_register: FrameRegister,
+    pub cfa_offset: i64,
+    pub return_address_offset: i64,
+    /// Where the caller's %rbp was saved, if the frame has changed it. Otherwise it still holds
+    /// the caller's value.
+    pub saved_rbp_offset: Option<i64>,
+}
+
+/// A register the CFA can be computed from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrameRegister {
+    Rsp,
+    Rbp,
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct Type {
     pub name: String,
     pub size: usize,
+    pub kind: TypeKind,
 }
 
 impl Type {
-    pub fn new(name: String, size: usize) -> Self {
-        Type {
-            name: name,
-            size: size,
-        }
+    pub fn new(name: String, size: usize, kind: TypeKind) -> Self {
+        Type { name, size, kind }
+    }
+}
+
+/// The shape of a type. Types that refer to other types do so by offset; see `DwarfData::get_type`.
+#[derive(Debug, Clone)]
+pub enum TypeKind {
+    Base(BaseEncoding),
+    /// Points to the given type, or to void
+    Pointer(Option<usize>),
+    /// A struct or union
+    Struct(Vec<Member>),
+    /// Element type and number of elements
+    Array(Option<usize>, usize),
+    /// A typedef or a const/volatile qualified type
+    Alias(Option<usize>),
+}
+
+impl Default for TypeKind {
+    fn default() -> Self {
+        TypeKind::Base(BaseEncoding::Signed)
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BaseEncoding {
+    Signed,
+    Unsigned,
+    SignedChar,
+    UnsignedChar,
+    Float,
+    Boolean,
+}
+
+#[derive(Debug, Clone)]
+pub struct Member {
+    pub name: String,
+    pub offset: usize,
+    pub type_offset: Option<usize>,
+}
+
 #[derive(Clone)]
 pub enum Location {
     Address(usize),
@@ -191,6 +512,11 @@ pub struct Variable {
     pub entity_type: Type,
     pub location: Location,
     pub line_number: usize, // Line number in source file
+    /// Addresses `[start, end)` of the innermost lexical block declaring the variable, or None if
+    /// it is visible throughout its function (or is a global)
+    pub scope: Option<(usize, usize)>,
+    /// Whether this is one of its function's parameters (rather than a local or global)
+    pub parameter: bool,
 }
 
 #[derive(Debug, Default, Clone)]
@@ -200,16 +526,33 @@ pub struct Function {
     pub text_length: usize,
     pub line_number: usize, // Line number in source file
     pub variables: Vec<Variable>,
+    /// Offset of the type the function returns (see `DwarfData::get_type`), or None for void
+    pub return_type: Option<usize>,
 }
 
 #[derive(Debug, Default, Clone)]
 pub struct File {
     pub name: String,
+    /// Directory the file was compiled in, which a relative `name` is relative to
+    pub comp_dir: Option<String>,
     pub global_variables: Vec<Variable>,
     pub functions: Vec<Function>,
     pub lines: Vec<Line>,
 }
 
+impl File {
+    /// Returns the path of the source file, made absolute if the compilation directory is known.
+    pub fn path(&self) -> String {
+        match &self.comp_dir {
+            Some(dir) => Path::new(dir)
+                .join(&self.name)
+                .to_string_lossy()
+                .into_owned(),
+            None => self.name.clone(),
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct Line {
     pub file: String,
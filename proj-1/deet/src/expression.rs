@@ -0,0 +1,346 @@
+//! A tiny evaluator for the expressions `print` accepts: variables, integer literals, `+ - * /`,
+//! array indexing and pointer dereferences, with C's precedence. Arithmetic is done on 64-bit
+//! integers.
+
+use crate::dwarf_data::{BaseEncoding, DwarfData, Type, TypeKind};
+use std::convert::TryInto;
+use std::fmt;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The expression couldn't be parsed
+    Syntax(String),
+    /// A variable isn't visible where the inferior is stopped
+    NoSymbol(String),
+    /// An operation doesn't make sense for the types involved
+    Type(String),
+    /// The inferior's memory couldn't be read
+    Memory(nix::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Syntax(message) => write!(f, "Syntax error: {}.", message),
+            Error::NoSymbol(name) => write!(f, "No symbol \"{}\" in current context.", name),
+            Error::Type(message) => write!(f, "{}.", message),
+            Error::Memory(err) => write!(f, "Error reading inferior memory: {}", err),
+        }
+    }
+}
+
+impl From<nix::Error> for Error {
+    fn from(err: nix::Error) -> Self {
+        Error::Memory(err)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+#[derive(Debug)]
+enum Expr {
+    Number(i64),
+    Variable(String),
+    Negate(Box<Expr>),
+    Deref(Box<Expr>),
+    Index(Box<Expr>, Box<Expr>),
+    Binary(Box<Expr>, Op, Box<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(i64),
+    Ident(String),
+    Punct(char),
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, Error> {
+    let mut tokens = Vec::new();
+    let mut chars = input.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c.is_ascii_alphanumeric() || c == '_' {
+            let mut word = String::new();
+            while let Some(&c) = chars.peek() {
+                if !c.is_ascii_alphanumeric() && c != '_' {
+                    break;
+                }
+                word.push(c);
+                chars.next();
+            }
+            if c.is_ascii_digit() {
+                let number = match word.strip_prefix("0x").or_else(|| word.strip_prefix("0X")) {
+                    Some(hex) => i64::from_str_radix(hex, 16),
+                    None => word.parse(),
+                };
+                match number {
+                    Ok(number) => tokens.push(Token::Number(number)),
+                    Err(_) => return Err(Error::Syntax(format!("invalid number \"{}\"", word))),
+                }
+            } else {
+                tokens.push(Token::Ident(word));
+            }
+        } else if "+-*/[]()".contains(c) {
+            tokens.push(Token::Punct(c));
+            chars.next();
+        } else {
+            return Err(Error::Syntax(format!("unexpected character '{}'", c)));
+        }
+    }
+    Ok(tokens)
+}
+
+/// Recursive descent parser over the tokens of one expression.
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    /// Consumes the next token if it is the punctuation `c`.
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(&Token::Punct(c)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), Error> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            Err(Error::Syntax(format!("expected '{}'", c)))
+        }
+    }
+
+    /// expr := term (('+' | '-') term)*
+    fn expr(&mut self) -> Result<Expr, Error> {
+        let mut lhs = self.term()?;
+        loop {
+            let op = if self.eat('+') {
+                Op::Add
+            } else if self.eat('-') {
+                Op::Sub
+            } else {
+                return Ok(lhs);
+            };
+            lhs = Expr::Binary(Box::new(lhs), op, Box::new(self.term()?));
+        }
+    }
+
+    /// term := unary (('*' | '/') unary)*
+    fn term(&mut self) -> Result<Expr, Error> {
+        let mut lhs = self.unary()?;
+        loop {
+            let op = if self.eat('*') {
+                Op::Mul
+            } else if self.eat('/') {
+                Op::Div
+            } else {
+                return Ok(lhs);
+            };
+            lhs = Expr::Binary(Box::new(lhs), op, Box::new(self.unary()?));
+        }
+    }
+
+    /// unary := ('-' | '*') unary | postfix
+    fn unary(&mut self) -> Result<Expr, Error> {
+        if self.eat('-') {
+            Ok(Expr::Negate(Box::new(self.unary()?)))
+        } else if self.eat('*') {
+            Ok(Expr::Deref(Box::new(self.unary()?)))
+        } else {
+            self.postfix()
+        }
+    }
+
+    /// postfix := primary ('[' expr ']')*
+    fn postfix(&mut self) -> Result<Expr, Error> {
+        let mut base = self.primary()?;
+        while self.eat('[') {
+            let index = self.expr()?;
+            self.expect(']')?;
+            base = Expr::Index(Box::new(base), Box::new(index));
+        }
+        Ok(base)
+    }
+
+    /// primary := number | identifier | '(' expr ')'
+    fn primary(&mut self) -> Result<Expr, Error> {
+        let token = self.peek().cloned();
+        self.pos += 1;
+        match token {
+            Some(Token::Number(number)) => Ok(Expr::Number(number)),
+            Some(Token::Ident(name)) => Ok(Expr::Variable(name)),
+            Some(Token::Punct('(')) => {
+                let inner = self.expr()?;
+                self.expect(')')?;
+                Ok(inner)
+            }
+            Some(Token::Punct(c)) => Err(Error::Syntax(format!("unexpected '{}'", c))),
+            None => Err(Error::Syntax("expression ends too early".to_string())),
+        }
+    }
+}
+
+fn parse(input: &str) -> Result<Expr, Error> {
+    let mut parser = Parser {
+        tokens: tokenize(input)?,
+        pos: 0,
+    };
+    let expr = parser.expr()?;
+    match parser.peek() {
+        None => Ok(expr),
+        Some(Token::Punct(c)) => Err(Error::Syntax(format!("unexpected '{}'", c))),
+        Some(_) => Err(Error::Syntax("missing operator".to_string())),
+    }
+}
+
+/// The result of evaluating an expression.
+#[derive(Debug)]
+pub enum Value<'a> {
+    /// An integer computed by the debugger
+    Int(i64),
+    /// An object of the given type living in the inferior's memory
+    Object { addr: usize, dtype: &'a Type },
+}
+
+/// Everything evaluation needs from the debugger: the types, a way to find variables and a way
+/// to read the inferior's memory.
+pub struct Scope<'a, L, R> {
+    pub debug_data: &'a DwarfData,
+    /// Returns the address and type of a variable visible where the inferior is stopped
+    pub lookup: L,
+    /// Reads the given number of bytes at an address (normally `Inferior::read_memory`)
+    pub read_memory: R,
+}
+
+impl<'a, L, R> Scope<'a, L, R>
+where
+    L: Fn(&str) -> Option<(usize, &'a Type)>,
+    R: Fn(usize, usize) -> Result<Vec<u8>, nix::Error>,
+{
+    /// Parses and evaluates `input`.
+    pub fn evaluate(&self, input: &str) -> Result<Value<'a>, Error> {
+        self.eval(&parse(input)?)
+    }
+
+    /// Returns the integer value of `value`, reading it from memory if need be.
+    pub fn to_int(&self, value: &Value<'a>) -> Result<i64, Error> {
+        let (addr, dtype) = match *value {
+            Value::Int(number) => return Ok(number),
+            Value::Object { addr, dtype } => (addr, self.strip(dtype)?),
+        };
+        match dtype.kind {
+            TypeKind::Base(BaseEncoding::Float) => Err(Error::Type(
+                "Only integer arithmetic is supported".to_string(),
+            )),
+            TypeKind::Base(encoding) => {
+                let bytes = (self.read_memory)(addr, dtype.size)?;
+                let len = bytes.len().min(8);
+                let mut buf = [0u8; 8];
+                buf[..len].copy_from_slice(&bytes[..len]);
+                let raw = u64::from_le_bytes(buf);
+                let signed =
+                    encoding == BaseEncoding::Signed || encoding == BaseEncoding::SignedChar;
+                if signed && len > 0 {
+                    // Shift up and back down to sign-extend values narrower than 8 bytes
+                    let shift = 64 - 8 * len as u32;
+                    Ok(((raw << shift) as i64) >> shift)
+                } else {
+                    Ok(raw as i64)
+                }
+            }
+            TypeKind::Pointer(_) => Err(Error::Type(
+                "Pointer arithmetic is not supported".to_string(),
+            )),
+            _ => Err(Error::Type(format!(
+                "Cannot use a value of type {} as an integer",
+                dtype.name
+            ))),
+        }
+    }
+
+    fn eval(&self, expr: &Expr) -> Result<Value<'a>, Error> {
+        match expr {
+            Expr::Number(number) => Ok(Value::Int(*number)),
+            Expr::Variable(name) => match (self.lookup)(name) {
+                Some((addr, dtype)) => Ok(Value::Object { addr, dtype }),
+                None => Err(Error::NoSymbol(name.clone())),
+            },
+            Expr::Negate(inner) => Ok(Value::Int(self.to_int(&self.eval(inner)?)?.wrapping_neg())),
+            Expr::Binary(lhs, op, rhs) => {
+                let lhs = self.to_int(&self.eval(lhs)?)?;
+                let rhs = self.to_int(&self.eval(rhs)?)?;
+                Ok(Value::Int(match op {
+                    Op::Add => lhs.wrapping_add(rhs),
+                    Op::Sub => lhs.wrapping_sub(rhs),
+                    Op::Mul => lhs.wrapping_mul(rhs),
+                    Op::Div if rhs == 0 => return Err(Error::Type("Division by zero".to_string())),
+                    Op::Div => lhs.wrapping_div(rhs),
+                }))
+            }
+            Expr::Deref(inner) => self.element(self.eval(inner)?, 0),
+            Expr::Index(base, index) => {
+                let index = self.to_int(&self.eval(index)?)?;
+                self.element(self.eval(base)?, index)
+            }
+        }
+    }
+
+    /// Returns element `index` of the array `base`, or of the array a pointer `base` points into.
+    fn element(&self, base: Value<'a>, index: i64) -> Result<Value<'a>, Error> {
+        let (addr, dtype) = match base {
+            Value::Object { addr, dtype } => (addr, self.strip(dtype)?),
+            Value::Int(_) => {
+                return Err(Error::Type(
+                    "Attempt to take contents of a non-pointer value".to_string(),
+                ))
+            }
+        };
+        let (start, element) = match dtype.kind {
+            TypeKind::Array(element, _) => (addr, element),
+            TypeKind::Pointer(target) => {
+                let bytes = (self.read_memory)(addr, std::mem::size_of::<usize>())?;
+                (usize::from_le_bytes(bytes.try_into().unwrap()), target)
+            }
+            _ => {
+                return Err(Error::Type(
+                    "Attempt to take contents of a non-pointer value".to_string(),
+                ))
+            }
+        };
+        let element_type = match element.and_then(|offset| self.debug_data.get_type(offset)) {
+            Some(element_type) => element_type,
+            None => {
+                return Err(Error::Type(
+                    "Attempt to take contents of a void pointer".to_string(),
+                ))
+            }
+        };
+        let size = self.strip(element_type)?.size as i64;
+        Ok(Value::Object {
+            addr: (start as i64).wrapping_add(index.wrapping_mul(size)) as usize,
+            dtype: element_type,
+        })
+    }
+
+    fn strip(&self, dtype: &'a Type) -> Result<&'a Type, Error> {
+        self.debug_data
+            .strip_aliases(dtype)
+            .ok_or_else(|| Error::Type("Unknown type".to_string()))
+    }
+}
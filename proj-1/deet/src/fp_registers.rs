@@ -0,0 +1,108 @@
+//! The floating-point registers of x86-64 (the x87 stack, its control and status words, and the
+//! SSE registers), as read with PTRACE_GETFPREGS, for `info registers float` and `print $xmm0`.
+
+use std::convert::TryInto;
+use std::fmt;
+
+/// Names of the control and status registers, in the order `info registers float` lists them
+const CONTROL_REGISTERS: [&str; 5] = ["fctrl", "fstat", "ftag", "fop", "mxcsr"];
+
+/// Returns the names of all the floating-point registers, in the order `info registers float`
+/// lists them.
+pub fn names() -> Vec<String> {
+    let stack = (0..8).map(|i| format!("st{}", i));
+    let control = CONTROL_REGISTERS.iter().map(|name| name.to_string());
+    let sse = (0..16).map(|i| format!("xmm{}", i));
+    stack.chain(control).chain(sse).collect()
+}
+
+/// Formats the register called `name` (without the `$`), or returns None if there is no such
+/// floating-point register. x87 registers are shown as a number along with their raw bytes, and
+/// SSE registers as the floats and doubles they hold along with their bits.
+pub fn format(regs: &libc::user_fpregs_struct, name: &str) -> Option<String> {
+    let index = |prefix: &str, count: usize| {
+        name.strip_prefix(prefix)?
+            .parse::<usize>()
+            .ok()
+            .filter(|&i| i < count)
+    };
+    if let Some(i) = index("st", 8) {
+        // Each 10-byte register is padded out to 16 bytes
+        let bytes = words_to_bytes(&regs.st_space[4 * i..4 * i + 4]);
+        let raw = u128::from_le_bytes(bytes) & ((1 << 80) - 1);
+        return Some(format!(
+            "{} (raw {:#022x})",
+            format_float(extended_to_f64(&bytes[..10])),
+            raw
+        ));
+    }
+    if let Some(i) = index("xmm", 16) {
+        let bytes = words_to_bytes(&regs.xmm_space[4 * i..4 * i + 4]);
+        let floats: Vec<String> = bytes
+            .chunks(4)
+            .map(|chunk| format_float(f32::from_le_bytes(chunk.try_into().unwrap())))
+            .collect();
+        let doubles: Vec<String> = bytes
+            .chunks(8)
+            .map(|chunk| format_float(f64::from_le_bytes(chunk.try_into().unwrap())))
+            .collect();
+        return Some(format!(
+            "{{v4_float = {{{}}}, v2_double = {{{}}}, uint128 = {:#034x}}}",
+            floats.join(", "),
+            doubles.join(", "),
+            u128::from_le_bytes(bytes)
+        ));
+    }
+    let value = match name {
+        "fctrl" => regs.cwd as u32,
+        "fstat" => regs.swd as u32,
+        "ftag" => regs.ftw as u32,
+        "fop" => regs.fop as u32,
+        "mxcsr" => regs.mxcsr,
+        _ => return None,
+    };
+    Some(format!("{:#x}", value))
+}
+
+/// Formats a float in decimal, or in scientific notation if it is very large or very small (as
+/// the bits of integers often are when read as floats), so that it doesn't run to hundreds of
+/// digits.
+fn format_float<F: Into<f64> + Copy + fmt::Display + fmt::LowerExp>(value: F) -> String {
+    let magnitude = value.into().abs();
+    if magnitude == 0.0 || !magnitude.is_finite() || (1e-4..1e16).contains(&magnitude) {
+        value.to_string()
+    } else {
+        format!("{:e}", value)
+    }
+}
+
+/// Joins four 32-bit words of a register, as ptrace lays them out, into its 16 bytes.
+fn words_to_bytes(words: &[libc::c_uint]) -> [u8; 16] {
+    let mut bytes = [0; 16];
+    for (chunk, word) in bytes.chunks_mut(4).zip(words) {
+        chunk.copy_from_slice(&word.to_le_bytes());
+    }
+    bytes
+}
+
+/// Converts an 80-bit x87 extended-precision number to the nearest double.
+fn extended_to_f64(bytes: &[u8]) -> f64 {
+    let mantissa = u64::from_le_bytes(bytes[..8].try_into().unwrap());
+    let sign_exponent = u16::from_le_bytes([bytes[8], bytes[9]]);
+    let sign = if sign_exponent & 0x8000 != 0 {
+        -1.0
+    } else {
+        1.0
+    };
+    let exponent = (sign_exponent & 0x7fff) as i32;
+    // The mantissa has an explicit integer bit, at the top
+    let fraction = mantissa as f64 / 2f64.powi(63);
+    let magnitude = match exponent {
+        0x7fff if mantissa << 1 == 0 => f64::INFINITY,
+        0x7fff => f64::NAN,
+        // Denormals are scaled as if their exponent were 1
+        0 => fraction * 2f64.powi(1 - 16383),
+        _ => fraction * 2f64.powi(exponent - 16383),
+    };
+    sign * magnitude
+}
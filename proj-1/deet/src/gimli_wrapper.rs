@@ -10,13 +10,27 @@ use gimli::{UnitOffset, UnitSectionOffset};
 use object::Object;
 use std::borrow;
 //use std::io::{BufWriter, Write};
-use crate::dwarf_data::{File, Function, Line, Location, Type, Variable};
+use crate::dwarf_data::{
+    BaseEncoding, File, Function, Line, Location, Member, Type, TypeKind, Variable,
+};
 use std::collections::HashMap;
 use std::convert::TryInto;
 use std::fmt::Write;
 use std::{io, path};
 
-pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<Vec<File>, Error> {
+/// A variable whose type can only be looked up once every type in the file has been read.
+struct PendingVariable {
+    variable: Variable,
+    type_offset: usize,
+    file_index: usize,
+    /// Index of the enclosing function, or None for global variables
+    function_index: Option<usize>,
+}
+
+pub fn load_file(
+    object: &object::File,
+    endian: gimli::RunTimeEndian,
+) -> Result<(Vec<File>, HashMap<usize, Type>), Error> {
     // Load a section and return as `Cow<[u8]>`.
     let load_section = |id: gimli::SectionId| -> Result<borrow::Cow<[u8]>, gimli::Error> {
         Ok(object
@@ -44,6 +58,11 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
 
     let mut compilation_units: Vec<File> = Vec::new();
 
+    // Variables are only added to compilation_units once their types are known
+    let mut pending_variables: Vec<PendingVariable> = Vec::new();
+    // Qualifier keyword ("const"/"volatile") for each qualified type, used when naming them
+    let mut qualifiers: HashMap<usize, &'static str> = HashMap::new();
+
     // Iterate over the compilation units.
     let mut iter = dwarf.units();
     while let Some(header) = iter.next()? {
@@ -51,9 +70,31 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
 
         // Iterate over the Debugging Information Entries (DIEs) in the unit.
         let mut depth = 0;
+        // Offsets and depths of the struct/array types enclosing the current entry, so that members
+        // and subranges can be attached to their parent type
+        let mut aggregates: Vec<(usize, isize)> = Vec::new();
+        // Address ranges and depths of the lexical blocks enclosing the current entry, innermost
+        // last, so that variables can be limited to the block declaring them
+        let mut blocks: Vec<((usize, usize), isize)> = Vec::new();
         let mut entries = unit.entries();
         while let Some((delta_depth, entry)) = entries.next_dfs()? {
             depth += delta_depth;
+            while aggregates
+                .last()
+                .map_or(false, |(_, agg_depth)| *agg_depth >= depth)
+            {
+                aggregates.pop();
+            }
+            while blocks
+                .last()
+                .is_some_and(|(_, block_depth)| *block_depth >= depth)
+            {
+                blocks.pop();
+            }
+            let parent_aggregate = aggregates
+                .last()
+                .filter(|(_, agg_depth)| *agg_depth == depth - 1)
+                .map(|(offset, _)| *offset);
             // Update the offset_to_type mapping for types
             // Update the variable list for formal params/variables
             match entry.tag() {
@@ -67,8 +108,17 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
                     } else {
                         "<unknown>".to_string()
                     };
+                    // The name may be relative to the directory the compiler ran in
+                    let comp_dir = match entry.attr(gimli::DW_AT_comp_dir) {
+                        Ok(Some(attr)) => match get_attr_value(&attr, &unit, &dwarf) {
+                            Ok(DebugValue::Str(dir)) => Some(dir),
+                            _ => None,
+                        },
+                        _ => None,
+                    };
                     compilation_units.push(File {
                         name,
+                        comp_dir,
                         global_variables: Vec::new(),
                         functions: Vec::new(),
                         lines: Vec::new(),
@@ -97,9 +147,129 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
                         // TODO: report error?
                         0
                     };
-                    let type_offset = entry.offset().0;
-                    offset_to_type
-                        .insert(type_offset, Type::new(name, byte_size.try_into().unwrap()));
+                    let encoding = match entry.attr_value(gimli::DW_AT_encoding)? {
+                        Some(gimli::AttributeValue::Encoding(gimli::DW_ATE_unsigned)) => {
+                            BaseEncoding::Unsigned
+                        }
+                        Some(gimli::AttributeValue::Encoding(gimli::DW_ATE_signed_char)) => {
+                            BaseEncoding::SignedChar
+                        }
+                        Some(gimli::AttributeValue::Encoding(gimli::DW_ATE_unsigned_char)) => {
+                            BaseEncoding::UnsignedChar
+                        }
+                        Some(gimli::AttributeValue::Encoding(gimli::DW_ATE_float)) => {
+                            BaseEncoding::Float
+                        }
+                        Some(gimli::AttributeValue::Encoding(gimli::DW_ATE_boolean)) => {
+                            BaseEncoding::Boolean
+                        }
+                        _ => BaseEncoding::Signed,
+                    };
+                    offset_to_type.insert(
+                        entry_offset(entry, &unit),
+                        Type::new(
+                            name,
+                            byte_size.try_into().unwrap(),
+                            TypeKind::Base(encoding),
+                        ),
+                    );
+                }
+                gimli::DW_TAG_pointer_type => {
+                    offset_to_type.insert(
+                        entry_offset(entry, &unit),
+                        Type::new(
+                            String::new(),
+                            get_udata(entry, gimli::DW_AT_byte_size).unwrap_or(8),
+                            TypeKind::Pointer(get_type_offset(entry, &unit, &dwarf)),
+                        ),
+                    );
+                }
+                gimli::DW_TAG_structure_type | gimli::DW_TAG_union_type => {
+                    let keyword = if entry.tag() == gimli::DW_TAG_structure_type {
+                        "struct"
+                    } else {
+                        "union"
+                    };
+                    let name = match get_name(entry, &unit, &dwarf) {
+                        Some(name) => format!("{} {}", keyword, name),
+                        None => format!("{} {{...}}", keyword),
+                    };
+                    let offset = entry_offset(entry, &unit);
+                    offset_to_type.insert(
+                        offset,
+                        Type::new(
+                            name,
+                            get_udata(entry, gimli::DW_AT_byte_size).unwrap_or(0),
+                            TypeKind::Struct(Vec::new()),
+                        ),
+                    );
+                    aggregates.push((offset, depth));
+                }
+                gimli::DW_TAG_member => {
+                    let parent =
+                        parent_aggregate.and_then(|offset| offset_to_type.get_mut(&offset));
+                    if let Some(Type {
+                        kind: TypeKind::Struct(members),
+                        ..
+                    }) = parent
+                    {
+                        members.push(Member {
+                            name: get_name(entry, &unit, &dwarf)
+                                .unwrap_or_else(|| "<anonymous>".to_string()),
+                            offset: get_udata(entry, gimli::DW_AT_data_member_location)
+                                .unwrap_or(0),
+                            type_offset: get_type_offset(entry, &unit, &dwarf),
+                        });
+                    }
+                }
+                gimli::DW_TAG_array_type => {
+                    let offset = entry_offset(entry, &unit);
+                    offset_to_type.insert(
+                        offset,
+                        Type::new(
+                            String::new(),
+                            get_udata(entry, gimli::DW_AT_byte_size).unwrap_or(0),
+                            TypeKind::Array(get_type_offset(entry, &unit, &dwarf), 0),
+                        ),
+                    );
+                    aggregates.push((offset, depth));
+                }
+                gimli::DW_TAG_subrange_type => {
+                    let parent =
+                        parent_aggregate.and_then(|offset| offset_to_type.get_mut(&offset));
+                    if let Some(Type {
+                        kind: TypeKind::Array(_, count),
+                        ..
+                    }) = parent
+                    {
+                        if let Some(upper_bound) = get_udata(entry, gimli::DW_AT_upper_bound) {
+                            *count = upper_bound + 1;
+                        } else if let Some(element_count) = get_udata(entry, gimli::DW_AT_count) {
+                            *count = element_count;
+                        }
+                    }
+                }
+                gimli::DW_TAG_typedef | gimli::DW_TAG_const_type | gimli::DW_TAG_volatile_type => {
+                    let offset = entry_offset(entry, &unit);
+                    let name = match entry.tag() {
+                        gimli::DW_TAG_typedef => get_name(entry, &unit, &dwarf).unwrap_or_default(),
+                        gimli::DW_TAG_const_type => {
+                            qualifiers.insert(offset, "const");
+                            String::new()
+                        }
+                        _ => {
+                            qualifiers.insert(offset, "volatile");
+                            String::new()
+                        }
+                    };
+                    offset_to_type.insert(
+                        offset,
+                        Type::new(
+                            name,
+                            0,
+                            TypeKind::Alias(get_type_offset(entry, &unit, &dwarf)),
+                        ),
+                    );
                 }
                 gimli::DW_TAG_subprogram => {
                     let mut func: Function = Default::default();
@@ -132,11 +302,19 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
                             _ => {}
                         }
                     }
+                    func.return_type = get_type_offset(entry, &unit, &dwarf);
                     compilation_units.last_mut().unwrap().functions.push(func);
                 }
+                gimli::DW_TAG_lexical_block => {
+                    // Blocks given as a list of ranges are rare without optimization; their
+                    // variables are treated as belonging to the enclosing block
+                    if let Some(range) = get_pc_range(entry) {
+                        blocks.push((range, depth));
+                    }
+                }
                 gimli::DW_TAG_formal_parameter | gimli::DW_TAG_variable => {
                     let mut name = String::new();
-                    let mut entity_type: Option<Type> = None;
+                    let mut type_offset: Option<usize> = None;
                     let mut location: Option<Location> = None;
                     let mut line_number = 0;
                     let mut attrs = entry.attrs();
@@ -151,9 +329,7 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
                             }
                             gimli::DW_AT_type => {
                                 if let Ok(DebugValue::Size(offset)) = val {
-                                    if let Some(dtype) = offset_to_type.get(&offset).clone() {
-                                        entity_type = Some(dtype.clone());
-                                    }
+                                    type_offset = Some(offset);
                                 }
                             }
                             gimli::DW_AT_location => {
@@ -169,28 +345,38 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
                             _ => {}
                         }
                     }
-                    if entity_type.is_some() && location.is_some() {
-                        let var = Variable {
+                    if let (Some(type_offset), Some(location)) = (type_offset, location) {
+                        let variable = Variable {
                             name,
-                            entity_type: entity_type.unwrap(),
-                            location: location.unwrap(),
+                            entity_type: Type::default(),
+                            location,
                             line_number: line_number.try_into().unwrap(),
+                            scope: blocks.last().map(|(range, _)| *range),
+                            parameter: entry.tag() == gimli::DW_TAG_formal_parameter,
                         };
+                        let file_index = compilation_units.len() - 1;
                         if depth == 1 {
-                            compilation_units
-                                .last_mut()
-                                .unwrap()
-                                .global_variables
-                                .push(var);
+                            pending_variables.push(PendingVariable {
+                                variable,
+                                type_offset,
+                                file_index,
+                                function_index: None,
+                            });
                         } else if depth > 1 {
-                            compilation_units
-                                .last_mut()
+                            let function_index = compilation_units
+                                .last()
                                 .unwrap()
                                 .functions
-                                .last_mut()
-                                .unwrap()
-                                .variables
-                                .push(var);
+                                .len()
+                                .checked_sub(1);
+                            if function_index.is_some() {
+                                pending_variables.push(PendingVariable {
+                                    variable,
+                                    type_offset,
+                                    file_index,
+                                    function_index,
+                                });
+                            }
                         }
                     }
                 }
@@ -240,7 +426,148 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
             }
         }
     }
-    Ok(compilation_units)
+
+    name_anonymous_types(&mut offset_to_type, &qualifiers);
+
+    // Now that every type has been read, the variables can be filled in
+    for pending in pending_variables {
+        let mut variable = pending.variable;
+        variable.entity_type = match offset_to_type.get(&pending.type_offset) {
+            Some(dtype) => dtype.clone(),
+            None => continue,
+        };
+        let file = &mut compilation_units[pending.file_index];
+        match pending.function_index {
+            Some(function_index) => file.functions[function_index].variables.push(variable),
+            None => file.global_variables.push(variable),
+        }
+    }
+    Ok((compilation_units, offset_to_type))
+}
+
+/// Gives pointer, array and qualified types a C-style name built from the types they refer to
+/// (e.g. `int *` or `struct point [4]`), and fills in array sizes that weren't given explicitly.
+fn name_anonymous_types(
+    offset_to_type: &mut HashMap<usize, Type>,
+    qualifiers: &HashMap<usize, &'static str>,
+) {
+    fn name_of(
+        types: &HashMap<usize, Type>,
+        qualifiers: &HashMap<usize, &'static str>,
+        offset: Option<usize>,
+        depth: usize,
+    ) -> String {
+        let offset = match offset {
+            Some(offset) => offset,
+            None => return "void".to_string(),
+        };
+        let dtype = match types.get(&offset) {
+            Some(dtype) => dtype,
+            None => return "<unknown>".to_string(),
+        };
+        if !dtype.name.is_empty() || depth > 8 {
+            return dtype.name.clone();
+        }
+        match dtype.kind {
+            TypeKind::Pointer(target) => {
+                format!("{} *", name_of(types, qualifiers, target, depth + 1))
+            }
+            TypeKind::Array(element, count) => format!(
+                "{} [{}]",
+                name_of(types, qualifiers, element, depth + 1),
+                count
+            ),
+            TypeKind::Alias(target) => format!(
+                "{} {}",
+                qualifiers.get(&offset).unwrap_or(&"const"),
+                name_of(types, qualifiers, target, depth + 1)
+            ),
+            _ => String::new(),
+        }
+    }
+
+    let unnamed: Vec<usize> = offset_to_type
+        .iter()
+        .filter(|(_, dtype)| dtype.name.is_empty())
+        .map(|(offset, _)| *offset)
+        .collect();
+    let names: Vec<(usize, String)> = unnamed
+        .into_iter()
+        .map(|offset| (offset, name_of(offset_to_type, qualifiers, Some(offset), 0)))
+        .collect();
+    for (offset, name) in names {
+        offset_to_type.get_mut(&offset).unwrap().name = name;
+    }
+
+    let unsized_arrays: Vec<(usize, usize)> = offset_to_type
+        .iter()
+        .filter_map(|(offset, dtype)| match dtype.kind {
+            TypeKind::Array(Some(element), count) if dtype.size == 0 => {
+                let element_size = offset_to_type.get(&element)?.size;
+                Some((*offset, element_size * count))
+            }
+            _ => None,
+        })
+        .collect();
+    for (offset, size) in unsized_arrays {
+        offset_to_type.get_mut(&offset).unwrap().size = size;
+    }
+}
+
+/// Returns the offset of a DIE from the start of .debug_info, which is how DW_AT_type refers to it.
+fn entry_offset<R: Reader>(
+    entry: &gimli::DebuggingInformationEntry<R>,
+    unit: &gimli::Unit<R>,
+) -> usize {
+    match entry.offset().to_unit_section_offset(unit) {
+        UnitSectionOffset::DebugInfoOffset(offset) => offset.0,
+        UnitSectionOffset::DebugTypesOffset(offset) => offset.0,
+    }
+}
+
+fn get_name<R: Reader>(
+    entry: &gimli::DebuggingInformationEntry<R>,
+    unit: &gimli::Unit<R>,
+    dwarf: &gimli::Dwarf<R>,
+) -> Option<String> {
+    let attr = entry.attr(gimli::DW_AT_name).ok()??;
+    match get_attr_value(&attr, unit, dwarf) {
+        Ok(DebugValue::Str(name)) => Some(name),
+        _ => None,
+    }
+}
+
+fn get_type_offset<R: Reader>(
+    entry: &gimli::DebuggingInformationEntry<R>,
+    unit: &gimli::Unit<R>,
+    dwarf: &gimli::Dwarf<R>,
+) -> Option<usize> {
+    let attr = entry.attr(gimli::DW_AT_type).ok()??;
+    match get_attr_value(&attr, unit, dwarf) {
+        Ok(DebugValue::Size(offset)) => Some(offset),
+        _ => None,
+    }
+}
+
+/// Returns the addresses `[low_pc, high_pc)` an entry covers, if it gives them as a single range.
+fn get_pc_range<R: Reader>(entry: &gimli::DebuggingInformationEntry<R>) -> Option<(usize, usize)> {
+    let low_pc = match entry.attr_value(gimli::DW_AT_low_pc).ok()?? {
+        gimli::AttributeValue::Addr(addr) => addr,
+        _ => return None,
+    };
+    // Since DWARF 4, high_pc is usually an offset from low_pc rather than an address
+    let high_pc = match entry.attr_value(gimli::DW_AT_high_pc).ok()?? {
+        gimli::AttributeValue::Addr(addr) => addr,
+        value => low_pc + value.udata_value()?,
+    };
+    Some((low_pc.try_into().ok()?, high_pc.try_into().ok()?))
+}
+
+fn get_udata<R: Reader>(
+    entry: &gimli::DebuggingInformationEntry<R>,
+    name: gimli::DwAt,
+) -> Option<usize> {
+    entry.attr(name).ok()??.udata_value()?.try_into().ok()
 }
 
 #[derive(Debug, Clone)]
@@ -327,16 +654,10 @@ fn get_attr_value<R: Reader>(
             dump_exprloc(w, unit.encoding(), data)?;
             Ok(DebugValue::Str(w.to_string()))
         }
-        gimli::AttributeValue::UnitRef(offset) => {
-            match offset.to_unit_section_offset(unit) {
-                UnitSectionOffset::DebugInfoOffset(goff) => {
-                    Ok(DebugValue::Size(goff.0))
-                }
-                UnitSectionOffset::DebugTypesOffset(goff) => {
-                    Ok(DebugValue::Size(goff.0))
-                }
-            }
-        }
+        gimli::AttributeValue::UnitRef(offset) => match offset.to_unit_section_offset(unit) {
+            UnitSectionOffset::DebugInfoOffset(goff) => Ok(DebugValue::Size(goff.0)),
+            UnitSectionOffset::DebugTypesOffset(goff) => Ok(DebugValue::Size(goff.0)),
+        },
         gimli::AttributeValue::DebugStrRef(offset) => {
             if let Ok(s) = dwarf.debug_str.get_str(offset) {
                 Ok(DebugValue::Str(format!("{}", s.to_string_lossy()?)))
@@ -356,9 +677,7 @@ fn get_attr_value<R: Reader>(
             dump_file_index(w, value, unit, dwarf)?;
             Ok(DebugValue::Str(w.to_string()))
         }
-        _ => {
-            Ok(DebugValue::NoVal)
-        }
+        _ => Ok(DebugValue::NoVal),
     }
 }
 
@@ -1,15 +1,17 @@
 use nix::sys::ptrace;
 use nix::sys::signal;
-use nix::sys::signal::Signal::SIGTRAP;
+use nix::sys::signal::Signal::{SIGCHLD, SIGSTOP, SIGTRAP};
+use nix::sys::uio::{self, IoVec, RemoteIoVec};
 use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
 use nix::unistd::Pid;
 use std::collections::HashMap;
+use std::convert::TryInto;
 use std::mem::size_of;
 use std::os::unix::process::CommandExt;
 use std::process::Child;
 use std::process::Command;
 
-use crate::dwarf_data::DwarfData;
+use crate::dwarf_data::{DwarfData, FrameRegister, Line};
 
 pub enum Status {
     /// Indicates inferior stopped. Contains the signal that stopped the process, as well as the
@@ -22,6 +24,65 @@ pub enum Status {
     /// Indicates the inferior exited due to a signal. Contains the signal that killed the
     /// process.
     Signaled(signal::Signal),
+
+    /// Indicates the inferior forked. Contains the pid of the new process, which starts out traced
+    /// and stopped.
+    Forked(Pid),
+
+    /// Indicates the inferior called exec. Contains the instruction pointer of the new program.
+    Execed(usize),
+
+    /// Indicates the inferior started a new thread. Contains the number the debugger gave the
+    /// thread and its thread id.
+    NewThread(usize, Pid),
+}
+
+/// A thread of the inferior.
+pub struct Thread {
+    /// The number the debugger refers to this thread by. The main thread is 1.
+    pub id: usize,
+    pub tid: Pid,
+    /// The signal the thread reported when it last stopped, if it stopped by itself (rather than
+    /// because we stopped it)
+    stopped_by: Option<signal::Signal>,
+    /// Whether the thread has yet to report a SIGSTOP we sent it, because it stopped for another
+    /// reason first
+    sigstop_pending: bool,
+    /// A signal that arrived while we were stopping the thread, to deliver when it resumes
+    deferred_signal: Option<signal::Signal>,
+    /// Whether the thread last stopped entering or leaving a syscall we were asked to catch
+    at_syscall: bool,
+}
+
+impl Thread {
+    fn new(id: usize, tid: Pid) -> Thread {
+        Thread {
+            id,
+            tid,
+            stopped_by: None,
+            sigstop_pending: false,
+            deferred_signal: None,
+            at_syscall: false,
+        }
+    }
+}
+
+/// A thread stopped entering or leaving a syscall, as found by `Inferior::syscall_stop`.
+pub enum SyscallStop {
+    /// About to make the syscall with this number, with these arguments
+    Entry(u64, Vec<u64>),
+    /// Back from the syscall with this number, which returned this value
+    Exit(u64, u64),
+}
+
+/// A frame on the current thread's stack, as found by `Inferior::backtrace`.
+pub struct Frame {
+    /// Where the frame is executing: the stop address for the innermost frame, and the return
+    /// address for the others
+    pub address: usize,
+    /// The function the frame belongs to (None if it has no debug info)
+    pub function: Option<String>,
+    pub line: Option<Line>,
 }
 
 /// This function calls ptrace with PTRACE_TRACEME to enable debugging on a process. You should use
@@ -35,35 +96,128 @@ fn child_traceme() -> Result<(), std::io::Error> {
 
 pub struct Inferior {
     child: Child,
+    /// The process being debugged (which is also the id of its main thread). This starts out as
+    /// `child`, but changes if we follow a fork.
+    pid: Pid,
+    /// Every thread of the process that is still alive, in the order they were created
+    threads: Vec<Thread>,
+    next_thread_id: usize,
+    /// The thread whose registers and stack we look at: the one that stopped most recently, unless
+    /// the user selected another one
+    current: Pid,
+    /// The syscalls to stop at (any, if empty), or None to let syscalls run without stopping
+    caught_syscalls: Option<Vec<u64>>,
 }
 
 fn align_addr_to_word(addr: usize) -> usize {
     addr & (-(size_of::<usize>() as isize) as usize)
 }
 
+/// Replaces the byte at `addr` in the memory of process `pid` with `val`, returning the original
+/// byte.
+fn write_byte_at(pid: Pid, addr: usize, val: u8) -> Result<u8, nix::Error> {
+    let aligned_addr = align_addr_to_word(addr);
+    let byte_offset = addr - aligned_addr;
+    let word = ptrace::read(pid, aligned_addr as ptrace::AddressType)? as u64;
+    let orig_byte = (word >> (8 * byte_offset)) & 0xff;
+    let masked_word = word & !(0xff << (8 * byte_offset));
+    let updated_word = masked_word | ((val as u64) << (8 * byte_offset));
+    ptrace::write(
+        pid,
+        aligned_addr as ptrace::AddressType,
+        updated_word as *mut std::ffi::c_void,
+    )?;
+    Ok(orig_byte as u8)
+}
+
+/// Sends `sig` to the single thread `tid` of process `pid` (kill would let any thread take it).
+fn tgkill(pid: Pid, tid: Pid, sig: signal::Signal) -> Result<(), nix::Error> {
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_tgkill,
+            pid.as_raw(),
+            tid.as_raw(),
+            sig as libc::c_int,
+        )
+    };
+    nix::errno::Errno::result(res).map(drop)
+}
+
+/// Number of addresses the debug registers can watch at once
+pub const MAX_WATCH_POINTS: usize = 4;
+
+/// Most frames a backtrace shows, in case a corrupted stack never leads back to main
+const MAX_BACKTRACE_FRAMES: usize = 1024;
+
+/// Reads debug register `index` (%dr0 to %dr7) of thread `tid`.
+fn debug_register(tid: Pid, index: usize) -> Result<usize, nix::Error> {
+    let offset = std::mem::offset_of!(libc::user, u_debugreg) + index * size_of::<u64>();
+    let value = unsafe {
+        nix::errno::Errno::clear();
+        libc::ptrace(libc::PTRACE_PEEKUSER, tid.as_raw(), offset, 0)
+    };
+    // The register may hold -1, so only errno tells whether the read failed
+    match nix::errno::Errno::result(value) {
+        Ok(_) | Err(nix::Error::Sys(nix::errno::Errno::UnknownErrno)) => Ok(value as usize),
+        Err(err) => Err(err),
+    }
+}
+
+/// Sets debug register `index` (%dr0 to %dr7) of thread `tid` to `value`.
+fn set_debug_register(tid: Pid, index: usize, value: usize) -> Result<(), nix::Error> {
+    let offset = std::mem::offset_of!(libc::user, u_debugreg) + index * size_of::<u64>();
+    let res = unsafe { libc::ptrace(libc::PTRACE_POKEUSER, tid.as_raw(), offset, value) };
+    nix::errno::Errno::result(res).map(drop)
+}
+
+/// Removes breakpoints from the process `pid` and lets it run untraced.
+fn release_process(
+    pid: Pid,
+    tids: &[Pid],
+    break_points: &HashMap<usize, u8>,
+) -> Result<(), nix::Error> {
+    for (addr, orig_byte) in break_points {
+        write_byte_at(pid, *addr, *orig_byte)?;
+    }
+    for tid in tids {
+        ptrace::detach(*tid, None)?;
+    }
+    Ok(())
+}
+
 impl Inferior {
-    /// Attempts to start a new inferior process. Returns Some(Inferior) if successful, or None if
-    /// an error is encountered.
-    pub fn new(
-        target: &str,
-        args: &Vec<String>,
-        break_points: &mut HashMap<usize, u8>,
-    ) -> Option<Inferior> {
+    /// Attempts to start a new inferior process, stopped before it runs any instructions (see
+    /// `insert_break_points`). Returns Some(Inferior) if successful, or None if an error is
+    /// encountered.
+    pub fn new(target: &str, args: &Vec<String>) -> Option<Inferior> {
         let mut cmd = Command::new(target);
         cmd.args(args);
         unsafe {
             cmd.pre_exec(child_traceme);
         }
         let child = cmd.spawn().expect("Child process error");
-        let mut inferior = Inferior { child };
-        let status = inferior.wait(None).ok()?;
+        let pid = Pid::from_raw(child.id() as i32);
+        let inferior = Inferior {
+            child,
+            pid,
+            threads: vec![Thread::new(1, pid)],
+            next_thread_id: 2,
+            current: pid,
+            caught_syscalls: None,
+        };
+        let status = inferior.wait_thread(pid).ok()?;
 
-        for (addr, orig_byte) in break_points {
-            // replacing the byte at breakpoint with the value 0xcc
-            *orig_byte = inferior
-                .write_byte(*addr, 0xcc)
-                .expect("Error setting breakpoint");
-        }
+        // Ask to be told about forks, execs and new threads, so that nothing escapes the debugger,
+        // and for syscall stops to be told apart from SIGTRAPs
+        ptrace::setoptions(
+            pid,
+            ptrace::Options::PTRACE_O_TRACEFORK
+                | ptrace::Options::PTRACE_O_TRACEVFORK
+                | ptrace::Options::PTRACE_O_TRACEEXEC
+                | ptrace::Options::PTRACE_O_TRACECLONE
+                | ptrace::Options::PTRACE_O_TRACESYSGOOD,
+        )
+        .ok()?;
 
         if let Status::Stopped(signal::Signal::SIGTRAP, _signal) = status {
             Some(inferior)
@@ -73,95 +227,873 @@ impl Inferior {
     }
 
     fn write_byte(&mut self, addr: usize, val: u8) -> Result<u8, nix::Error> {
-        let aligned_addr = align_addr_to_word(addr);
-        let byte_offset = addr - aligned_addr;
-        let word = ptrace::read(self.pid(), aligned_addr as ptrace::AddressType)? as u64;
-        let orig_byte = (word >> (8 * byte_offset)) & 0xff;
-        let masked_word = word & !(0xff << (8 * byte_offset));
-        let updated_word = masked_word | ((val as u64) << (8 * byte_offset));
-        ptrace::write(
-            self.pid(),
-            aligned_addr as ptrace::AddressType,
-            updated_word as *mut std::ffi::c_void,
-        )?;
-        Ok(orig_byte as u8)
+        write_byte_at(self.current, addr, val)
+    }
+
+    /// Sets every breakpoint in `break_points` in a newly started inferior, recording the byte each
+    /// one replaced.
+    pub fn insert_break_points(
+        &mut self,
+        break_points: &mut HashMap<usize, u8>,
+    ) -> Result<(), nix::Error> {
+        for (addr, orig_byte) in break_points {
+            // replacing the byte at breakpoint with the value 0xcc
+            *orig_byte = self.write_byte(*addr, 0xcc)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the address the program's entry point was loaded at, from the auxiliary vector the
+    /// kernel gave it.
+    pub fn entry_point(&self) -> Result<usize, std::io::Error> {
+        let auxv = std::fs::read(format!("/proc/{}/auxv", self.pid))?;
+        // The vector is (type, value) pairs of words
+        let words: Vec<usize> = auxv
+            .chunks_exact(size_of::<usize>())
+            .map(|word| usize::from_ne_bytes(word.try_into().unwrap()))
+            .collect();
+        words
+            .chunks_exact(2)
+            .find(|entry| entry[0] == libc::AT_ENTRY as usize)
+            .map(|entry| entry[1])
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "no AT_ENTRY"))
+    }
+
+    /// Makes every thread trap after writing to any of `watch_points`, given as (address, length)
+    /// pairs, using the debug registers. There can be at most `MAX_WATCH_POINTS`, each 1, 2, 4 or
+    /// 8 bytes long and aligned to its length. Threads don't inherit debug registers, so this
+    /// must be done again for new threads.
+    pub fn set_watch_points(&self, watch_points: &[(usize, usize)]) -> Result<(), nix::Error> {
+        // %dr7 enables %dr0 to %dr3, each with 2 bits for when it traps and 2 for its length
+        let mut control = 0;
+        for (index, &(_, len)) in watch_points.iter().enumerate() {
+            let len_bits = match len {
+                1 => 0b00,
+                2 => 0b01,
+                8 => 0b10,
+                _ => 0b11,
+            };
+            let write_only = 0b01;
+            control |= 1 << (2 * index) | (write_only | len_bits << 2) << (16 + 4 * index);
+        }
+        for thread in &self.threads {
+            // The addresses can only change while their registers are disabled
+            set_debug_register(thread.tid, 7, 0)?;
+            for (index, &(addr, _)) in watch_points.iter().enumerate() {
+                set_debug_register(thread.tid, index, addr)?;
+            }
+            set_debug_register(thread.tid, 7, control)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the indexes (into the list given to `set_watch_points`) of the watchpoints the
+    /// current thread triggered in stopping, and forgets them, since %dr6 only ever has bits added.
+    pub fn watch_point_hits(&self) -> Result<Vec<usize>, nix::Error> {
+        let status = debug_register(self.current, 6)?;
+        if status & 0b1111 == 0 {
+            return Ok(Vec::new());
+        }
+        set_debug_register(self.current, 6, 0)?;
+        Ok((0..MAX_WATCH_POINTS)
+            .filter(|index| status & (1 << index) != 0)
+            .collect())
+    }
+
+    /// Sets a breakpoint at `addr` while the inferior is running, returning the byte it replaced.
+    pub fn insert_break_point(&mut self, addr: usize) -> Result<u8, nix::Error> {
+        self.write_byte(addr, 0xcc)
+    }
+
+    /// Deals with a `Status::Forked` stop. If `follow` is set, the debugger switches over to the
+    /// new process and lets the parent run freely; otherwise the new process is let go. Either
+    /// way, breakpoints are removed from the released process so it doesn't trap on them.
+    pub fn handle_fork(
+        &mut self,
+        new_pid: Pid,
+        follow: bool,
+        break_points: &HashMap<usize, u8>,
+    ) -> Result<(), nix::Error> {
+        // The new process starts with a SIGSTOP, which we need to collect before touching it
+        waitpid(new_pid, None)?;
+        if !follow {
+            return release_process(new_pid, &[new_pid], break_points);
+        }
+
+        let old_pid = std::mem::replace(&mut self.pid, new_pid);
+        let old_threads = std::mem::replace(&mut self.threads, vec![Thread::new(1, new_pid)]);
+        self.next_thread_id = 2;
+        self.current = new_pid;
+        let tids: Vec<Pid> = old_threads.iter().map(|thread| thread.tid).collect();
+        release_process(old_pid, &tids, break_points)?;
+        // Threads we were in the middle of stopping still have a SIGSTOP coming, which would stop
+        // the whole (now untraced) process
+        if old_threads.iter().any(|thread| thread.sigstop_pending) {
+            signal::kill(old_pid, signal::Signal::SIGCONT)?;
+        }
+        Ok(())
+    }
+
+    /// Makes the inferior stop whenever a thread enters or leaves one of `syscalls` (any syscall,
+    /// if it is empty) once it is resumed, or stops catching syscalls if there are None.
+    pub fn catch_syscalls(&mut self, syscalls: Option<Vec<u64>>) {
+        self.caught_syscalls = syscalls;
+    }
+
+    /// Returns the syscall the current thread stopped entering or leaving, if it stopped for one
+    /// it was asked to catch.
+    pub fn syscall_stop(&self) -> Result<Option<SyscallStop>, nix::Error> {
+        if !self.current_thread().at_syscall {
+            return Ok(None);
+        }
+        let mut info: libc::ptrace_syscall_info = unsafe { std::mem::zeroed() };
+        let res = unsafe {
+            libc::ptrace(
+                libc::PTRACE_GET_SYSCALL_INFO,
+                self.current.as_raw(),
+                size_of::<libc::ptrace_syscall_info>(),
+                &mut info as *mut libc::ptrace_syscall_info,
+            )
+        };
+        nix::errno::Errno::result(res)?;
+        // The number is left in orig_rax all the way through the syscall
+        let regs = ptrace::getregs(self.current)?;
+        Ok(match info.op {
+            libc::PTRACE_SYSCALL_INFO_ENTRY => {
+                let args = unsafe { info.u.entry.args };
+                Some(SyscallStop::Entry(regs.orig_rax, args.to_vec()))
+            }
+            libc::PTRACE_SYSCALL_INFO_EXIT => Some(SyscallStop::Exit(regs.orig_rax, regs.rax)),
+            _ => None,
+        })
+    }
+
+    /// Lets thread `tid` carry on, delivering `signal` to it, until it next stops (including at a
+    /// syscall, if we are catching them).
+    fn cont(&self, tid: Pid, signal: Option<signal::Signal>) -> Result<(), nix::Error> {
+        if self.caught_syscalls.is_some() {
+            ptrace::syscall(tid, signal)
+        } else {
+            ptrace::cont(tid, signal)
+        }
     }
 
     /// commend 'contunie' after pause the debugger
     pub fn wake_up(&mut self, break_points: &HashMap<usize, u8>) -> Result<Status, nix::Error> {
-        let pid = self.pid();
-        let mut regs = ptrace::getregs(pid)?;
+        self.resume(break_points, false)
+            .map(|(status, _instructions)| status)
+    }
+
+    /// Like `wake_up`, but single-steps the current thread in order to count how many
+    /// instructions it executes before the inferior stops. Returns the count along with the
+    /// status. This is very slow.
+    pub fn wake_up_counting(
+        &mut self,
+        break_points: &HashMap<usize, u8>,
+    ) -> Result<(Status, u64), nix::Error> {
+        self.resume(break_points, true)
+    }
+
+    /// Resumes every thread and waits until something happens that the debugger should hear
+    /// about, at which point all threads are stopped again. If `counting`, the current thread is
+    /// single-stepped instead and the number of instructions it executed is returned too.
+    fn resume(
+        &mut self,
+        break_points: &HashMap<usize, u8>,
+        counting: bool,
+    ) -> Result<(Status, u64), nix::Error> {
+        let mut count = 0;
+        let tids: Vec<Pid> = self.threads.iter().map(|thread| thread.tid).collect();
+        for tid in tids {
+            let at_breakpoint = self.trapped_at_breakpoint(tid, break_points)?;
+            if let Some(status) = self.step_over_breakpoint(tid, break_points)? {
+                return Ok((status, 1));
+            }
+            if at_breakpoint && counting && tid == self.current {
+                count += 1;
+            }
+        }
+
+        let mut stepping_from = None;
+        for index in 0..self.threads.len() {
+            let thread = &mut self.threads[index];
+            thread.stopped_by = None;
+            thread.at_syscall = false;
+            let (tid, signal) = (thread.tid, thread.deferred_signal.take());
+            if counting && tid == self.current {
+                stepping_from = Some(ptrace::getregs(tid)?.rip as usize);
+                ptrace::step(tid, signal)?;
+            } else {
+                self.cont(tid, signal)?;
+            }
+        }
+
+        // New threads can report their first stop before their creator tells us about them
+        let mut early_threads = Vec::new();
+        loop {
+            match waitpid(None, Some(WaitPidFlag::__WALL))? {
+                WaitStatus::Exited(tid, exit_code) => {
+                    if tid == self.pid {
+                        return Ok((Status::Exited(exit_code), count));
+                    }
+                    self.forget_thread(tid);
+                }
+                WaitStatus::Signaled(tid, signal, _core_dumped) => {
+                    if tid == self.pid {
+                        return Ok((Status::Signaled(signal), count));
+                    }
+                    self.forget_thread(tid);
+                }
+                WaitStatus::Stopped(tid, signal) => {
+                    let thread = match self.threads.iter_mut().find(|thread| thread.tid == tid) {
+                        Some(thread) => thread,
+                        None => {
+                            if signal == SIGSTOP {
+                                early_threads.push(tid);
+                            }
+                            continue;
+                        }
+                    };
+                    // Let the thread carry on (or keep stepping) if the stop was routine
+                    let stepping = counting && tid == self.current;
+                    let resume_with = match signal {
+                        SIGSTOP if thread.sigstop_pending => {
+                            thread.sigstop_pending = false;
+                            Some(None)
+                        }
+                        // A child of the inferior changing state is routine (e.g. after a fork),
+                        // so pass the signal along rather than stopping
+                        SIGCHLD => Some(Some(SIGCHLD)),
+                        SIGTRAP if stepping => {
+                            let rip = ptrace::getregs(tid)?.rip as usize;
+                            let from = stepping_from.unwrap_or(rip);
+                            // Executing one of our int3s traps too, which means a breakpoint was hit
+                            if break_points.contains_key(&from) && rip == from + 1 {
+                                None
+                            } else {
+                                count += 1;
+                                stepping_from = Some(rip);
+                                Some(None)
+                            }
+                        }
+                        _ => None,
+                    };
+                    match resume_with {
+                        Some(signal) if stepping => ptrace::step(tid, signal)?,
+                        Some(signal) => self.cont(tid, signal)?,
+                        None => {
+                            thread.stopped_by = Some(signal);
+                            self.current = tid;
+                            self.stop_other_threads(&[tid], break_points)?;
+                            let rip = ptrace::getregs(tid)?.rip as usize;
+                            return Ok((Status::Stopped(signal, rip), count));
+                        }
+                    }
+                }
+                WaitStatus::PtraceSyscall(tid) => {
+                    let number = ptrace::getregs(tid)?.orig_rax;
+                    let caught = match &self.caught_syscalls {
+                        Some(syscalls) => syscalls.is_empty() || syscalls.contains(&number),
+                        None => false,
+                    };
+                    let thread = match self.threads.iter_mut().find(|thread| thread.tid == tid) {
+                        Some(thread) if caught => thread,
+                        _ => {
+                            self.cont(tid, None)?;
+                            continue;
+                        }
+                    };
+                    thread.stopped_by = Some(SIGTRAP);
+                    thread.at_syscall = true;
+                    self.current = tid;
+                    self.stop_other_threads(&[tid], break_points)?;
+                    let rip = ptrace::getregs(tid)?.rip as usize;
+                    return Ok((Status::Stopped(SIGTRAP, rip), count));
+                }
+                WaitStatus::PtraceEvent(tid, _signal, event) => match event {
+                    libc::PTRACE_EVENT_CLONE => {
+                        let new_tid = Pid::from_raw(ptrace::getevent(tid)? as i32);
+                        // The new thread starts with a SIGSTOP, which we need to collect
+                        match early_threads.iter().position(|early| *early == new_tid) {
+                            Some(index) => {
+                                early_threads.remove(index);
+                            }
+                            None => {
+                                waitpid(new_tid, Some(WaitPidFlag::__WALL))?;
+                            }
+                        }
+                        let id = self.add_thread(new_tid);
+                        self.stop_other_threads(&[tid, new_tid], break_points)?;
+                        return Ok((Status::NewThread(id, new_tid), count));
+                    }
+                    libc::PTRACE_EVENT_FORK | libc::PTRACE_EVENT_VFORK => {
+                        let new_pid = Pid::from_raw(ptrace::getevent(tid)? as i32);
+                        self.current = tid;
+                        self.stop_other_threads(&[tid], break_points)?;
+                        return Ok((Status::Forked(new_pid), count));
+                    }
+                    libc::PTRACE_EVENT_EXEC => {
+                        // exec gets rid of every other thread, and the process carries on in
+                        // its main thread
+                        self.threads = vec![Thread::new(1, self.pid)];
+                        self.next_thread_id = 2;
+                        self.current = self.pid;
+                        let regs = ptrace::getregs(self.pid)?;
+                        return Ok((Status::Execed(regs.rip as usize), count));
+                    }
+                    other => panic!("waitpid returned unexpected ptrace event: {}", other),
+                },
+                other => panic!("waitpid returned unexpected status: {:?}", other),
+            }
+        }
+    }
+
+    /// Stops every thread other than those in `except` (which are already stopped), so that the
+    /// debugger sees the whole inferior standing still.
+    fn stop_other_threads(
+        &mut self,
+        except: &[Pid],
+        break_points: &HashMap<usize, u8>,
+    ) -> Result<(), nix::Error> {
+        let threads: Vec<(Pid, bool)> = self
+            .threads
+            .iter()
+            .map(|thread| (thread.tid, thread.sigstop_pending))
+            .filter(|(tid, _)| !except.contains(tid))
+            .collect();
+        for (tid, already_pending) in threads {
+            // A thread still stopping for our last SIGSTOP would take a second one as a new stop
+            if !already_pending {
+                match tgkill(self.pid, tid, SIGSTOP) {
+                    // The thread is exiting, which it will report below
+                    Ok(()) | Err(nix::Error::Sys(nix::errno::Errno::ESRCH)) => {}
+                    Err(err) => return Err(err),
+                }
+            }
+            // The thread may stop for another reason before our SIGSTOP arrives
+            let (sigstop_pending, deferred_signal) = match waitpid(tid, Some(WaitPidFlag::__WALL))?
+            {
+                WaitStatus::Stopped(_, SIGSTOP) => (false, None),
+                WaitStatus::Stopped(_, SIGTRAP) => {
+                    // If it hit a breakpoint, back it up so that it hits it again once resumed
+                    let mut regs = ptrace::getregs(tid)?;
+                    if break_points.contains_key(&(regs.rip as usize - 1)) {
+                        regs.rip -= 1;
+                        ptrace::setregs(tid, regs)?;
+                    }
+                    (true, None)
+                }
+                WaitStatus::Stopped(_, signal) => (true, Some(signal)),
+                WaitStatus::PtraceEvent(_, _, libc::PTRACE_EVENT_CLONE) => {
+                    let new_tid = Pid::from_raw(ptrace::getevent(tid)? as i32);
+                    waitpid(new_tid, Some(WaitPidFlag::__WALL))?;
+                    self.add_thread(new_tid);
+                    (true, None)
+                }
+                WaitStatus::PtraceEvent(_, _, event)
+                    if event == libc::PTRACE_EVENT_FORK || event == libc::PTRACE_EVENT_VFORK =>
+                {
+                    // The debugger only hears about forks from the thread that stopped
+                    // first, so let this one go
+                    let new_pid = Pid::from_raw(ptrace::getevent(tid)? as i32);
+                    waitpid(new_pid, None)?;
+                    release_process(new_pid, &[new_pid], break_points)?;
+                    (true, None)
+                }
+                WaitStatus::Exited(..) | WaitStatus::Signaled(..) => {
+                    self.forget_thread(tid);
+                    continue;
+                }
+                _ => (true, None),
+            };
+            if let Some(thread) = self.threads.iter_mut().find(|thread| thread.tid == tid) {
+                thread.sigstop_pending = sigstop_pending;
+                thread.deferred_signal = deferred_signal;
+            }
+        }
+        Ok(())
+    }
+
+    fn add_thread(&mut self, tid: Pid) -> usize {
+        let id = self.next_thread_id;
+        self.next_thread_id += 1;
+        self.threads.push(Thread::new(id, tid));
+        id
+    }
+
+    fn forget_thread(&mut self, tid: Pid) {
+        self.threads.retain(|thread| thread.tid != tid);
+        if self.current == tid {
+            self.current = self.pid;
+        }
+    }
+
+    /// Returns whether thread `tid` stopped because it hit one of our breakpoints.
+    fn trapped_at_breakpoint(
+        &self,
+        tid: Pid,
+        break_points: &HashMap<usize, u8>,
+    ) -> Result<bool, nix::Error> {
+        let trapped = self
+            .threads
+            .iter()
+            .any(|thread| thread.tid == tid && thread.stopped_by == Some(SIGTRAP));
+        Ok(trapped && break_points.contains_key(&(ptrace::getregs(tid)?.rip as usize - 1)))
+    }
+
+    /// Returns the address of the breakpoint the current thread stopped at by hitting it, if any.
+    pub fn break_point_hit(
+        &self,
+        break_points: &HashMap<usize, u8>,
+    ) -> Result<Option<usize>, nix::Error> {
+        if self.trapped_at_breakpoint(self.current, break_points)? {
+            Ok(Some(self.instruction_pointer()? - 1))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// If thread `tid` stopped at a breakpoint, executes the instruction the breakpoint replaced
+    /// and puts the breakpoint back. Returns the status if the inferior ended during that step.
+    fn step_over_breakpoint(
+        &mut self,
+        tid: Pid,
+        break_points: &HashMap<usize, u8>,
+    ) -> Result<Option<Status>, nix::Error> {
+        if !self.trapped_at_breakpoint(tid, break_points)? {
+            return Ok(None);
+        }
+        let mut regs = ptrace::getregs(tid)?;
         let rip = regs.rip as usize;
 
-        // check if inferior stopped at a breakpoint
         if let Some(orig_byte) = break_points.get(&(rip - 1)) {
-            self.write_byte(rip - 1, *orig_byte)
+            write_byte_at(tid, rip - 1, *orig_byte)
                 .expect("Error restoring original first byte of instruction");
             regs.rip = (rip - 1) as u64;
-            ptrace::setregs(pid, regs).expect("Error rewingding instruction pointer");
+            ptrace::setregs(tid, regs).expect("Error rewingding instruction pointer");
 
-            ptrace::step(pid, None)?;
-            let status = self.wait(None)?;
+            ptrace::step(tid, None)?;
+            let status = self.wait_thread(tid)?;
             match status {
                 Status::Stopped(SIGTRAP, _ins_ptr) => {
-                    self.write_byte(rip - 1, 0xcc)
-                        .expect("Error restoring 0xcc in breakpoint");
+                    write_byte_at(tid, rip - 1, 0xcc).expect("Error restoring 0xcc in breakpoint");
                 }
                 Status::Exited(exit_code) => {
-                    return Ok(Status::Exited(exit_code));
+                    return Ok(Some(Status::Exited(exit_code)));
                 }
                 Status::Signaled(signal) => {
-                    return Ok(Status::Signaled(signal));
+                    return Ok(Some(Status::Signaled(signal)));
                 }
                 _ => {}
             }
         }
+        Ok(None)
+    }
+
+    /// Reads `len` bytes of the inferior's memory starting at `addr`, in one system call if
+    /// possible rather than a word at a time through ptrace.
+    pub fn read_memory(&self, addr: usize, len: usize) -> Result<Vec<u8>, nix::Error> {
+        let mut bytes = vec![0; len];
+        let remote = RemoteIoVec { base: addr, len };
+        match uio::process_vm_readv(
+            self.current,
+            &[IoVec::from_mut_slice(&mut bytes)],
+            &[remote],
+        ) {
+            Ok(read) if read == len => Ok(bytes),
+            // process_vm_readv may not be allowed (e.g. in some containers), and stops at the
+            // first page it can't read, for which ptrace gives the error
+            _ => self.peek_memory(addr, len),
+        }
+    }
+
+    /// Reads `len` bytes of the inferior's memory starting at `addr` through ptrace.
+    fn peek_memory(&self, addr: usize, len: usize) -> Result<Vec<u8>, nix::Error> {
+        let start = align_addr_to_word(addr);
+        let mut bytes = Vec::with_capacity(len + size_of::<usize>() * 2);
+        let mut word_addr = start;
+        while word_addr < addr + len {
+            let word = ptrace::read(self.current, word_addr as ptrace::AddressType)? as u64;
+            bytes.extend_from_slice(&word.to_le_bytes());
+            word_addr += size_of::<usize>();
+        }
+        Ok(bytes[addr - start..addr - start + len].to_vec())
+    }
+
+    /// Writes `bytes` to the inferior's memory starting at `addr`, in one system call if possible
+    /// rather than a word at a time through ptrace.
+    #[allow(dead_code)]
+    pub fn write_memory(&self, addr: usize, bytes: &[u8]) -> Result<(), nix::Error> {
+        let remote = RemoteIoVec {
+            base: addr,
+            len: bytes.len(),
+        };
+        match uio::process_vm_writev(self.current, &[IoVec::from_slice(bytes)], &[remote]) {
+            Ok(written) if written == bytes.len() => return Ok(()),
+            // Read-only memory, like the code, can only be written through ptrace
+            _ => {}
+        }
+        let end = addr + bytes.len();
+        let mut word_addr = align_addr_to_word(addr);
+        while word_addr < end {
+            let word = ptrace::read(self.current, word_addr as ptrace::AddressType)? as u64;
+            let mut word_bytes = word.to_le_bytes();
+            for (i, byte) in word_bytes.iter_mut().enumerate() {
+                if (addr..end).contains(&(word_addr + i)) {
+                    *byte = bytes[word_addr + i - addr];
+                }
+            }
+            ptrace::write(
+                self.current,
+                word_addr as ptrace::AddressType,
+                u64::from_le_bytes(word_bytes) as *mut std::ffi::c_void,
+            )?;
+            word_addr += size_of::<usize>();
+        }
+        Ok(())
+    }
+
+    /// Returns the registers of the current thread.
+    pub fn registers(&self) -> Result<libc::user_regs_struct, nix::Error> {
+        ptrace::getregs(self.current)
+    }
+
+    /// Returns the floating-point registers of the current thread: the x87 stack, the SSE
+    /// registers, and their control and status words.
+    pub fn fp_registers(&self) -> Result<libc::user_fpregs_struct, nix::Error> {
+        let mut regs: libc::user_fpregs_struct = unsafe { std::mem::zeroed() };
+        let res = unsafe {
+            libc::ptrace(
+                libc::PTRACE_GETFPREGS,
+                self.current.as_raw(),
+                std::ptr::null_mut::<libc::c_void>(),
+                &mut regs as *mut libc::user_fpregs_struct,
+            )
+        };
+        nix::errno::Errno::result(res)?;
+        Ok(regs)
+    }
+
+    /// Returns the current frame pointer (%rbp) of the current thread.
+    pub fn frame_pointer(&self) -> Result<usize, nix::Error> {
+        Ok(ptrace::getregs(self.current)?.rbp as usize)
+    }
+
+    /// Returns the current instruction pointer (%rip) of the current thread.
+    pub fn instruction_pointer(&self) -> Result<usize, nix::Error> {
+        self.thread_instruction_pointer(self.current)
+    }
+
+    /// Returns the instruction pointer (%rip) of thread `tid`.
+    pub fn thread_instruction_pointer(&self, tid: Pid) -> Result<usize, nix::Error> {
+        Ok(ptrace::getregs(tid)?.rip as usize)
+    }
+
+    /// Executes a single instruction in the current thread, leaving the other threads stopped.
+    pub fn step_instruction(
+        &mut self,
+        break_points: &HashMap<usize, u8>,
+    ) -> Result<Status, nix::Error> {
+        let tid = self.current;
+        if self.trapped_at_breakpoint(tid, break_points)? {
+            // Stepping over the breakpoint executes the instruction under it
+            if let Some(status) = self.step_over_breakpoint(tid, break_points)? {
+                return Ok(status);
+            }
+            self.set_stopped_by(tid, None);
+            return Ok(Status::Stopped(SIGTRAP, self.instruction_pointer()?));
+        }
+
+        let from = self.instruction_pointer()?;
+        let signal = self
+            .threads
+            .iter_mut()
+            .find(|thread| thread.tid == tid)
+            .and_then(|thread| thread.deferred_signal.take());
+        ptrace::step(tid, signal)?;
+        let status = self.wait_thread(tid)?;
+        if let Status::Stopped(signal, rip) = status {
+            // Stepping onto one of our int3s executes it, which counts as hitting the breakpoint
+            let hit_breakpoint =
+                signal == SIGTRAP && break_points.contains_key(&from) && rip == from + 1;
+            let stopped_by = if signal != SIGTRAP || hit_breakpoint {
+                Some(signal)
+            } else {
+                None
+            };
+            self.set_stopped_by(tid, stopped_by);
+        }
+        Ok(status)
+    }
+
+    /// Lets the inferior run until the current thread reaches `addr` (or something else stops it),
+    /// using a temporary breakpoint there if there isn't one already. If it stops at `addr`, it is
+    /// left about to execute the instruction there.
+    pub fn run_to(
+        &mut self,
+        addr: usize,
+        break_points: &HashMap<usize, u8>,
+    ) -> Result<Status, nix::Error> {
+        let mut with_temporary = break_points.clone();
+        let temporary = !break_points.contains_key(&addr);
+        if temporary {
+            with_temporary.insert(addr, self.write_byte(addr, 0xcc)?);
+        }
+        let status = self.wake_up(&with_temporary);
+        if temporary {
+            write_byte_at(self.pid, addr, with_temporary[&addr])?;
+            // Nothing will step over the temporary breakpoint once it is gone, so back up any
+            // thread that hit it to run the original instruction instead
+            let tids: Vec<Pid> = self.threads.iter().map(|thread| thread.tid).collect();
+            for tid in tids {
+                let mut regs = ptrace::getregs(tid)?;
+                if regs.rip as usize == addr + 1
+                    && self.trapped_at_breakpoint(tid, &with_temporary)?
+                {
+                    regs.rip -= 1;
+                    ptrace::setregs(tid, regs)?;
+                    self.set_stopped_by(tid, None);
+                }
+            }
+        }
+        match status? {
+            Status::Stopped(signal, rip) if temporary && rip == addr + 1 => {
+                Ok(Status::Stopped(signal, addr))
+            }
+            status => Ok(status),
+        }
+    }
+
+    /// Records why thread `tid` last stopped.
+    fn set_stopped_by(&mut self, tid: Pid, signal: Option<signal::Signal>) {
+        if let Some(thread) = self.threads.iter_mut().find(|thread| thread.tid == tid) {
+            thread.stopped_by = signal;
+            thread.at_syscall = false;
+        }
+    }
+
+    /// Returns the address of the instruction the current thread stopped at. This is one less than
+    /// %rip after hitting a breakpoint, since %rip is then past the int3.
+    pub fn stop_address(&self, break_points: &HashMap<usize, u8>) -> Result<usize, nix::Error> {
+        let rip = self.instruction_pointer()?;
+        if self.trapped_at_breakpoint(self.current, break_points)? {
+            Ok(rip - 1)
+        } else {
+            Ok(rip)
+        }
+    }
+
+    /// Returns the current stack pointer (%rsp) of the current thread.
+    pub fn stack_pointer(&self) -> Result<usize, nix::Error> {
+        Ok(ptrace::getregs(self.current)?.rsp as usize)
+    }
+
+    /// Returns the current thread's %rax, which holds the return value after a function returns.
+    pub fn return_value(&self) -> Result<u64, nix::Error> {
+        Ok(ptrace::getregs(self.current)?.rax)
+    }
+
+    /// Reads the word of the inferior's memory at `addr`.
+    pub fn read_word(&self, addr: usize) -> Result<usize, nix::Error> {
+        Ok(ptrace::read(self.current, addr as ptrace::AddressType)? as usize)
+    }
+
+    /// Pops the current thread's stack frame as if the function starting at `function_start` had
+    /// returned right away, optionally with `return_value` in %rax. Returns the new instruction
+    /// pointer, which is in the caller just after the call.
+    ///
+    /// Like `backtrace`, this relies on the frame layout the `push %rbp; mov %rsp,%rbp`
+    /// prologue sets up, so it only works for functions compiled with frame pointers.
+    pub fn force_return(
+        &mut self,
+        function_start: usize,
+        return_value: Option<u64>,
+        break_points: &HashMap<usize, u8>,
+    ) -> Result<usize, nix::Error> {
+        let mut regs = ptrace::getregs(self.current)?;
+        let (return_addr, caller_rbp, caller_rsp) =
+            self.caller_frame(function_start, break_points)?;
+        regs.rip = return_addr as u64;
+        regs.rbp = caller_rbp as u64;
+        regs.rsp = caller_rsp as u64;
+        if let Some(value) = return_value {
+            regs.rax = value;
+        }
+        ptrace::setregs(self.current, regs)?;
+        Ok(regs.rip as usize)
+    }
 
-        ptrace::cont(pid, None)?;
-        self.wait(None)
+    /// Returns where the function starting at `function_start`, which the current thread is in,
+    /// will return to: the return address, and the caller's %rbp and %rsp once it has returned.
+    /// Like `force_return`, this needs frame pointers.
+    pub fn caller_frame(
+        &self,
+        function_start: usize,
+        break_points: &HashMap<usize, u8>,
+    ) -> Result<(usize, usize, usize), nix::Error> {
+        let regs = ptrace::getregs(self.current)?;
+        let (rsp, rbp) = (regs.rsp as usize, regs.rbp as usize);
+        // A thread that hit a breakpoint has only executed the int3, not the instruction under it
+        let pc = self.stop_address(break_points)?;
+        // Until the prologue has run, the frame isn't where %rbp says it is
+        Ok(if pc == function_start {
+            // Nothing pushed yet: the return address is on top of the stack
+            (self.read_word(rsp)?, rbp, rsp + 8)
+        } else if pc == function_start + 1 {
+            // Only the caller's %rbp has been pushed
+            (self.read_word(rsp + 8)?, self.read_word(rsp)?, rsp + 16)
+        } else {
+            (self.read_word(rbp + 8)?, self.read_word(rbp)?, rbp + 16)
+        })
     }
 
     /// Returns the pid of this inferior.
     pub fn pid(&self) -> Pid {
-        nix::unistd::Pid::from_raw(self.child.id() as i32)
+        self.pid
     }
 
-    /// Calls waitpid on this inferior and returns a Status to indicate the state of the process
+    /// Returns the threads of this inferior, in the order they were created.
+    pub fn threads(&self) -> &[Thread] {
+        &self.threads
+    }
+
+    /// Returns the thread whose registers and stack are being looked at.
+    pub fn current_thread(&self) -> &Thread {
+        self.threads
+            .iter()
+            .find(|thread| thread.tid == self.current)
+            .unwrap_or(&self.threads[0])
+    }
+
+    /// Makes thread number `id` the current thread. Returns false if there is no such thread.
+    pub fn select_thread(&mut self, id: usize) -> bool {
+        match self.threads.iter().find(|thread| thread.id == id) {
+            Some(thread) => {
+                self.current = thread.tid;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Calls waitpid on thread `tid` of this inferior and returns a Status to indicate its state
     /// after the waitpid call.
-    pub fn wait(&self, options: Option<WaitPidFlag>) -> Result<Status, nix::Error> {
-        Ok(match waitpid(self.pid(), options)? {
+    fn wait_thread(&self, tid: Pid) -> Result<Status, nix::Error> {
+        Ok(match waitpid(tid, Some(WaitPidFlag::__WALL))? {
             WaitStatus::Exited(_pid, exit_code) => Status::Exited(exit_code),
             WaitStatus::Signaled(_pid, signal, _core_dumped) => Status::Signaled(signal),
-            WaitStatus::Stopped(_pid, signal) => {
-                let regs = ptrace::getregs(self.pid())?;
+            // Events from a single step (say, over a syscall instruction) are treated as plain stops
+            WaitStatus::Stopped(_pid, signal) | WaitStatus::PtraceEvent(_pid, signal, _) => {
+                let regs = ptrace::getregs(tid)?;
                 Status::Stopped(signal, regs.rip as usize)
             }
             other => panic!("waitpid returned unexpected status: {:?}", other),
         })
     }
 
+    /// Removes the breakpoints and lets the inferior run on its own, untraced.
+    pub fn detach(&mut self, break_points: &HashMap<usize, u8>) -> Result<(), nix::Error> {
+        let tids: Vec<Pid> = self.threads.iter().map(|thread| thread.tid).collect();
+        // Threads sitting just past one of our int3s have to run the original instruction instead
+        for tid in &tids {
+            if self.trapped_at_breakpoint(*tid, break_points)? {
+                let mut regs = ptrace::getregs(*tid)?;
+                regs.rip -= 1;
+                ptrace::setregs(*tid, regs)?;
+            }
+        }
+        release_process(self.pid, &tids, break_points)?;
+        // As when letting go of a process after a fork, threads we were in the middle of stopping
+        // still have a SIGSTOP coming
+        if self.threads.iter().any(|thread| thread.sigstop_pending) {
+            signal::kill(self.pid, signal::Signal::SIGCONT)?;
+        }
+        Ok(())
+    }
+
     pub fn kill(&mut self) -> Result<(), std::io::Error> {
-        println!("Killing running inferior (pid {})", self.pid());
-        self.child.kill()
+        if self.pid() != Pid::from_raw(self.child.id() as i32) {
+            // We followed a fork, so the process being debugged isn't the one we spawned
+            signal::kill(self.pid(), signal::Signal::SIGKILL)
+                .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))?;
+            // The main thread is only reported once the other threads have been collected
+            loop {
+                match waitpid(None, Some(WaitPidFlag::__WALL)) {
+                    Ok(WaitStatus::Exited(tid, _)) | Ok(WaitStatus::Signaled(tid, _, _))
+                        if tid == self.pid() =>
+                    {
+                        return Ok(());
+                    }
+                    Ok(_) => {}
+                    Err(err) => return Err(std::io::Error::new(std::io::ErrorKind::Other, err)),
+                }
+            }
+        }
+        self.child.kill()?;
+        // Collect the exit now, rather than leaving it for whatever we wait on next
+        self.child.wait().map(drop)
     }
 
-    pub fn print_backtrace(&self, debug: &DwarfData) -> Result<(), nix::Error> {
-        let mut instruction_ptr = ptrace::getregs(self.pid())?.rip as usize;
-        let mut base_ptr = ptrace::getregs(self.pid())?.rbp as usize;
+    /// Walks the current thread's stack from the innermost frame out to main. Each caller is found
+    /// from the call frame information, so that functions built without frame pointers can be
+    /// unwound, or by following the %rbp chain where there is none.
+    pub fn backtrace(
+        &self,
+        debug: &DwarfData,
+        break_points: &HashMap<usize, u8>,
+    ) -> Result<Vec<Frame>, nix::Error> {
+        let mut frames = Vec::new();
+        let regs = ptrace::getregs(self.current)?;
+        let mut instruction_ptr = self.stop_address(break_points)?;
+        let (mut stack_ptr, mut base_ptr) = (regs.rsp as usize, regs.rbp as usize);
         loop {
-            let line = DwarfData::get_line_from_addr(debug, instruction_ptr)
-                .expect("Error getting line from %rip");
-            let func = DwarfData::get_function_from_addr(debug, instruction_ptr)
-                .expect("Error getting function from %rip");
-            println!("{} {}", func, line);
-
-            if func == "main" {
+            let function = DwarfData::get_function_from_addr(debug, instruction_ptr);
+            // Without debug info (e.g. in libc) we can't rely on the frame layout, so stop there
+            let last = function
+                .as_deref()
+                .is_none_or(|function| function == "main");
+            frames.push(Frame {
+                address: instruction_ptr,
+                function,
+                line: DwarfData::get_line_from_addr(debug, instruction_ptr),
+            });
+            if last || frames.len() >= MAX_BACKTRACE_FRAMES {
+                break;
+            }
+            let previous_stack_ptr = stack_ptr;
+            // A return address is just past its call, which may be the last instruction of the
+            // function (or even of its unwind info), so look up the call itself
+            let lookup = if frames.len() == 1 {
+                instruction_ptr
+            } else {
+                instruction_ptr - 1
+            };
+            match debug.unwind_rule(lookup) {
+                Some(rule) => {
+                    let cfa_base = match rule.cfa_register {
+                        FrameRegister::Rsp => stack_ptr,
+                        FrameRegister::Rbp => base_ptr,
+                    };
+                    let cfa = cfa_base.wrapping_add(rule.cfa_offset as usize);
+                    instruction_ptr =
+                        self.read_word(cfa.wrapping_add(rule.return_address_offset as usize))?;
+                    if let Some(offset) = rule.saved_rbp_offset {
+                        base_ptr = self.read_word(cfa.wrapping_add(offset as usize))?;
+                    }
+                    stack_ptr = cfa;
+                }
+                None => {
+                    instruction_ptr = self.read_word(base_ptr + 8)?;
+                    stack_ptr = base_ptr + 16;
+                    base_ptr = self.read_word(base_ptr)?;
+                }
+            }
+            // Each caller's frame is further up the stack than the frame it called; anything else
+            // means the stack is corrupted, and following it could go round in circles
+            if stack_ptr <= previous_stack_ptr {
                 break;
             }
-            instruction_ptr =
-                ptrace::read(self.pid(), (base_ptr + 8) as ptrace::AddressType)? as usize;
-            base_ptr = ptrace::read(self.pid(), base_ptr as ptrace::AddressType)? as usize;
         }
-        Ok(())
+        Ok(frames)
     }
 }
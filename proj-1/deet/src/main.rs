@@ -2,25 +2,72 @@
 /// Print source code on stop
 mod debugger;
 mod debugger_command;
+mod disassembler;
 mod dwarf_data;
+mod expression;
+mod fp_registers;
 mod gimli_wrapper;
 mod inferior;
+mod output;
+mod syscalls;
+mod values;
 
 use crate::debugger::Debugger;
-use nix::sys::signal::{signal, SigHandler, Signal};
+use crate::output::Output;
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
 use std::env;
 
 fn main() {
     let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        println!("Usage: {} <target program>", args[0]);
+    let mut follow_fork = false;
+    let mut count_instructions = false;
+    let mut no_color = false;
+    let mut no_pager = false;
+    let mut json_rpc = false;
+    let mut commands = None;
+    let mut positional = Vec::new();
+    let mut rest = args[1..].iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--follow-fork" => follow_fork = true,
+            "--count-instructions" => count_instructions = true,
+            "--no-color" => no_color = true,
+            "--no-pager" => no_pager = true,
+            "--json-rpc" => json_rpc = true,
+            // Leaving the file out makes this None, which shows the usage below
+            "--commands" => commands = Some(rest.next()),
+            _ => positional.push(arg),
+        }
+    }
+    if positional.len() != 1 || commands == Some(None) {
+        println!(
+            "Usage: {} [--follow-fork] [--count-instructions] [--no-color] [--no-pager] \
+             [--json-rpc] [--commands <file>] <target program>",
+            args[0]
+        );
         std::process::exit(1);
     }
-    let target = &args[1];
+    let target = positional[0];
+
+    // Don't let ctrl+c kill the debugger. Instead, it interrupts the inferior if one is running
+    let interrupt = SigAction::new(
+        SigHandler::SigAction(debugger::interrupt_inferior),
+        SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    unsafe { sigaction(Signal::SIGINT, &interrupt) }.expect("Error installing SIGINT handler");
 
-    // Disable handling of ctrl+c in this process (so that ctrl+c only gets delivered to child
-    // processes)
-    unsafe { signal(Signal::SIGINT, SigHandler::SigIgn) }.expect("Error disabling SIGINT handling");
+    if count_instructions {
+        println!("Warning: counting instructions single-steps the inferior, which is very slow");
+    }
 
-    Debugger::new(target).run();
+    let output = Output::new(no_color, no_pager, json_rpc);
+    let mut debugger = Debugger::new(target, follow_fork, count_instructions, output);
+    if let Some(Some(path)) = commands {
+        if let Err(err) = debugger.source(path) {
+            println!("Could not read {}: {}", path, err);
+            std::process::exit(1);
+        }
+    }
+    debugger.run();
 }
@@ -0,0 +1,127 @@
+use nix::unistd::isatty;
+use serde_json::json;
+use std::io::{self, BufRead, Write};
+
+/// What a piece of output is, which decides its color.
+#[derive(Clone, Copy)]
+pub enum Style {
+    /// Function names
+    Function,
+    /// Raw addresses in the inferior
+    Address,
+    /// `file:line` locations
+    Location,
+    /// The source line the inferior is stopped at
+    CurrentLine,
+}
+
+impl Style {
+    fn escape_code(self) -> &'static str {
+        match self {
+            Style::Function => "\x1b[33m",
+            Style::Address => "\x1b[34m",
+            Style::Location => "\x1b[32m",
+            Style::CurrentLine => "\x1b[1m",
+        }
+    }
+}
+
+/// Decides how output is shown: whether it is colored, and whether output longer than the terminal
+/// is shown a page at a time. Both only make sense for a person at a terminal, so both are off
+/// when stdout isn't one.
+///
+/// With `--json-rpc`, output is for a program instead: each message is printed as a JSON object on
+/// a line of its own (see `event`), and neither colored nor paged.
+pub struct Output {
+    color: bool,
+    pager: bool,
+    json: bool,
+}
+
+impl Output {
+    pub fn new(no_color: bool, no_pager: bool, json: bool) -> Output {
+        let terminal = isatty(libc::STDOUT_FILENO).unwrap_or(false) && !json;
+        Output {
+            color: terminal && !no_color,
+            // Paging waits for Enter, so it also needs someone typing at stdin
+            pager: terminal && !no_pager && isatty(libc::STDIN_FILENO).unwrap_or(false),
+            json,
+        }
+    }
+
+    /// Returns whether output is JSON events rather than text.
+    pub fn json(&self) -> bool {
+        self.json
+    }
+
+    /// Prints a line of text, which in JSON mode is sent as an `output` event.
+    pub fn line(&self, text: &str) {
+        if self.json {
+            self.event(json!({ "event": "output", "text": text }));
+        } else {
+            println!("{}", text);
+        }
+    }
+
+    /// Prints a message saying that something went wrong, which in JSON mode is sent as an `error`
+    /// event.
+    pub fn error(&self, message: &str) {
+        if self.json {
+            self.event(json!({ "event": "error", "message": message }));
+        } else {
+            println!("{}", message);
+        }
+    }
+
+    /// Prints `event`, which should be an object whose `event` field says what it is, on a line of
+    /// its own. Only meant for JSON mode.
+    pub fn event(&self, event: serde_json::Value) {
+        println!("{}", event);
+    }
+
+    /// Returns `text` in the color for `style`, or unchanged if output isn't colored.
+    pub fn paint(&self, text: &str, style: Style) -> String {
+        if self.color {
+            format!("{}{}\x1b[0m", style.escape_code(), text)
+        } else {
+            text.to_string()
+        }
+    }
+
+    /// Prints `lines`, pausing after each screenful until the user presses Enter (or stopping
+    /// early if they type q) when paging is on.
+    pub fn print_paged(&self, lines: &[String]) {
+        // Leave a row for the prompt
+        let page_size = terminal_height().saturating_sub(1).max(1);
+        if !self.pager || lines.len() <= page_size {
+            for line in lines {
+                self.line(line);
+            }
+            return;
+        }
+        for (i, page) in lines.chunks(page_size).enumerate() {
+            if i > 0 {
+                print!("--More-- (Enter to continue, q to stop) ");
+                let _ = io::stdout().flush();
+                let mut answer = String::new();
+                if io::stdin().lock().read_line(&mut answer).unwrap_or(0) == 0
+                    || answer.trim() == "q"
+                {
+                    return;
+                }
+            }
+            for line in page {
+                println!("{}", line);
+            }
+        }
+    }
+}
+
+/// Returns the number of rows in the terminal stdout is connected to, or 24 if that can't be told.
+fn terminal_height() -> usize {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    match unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } {
+        0 if size.ws_row > 0 => size.ws_row as usize,
+        _ => 24,
+    }
+}
@@ -0,0 +1,190 @@
+//! Names of the x86-64 Linux system calls deet knows about, and what arguments each one takes,
+//! for `catch syscall`. Syscalls missing from the table can still be caught by number.
+
+/// Each syscall's name, number and arguments, given as a letter per argument: `i` for an `int`,
+/// `l` for a `long` (or `size_t` and the like) and `p` for a pointer
+const SYSCALLS: &[(&str, libc::c_long, &str)] = &[
+    ("read", libc::SYS_read, "ipl"),
+    ("write", libc::SYS_write, "ipl"),
+    ("open", libc::SYS_open, "pii"),
+    ("close", libc::SYS_close, "i"),
+    ("stat", libc::SYS_stat, "pp"),
+    ("fstat", libc::SYS_fstat, "ip"),
+    ("lstat", libc::SYS_lstat, "pp"),
+    ("poll", libc::SYS_poll, "pli"),
+    ("lseek", libc::SYS_lseek, "ili"),
+    ("mmap", libc::SYS_mmap, "pliiil"),
+    ("mprotect", libc::SYS_mprotect, "pli"),
+    ("munmap", libc::SYS_munmap, "pl"),
+    ("brk", libc::SYS_brk, "p"),
+    ("rt_sigaction", libc::SYS_rt_sigaction, "ippl"),
+    ("rt_sigprocmask", libc::SYS_rt_sigprocmask, "ippl"),
+    ("rt_sigreturn", libc::SYS_rt_sigreturn, ""),
+    ("ioctl", libc::SYS_ioctl, "iip"),
+    ("pread64", libc::SYS_pread64, "ipll"),
+    ("pwrite64", libc::SYS_pwrite64, "ipll"),
+    ("readv", libc::SYS_readv, "ipi"),
+    ("writev", libc::SYS_writev, "ipi"),
+    ("access", libc::SYS_access, "pi"),
+    ("pipe", libc::SYS_pipe, "p"),
+    ("select", libc::SYS_select, "ipppp"),
+    ("sched_yield", libc::SYS_sched_yield, ""),
+    ("mremap", libc::SYS_mremap, "pllip"),
+    ("madvise", libc::SYS_madvise, "pli"),
+    ("dup", libc::SYS_dup, "i"),
+    ("dup2", libc::SYS_dup2, "ii"),
+    ("pause", libc::SYS_pause, ""),
+    ("nanosleep", libc::SYS_nanosleep, "pp"),
+    ("getpid", libc::SYS_getpid, ""),
+    ("socket", libc::SYS_socket, "iii"),
+    ("connect", libc::SYS_connect, "ipi"),
+    ("accept", libc::SYS_accept, "ipp"),
+    ("sendto", libc::SYS_sendto, "iplipi"),
+    ("recvfrom", libc::SYS_recvfrom, "iplipp"),
+    ("sendmsg", libc::SYS_sendmsg, "ipi"),
+    ("recvmsg", libc::SYS_recvmsg, "ipi"),
+    ("shutdown", libc::SYS_shutdown, "ii"),
+    ("bind", libc::SYS_bind, "ipi"),
+    ("listen", libc::SYS_listen, "ii"),
+    ("clone", libc::SYS_clone, "lpppl"),
+    ("fork", libc::SYS_fork, ""),
+    ("vfork", libc::SYS_vfork, ""),
+    ("execve", libc::SYS_execve, "ppp"),
+    ("exit", libc::SYS_exit, "i"),
+    ("wait4", libc::SYS_wait4, "ipip"),
+    ("kill", libc::SYS_kill, "ii"),
+    ("uname", libc::SYS_uname, "p"),
+    ("fcntl", libc::SYS_fcntl, "iil"),
+    ("flock", libc::SYS_flock, "ii"),
+    ("fsync", libc::SYS_fsync, "i"),
+    ("truncate", libc::SYS_truncate, "pl"),
+    ("ftruncate", libc::SYS_ftruncate, "il"),
+    ("getdents", libc::SYS_getdents, "ipi"),
+    ("getcwd", libc::SYS_getcwd, "pl"),
+    ("chdir", libc::SYS_chdir, "p"),
+    ("rename", libc::SYS_rename, "pp"),
+    ("mkdir", libc::SYS_mkdir, "pi"),
+    ("rmdir", libc::SYS_rmdir, "p"),
+    ("creat", libc::SYS_creat, "pi"),
+    ("link", libc::SYS_link, "pp"),
+    ("unlink", libc::SYS_unlink, "p"),
+    ("symlink", libc::SYS_symlink, "pp"),
+    ("readlink", libc::SYS_readlink, "ppi"),
+    ("chmod", libc::SYS_chmod, "pi"),
+    ("umask", libc::SYS_umask, "i"),
+    ("gettimeofday", libc::SYS_gettimeofday, "pp"),
+    ("getuid", libc::SYS_getuid, ""),
+    ("getgid", libc::SYS_getgid, ""),
+    ("geteuid", libc::SYS_geteuid, ""),
+    ("getegid", libc::SYS_getegid, ""),
+    ("getppid", libc::SYS_getppid, ""),
+    ("setsid", libc::SYS_setsid, ""),
+    ("sigaltstack", libc::SYS_sigaltstack, "pp"),
+    ("arch_prctl", libc::SYS_arch_prctl, "ip"),
+    ("prctl", libc::SYS_prctl, "illll"),
+    ("gettid", libc::SYS_gettid, ""),
+    ("futex", libc::SYS_futex, "piippi"),
+    ("getdents64", libc::SYS_getdents64, "ipi"),
+    ("set_tid_address", libc::SYS_set_tid_address, "p"),
+    ("clock_gettime", libc::SYS_clock_gettime, "ip"),
+    ("clock_nanosleep", libc::SYS_clock_nanosleep, "iipp"),
+    ("exit_group", libc::SYS_exit_group, "i"),
+    ("tgkill", libc::SYS_tgkill, "iii"),
+    ("openat", libc::SYS_openat, "ipii"),
+    ("mkdirat", libc::SYS_mkdirat, "ipi"),
+    ("newfstatat", libc::SYS_newfstatat, "ippi"),
+    ("unlinkat", libc::SYS_unlinkat, "ipi"),
+    ("readlinkat", libc::SYS_readlinkat, "ippi"),
+    ("faccessat", libc::SYS_faccessat, "ipi"),
+    ("pselect6", libc::SYS_pselect6, "ippppp"),
+    ("ppoll", libc::SYS_ppoll, "plppl"),
+    ("set_robust_list", libc::SYS_set_robust_list, "pl"),
+    ("epoll_wait", libc::SYS_epoll_wait, "ipii"),
+    ("epoll_ctl", libc::SYS_epoll_ctl, "iiip"),
+    ("accept4", libc::SYS_accept4, "ippi"),
+    ("epoll_create1", libc::SYS_epoll_create1, "i"),
+    ("dup3", libc::SYS_dup3, "iii"),
+    ("pipe2", libc::SYS_pipe2, "pi"),
+    ("prlimit64", libc::SYS_prlimit64, "iipp"),
+    ("getrandom", libc::SYS_getrandom, "pli"),
+    ("statx", libc::SYS_statx, "ipiip"),
+    ("rseq", libc::SYS_rseq, "piii"),
+    ("clone3", libc::SYS_clone3, "pl"),
+    ("faccessat2", libc::SYS_faccessat2, "ipii"),
+];
+
+/// Returns the number of the syscall called `name`, which may also just be given as a number.
+pub fn number(name: &str) -> Option<u64> {
+    if let Ok(number) = name.parse() {
+        return Some(number);
+    }
+    SYSCALLS
+        .iter()
+        .find(|(known, _, _)| *known == name)
+        .map(|(_, number, _)| *number as u64)
+}
+
+/// Returns the name of syscall `number`, or the number itself if it isn't in the table.
+pub fn name(number: u64) -> String {
+    match SYSCALLS
+        .iter()
+        .find(|(_, known, _)| *known as u64 == number)
+    {
+        Some((name, _, _)) => name.to_string(),
+        None => format!("syscall {}", number),
+    }
+}
+
+/// Returns how many arguments syscall `number` takes. All six argument registers are counted for
+/// syscalls that aren't in the table.
+pub fn arg_count(number: u64) -> usize {
+    SYSCALLS
+        .iter()
+        .find(|(_, known, _)| *known as u64 == number)
+        .map_or(6, |(_, _, kinds)| kinds.len())
+}
+
+/// Formats the arguments syscall `number` was called with, which are taken from `args` (the six
+/// argument registers). Integers are shown in decimal and pointers in hex. For syscalls that
+/// aren't in the table, every register is shown, in whichever way suits its value.
+pub fn format_args(number: u64, args: &[u64]) -> Vec<String> {
+    match SYSCALLS
+        .iter()
+        .find(|(_, known, _)| *known as u64 == number)
+    {
+        Some((_, _, kinds)) => kinds
+            .chars()
+            .zip(args)
+            .map(|(kind, &arg)| match kind {
+                'p' => format!("{:#x}", arg),
+                // Only the low half of the register holds an int
+                'i' => (arg as i32).to_string(),
+                _ => (arg as i64).to_string(),
+            })
+            .collect(),
+        None => args.iter().map(|&arg| format_value(arg)).collect(),
+    }
+}
+
+/// Formats a value of unknown type the way it is most likely meant: small numbers (including
+/// negative ones, like -1 or AT_FDCWD) in decimal, and anything bigger, which is probably an
+/// address, in hex.
+pub fn format_value(value: u64) -> String {
+    let signed = value as i64;
+    if signed.unsigned_abs() < 1 << 32 {
+        signed.to_string()
+    } else {
+        format!("{:#x}", value)
+    }
+}
+
+/// Formats what a syscall returned, which is -errno if it failed.
+pub fn format_return(value: u64) -> String {
+    let signed = value as i64;
+    if (-4095..0).contains(&signed) {
+        let errno = nix::errno::Errno::from_i32(-signed as i32);
+        format!("-1 {:?} ({})", errno, errno.desc())
+    } else {
+        format_value(value)
+    }
+}
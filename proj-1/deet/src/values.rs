@@ -0,0 +1,169 @@
+//! Formats values read from the inferior's memory according to their DWARF types, roughly the way
+//! gdb's `print` does.
+
+use crate::dwarf_data::{BaseEncoding, DwarfData, Type, TypeKind};
+use std::convert::TryInto;
+
+/// How integers should be printed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Natural,
+    Hex,
+}
+
+/// Reads `len` bytes of memory at an address.
+type ReadMemory<'a> = dyn Fn(usize, usize) -> Result<Vec<u8>, nix::Error> + 'a;
+
+/// Formats the value of type `dtype` stored at `addr`. `read_memory` is used to fetch the bytes
+/// (normally `Inferior::read_memory`).
+pub fn format_value<F>(
+    debug_data: &DwarfData,
+    dtype: &Type,
+    addr: usize,
+    format: Format,
+    read_memory: &F,
+) -> Result<String, nix::Error>
+where
+    F: Fn(usize, usize) -> Result<Vec<u8>, nix::Error> + ?Sized,
+{
+    let dtype = match debug_data.strip_aliases(dtype) {
+        Some(dtype) => dtype,
+        None => return Ok("<unknown type>".to_string()),
+    };
+    match &dtype.kind {
+        TypeKind::Base(encoding) => {
+            let bytes = read_memory(addr, dtype.size)?;
+            Ok(format_base(*encoding, &bytes, format))
+        }
+        TypeKind::Pointer(_) => {
+            let target = read_unsigned(&read_memory(addr, dtype.size)?);
+            Ok(format!("({}) {:#x}", dtype.name, target))
+        }
+        TypeKind::Struct(members) => {
+            let bytes = read_memory(addr, dtype.size)?;
+            let read_memory: &ReadMemory =
+                &|at, len| read_buffered(addr, &bytes, at, len, read_memory);
+            let mut fields = Vec::new();
+            for member in members {
+                let value = match member
+                    .type_offset
+                    .and_then(|offset| debug_data.get_type(offset))
+                {
+                    Some(member_type) => format_value(
+                        debug_data,
+                        member_type,
+                        addr + member.offset,
+                        format,
+                        read_memory,
+                    )?,
+                    None => "<unknown type>".to_string(),
+                };
+                fields.push(format!("{} = {}", member.name, value));
+            }
+            Ok(format!("{{{}}}", fields.join(", ")))
+        }
+        TypeKind::Array(element, count) => {
+            let element_type = match element.and_then(|offset| debug_data.get_type(offset)) {
+                Some(element_type) => element_type,
+                None => return Ok("<unknown type>".to_string()),
+            };
+            let element_size = match debug_data.strip_aliases(element_type) {
+                Some(stripped) => stripped.size,
+                None => return Ok("<unknown type>".to_string()),
+            };
+            let bytes = read_memory(addr, count * element_size)?;
+            let read_memory: &ReadMemory =
+                &|at, len| read_buffered(addr, &bytes, at, len, read_memory);
+            let mut elements = Vec::new();
+            for i in 0..*count {
+                elements.push(format_value(
+                    debug_data,
+                    element_type,
+                    addr + i * element_size,
+                    format,
+                    read_memory,
+                )?);
+            }
+            Ok(format!("[{}]", elements.join(", ")))
+        }
+        TypeKind::Alias(_) => unreachable!("aliases are stripped above"),
+    }
+}
+
+/// Formats a value of type `dtype` that a function returned in %rax, which holds `rax`. Returns
+/// None for types that aren't returned that way: floats come back in %xmm0, and structs and arrays
+/// may not fit in a register.
+pub fn format_return_value(debug_data: &DwarfData, dtype: &Type, rax: u64) -> Option<String> {
+    let stripped = debug_data.strip_aliases(dtype)?;
+    match stripped.kind {
+        TypeKind::Base(BaseEncoding::Float) | TypeKind::Struct(_) | TypeKind::Array(..) => None,
+        _ if stripped.size > 8 => None,
+        _ => {
+            let bytes = rax.to_le_bytes();
+            let read_register = |_, len: usize| Ok(bytes[..len].to_vec());
+            format_value(debug_data, dtype, 0, Format::Natural, &read_register).ok()
+        }
+    }
+}
+
+/// Returns the `len` bytes at `at` from `bytes`, which were read from `addr` all at once, so that
+/// the members of a struct or array don't each need reading from the inferior separately. Falls back
+/// to `read_memory` for anything outside `bytes`.
+fn read_buffered<F>(
+    addr: usize,
+    bytes: &[u8],
+    at: usize,
+    len: usize,
+    read_memory: &F,
+) -> Result<Vec<u8>, nix::Error>
+where
+    F: Fn(usize, usize) -> Result<Vec<u8>, nix::Error> + ?Sized,
+{
+    if at >= addr && at + len <= addr + bytes.len() {
+        Ok(bytes[at - addr..at - addr + len].to_vec())
+    } else {
+        read_memory(at, len)
+    }
+}
+
+fn read_unsigned(bytes: &[u8]) -> u64 {
+    let mut buf = [0u8; 8];
+    let len = bytes.len().min(8);
+    buf[..len].copy_from_slice(&bytes[..len]);
+    u64::from_le_bytes(buf)
+}
+
+fn read_signed(bytes: &[u8]) -> i64 {
+    let len = bytes.len().min(8);
+    if len == 0 {
+        return 0;
+    }
+    // Shift up and back down to sign-extend values narrower than 8 bytes
+    let shift = 64 - 8 * len as u32;
+    ((read_unsigned(bytes) << shift) as i64) >> shift
+}
+
+fn format_base(encoding: BaseEncoding, bytes: &[u8], format: Format) -> String {
+    if format == Format::Hex && encoding != BaseEncoding::Float {
+        return format!("{:#x}", read_unsigned(bytes));
+    }
+    match encoding {
+        BaseEncoding::Signed => read_signed(bytes).to_string(),
+        BaseEncoding::Unsigned => read_unsigned(bytes).to_string(),
+        BaseEncoding::SignedChar | BaseEncoding::UnsignedChar => {
+            let value = if encoding == BaseEncoding::SignedChar {
+                read_signed(bytes)
+            } else {
+                read_unsigned(bytes) as i64
+            };
+            let byte = read_unsigned(bytes) as u8;
+            format!("{} {:?}", value, byte as char)
+        }
+        BaseEncoding::Boolean => (read_unsigned(bytes) != 0).to_string(),
+        BaseEncoding::Float => match bytes.len() {
+            4 => f32::from_le_bytes(bytes.try_into().unwrap()).to_string(),
+            8 => f64::from_le_bytes(bytes.try_into().unwrap()).to_string(),
+            _ => "<unsupported float>".to_string(),
+        },
+    }
+}
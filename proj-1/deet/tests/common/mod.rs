@@ -0,0 +1,113 @@
+use nix::sys::signal::{self, Signal};
+use nix::unistd::Pid;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::Once;
+use std::thread;
+use std::time::Duration;
+
+static BUILD_SAMPLES: Once = Once::new();
+
+/// Compiles the sample programs using the Makefile (once per test binary).
+fn build_samples() {
+    BUILD_SAMPLES.call_once(|| {
+        let status = Command::new("make")
+            .current_dir(env!("CARGO_MANIFEST_DIR"))
+            .stdout(Stdio::null())
+            .status()
+            .expect("Could not run make to build the sample programs");
+        assert!(status.success(), "Failed to build the sample programs");
+    });
+}
+
+/// Returns the path of a compiled sample program, e.g. `sample_path("function_calls")`.
+pub fn sample_path(name: &str) -> PathBuf {
+    build_samples();
+    let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    path.push("samples");
+    path.push(name);
+    path
+}
+
+/// Starts deet on the given sample program with extra command-line `flags`.
+fn spawn_deet(flags: &[&str], sample: &str) -> Child {
+    spawn_deet_on(flags, &sample_path(sample))
+}
+
+/// Starts deet on the program at `path` with extra command-line `flags`.
+fn spawn_deet_on(flags: &[&str], path: &Path) -> Child {
+    Command::new(env!("CARGO_BIN_EXE_deet"))
+        .args(flags)
+        .arg(path)
+        // Keep the command history out of the real home directory
+        .env("HOME", env!("CARGO_TARGET_TMPDIR"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("Could not execute deet binary")
+}
+
+/// Types each of `commands` at the deet prompt.
+fn send_commands(child: &mut Child, commands: &[&str]) {
+    let stdin = child
+        .stdin
+        .as_mut()
+        .expect("deet somehow missing stdin pipe!");
+    for command in commands {
+        writeln!(stdin, "{}", command).expect("Error writing command to deet");
+    }
+    stdin.flush().expect("Error writing command to deet");
+}
+
+/// Waits for deet to exit and returns everything it printed to stdout.
+fn finish_deet(child: Child) -> String {
+    let output = child.wait_with_output().expect("Error waiting for deet");
+    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+    println!("deet output:\n{}", stdout);
+    assert!(output.status.success(), "deet exited unsuccessfully");
+    stdout
+}
+
+/// Runs deet on the given sample program with extra command-line `flags`, types each of
+/// `commands` at the prompt, and returns everything deet printed to stdout.
+pub fn run_deet(flags: &[&str], sample: &str, commands: &[&str]) -> String {
+    let mut child = spawn_deet(flags, sample);
+    send_commands(&mut child, commands);
+    finish_deet(child)
+}
+
+/// Like `run_deet`, but sends deet a SIGINT (as if ctrl+c were pressed) a second after typing
+/// `before`, and then types `after`.
+#[allow(dead_code)]
+pub fn run_deet_interrupted(sample: &str, before: &[&str], after: &[&str]) -> String {
+    let mut child = spawn_deet(&[], sample);
+    send_commands(&mut child, before);
+    thread::sleep(Duration::from_secs(1));
+    signal::kill(Pid::from_raw(child.id() as i32), Signal::SIGINT)
+        .expect("Error sending SIGINT to deet");
+    send_commands(&mut child, after);
+    finish_deet(child)
+}
+
+/// Like `run_deet`, but debugs a copy of the `sample` program, which is replaced with a copy of
+/// the `rebuilt` program (as if it were recompiled) a second after typing `before`. `after` is
+/// then typed.
+#[allow(dead_code)]
+pub fn run_deet_rebuilt(sample: &str, rebuilt: &str, before: &[&str], after: &[&str]) -> String {
+    let path = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(format!("{}-rebuilt", sample));
+    fs::copy(sample_path(sample), &path).expect("Error copying sample program");
+    let mut child = spawn_deet_on(&[], &path);
+    send_commands(&mut child, before);
+    thread::sleep(Duration::from_secs(1));
+    // Like a linker, write the new program alongside and move it into place, since the old one
+    // may still be running
+    let new_path = path.with_extension("new");
+    fs::copy(sample_path(rebuilt), &new_path).expect("Error copying sample program");
+    fs::rename(&new_path, &path).expect("Error replacing sample program");
+    send_commands(&mut child, after);
+    let output = finish_deet(child);
+    let _ = fs::remove_file(&path);
+    output
+}
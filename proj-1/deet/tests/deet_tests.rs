@@ -0,0 +1,1252 @@
+mod common;
+
+use common::{run_deet, run_deet_interrupted, run_deet_rebuilt, sample_path};
+use std::path::Path;
+
+/// When the inferior forks, the debugger should report it and let the new process run on its own
+#[test]
+fn test_fork_detaches_child() {
+    let output = run_deet(&[], "fork", &["run"]);
+    assert!(output.contains("Detaching after fork from child process"));
+    assert!(output.contains("Hello from the child!"));
+    assert!(output.contains("Hello from the parent!"));
+    assert!(output.contains("Child exited (status: 0)"));
+}
+
+/// With --follow-fork, breakpoints should trap in the new process instead
+#[test]
+fn test_follow_fork_attaches_child() {
+    let output = run_deet(&["--follow-fork"], "fork", &["break 8", "run", "continue"]);
+    assert!(output.contains("Attaching after fork to child process"));
+    assert!(output.contains("fork.c:8"));
+    assert!(output.contains("Hello from the child!"));
+    assert!(output.contains("Child exited (status: 0)"));
+}
+
+/// print should lay out structs and arrays from their DWARF types, and follow one pointer level
+#[test]
+fn test_print_structs_and_arrays() {
+    let output = run_deet(
+        &[],
+        "structs",
+        &[
+            "break 17",
+            "run",
+            "print origin",
+            "print/x origin",
+            "print primes",
+            "print *first",
+            "continue",
+        ],
+    );
+    assert!(output.contains("origin = {x = 3, y = -4}"));
+    assert!(output.contains("origin = {x = 0x3, y = 0xfffffffc}"));
+    assert!(output.contains("primes = [2, 3, 5, 7]"));
+    assert!(output.contains("*first = 2"));
+}
+
+/// Arrays spanning several pages of memory should be read in full
+#[test]
+fn test_print_large_array() {
+    let output = run_deet(
+        &[],
+        "buffer",
+        &[
+            "break 10",
+            "run",
+            "print buffer",
+            "print buffer[4095]",
+            "continue",
+        ],
+    );
+    let squares: Vec<String> = (0..4096).map(|i: u64| (i * i).to_string()).collect();
+    assert!(output.contains(&format!("buffer = [{}]\n", squares.join(", "))));
+    assert!(output.contains("buffer[4095] = 16769025"));
+    assert!(output.contains("Child exited (status: 0)"));
+}
+
+/// ctrl+c while the inferior is running should stop it and return to the prompt
+#[test]
+fn test_interrupt_running_inferior() {
+    let output = run_deet_interrupted("spin", &["run"], &["backtrace", "quit"]);
+    assert!(output.contains("Received signal SIGINT"));
+    assert!(output.contains("Stopped at"));
+    assert!(output.contains("main "));
+    assert!(output.contains("spin.c:"));
+    assert!(output.contains("Killing running inferior"));
+}
+
+/// Without debug info, stops and backtraces should fall back to raw addresses rather than crash
+#[test]
+fn test_missing_debug_info() {
+    let output = run_deet(
+        &[],
+        "segfault_nodebug",
+        &[
+            "backtrace",
+            "break func2",
+            "run",
+            "backtrace",
+            "print a",
+            "quit",
+        ],
+    );
+    assert!(output.contains("No inferior is running"));
+    assert!(output.contains("Function name not found"));
+    assert!(output.contains("Received signal SIGSEGV"));
+    assert!(output.contains("Stopped at 0x"));
+    assert!(output.contains(" in ??"));
+    assert!(output.contains("No symbol \"a\" in current context."));
+    assert!(output.contains("Killing running inferior"));
+}
+
+/// print should evaluate integer arithmetic, indexing and dereferences on the inferior's variables
+#[test]
+fn test_print_expressions() {
+    let output = run_deet(
+        &[],
+        "expressions",
+        &[
+            "break 16",
+            "run",
+            "print n + 1",
+            "print arr[2]",
+            "print arr[n - 3] * 2",
+            "print *p + p[1]",
+            "print (n + 3) / 2 - -1",
+            "print/x n * 16",
+            "print arr",
+            "print n / 0",
+            "print nope + 1",
+            "print n +",
+            "print n[1]",
+            "continue",
+        ],
+    );
+    assert!(output.contains("n + 1 = 6"));
+    assert!(output.contains("arr[2] = 30"));
+    assert!(output.contains("arr[n - 3] * 2 = 60"));
+    assert!(output.contains("*p + p[1] = 50"));
+    assert!(output.contains("(n + 3) / 2 - -1 = 5"));
+    assert!(output.contains("n * 16 = 0x50"));
+    assert!(output.contains("arr = [10, 20, 30, 40, 50]"));
+    assert!(output.contains("Division by zero."));
+    assert!(output.contains("No symbol \"nope\" in current context."));
+    assert!(output.contains("Syntax error: expression ends too early."));
+    assert!(output.contains("Attempt to take contents of a non-pointer value."));
+    assert!(output.contains("sum = 150"));
+    assert!(output.contains("Child exited (status: 0)"));
+}
+
+/// kill should end the inferior but keep the breakpoints, so that it can be run again
+#[test]
+fn test_kill_and_run_again() {
+    let output = run_deet(
+        &[],
+        "function_calls",
+        &["break 10", "run", "kill", "kill", "run", "continue"],
+    );
+    assert_eq!(output.matches("Killing running inferior").count(), 1);
+    assert!(output.contains("No inferior is running"));
+    assert_eq!(
+        stop_lines(&output),
+        ["function_calls.c:10", "function_calls.c:10"]
+    );
+    assert_eq!(output.matches("func2(42, 5) was called").count(), 1);
+    assert!(output.contains("Child exited (status: 0)"));
+}
+
+/// detach should let the inferior finish on its own, without tripping over our breakpoints
+#[test]
+fn test_detach() {
+    let output = run_deet(
+        &[],
+        "function_calls",
+        &[
+            "break 10",
+            "break 13",
+            "run",
+            "detach",
+            "backtrace",
+            "detach",
+        ],
+    );
+    assert!(output.contains("function_calls.c:10"));
+    assert!(output.contains("Detaching from inferior"));
+    assert_eq!(output.matches("No inferior is running").count(), 2);
+    assert!(output.contains("end of func1"));
+    assert!(!output.contains("Killing running inferior"));
+}
+
+/// Threads should be reported as they start, stop on breakpoints, and be listed and selectable
+#[test]
+fn test_threads() {
+    let output = run_deet(
+        &[],
+        "threads",
+        &[
+            "break 6",
+            "run",
+            "info threads",
+            "print id",
+            "thread 1",
+            "info threads",
+            "thread 2",
+            "backtrace",
+            "continue",
+        ],
+    );
+    assert!(output.contains("[New thread 2 (LWP "));
+    assert!(output.contains("[Switching to thread 2 (LWP "));
+    assert!(output.contains("threads.c:6"));
+    assert!(output.contains("id = 7"));
+    // Each listing should show both threads, with the selected one marked
+    assert!(output.contains("  1 (LWP "));
+    assert!(output.contains("* 2 (LWP "));
+    assert!(output.contains("[Switching to thread 1 (LWP "));
+    assert!(output.contains("* 1 (LWP "));
+    assert!(output.contains("  2 (LWP "));
+    assert!(output.contains("worker "));
+    assert!(output.contains("Worker finished"));
+    assert!(output.contains("Child exited (status: 0)"));
+}
+
+/// Two threads hitting the same breakpoint over and over should each be stopped and resumed with
+/// their own registers, so that both run to completion and nothing else stops them
+#[test]
+fn test_threads_sharing_breakpoint() {
+    let mut commands = vec!["break tick", "run", "finish"];
+    commands.extend(["continue"; 25].iter());
+    let output = run_deet(&[], "thread_pair", &commands);
+    assert!(output.contains("hit 20 times"), "{}", output);
+    assert!(!output.contains("hit 21 times"), "{}", output);
+    assert!(!output.contains("Received signal"), "{}", output);
+    assert!(output.contains("Totals: 55 55"), "{}", output);
+    assert!(output.contains("Child exited (status: 0)"), "{}", output);
+}
+
+/// Writes a command file for deet to run and returns its path.
+fn write_script(name: &str, contents: &str) -> String {
+    let path = format!("{}/{}", env!("CARGO_TARGET_TMPDIR"), name);
+    std::fs::write(&path, contents).expect("Error writing command file");
+    path
+}
+
+const SCRIPT: &str = "# Stop in func2 and look around
+break 10
+run
+
+backtrace
+continue
+";
+
+/// Checks the output of running SCRIPT on function_calls.
+fn assert_ran_script(output: &str) {
+    assert!(output.contains("(deet) break 10"));
+    assert!(!output.contains("Stop in func2"));
+    assert!(output.contains("function_calls.c:10"));
+    assert!(output.contains("func2 "));
+    assert!(output.contains("func1 "));
+    assert!(output.contains("main "));
+    assert!(output.contains("Child exited (status: 0)"));
+    assert!(!output.contains("Unrecognized command."));
+}
+
+/// --commands should run a file of commands before reading any from the prompt
+#[test]
+fn test_commands_file() {
+    let path = write_script("commands_file.deet", SCRIPT);
+    let output = run_deet(&["--commands", &path], "function_calls", &[]);
+    assert_ran_script(&output);
+}
+
+/// source should run a file of commands from the prompt, and complain about missing files
+#[test]
+fn test_source_command() {
+    let path = write_script("source_command.deet", SCRIPT);
+    let output = run_deet(
+        &[],
+        "function_calls",
+        &["source /nonexistent.deet", &format!("source {}", path)],
+    );
+    assert!(output.contains("Could not read /nonexistent.deet"));
+    assert_ran_script(&output);
+}
+
+/// Returns the durations/counts printed on each "Ran for" line of deet's output.
+fn run_times(output: &str) -> Vec<&str> {
+    output
+        .lines()
+        .filter_map(|line| line.strip_prefix("Ran for "))
+        .collect()
+}
+
+/// Every stop should report how long the inferior ran since it was resumed
+#[test]
+fn test_reports_run_time() {
+    let output = run_deet(&[], "count", &["break 5", "run", "continue"]);
+    let times = run_times(&output);
+    assert_eq!(times.len(), 2);
+    assert!(times.iter().all(|time| *time != "0ns"));
+}
+
+/// With --count-instructions, stops should also report how many instructions were executed
+#[test]
+fn test_count_instructions() {
+    let output = run_deet(
+        &["--count-instructions"],
+        "count",
+        &["break 5", "run", "continue"],
+    );
+    assert!(output.contains("Warning: counting instructions"));
+    assert!(output.contains("Child exited (status: 0)"));
+    let times = run_times(&output);
+    assert_eq!(times.len(), 2);
+    for time in times {
+        let count: u64 = time
+            .split(" (")
+            .nth(1)
+            .and_then(|count| count.strip_suffix(" instructions)"))
+            .expect("Instruction count missing")
+            .parse()
+            .unwrap();
+        assert!(count > 0);
+    }
+}
+
+/// return should pop the current frame, with the caller seeing the value it was given, whether the
+/// function stopped in its prologue or further in
+#[test]
+fn test_return() {
+    let output = run_deet(
+        &[],
+        "expressions",
+        &["break 6", "run", "return n * 2 - 3", "continue"],
+    );
+    assert!(output.contains("Returned from sum"));
+    assert!(output.contains("printf(\"sum = %d\\n\", sum(arr, n));"));
+    assert!(output.contains("sum = 7"));
+    assert!(output.contains("Child exited (status: 0)"));
+
+    let output = run_deet(
+        &[],
+        "expressions",
+        &["break sum", "run", "return 99", "continue"],
+    );
+    assert!(output.contains("Returned from sum"));
+    assert!(output.contains("sum = 99"));
+    assert!(output.contains("Child exited (status: 0)"));
+}
+
+/// main has no caller to return to, so return should refuse and leave the program be
+#[test]
+fn test_return_from_main() {
+    let output = run_deet(
+        &[],
+        "expressions",
+        &["break 16", "run", "return 1", "continue"],
+    );
+    assert!(output.contains("Can't return from main"));
+    assert!(output.contains("sum = 150"));
+    assert!(output.contains("Child exited (status: 0)"));
+}
+
+/// Returns the `name = value` lines deet printed at each stop, one string per stop.
+fn locals_at_stops(output: &str) -> Vec<String> {
+    output
+        .split("Stopped at ")
+        .skip(1)
+        .map(|stop| {
+            stop.lines()
+                .filter(|line| line.contains(" = ") && !line.contains('('))
+                .map(|line| format!("{}\n", line))
+                .collect()
+        })
+        .collect()
+}
+
+/// info locals should print every local in scope where the inferior stopped, leaving out those of
+/// blocks it isn't in and those shadowed by an inner block
+#[test]
+fn test_info_locals() {
+    let output = run_deet(
+        &[],
+        "scopes",
+        &[
+            "break 7",
+            "break 11",
+            "break 13",
+            "run",
+            "info locals",
+            "continue",
+            "info locals",
+            "continue",
+            "continue",
+            "info locals",
+            "continue",
+            "info locals",
+            "print i",
+            "continue",
+        ],
+    );
+    let locals = locals_at_stops(&output);
+    assert_eq!(locals[0], "total = 0\ni = 0\nsquare = 0\n");
+    assert_eq!(locals[1], "total = 0\ni = 1\nsquare = 1\n");
+    assert_eq!(locals[3], "total = 100\n");
+    assert_eq!(locals[4], "total = 5\n");
+    assert!(output.contains("No symbol \"i\" in current context."));
+    assert!(output.contains("Child exited (status: 0)"));
+}
+
+/// Returns the lines deet reported stopping at, in order.
+fn stop_lines(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .filter_map(|line| line.strip_prefix("Stopped at "))
+        .map(|location| location.rsplit('/').next().unwrap().to_string())
+        .collect()
+}
+
+/// step should go line by line, entering func3 but not printf, which has no line info
+#[test]
+fn test_step() {
+    let output = run_deet(
+        &[],
+        "function_calls",
+        &[
+            "break 10", "run", "step", "step", "step", "step", "step", "continue",
+        ],
+    );
+    assert_eq!(
+        stop_lines(&output),
+        vec![
+            "function_calls.c:10",
+            "function_calls.c:11",
+            "function_calls.c:12",
+            "function_calls.c:13",
+            "function_calls.c:5",
+            "function_calls.c:6",
+        ]
+    );
+    assert!(output.contains("Child exited (status: 0)"));
+}
+
+/// next should run func3 to completion rather than entering it
+#[test]
+fn test_next() {
+    let output = run_deet(
+        &[],
+        "function_calls",
+        &["break 12", "run", "next", "next", "next", "continue"],
+    );
+    assert_eq!(
+        stop_lines(&output),
+        vec![
+            "function_calls.c:12",
+            "function_calls.c:13",
+            "function_calls.c:14",
+            "function_calls.c:19",
+        ]
+    );
+    assert!(output.contains("Hello from func3! 100"));
+    assert!(output.contains("Child exited (status: 0)"));
+}
+
+/// deet's output is piped in tests, so it should be neither colored nor paged even without
+/// --no-color and --no-pager
+#[test]
+fn test_no_color_when_not_a_tty() {
+    let output = run_deet(
+        &[],
+        "function_calls",
+        &["break 6", "run", "backtrace", "kill"],
+    );
+    assert!(output.contains("Stopped at"));
+    for function in &["func3", "func2", "func1", "main"] {
+        assert!(output.contains(&format!("{} ", function)), "{}", output);
+    }
+    assert!(!output.contains('\x1b'), "{:?}", output);
+    assert!(!output.contains("--More--"));
+}
+
+/// When the target is rebuilt between runs, run should reload its debugging symbols and move
+/// breakpoints to where their functions now are
+#[test]
+fn test_reload_rebuilt_target() {
+    let output = run_deet_rebuilt(
+        "hello",
+        "function_calls",
+        &["break main", "run"],
+        &["run", "continue"],
+    );
+    assert!(output.contains("has changed, reloading debugging symbols"));
+    assert!(output.contains("Moved break point 0 (main) to 0x"));
+    let stops = stop_lines(&output);
+    assert_eq!(stops.len(), 2);
+    assert!(stops[0].starts_with("hello.c:"), "{:?}", stops);
+    assert!(stops[1].starts_with("function_calls.c:"), "{:?}", stops);
+    assert!(output.contains("Hello from func3!"));
+    assert!(output.contains("Child exited (status: 0)"));
+}
+
+/// A macro defined with define should run its lines each time its name is entered, with $arg0
+/// replaced by what follows the name
+#[test]
+fn test_define_macro() {
+    let output = run_deet(
+        &[],
+        "function_calls",
+        &[
+            "define stop_at",
+            "break $arg0",
+            "end",
+            "define report",
+            "backtrace",
+            "continue",
+            "end",
+            "stop_at 6",
+            "run",
+            "report",
+            "report",
+        ],
+    );
+    assert!(output.contains("Set break point 0 at"), "{}", output);
+    assert_eq!(stop_lines(&output).len(), 2, "{}", output);
+    let backtraces = output.lines().filter(|line| line.starts_with("func3 "));
+    assert_eq!(backtraces.count(), 2, "{}", output);
+    assert!(output.contains("Child exited (status: 0)"), "{}", output);
+    assert!(!output.contains("Unrecognized command"), "{}", output);
+}
+
+/// finish should run to the end of the current function and show what it returned
+#[test]
+fn test_finish_shows_return_value() {
+    let output = run_deet(
+        &[],
+        "expressions",
+        &["break sum", "run", "finish", "continue"],
+    );
+    assert!(output.contains("Run till exit from sum"), "{}", output);
+    assert!(output.contains("Value returned is 150"), "{}", output);
+    let stops = stop_lines(&output);
+    assert_eq!(stops.len(), 2, "{:?}", stops);
+    assert!(stops[1].starts_with("expressions.c:16"), "{:?}", stops);
+    assert!(output.contains("sum = 150"));
+}
+
+/// Finishing a void function shouldn't show a return value
+#[test]
+fn test_finish_void_function() {
+    let output = run_deet(
+        &[],
+        "function_calls",
+        &["break func3", "run", "step-out", "kill"],
+    );
+    assert!(output.contains("Run till exit from func3"), "{}", output);
+    assert_eq!(stop_lines(&output).len(), 2, "{}", output);
+    assert!(!output.contains("Value returned"), "{}", output);
+}
+
+/// list /m should show each source line of the function followed by its instructions, marking the
+/// current one, and show what a breakpoint replaced rather than the int3
+#[test]
+fn test_list_mixed() {
+    let output = run_deet(
+        &[],
+        "function_calls",
+        &["break func3", "run", "list /m", "kill"],
+    );
+    let listing: Vec<&str> = output
+        .lines()
+        .skip_while(|line| !line.starts_with("5\t"))
+        .take_while(|line| !line.starts_with("Killing"))
+        .collect();
+    let source_lines: Vec<&str> = listing
+        .iter()
+        .filter(|line| !line.starts_with(' ') && !line.starts_with("=>"))
+        .copied()
+        .collect();
+    assert_eq!(
+        source_lines,
+        vec![
+            "5\tvoid func3(int a) {",
+            "6\t    printf(\"Hello from func3! %d\\n\", a);",
+            "7\t}",
+        ],
+        "{}",
+        output
+    );
+    // The breakpoint is on the first instruction, and every line has some
+    assert!(listing[1].starts_with("=> 0x"), "{}", output);
+    assert!(listing[1].ends_with(" <+0>:\tpush   %rbp"), "{}", output);
+    for (i, line) in listing.iter().enumerate() {
+        if source_lines.contains(line) {
+            assert!(listing[i + 1].starts_with("   0x") || listing[i + 1].starts_with("=> 0x"));
+        }
+    }
+    assert!(listing.last().unwrap().ends_with("\tret"), "{}", output);
+}
+
+/// Stopping at a breakpoint should say which one it was and how many times it has been hit
+#[test]
+fn test_break_point_hit_counts() {
+    let output = run_deet(
+        &[],
+        "function_calls",
+        &[
+            "break func3",
+            "break func2",
+            "run",
+            "continue",
+            "continue",
+            "continue",
+        ],
+    );
+    let hits: Vec<&str> = output
+        .lines()
+        .filter(|line| line.starts_with("Breakpoint "))
+        .collect();
+    assert_eq!(hits.len(), 3, "{}", output);
+    assert!(hits[0].starts_with("Breakpoint 1 at func2 ("), "{:?}", hits);
+    assert!(
+        hits[0].ends_with("function_calls.c:9), hit 1 time"),
+        "{:?}",
+        hits
+    );
+    assert!(hits[1].starts_with("Breakpoint 0 at func3 ("), "{:?}", hits);
+    assert!(hits[1].ends_with(", hit 1 time"), "{:?}", hits);
+    assert!(hits[2].starts_with("Breakpoint 0 at func3 ("), "{:?}", hits);
+    assert!(hits[2].ends_with(", hit 2 times"), "{:?}", hits);
+    assert!(output.contains("Child exited (status: 0)"));
+}
+
+/// A name shared by static functions in two files should get one breakpoint at each of them
+#[test]
+fn test_break_point_multiple_locations() {
+    let output = run_deet(
+        &[],
+        "statics",
+        &["break report", "run", "continue", "continue", "continue"],
+    );
+    assert!(
+        output.contains("Set break point 0 at 2 locations: 0x"),
+        "{}",
+        output
+    );
+    assert_eq!(
+        stop_lines(&output),
+        vec!["statics.c:5", "statics_other.c:3", "statics.c:5"]
+    );
+    assert!(output.contains("Breakpoint 0 at report ("));
+    assert!(output.contains(", hit 3 times"));
+    assert!(output.contains("Child exited (status: 0)"));
+}
+
+/// info sources should list every file of a multi-file target, and list should show one by name,
+/// without a running inferior
+#[test]
+fn test_info_sources_and_list_file() {
+    let output = run_deet(
+        &[],
+        "statics",
+        &["info sources", "list statics_other.c", "list nowhere.c"],
+    );
+    let sources: Vec<&str> = output
+        .lines()
+        .filter(|line| line.ends_with(".c"))
+        .map(|line| line.trim_start_matches("(deet) "))
+        .collect();
+    let expected = [sample_path("statics.c"), sample_path("lib/statics_other.c")];
+    assert_eq!(sources.len(), 2, "{}", output);
+    for (source, expected) in sources.iter().zip(&expected) {
+        assert_eq!(
+            Path::new(source).canonicalize().unwrap(),
+            expected.canonicalize().unwrap()
+        );
+    }
+    assert!(
+        output.contains("3\tstatic void report(int n) {\n4\t    printf(\"report(%d) from statics_other.c\\n\", n);"),
+        "{}",
+        output
+    );
+    assert!(
+        output.contains("No source file named nowhere.c."),
+        "{}",
+        output
+    );
+}
+
+#[test]
+fn test_tracepoint() {
+    let output = run_deet(
+        &[],
+        "expressions",
+        &["dprintf 8 \"i = {i}, total = {total}\"", "run"],
+    );
+    let traces: Vec<&str> = output
+        .lines()
+        .filter(|line| line.starts_with("i = "))
+        .collect();
+    assert_eq!(
+        traces,
+        vec![
+            "i = 0, total = 0",
+            "i = 1, total = 10",
+            "i = 2, total = 30",
+            "i = 3, total = 60",
+            "i = 4, total = 100",
+        ],
+        "{}",
+        output
+    );
+    assert!(output.contains("sum = 150"), "{}", output);
+    assert!(output.contains("Child exited (status: 0)"), "{}", output);
+    assert!(stop_lines(&output).is_empty(), "{}", output);
+}
+
+/// trace <function> should log each call with its arguments and each return with its value,
+/// indented by nesting depth, without stopping
+#[test]
+fn test_trace_calls() {
+    let output = run_deet(
+        &[],
+        "squares",
+        &["trace sum_squares", "trace square", "run"],
+    );
+    let traces: Vec<&str> = output
+        .lines()
+        .filter(|line| line.trim_start().starts_with("->") || line.trim_start().starts_with("<-"))
+        .collect();
+    assert_eq!(
+        traces,
+        vec![
+            "-> sum_squares(n=3)",
+            "  -> square(n=1)",
+            "  <- square = 1",
+            "  -> square(n=2)",
+            "  <- square = 4",
+            "  -> square(n=3)",
+            "  <- square = 9",
+            "<- sum_squares = 14",
+        ],
+        "{}",
+        output
+    );
+    assert!(output.contains("sum = 14"), "{}", output);
+    assert!(output.contains("Child exited (status: 0)"), "{}", output);
+    assert!(stop_lines(&output).is_empty(), "{}", output);
+}
+
+/// A recorded run should replay with the same stops, shown as they were live, without running the
+/// program again
+#[test]
+fn test_record_and_replay() {
+    let recording = Path::new(env!("CARGO_TARGET_TMPDIR")).join("count-recording.jsonl");
+    let record = format!("record {}", recording.display());
+    let live = run_deet(
+        &[],
+        "count",
+        &[&record, "break 5", "run", "next", "continue", "record"],
+    );
+    assert!(live.contains("Recorded 3 stops to"), "{}", live);
+    let recorded = std::fs::read_to_string(&recording).unwrap();
+    assert_eq!(recorded.lines().count(), 3, "{}", recorded);
+    assert!(recorded.contains("\"registers\""), "{}", recorded);
+
+    let replay = format!("replay {}", recording.display());
+    let replayed = run_deet(&[], "count", &[&replay, "replay", "replay", "replay"]);
+    assert_eq!(stop_lines(&replayed), vec!["count.c:5", "count.c:6"]);
+    assert_eq!(stop_lines(&replayed), stop_lines(&live));
+    let reports = |output: &str| -> Vec<String> {
+        output
+            .lines()
+            .filter(|line| {
+                line.starts_with("Breakpoint ")
+                    || line.starts_with("Stepped to ")
+                    || line.starts_with("Child exited")
+            })
+            .map(str::to_string)
+            .collect()
+    };
+    assert_eq!(reports(&replayed), reports(&live));
+    assert_eq!(reports(&replayed).len(), 3, "{}", replayed);
+    assert!(replayed.contains("End of recording"), "{}", replayed);
+    // Each replayed stop shows the registers recorded with it
+    for line in recorded.lines() {
+        let stop: serde_json::Value = serde_json::from_str(line).unwrap();
+        if let Some(rsp) = stop["registers"]["rsp"].as_u64() {
+            let shown = format!("rsp    {:#018x}", rsp);
+            assert!(replayed.contains(&shown), "{}", replayed);
+        }
+    }
+    let shown_registers = replayed
+        .lines()
+        .filter(|line| line.starts_with("rip    0x"))
+        .count();
+    assert_eq!(shown_registers, 2, "{}", replayed);
+    // The program never ran, so it printed nothing
+    assert!(!replayed.lines().any(|line| line == "1"), "{}", replayed);
+}
+
+/// until should run the rest of a loop and stop at the line after it
+#[test]
+fn test_until_after_loop() {
+    let output = run_deet(
+        &[],
+        "expressions",
+        &[
+            "break sum",
+            "run",
+            "next",
+            "next",
+            "next",
+            "until 10",
+            "print total",
+            "continue",
+        ],
+    );
+    let stops = stop_lines(&output);
+    assert_eq!(stops.len(), 5, "{}", output);
+    // Inside the loop body on the first iteration
+    assert!(stops[3].starts_with("expressions.c:8"), "{:?}", stops);
+    assert!(stops[4].starts_with("expressions.c:10"), "{:?}", stops);
+    assert!(output.contains("total = 150"), "{}", output);
+    // The temporary breakpoint is gone, so the program runs to the end
+    assert!(output.contains("Child exited (status: 0)"), "{}", output);
+}
+
+/// until should only stop in the frame it was run from, while advance stops in the first frame to
+/// get to the line
+#[test]
+fn test_until_skips_recursive_calls() {
+    let output = run_deet(
+        &[],
+        "recursion",
+        &["break 11", "run", "step", "until 7", "print n", "kill"],
+    );
+    let stops = stop_lines(&output);
+    assert_eq!(stops.len(), 3, "{}", output);
+    assert!(stops[2].starts_with("recursion.c:7"), "{:?}", stops);
+    assert!(output.contains("n = 3"), "{}", output);
+
+    let output = run_deet(
+        &[],
+        "recursion",
+        &["break 11", "run", "step", "advance 7", "print n", "kill"],
+    );
+    let stops = stop_lines(&output);
+    assert!(stops[2].starts_with("recursion.c:7"), "{:?}", stops);
+    assert!(output.contains("n = 0"), "{}", output);
+}
+
+/// Each stop should say why it happened: a breakpoint, the end of a step, or a signal
+#[test]
+fn test_stop_reasons() {
+    let output = run_deet(
+        &[],
+        "function_calls",
+        &["break func2", "run", "next", "continue"],
+    );
+    let reasons: Vec<&str> = output
+        .lines()
+        .filter(|line| {
+            line.starts_with("Breakpoint ")
+                || line.starts_with("Stepped to ")
+                || line.starts_with("Received signal ")
+        })
+        .collect();
+    assert_eq!(reasons.len(), 2, "{}", output);
+    assert!(
+        reasons[0].starts_with("Breakpoint 0 at func2 ("),
+        "{:?}",
+        reasons
+    );
+    assert!(
+        reasons[0].ends_with("function_calls.c:9), hit 1 time"),
+        "{:?}",
+        reasons
+    );
+    assert!(
+        reasons[1].starts_with("Stepped to func2 ("),
+        "{:?}",
+        reasons
+    );
+    assert!(
+        reasons[1].ends_with("function_calls.c:10)"),
+        "{:?}",
+        reasons
+    );
+    assert!(!output.contains("Child stopped"), "{}", output);
+
+    let output = run_deet(&[], "segfault", &["run", "kill"]);
+    assert!(output.contains("Received signal SIGSEGV"), "{}", output);
+    assert!(!output.contains("Breakpoint "), "{}", output);
+}
+
+/// Returns the JSON events deet printed in `--json-rpc` mode, skipping the inferior's own output.
+fn json_events(output: &str) -> Vec<serde_json::Value> {
+    output
+        .lines()
+        .filter(|line| line.starts_with('{'))
+        .map(|line| serde_json::from_str(line).expect("deet printed invalid JSON"))
+        .collect()
+}
+
+#[test]
+fn test_json_rpc() {
+    let output = run_deet(
+        &["--json-rpc"],
+        "function_calls",
+        &[
+            r#"{"cmd": "break", "args": ["func2"]}"#,
+            r#"{"cmd": "break", "args": ["func3"]}"#,
+            r#"{"cmd": "run"}"#,
+            r#"{"cmd": "print", "args": ["global"]}"#,
+            r#"{"cmd": "continue"}"#,
+            r#"{"cmd": "continue", "args": []}"#,
+            r#"{"cmd": "continue"}"#,
+            "not json",
+        ],
+    );
+    assert!(!output.contains("(deet)"), "{}", output);
+    let events = json_events(&output);
+    let kinds: Vec<&str> = events
+        .iter()
+        .map(|event| event["event"].as_str().unwrap())
+        .filter(|&kind| kind != "ready" && kind != "output")
+        .collect();
+    assert_eq!(
+        kinds,
+        [
+            "breakpoint",
+            "breakpoint",
+            "stop",
+            "value",
+            "stop",
+            "stop",
+            "exited",
+            "error"
+        ],
+        "{}",
+        output
+    );
+
+    let stops: Vec<&serde_json::Value> = events
+        .iter()
+        .filter(|event| event["event"] == "stop")
+        .collect();
+    let expected = [("func2", 9, 0, 1), ("func3", 5, 1, 1), ("func3", 5, 1, 2)];
+    for (stop, (function, line, number, hits)) in stops.iter().zip(expected.iter()) {
+        assert_eq!(stop["reason"], "breakpoint", "{}", stop);
+        assert_eq!(stop["function"], *function, "{}", stop);
+        assert_eq!(stop["line"], *line, "{}", stop);
+        assert!(
+            stop["file"].as_str().unwrap().ends_with("function_calls.c"),
+            "{}",
+            stop
+        );
+        assert_eq!(
+            stop["breakpoints"],
+            serde_json::json!([{ "number": number, "hits": hits }]),
+            "{}",
+            stop
+        );
+    }
+    let value = events
+        .iter()
+        .find(|event| event["event"] == "value")
+        .unwrap();
+    assert_eq!(value["expression"], "global");
+    assert_eq!(value["value"], "5");
+    let exited = events
+        .iter()
+        .find(|event| event["event"] == "exited")
+        .unwrap();
+    assert_eq!(exited["status"], 0);
+}
+
+/// break +N and break -N should count lines from where the inferior is stopped
+#[test]
+fn test_break_relative_line() {
+    let output = run_deet(
+        &[],
+        "function_calls",
+        &["break 10", "run", "break +2", "continue", "continue"],
+    );
+    assert!(output.contains("Set break point 1 at"), "{}", output);
+    assert_eq!(
+        stop_lines(&output),
+        vec!["function_calls.c:10", "function_calls.c:12"]
+    );
+    assert!(output.contains("Child exited (status: 0)"), "{}", output);
+
+    // Line 15 is blank, and there is no line -8
+    let output = run_deet(
+        &[],
+        "function_calls",
+        &[
+            "break +1",
+            "break 12",
+            "run",
+            "break +3",
+            "break -20",
+            "kill",
+        ],
+    );
+    let errors: Vec<&str> = output
+        .lines()
+        .filter(|line| line.starts_with("No ") || line.starts_with("Incorrect "))
+        .collect();
+    assert_eq!(
+        errors,
+        [
+            "No inferior is running",
+            "No code at that line",
+            "Incorrect line number"
+        ],
+        "{}",
+        output
+    );
+}
+
+/// A position-independent executable isn't loaded at the addresses it was linked at, so
+/// breakpoints, line lookups and globals should be moved to wherever it was loaded, on every run
+#[test]
+fn test_position_independent_executable() {
+    let output = run_deet(
+        &[],
+        "function_calls_pie",
+        &[
+            "break 6",
+            "break 12",
+            "run",
+            "print sum",
+            "print global",
+            "continue",
+            "backtrace",
+            "run",
+            "kill",
+        ],
+    );
+    assert!(output.contains("sum = 47"), "{}", output);
+    assert!(output.contains("global = 5"), "{}", output);
+    assert!(
+        output.contains("Breakpoint 0 at func3 (") && output.contains("function_calls.c:6)"),
+        "{}",
+        output
+    );
+    let frames: Vec<&str> = output
+        .lines()
+        .filter(|line| line.starts_with("func") || line.starts_with("main "))
+        .map(|line| line.rsplit('/').next().unwrap())
+        .collect();
+    assert_eq!(
+        frames,
+        [
+            "function_calls.c:6",
+            "function_calls.c:14",
+            "function_calls.c:19",
+            "function_calls.c:24"
+        ],
+        "{}",
+        output
+    );
+    let stops_at_line_12 = output
+        .lines()
+        .filter(|line| line.starts_with("Stopped at ") && line.ends_with("function_calls.c:12"))
+        .count();
+    assert_eq!(stops_at_line_12, 2, "{}", output);
+}
+
+/// Code built with -O2 and no frame pointers leaves %rbp pointing nowhere useful, so backtrace
+/// should find each caller from the call frame information instead
+#[test]
+fn test_backtrace_without_frame_pointers() {
+    let output = run_deet(
+        &[],
+        "optimized",
+        &["break leaf", "run", "backtrace", "kill"],
+    );
+    assert!(
+        !output.contains("Error reading inferior stack"),
+        "{}",
+        output
+    );
+    let frames: Vec<&str> = output
+        .lines()
+        .filter(|line| {
+            ["leaf ", "middle ", "main "]
+                .iter()
+                .any(|f| line.starts_with(f))
+        })
+        .map(|line| line.rsplit('/').next().unwrap())
+        .collect();
+    assert_eq!(
+        frames,
+        ["optimized.c:7", "optimized.c:14", "optimized.c:20"],
+        "{}",
+        output
+    );
+}
+
+/// A stack whose frames point back at themselves should cut the backtrace short instead of being
+/// walked forever
+#[test]
+fn test_backtrace_of_corrupted_stack() {
+    let output = run_deet(
+        &[],
+        "corrupt_stack",
+        &["break 11", "run", "backtrace", "kill"],
+    );
+    let frames = output
+        .lines()
+        .filter(|line| line.starts_with("corrupt "))
+        .count();
+    assert!((1..=2).contains(&frames), "{}", output);
+    assert!(!output.contains("main "), "{}", output);
+}
+
+/// watch -l should stop whenever the heap memory it was given changes, from whichever function
+/// changes it, but not when it is written with the value it already held
+#[test]
+fn test_watch_location() {
+    let output = run_deet(
+        &[],
+        "heap",
+        &[
+            "watch -l *total",
+            "break 12",
+            "run",
+            "watch -l 0",
+            "watch -l *total",
+            "continue",
+            "backtrace",
+            "continue",
+            "continue",
+            "continue",
+            "kill",
+        ],
+    );
+    assert!(output.contains("No inferior is running"), "{}", output);
+    assert!(
+        output.contains("Cannot access memory at address 0x0"),
+        "{}",
+        output
+    );
+    assert!(
+        output.contains("Set watchpoint 1 on -location *total (4 bytes at 0x"),
+        "{}",
+        output
+    );
+    let changes: Vec<&str> = output
+        .lines()
+        .filter(|line| line.starts_with("Old value") || line.starts_with("New value"))
+        .take(6)
+        .collect();
+    assert_eq!(
+        changes,
+        [
+            "Old value = 0",
+            "New value = 2",
+            "Old value = 2",
+            "New value = 6",
+            "Old value = 6",
+            "New value = 12"
+        ],
+        "{}",
+        output
+    );
+    assert!(
+        output.contains("Watchpoint 1 (-location *total), hit 3 times"),
+        "{}",
+        output
+    );
+    assert!(output.contains("heap.c:7\n"), "{}", output);
+    assert!(
+        output.lines().any(|line| line.starts_with("add ")),
+        "{}",
+        output
+    );
+    // Line 16 only writes back the value that is already there
+    assert!(!output.contains("heap.c:16"), "{}", output);
+}
+
+/// catch syscall should stop on the way into a write, with its fd and byte count, and on the way
+/// out, with what it returned, but not at other syscalls
+#[test]
+fn test_catch_syscall() {
+    let output = run_deet(
+        &[],
+        "hello",
+        &[
+            "catch syscall nosuch",
+            "catch syscall write",
+            "run",
+            "continue",
+            "continue",
+        ],
+    );
+    assert!(output.contains("Unknown syscall nosuch"), "{}", output);
+    assert!(
+        output.contains("Set catchpoint 0 on syscall write"),
+        "{}",
+        output
+    );
+    let entry = output
+        .lines()
+        .find(|line| line.starts_with("Catchpoint 0 (call to syscall write(1, 0x"))
+        .unwrap_or_else(|| panic!("{}", output));
+    assert!(entry.ends_with(", 13)), hit 1 time"), "{}", output);
+    assert!(
+        output.contains("Catchpoint 0 (returned from syscall write = 13), hit 2 times"),
+        "{}",
+        output
+    );
+    assert_eq!(output.matches("Catchpoint 0").count(), 2, "{}", output);
+    assert!(output.contains("Hello world!"), "{}", output);
+    assert!(output.contains("Child exited (status: 0)"), "{}", output);
+}
+
+/// print $xmm0 should show the double passed in it both as floats and in hex, and info registers
+/// float should list it along with the x87 and control registers
+#[test]
+fn test_float_registers() {
+    let output = run_deet(
+        &[],
+        "floats",
+        &[
+            "break half",
+            "run",
+            "print $xmm0",
+            "print $xmm16",
+            "info registers float",
+            "kill",
+        ],
+    );
+    assert!(
+        output.contains(
+            "$xmm0 = {v4_float = {0, 2.0625, 0, 0}, v2_double = {2.5, 0}, \
+             uint128 = 0x00000000000000004004000000000000}"
+        ),
+        "{}",
+        output
+    );
+    assert!(
+        output.contains("No symbol \"$xmm16\" in current context."),
+        "{}",
+        output
+    );
+    let registers: Vec<&str> = output
+        .lines()
+        .filter(|line| !line.starts_with('$') && line.contains(" = "))
+        .map(|line| line.split(" = ").next().unwrap())
+        .collect();
+    assert_eq!(registers.len(), 8 + 5 + 16, "{}", output);
+    assert_eq!(registers[0], "st0", "{}", output);
+    assert!(registers.contains(&"mxcsr"), "{}", output);
+    assert!(
+        output.contains("\nxmm0 = {v4_float = {0, 2.0625, 0, 0}, v2_double = {2.5, 0}"),
+        "{}",
+        output
+    );
+}
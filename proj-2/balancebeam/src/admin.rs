@@ -0,0 +1,122 @@
+//! The admin API, served on --admin-bind. It answers one request per connection:
+//!
+//! * `POST /upstreams/<address>/drain?max=<n>` drains an upstream (see
+//!   `ProxyState::drain_upstream`), letting it take at most n more requests (0 if not given)
+//! * `GET /upstreams/<address>/drain` reports how far along the drain is
+//!
+//! `<address>` is the upstream as given in `Config::upstream`.
+
+use tokio::net::TcpListener;
+use tracing::Instrument;
+
+use crate::{request, response, ProxyState};
+
+/// Answers admin requests on `listener` until the task running this is aborted.
+pub async fn serve_admin(listener: TcpListener, state: ProxyState) {
+    loop {
+        let (mut stream, client_addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                tracing::warn!("Failed to accept admin connection: {}", err);
+                continue;
+            }
+        };
+        let state = state.clone();
+        let span = tracing::info_span!("admin", client_ip = %client_addr.ip());
+        tokio::spawn(
+            async move {
+                let read = request::read_from_stream(
+                    &mut stream,
+                    state.io_buffer_bytes,
+                    state.max_header_bytes,
+                    state.client_read_timeout,
+                );
+                let mut response = match read.await {
+                    Ok(request) => {
+                        tracing::info!("{}", request::format_request_line(&request));
+                        answer(&state, &request)
+                    }
+                    Err(err) => {
+                        tracing::debug!("Error reading admin request: {}", err);
+                        response::make_http_error(http::StatusCode::BAD_REQUEST)
+                    }
+                };
+                response
+                    .headers_mut()
+                    .insert("connection", http::HeaderValue::from_static("close"));
+                if let Err(err) = response::write_to_stream(&response, &mut stream).await {
+                    tracing::warn!("Failed to send admin response: {}", err);
+                }
+            }
+            .instrument(span),
+        );
+    }
+}
+
+/// Carries out an admin request and returns the response to it.
+fn answer(state: &ProxyState, request: &http::Request<Vec<u8>>) -> http::Response<Vec<u8>> {
+    let address = match request
+        .uri()
+        .path()
+        .strip_prefix("/upstreams/")
+        .and_then(|path| path.strip_suffix("/drain"))
+    {
+        Some(address) => address,
+        None => return response::make_http_error(http::StatusCode::NOT_FOUND),
+    };
+    match *request.method() {
+        http::Method::POST => {
+            let max = request
+                .uri()
+                .query()
+                .unwrap_or("")
+                .split('&')
+                .find_map(|pair| pair.strip_prefix("max="));
+            let max = match max.map(str::parse::<usize>) {
+                None => 0,
+                Some(Ok(max)) => max,
+                Some(Err(_)) => {
+                    return response::make_text_response(
+                        http::StatusCode::BAD_REQUEST,
+                        "max must be a number of requests\n",
+                    )
+                }
+            };
+            if !state.drain_upstream(address, max) {
+                return unknown_upstream(address);
+            }
+            response::make_text_response(
+                http::StatusCode::OK,
+                &format!("Draining {}, allowing {} more requests\n", address, max),
+            )
+        }
+        http::Method::GET => match state.drain_status(address) {
+            Some((0, 0)) => response::make_text_response(
+                http::StatusCode::OK,
+                &format!("{} is drained\n", address),
+            ),
+            Some((allowed, in_flight)) => response::make_text_response(
+                http::StatusCode::OK,
+                &format!(
+                    "{} is draining: {} requests in flight, {} more allowed\n",
+                    address, in_flight, allowed
+                ),
+            ),
+            None if state.upstream_addresses.contains(&address.to_string()) => {
+                response::make_text_response(
+                    http::StatusCode::OK,
+                    &format!("{} is not draining\n", address),
+                )
+            }
+            None => unknown_upstream(address),
+        },
+        _ => response::make_http_error(http::StatusCode::METHOD_NOT_ALLOWED),
+    }
+}
+
+fn unknown_upstream(address: &str) -> http::Response<Vec<u8>> {
+    response::make_text_response(
+        http::StatusCode::NOT_FOUND,
+        &format!("No upstream {}\n", address),
+    )
+}
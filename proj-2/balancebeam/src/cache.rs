@@ -0,0 +1,242 @@
+//! Caches upstream responses for --response-cache-entries. A response is answered with for as long
+//! as its Cache-Control or Expires says it is fresh. After that, one with an ETag or Last-Modified
+//! is revalidated with a conditional request, so that a resource that hasn't changed costs the
+//! upstream a 304 instead of its whole body.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant, SystemTime};
+
+use parking_lot::Mutex;
+
+use crate::response;
+
+/// Request headers that responses may vary by and still be cached. Requests differing in these
+/// get different cache keys (see `sharing_key`), so the responses don't get mixed up.
+const CACHE_KEY_HEADERS: [&str; 3] = ["accept", "accept-encoding", "accept-language"];
+
+/// Headers of a 304 that say nothing about the cached response, and so aren't copied into it.
+const NOT_MODIFIED_SKIPPED_HEADERS: [&str; 5] = [
+    "connection",
+    "keep-alive",
+    "transfer-encoding",
+    "content-length",
+    "content-encoding",
+];
+
+/// What the cache has for a request.
+pub enum Lookup {
+    /// A fresh response to answer with
+    Fresh(http::Response<Vec<u8>>),
+    /// A stale response that may still be current. These are the headers (If-None-Match and/or
+    /// If-Modified-Since) to ask the upstream whether it is.
+    Stale(Vec<(&'static str, http::HeaderValue)>),
+    /// Nothing usable
+    Miss,
+}
+
+struct Entry {
+    response: http::Response<Vec<u8>>,
+    /// When the response was stored, or last revalidated
+    stored_at: Instant,
+    /// How long after `stored_at` the response stays fresh
+    fresh_for: Duration,
+}
+
+impl Entry {
+    fn new(response: http::Response<Vec<u8>>) -> Entry {
+        let fresh_for = freshness_lifetime(&response);
+        Entry {
+            response,
+            stored_at: Instant::now(),
+            fresh_for,
+        }
+    }
+
+    /// Returns a copy of the response, saying how long it has been cached for.
+    fn cached_response(&self) -> http::Response<Vec<u8>> {
+        let mut response = response::clone_response(&self.response);
+        response.headers_mut().insert(
+            "age",
+            http::HeaderValue::from(self.stored_at.elapsed().as_secs()),
+        );
+        response
+    }
+
+    /// Returns the conditional request headers that ask whether the response is still current.
+    fn conditions(&self) -> Vec<(&'static str, http::HeaderValue)> {
+        let headers = self.response.headers();
+        let mut conditions = Vec::new();
+        if let Some(etag) = headers.get("etag") {
+            conditions.push(("if-none-match", etag.clone()));
+        }
+        if let Some(last_modified) = headers.get("last-modified") {
+            conditions.push(("if-modified-since", last_modified.clone()));
+        }
+        conditions
+    }
+}
+
+pub struct ResponseCache {
+    entries: Mutex<HashMap<String, Entry>>,
+    max_entries: usize,
+}
+
+impl ResponseCache {
+    pub fn new(max_entries: usize) -> ResponseCache {
+        ResponseCache {
+            entries: Mutex::new(HashMap::new()),
+            max_entries,
+        }
+    }
+
+    /// Looks up the response cached under `key`. Stale responses that can't be revalidated are
+    /// forgotten.
+    pub fn lookup(&self, key: &str) -> Lookup {
+        let mut entries = self.entries.lock();
+        let entry = match entries.get(key) {
+            Some(entry) => entry,
+            None => return Lookup::Miss,
+        };
+        if entry.stored_at.elapsed() < entry.fresh_for {
+            return Lookup::Fresh(entry.cached_response());
+        }
+        let conditions = entry.conditions();
+        if conditions.is_empty() {
+            entries.remove(key);
+            return Lookup::Miss;
+        }
+        Lookup::Stale(conditions)
+    }
+
+    /// Caches `response` under `key` (replacing whatever was there), if it is a 200 that can
+    /// be cached: one that is fresh for a while or can be revalidated, and that varies by no
+    /// request headers other than those in the key. Returns whether it was cached.
+    ///
+    /// When the cache is full, the response that goes stale soonest makes way.
+    pub fn store(&self, key: &str, response: &http::Response<Vec<u8>>) -> bool {
+        if response.status() != http::StatusCode::OK || !varies_only_by_key(response) {
+            return false;
+        }
+        let entry = Entry::new(response::clone_response(response));
+        if entry.fresh_for.is_zero() && entry.conditions().is_empty() {
+            return false;
+        }
+        let mut entries = self.entries.lock();
+        if entries.len() >= self.max_entries && !entries.contains_key(key) {
+            let soonest_stale = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.stored_at + entry.fresh_for)
+                .map(|(key, _)| key.clone());
+            if let Some(soonest_stale) = soonest_stale {
+                entries.remove(&soonest_stale);
+            }
+        }
+        entries.insert(key.to_string(), entry);
+        true
+    }
+
+    /// Updates the response cached under `key` after the upstream answered the conditional
+    /// request from `lookup` with `not_modified` (a 304): its headers replace the cached ones, and
+    /// the response is fresh again. Returns the refreshed response to answer with, or None if it
+    /// has been evicted in the meantime.
+    pub fn revalidated(
+        &self,
+        key: &str,
+        not_modified: &http::Response<Vec<u8>>,
+    ) -> Option<http::Response<Vec<u8>>> {
+        let mut entries = self.entries.lock();
+        let entry = entries.get_mut(key)?;
+        let headers = entry.response.headers_mut();
+        for name in not_modified.headers().keys() {
+            if NOT_MODIFIED_SKIPPED_HEADERS.contains(&name.as_str()) {
+                continue;
+            }
+            headers.remove(name);
+            for value in not_modified.headers().get_all(name) {
+                headers.append(name, value.clone());
+            }
+        }
+        entry.fresh_for = freshness_lifetime(&entry.response);
+        entry.stored_at = Instant::now();
+        Some(entry.cached_response())
+    }
+
+    /// Forgets the response cached under `key`, if any.
+    pub fn remove(&self, key: &str) {
+        self.entries.lock().remove(key);
+    }
+}
+
+/// Returns the Cache-Control directives of `response`, lowercased, with their values if they
+/// have any (e.g. `("max-age", Some("60"))`).
+fn cache_control(response: &http::Response<Vec<u8>>) -> Vec<(String, Option<String>)> {
+    response
+        .headers()
+        .get_all("cache-control")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|directive| match directive.split_once('=') {
+            Some((name, value)) => (
+                name.trim().to_ascii_lowercase(),
+                Some(value.trim().trim_matches('"').to_string()),
+            ),
+            None => (directive.trim().to_ascii_lowercase(), None),
+        })
+        .collect()
+}
+
+/// Returns how long `response` stays fresh from now: what its `s-maxage` or `max-age` says, or
+/// else how long until it `Expires`, less its `Age`. Responses that must always be revalidated
+/// (`no-cache`), or that don't say, are stale right away.
+fn freshness_lifetime(response: &http::Response<Vec<u8>>) -> Duration {
+    let directives = cache_control(response);
+    if directives.iter().any(|(name, _)| name == "no-cache") {
+        return Duration::ZERO;
+    }
+    let seconds = |name: &str| {
+        directives
+            .iter()
+            .find(|(directive, _)| directive == name)
+            .and_then(|(_, value)| value.as_deref()?.parse::<u64>().ok())
+            .map(Duration::from_secs)
+    };
+    let header_date = |name: &str| {
+        let value = response.headers().get(name)?.to_str().ok()?;
+        httpdate::parse_http_date(value).ok()
+    };
+    let lifetime = match seconds("s-maxage").or_else(|| seconds("max-age")) {
+        Some(lifetime) => lifetime,
+        // An Expires that can't be parsed (such as 0) means the response has already expired
+        None => match header_date("expires") {
+            Some(expires) => {
+                let date = header_date("date").unwrap_or_else(SystemTime::now);
+                expires.duration_since(date).unwrap_or_default()
+            }
+            None => Duration::ZERO,
+        },
+    };
+    let age = response
+        .headers()
+        .get("age")
+        .and_then(|value| value.to_str().ok()?.parse::<u64>().ok())
+        .map(Duration::from_secs)
+        .unwrap_or_default();
+    lifetime.saturating_sub(age)
+}
+
+/// Returns whether the only request headers `response` says it varies by are in the cache key.
+fn varies_only_by_key(response: &http::Response<Vec<u8>>) -> bool {
+    response
+        .headers()
+        .get_all("vary")
+        .iter()
+        .all(|value| match value.to_str() {
+            Ok(value) => value
+                .split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .all(|name| CACHE_KEY_HEADERS.contains(&name.to_ascii_lowercase().as_str())),
+            Err(_) => false,
+        })
+}
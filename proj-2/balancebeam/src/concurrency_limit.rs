@@ -0,0 +1,112 @@
+//! Adapts how many connections each upstream may have open for --adaptive-concurrency. While an
+//! upstream's latency holds steady its limit creeps up, and when the latency climbs well above its
+//! usual level the limit is cut (additive increase, multiplicative decrease). A struggling
+//! upstream is then given fewer requests at once, instead of a longer queue of its own.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use parking_lot::Mutex;
+
+/// Limit of an upstream that hasn't responded yet
+const INITIAL_LIMIT: f64 = 20.0;
+
+/// Highest limit when --max-upstream-connections doesn't set one
+const DEFAULT_CEILING: usize = 1000;
+
+/// Weight given to each new response time in the recent latency
+const RECENT_SMOOTHING: f64 = 0.3;
+
+/// Weight given to each new response time in the baseline latency. It is small, so that the
+/// baseline only follows latency that stays up for a good while (a new normal) rather than a
+/// passing spike. Until the upstream has responded enough times for that weight to make sense,
+/// the baseline is the plain average of its response times.
+const BASELINE_SMOOTHING: f64 = 0.02;
+
+/// Responses an upstream has to give before its limit may be cut, so that a baseline taken from
+/// the first one or two (which may be unusually quick or slow) doesn't count as its usual latency
+const WARM_UP_RESPONSES: u32 = 10;
+
+/// The upstream counts as congested once its recent latency is this many times its baseline...
+const TOLERANCE: f64 = 1.5;
+
+/// ...plus this many ms, so that the jitter of an upstream answering in a fraction of a ms doesn't
+/// look like congestion
+const SLACK_MS: f64 = 5.0;
+
+/// What the limit is multiplied by when the upstream is congested
+const BACKOFF: f64 = 0.8;
+
+struct Limit {
+    /// Fractional, so that it can rise by less than a connection at a time
+    limit: f64,
+    /// Number of responses recorded
+    responses: u32,
+    /// Moving averages of the upstream's response time, in milliseconds
+    recent_ms: f64,
+    baseline_ms: f64,
+}
+
+pub struct ConcurrencyLimits {
+    limits: Mutex<HashMap<String, Limit>>,
+    ceiling: f64,
+}
+
+impl ConcurrencyLimits {
+    /// Creates limits that never go above `ceiling` (or `DEFAULT_CEILING` if it is 0).
+    pub fn new(ceiling: usize) -> ConcurrencyLimits {
+        let ceiling = if ceiling == 0 {
+            DEFAULT_CEILING
+        } else {
+            ceiling
+        };
+        ConcurrencyLimits {
+            limits: Mutex::new(HashMap::new()),
+            ceiling: ceiling as f64,
+        }
+    }
+
+    /// Returns how many connections the upstream at `address` may have open at once.
+    pub fn limit(&self, address: &str) -> usize {
+        let limit = match self.limits.lock().get(address) {
+            Some(limit) => limit.limit,
+            None => INITIAL_LIMIT.min(self.ceiling),
+        };
+        limit as usize
+    }
+
+    /// Adjusts the limit of the upstream at `address` after it took `elapsed` to respond. Raises
+    /// it by one connection for every limit's worth of responses while the latency is steady, and
+    /// cuts it when the latency has climbed. Returns whether the limit went up.
+    pub fn record(&self, address: &str, elapsed: Duration) -> bool {
+        let sample = elapsed.as_secs_f64() * 1000.0;
+        let mut limits = self.limits.lock();
+        let limit = limits.entry(address.to_string()).or_insert_with(|| Limit {
+            limit: INITIAL_LIMIT.min(self.ceiling),
+            responses: 0,
+            recent_ms: sample,
+            baseline_ms: sample,
+        });
+        limit.responses += 1;
+        let baseline_weight = (1.0 / limit.responses as f64).max(BASELINE_SMOOTHING);
+        limit.recent_ms += RECENT_SMOOTHING * (sample - limit.recent_ms);
+        limit.baseline_ms += baseline_weight * (sample - limit.baseline_ms);
+        let before = limit.limit as usize;
+        let congested = limit.recent_ms > limit.baseline_ms * TOLERANCE + SLACK_MS;
+        if congested && limit.responses > WARM_UP_RESPONSES {
+            limit.limit = (limit.limit * BACKOFF).max(1.0);
+            if limit.limit as usize != before {
+                tracing::info!(
+                    "Upstream {} slowed down ({:.1}ms, usually {:.1}ms), lowering its limit to {}",
+                    address,
+                    limit.recent_ms,
+                    limit.baseline_ms,
+                    limit.limit as usize
+                );
+            }
+        } else {
+            limit.limit = (limit.limit + 1.0 / limit.limit).min(self.ceiling);
+        }
+        limit.limit as usize > before
+    }
+}
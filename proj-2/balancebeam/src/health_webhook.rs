@@ -0,0 +1,148 @@
+//! Tells a webhook (--health-webhook) when upstreams go from healthy to unhealthy or back, by
+//! POSTing `{"upstream": "<address>", "state": "healthy" or "unhealthy", "timestamp": <Unix time>}`
+//! to it. An upstream's new state is only reported once it has held for the debounce period, so an
+//! upstream flapping faster than that is reported once it settles rather than on every flap.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use parking_lot::Mutex;
+use tokio::net::TcpStream;
+
+use crate::{request, response};
+
+/// Longest to spend delivering an event, so that a slow webhook can't pile up tasks
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Bytes to read the webhook's response in at a time
+const READ_BUFFER_BYTES: usize = 8 * 1024;
+
+#[derive(Default)]
+struct UpstreamHealth {
+    /// State last reported to the webhook, or None if nothing has been reported (in which case
+    /// the upstream is healthy, as every upstream starts out)
+    reported: Option<bool>,
+    /// Number of changes so far, which tells a pending report whether another change came after
+    changes: u64,
+}
+
+pub struct HealthWebhook {
+    url: http::Uri,
+    /// `host:port` to connect to for `url`
+    address: String,
+    debounce: Duration,
+    upstreams: Mutex<HashMap<String, UpstreamHealth>>,
+}
+
+impl HealthWebhook {
+    /// Creates a webhook POSTing to `url`, which must be an `http://` URL, after changes have held
+    /// for `debounce`.
+    pub fn new(url: http::Uri, debounce: Duration) -> Result<HealthWebhook, String> {
+        if url.scheme_str() != Some("http") {
+            return Err(format!("Health webhook {} must be an http:// URL", url));
+        }
+        let authority = url
+            .authority()
+            .ok_or_else(|| format!("Health webhook {} has no host", url))?;
+        let address = match authority.port_u16() {
+            Some(_) => authority.to_string(),
+            None => format!("{}:80", authority.host()),
+        };
+        Ok(HealthWebhook {
+            url,
+            address,
+            debounce,
+            upstreams: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Notes that `upstream` just became healthy (or unhealthy), and reports it to the webhook
+    /// once the debounce period has passed if it is still that way and that isn't what was last
+    /// reported. Returns right away; the report is made by a task of its own.
+    pub fn changed(self: &Arc<Self>, upstream: &str, healthy: bool) {
+        let changes = {
+            let mut upstreams = self.upstreams.lock();
+            let health = upstreams.entry(upstream.to_string()).or_default();
+            health.changes += 1;
+            health.changes
+        };
+        let webhook = self.clone();
+        let upstream = upstream.to_string();
+        tokio::spawn(async move {
+            tokio::time::sleep(webhook.debounce).await;
+            {
+                let mut upstreams = webhook.upstreams.lock();
+                let health = upstreams.get_mut(&upstream).unwrap();
+                // A later change reports for itself
+                if health.changes != changes || health.reported.unwrap_or(true) == healthy {
+                    return;
+                }
+                health.reported = Some(healthy);
+            }
+            match tokio::time::timeout(DELIVERY_TIMEOUT, webhook.deliver(&upstream, healthy)).await
+            {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => tracing::warn!("Failed to deliver health webhook: {}", err),
+                Err(_) => tracing::warn!("Timed out delivering health webhook to {}", webhook.url),
+            }
+        });
+    }
+
+    /// POSTs the event saying `upstream` is now healthy (or unhealthy) to the webhook.
+    async fn deliver(&self, upstream: &str, healthy: bool) -> Result<(), String> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let body = format!(
+            "{{\"upstream\":{},\"state\":\"{}\",\"timestamp\":{}}}",
+            json_string(upstream),
+            if healthy { "healthy" } else { "unhealthy" },
+            timestamp
+        );
+        let path = self.url.path_and_query().map_or("/", |path| path.as_str());
+        let request = http::Request::builder()
+            .method(http::Method::POST)
+            .uri(path)
+            .header("Host", self.url.authority().unwrap().as_str())
+            .header("Content-Type", "application/json")
+            .header("Content-Length", body.len().to_string())
+            .header("Connection", "close")
+            .body(body.into_bytes())
+            .unwrap();
+        let mut stream = TcpStream::connect(&self.address)
+            .await
+            .map_err(|err| format!("couldn't connect to {}: {}", self.address, err))?;
+        request::write_to_stream(&request, &mut stream)
+            .await
+            .map_err(|err| format!("couldn't send to {}: {}", self.url, err))?;
+        let response = response::read_from_stream(&mut stream, request.method(), READ_BUFFER_BYTES)
+            .await
+            .map_err(|err| format!("bad response from {}: {}", self.url, err))?;
+        if !response.status().is_success() {
+            return Err(format!("{} answered {}", self.url, response.status()));
+        }
+        tracing::info!(
+            "Told health webhook that upstream {} is {}",
+            upstream,
+            if healthy { "healthy" } else { "unhealthy" }
+        );
+        Ok(())
+    }
+}
+
+/// Quotes `value` as a JSON string.
+fn json_string(value: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
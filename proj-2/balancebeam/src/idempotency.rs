@@ -0,0 +1,174 @@
+//! Deduplicates retried requests for --idempotency-key-ttl. A request carrying an Idempotency-Key
+//! header is forwarded only once: requests with the same key that arrive while it is in flight
+//! wait for its response, and ones that arrive after it are answered with a copy of that response
+//! until the TTL is up. Keys are only shared by requests from the same client to the same method
+//! and path, and a key reused for a request with a different body is rejected rather than
+//! answered with a response meant for something else.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use tokio::sync::broadcast;
+
+use crate::response;
+
+/// The upstream's response to a request with an idempotency key (None if the request failed
+/// without one).
+pub type KeyedResponse = Option<Arc<http::Response<Vec<u8>>>>;
+
+struct Entry {
+    /// Fingerprint of the first request with the key, which later ones must match
+    fingerprint: u64,
+    state: EntryState,
+}
+
+enum EntryState {
+    /// The first request with the key is on its way to the upstream, and its response will be
+    /// sent here
+    InFlight(broadcast::Sender<KeyedResponse>),
+    /// The upstream answered the first request with the key with this, at this time
+    Answered(Arc<http::Response<Vec<u8>>>, Instant),
+}
+
+/// What to do with a request with an idempotency key (see `IdempotencyKeys::begin`).
+pub enum KeyState<'a> {
+    /// Forward it, since it is the first with its key, and `finish` it with its response
+    First(PendingRequest<'a>),
+    /// Wait for the response to the request with the same key that is in flight
+    InFlight(broadcast::Receiver<KeyedResponse>),
+    /// Answer with this, the response to an earlier request with the same key
+    Answered(http::Response<Vec<u8>>),
+    /// Reject it, since the key was first used for a request with a different fingerprint
+    Mismatch,
+    /// Forward it without remembering its key, since every key remembered is still in flight and
+    /// there is no room for another
+    Untracked,
+}
+
+pub struct IdempotencyKeys {
+    entries: Mutex<HashMap<String, Entry>>,
+    /// How long responses are answered with after they arrive
+    ttl: Duration,
+    max_entries: usize,
+}
+
+impl IdempotencyKeys {
+    pub fn new(ttl: Duration, max_entries: usize) -> IdempotencyKeys {
+        IdempotencyKeys {
+            entries: Mutex::new(HashMap::new()),
+            ttl,
+            max_entries,
+        }
+    }
+
+    /// Looks up `key` for a request with `fingerprint` that has just arrived. If no request with
+    /// it is in flight or was answered within the TTL, this one becomes the request to forward.
+    /// Responses that are past the TTL are forgotten.
+    ///
+    /// When there are already `max_entries` keys, the one answered longest ago makes way.
+    pub fn begin(&self, key: &str, fingerprint: u64) -> KeyState<'_> {
+        let mut entries = self.entries.lock();
+        entries.retain(|_, entry| match entry.state {
+            EntryState::InFlight(_) => true,
+            EntryState::Answered(_, answered_at) => answered_at.elapsed() < self.ttl,
+        });
+        match entries.get(key) {
+            Some(entry) if entry.fingerprint != fingerprint => return KeyState::Mismatch,
+            Some(Entry {
+                state: EntryState::InFlight(sender),
+                ..
+            }) => return KeyState::InFlight(sender.subscribe()),
+            Some(Entry {
+                state: EntryState::Answered(response, _),
+                ..
+            }) => return KeyState::Answered(response::clone_response(response)),
+            None => {}
+        }
+        if entries.len() >= self.max_entries {
+            let oldest = entries
+                .iter()
+                .filter_map(|(key, entry)| match entry.state {
+                    EntryState::InFlight(_) => None,
+                    EntryState::Answered(_, answered_at) => Some((key, answered_at)),
+                })
+                .min_by_key(|(_, answered_at)| *answered_at)
+                .map(|(key, _)| key.clone());
+            match oldest {
+                Some(oldest) => entries.remove(&oldest),
+                None => return KeyState::Untracked,
+            };
+        }
+        entries.insert(
+            key.to_string(),
+            Entry {
+                fingerprint,
+                state: EntryState::InFlight(broadcast::channel(1).0),
+            },
+        );
+        KeyState::First(PendingRequest {
+            keys: self,
+            key: key.to_string(),
+            fingerprint,
+            finished: false,
+        })
+    }
+}
+
+/// Returns the fingerprint of `request` that requests reusing its idempotency key must match: a
+/// hash of its URI and body.
+pub fn fingerprint(request: &http::Request<Vec<u8>>) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    request.uri().to_string().hash(&mut hasher);
+    request.body().hash(&mut hasher);
+    hasher.finish()
+}
+
+/// The request forwarded for an idempotency key. If it is dropped before `finish` is called, the
+/// key is forgotten and the requests waiting on it go ahead on their own.
+pub struct PendingRequest<'a> {
+    keys: &'a IdempotencyKeys,
+    key: String,
+    fingerprint: u64,
+    finished: bool,
+}
+
+impl PendingRequest<'_> {
+    /// Remembers `response` for the key and hands it to the requests waiting on it. If the request
+    /// failed without a response (None), the key is forgotten instead, so that a retry is
+    /// forwarded again.
+    pub fn finish(mut self, response: Option<&http::Response<Vec<u8>>>) {
+        self.finished = true;
+        let response = response.map(|response| Arc::new(response::clone_response(response)));
+        let mut entries = self.keys.entries.lock();
+        let previous = match &response {
+            Some(answer) => entries.insert(
+                self.key.clone(),
+                Entry {
+                    fingerprint: self.fingerprint,
+                    state: EntryState::Answered(answer.clone(), Instant::now()),
+                },
+            ),
+            None => entries.remove(&self.key),
+        };
+        if let Some(Entry {
+            state: EntryState::InFlight(sender),
+            ..
+        }) = previous
+        {
+            // Nobody may be waiting
+            let _ = sender.send(response);
+        }
+    }
+}
+
+impl Drop for PendingRequest<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.keys.entries.lock().remove(&self.key);
+        }
+    }
+}
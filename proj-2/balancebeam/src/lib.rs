@@ -0,0 +1,3178 @@
+//! The core of balancebeam: a proxy that balances HTTP requests across upstream servers. The
+//! balancebeam binary is a thin command-line wrapper around `Proxy`, which can also be embedded in
+//! another Tokio application.
+
+mod admin;
+mod cache;
+mod concurrency_limit;
+mod health_webhook;
+mod idempotency;
+mod rate_limiter;
+mod request;
+mod response;
+pub mod tls;
+mod trace_context;
+pub mod upstream;
+
+use std::{
+    collections::{HashMap, HashSet},
+    net::{SocketAddr, ToSocketAddrs},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::{TcpListener, TcpSocket, TcpStream},
+    sync::{broadcast, watch, Notify, RwLock},
+};
+use tracing::Instrument;
+
+use cache::{Lookup, ResponseCache};
+use concurrency_limit::ConcurrencyLimits;
+use health_webhook::HealthWebhook;
+use idempotency::{IdempotencyKeys, KeyState};
+use rate_limiter::RateLimiter;
+use trace_context::TraceContext;
+
+/// Ways of picking which living upstream a new upstream connection goes to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum BalancingStrategy {
+    /// A random upstream each time (see `Config::rng_seed`)
+    Random,
+    /// Each upstream in turn, in order of address
+    RoundRobin,
+}
+
+/// Which requests a rule in `Config::routes` applies to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RouteMatch {
+    /// Requests using this method
+    Method(http::Method),
+    /// Requests for paths starting with this
+    PathPrefix(String),
+}
+
+impl RouteMatch {
+    /// Returns whether `request` is one this rule applies to.
+    fn matches(&self, request: &http::Request<Vec<u8>>) -> bool {
+        match self {
+            RouteMatch::Method(method) => request.method() == method,
+            RouteMatch::PathPrefix(prefix) => request.uri().path().starts_with(prefix.as_str()),
+        }
+    }
+}
+
+/// What the requests a `RateLimitRule` applies to are counted by.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RateLimitKey {
+    /// Each client IP gets its own count
+    ClientIp,
+    /// Every client shares one count
+    All,
+    /// Each value of this request header gets its own count (e.g. an API key). Requests without
+    /// it are counted by client IP.
+    Header(http::HeaderName),
+}
+
+/// A rule in `Config::rate_limit_rules`, which limits the requests it matches instead of
+/// `Config::max_requests_per_minute`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RateLimitRule {
+    /// Requests the rule applies to: those matching all of these (every request if empty)
+    pub matches: Vec<RouteMatch>,
+    /// Maximum number of matching requests to accept per key per minute (0 = unlimited)
+    pub max_requests_per_minute: usize,
+    pub key: RateLimitKey,
+}
+
+impl RateLimitRule {
+    /// Returns what `request`, from `client_ip`, is counted by under this rule.
+    fn key(&self, request: &http::Request<Vec<u8>>, client_ip: &str) -> String {
+        match &self.key {
+            RateLimitKey::ClientIp => client_ip.to_string(),
+            RateLimitKey::All => String::new(),
+            RateLimitKey::Header(name) => match request.headers().get(name) {
+                Some(value) => format!("{}={}", name, String::from_utf8_lossy(value.as_bytes())),
+                None => client_ip.to_string(),
+            },
+        }
+    }
+}
+
+/// Everything a `Proxy` needs to know to start. `Config::default()` matches balancebeam's
+/// command-line defaults, with no upstreams.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Addresses to listen on (a port of 0 picks a free one; see `Proxy::local_addrs`)
+    pub bind: Vec<String>,
+    /// Number of connections that may wait to be accepted on each listening socket (the system
+    /// may cap this, e.g. at net.core.somaxconn on Linux)
+    pub listen_backlog: u32,
+    /// Whether to set TCP_NODELAY on client and upstream connections, so that small writes (such
+    /// as a response's headers) go out straight away instead of being held back to be batched
+    pub tcp_nodelay: bool,
+    /// Upstream servers to forward requests to, as `host:port`, optionally with a scheme, or as
+    /// `unix:<path>` for servers listening on a Unix socket
+    pub upstream: Vec<String>,
+    /// Named groups of upstream servers (given like `upstream`) that particular requests are sent
+    /// to instead (see `virtual_hosts` and `routes`)
+    pub upstream_groups: HashMap<String, Vec<String>>,
+    /// Which group in `upstream_groups` requests for each host go to, keyed by host name without a
+    /// port. Requests for other hosts go to `upstream`, or get a 404 if it is empty.
+    pub virtual_hosts: HashMap<String, String>,
+    /// Rules sending requests to groups in `upstream_groups` by method or path, checked in order
+    /// before `virtual_hosts`. The first rule a request matches lists the groups it may go to in
+    /// order of preference: it goes to the first with a living upstream, or to the last if none
+    /// has one. Requests matching no rule are sent as if there were none.
+    pub routes: Vec<(RouteMatch, Vec<String>)>,
+    /// Seconds between active health checks
+    pub active_health_check_interval: usize,
+    /// Path to request for active health checks
+    pub active_health_check_path: String,
+    /// Longest to wait between active health checks of an upstream that keeps failing them, in
+    /// seconds. The wait doubles after each failure (with some randomness), starting from
+    /// `active_health_check_interval`. 0 checks failed upstreams on every interval.
+    pub health_check_max_backoff: usize,
+    /// `http://` URL to POST a JSON event to whenever an upstream becomes unhealthy or healthy
+    /// again (see `health_webhook`)
+    pub health_webhook: Option<http::Uri>,
+    /// How long an upstream has to stay healthy or unhealthy before the webhook is told, in
+    /// milliseconds, so that a flapping upstream isn't reported on every flap
+    pub health_webhook_debounce_ms: u64,
+    /// Maximum number of requests to accept per IP per minute (0 = unlimited)
+    pub max_requests_per_minute: usize,
+    /// Rules limiting particular requests, checked in order. The first rule a request matches
+    /// decides its limit, in place of `max_requests_per_minute`; requests matching no rule are
+    /// limited by `max_requests_per_minute` as usual.
+    pub rate_limit_rules: Vec<RateLimitRule>,
+    /// Status to reply to requests over `max_requests_per_minute` with
+    pub rate_limit_status: http::StatusCode,
+    /// Body to reply to requests over `max_requests_per_minute` with (a short plain text
+    /// description of the status if None)
+    pub rate_limit_body: Option<Vec<u8>>,
+    /// Content-Type of `rate_limit_body`
+    pub rate_limit_content_type: http::HeaderValue,
+    /// How to pick the upstream for each new upstream connection
+    pub balancing_strategy: BalancingStrategy,
+    /// Seed for the random number generator used to pick upstreams (random if None)
+    pub rng_seed: Option<u64>,
+    /// Whether to close client connections bound to an upstream once it is marked unhealthy
+    pub drain_on_unhealthy: bool,
+    /// Take an upstream out of rotation after this many consecutive 5xx responses (0 = never)
+    pub consecutive_errors: usize,
+    /// Whether to connect over TLS to upstreams given without a scheme
+    pub upstream_tls: bool,
+    /// Whether to skip verifying the certificates of TLS upstreams
+    pub upstream_tls_insecure: bool,
+    /// HTML to serve (with a 503) when every upstream is down (a plain 502 if None)
+    pub maintenance_page: Option<Vec<u8>>,
+    /// PROXY protocol version to announce client addresses to upstreams with (None to not send it)
+    pub send_proxy_protocol: Option<upstream::ProxyProtocol>,
+    /// Seconds a client may take to send each request before it is disconnected (0 = no limit)
+    pub client_idle_timeout: u64,
+    /// Seconds a client may go without sending anything partway through a request before it is
+    /// disconnected (0 = no limit). Unlike `client_idle_timeout`, this applies to each read, so it
+    /// catches clients that have stalled without cutting off ones that are just slow.
+    pub client_read_timeout: u64,
+    /// Most bytes the request line and headers of a request may take up. Requests with more get
+    /// a 431.
+    pub max_header_bytes: usize,
+    /// Seconds a client may leave its connection idle between requests before it is closed (0 = no
+    /// limit). The time taken to send each request is still limited by `client_idle_timeout`.
+    pub keepalive_timeout: u64,
+    /// Close client connections once this many bytes have gone back and forth over them (0 = no
+    /// limit). The response that reaches the limit is still sent in full, but a request that
+    /// reaches it is cut off.
+    pub max_bytes_per_connection: usize,
+    /// Reply 503 to new requests while more than this many are in flight (0 = no limit)
+    pub shed_at_inflight: usize,
+    /// Reply 503 to new requests while upstreams average more than this many ms (0 = no limit)
+    pub shed_at_latency_ms: u64,
+    /// Maximum number of connections to open to each upstream at once (0 = no limit)
+    pub max_upstream_connections: usize,
+    /// Whether to limit the connections to each upstream by how it is coping: the limit starts at
+    /// 20, creeps up while the upstream's latency is steady and is cut when the latency climbs,
+    /// staying between 1 and `max_upstream_connections` (or 1000). Clients over the limit queue
+    /// as with `max_upstream_connections`. Connections already open when the limit is cut are
+    /// left open.
+    pub adaptive_concurrency: bool,
+    /// Seconds a client may wait for a connection slot when every upstream is at its connection
+    /// limit (see `max_upstream_connections`) before getting a 503 (0 = don't wait)
+    pub queue_timeout: u64,
+    /// Maximum number of clients that may wait for a connection slot at once
+    pub max_queued: usize,
+    /// Idle connections to open to each upstream at startup (and when it comes back after failing),
+    /// for clients to use instead of connecting themselves. They count towards
+    /// `max_upstream_connections`. Can't be used with `send_proxy_protocol`.
+    pub warm_connections: usize,
+    /// Replaces the status of upstream responses with the given status with another one
+    pub status_rewrites: HashMap<http::StatusCode, http::StatusCode>,
+    /// Replaces the body of upstream responses with the given status with this HTML
+    pub error_pages: HashMap<http::StatusCode, Vec<u8>>,
+    /// Replace upstream responses with these media types (as returned by `media_type`) with a 403
+    pub blocked_content_types: Vec<String>,
+    /// Only forward requests using these methods (any if empty)
+    pub allowed_methods: Vec<http::Method>,
+    /// Only forward requests for paths starting with one of these (any if empty)
+    pub allowed_path_prefixes: Vec<String>,
+    /// Host header to send to upstreams in place of the client's (the client's is kept if None)
+    pub upstream_host_header: Option<http::HeaderValue>,
+    /// Host headers to send to particular upstreams (keyed by their address as given in
+    /// `upstream`), overriding `upstream_host_header`
+    pub upstream_host_headers: HashMap<String, http::HeaderValue>,
+    /// Path prefix to remove from requests before forwarding them, from paths that start with it
+    /// (as whole segments: `/api` is removed from `/api/foo` but not from `/apiary`). Other paths
+    /// are forwarded as they are.
+    pub strip_path_prefix: Option<String>,
+    /// Path prefix to put in front of every request's path before forwarding it (after
+    /// `strip_path_prefix` is removed)
+    pub add_path_prefix: Option<String>,
+    /// Answer every request with a canned 200 whose body is this many bytes, instead of forwarding
+    /// it to an upstream (None to proxy as usual). This is for measuring the proxy's own overhead.
+    pub loopback_upstream: Option<usize>,
+    /// Shut down gracefully after receiving this many requests (0 = never)
+    pub shutdown_after_requests: usize,
+    /// Shut down gracefully after serving for this many seconds (0 = never)
+    pub shutdown_after_seconds: u64,
+    /// Path the proxy answers itself with a 200 for as long as it is running (None to forward it)
+    pub liveness_path: Option<String>,
+    /// Path the proxy answers itself with a 200 while any upstream is alive, and a 503 otherwise
+    /// (None to forward it)
+    pub readiness_path: Option<String>,
+    /// PEM certificate chain to serve clients TLS with (plain HTTP if None; needs `tls_key`)
+    pub tls_cert: Option<Vec<u8>>,
+    /// PEM private key for `tls_cert`
+    pub tls_key: Option<Vec<u8>>,
+    /// PEM certificates of the CAs client certificates must be signed by (client certificates
+    /// aren't asked for if None; needs `tls_cert`)
+    pub client_ca: Option<Vec<u8>>,
+    /// Header to tell upstreams the identity in the client's certificate with, when `client_ca` is
+    /// set. Clients can't set it themselves: any such header they send is removed.
+    pub client_cert_header: String,
+    /// Address to serve the admin API on (not served if None). Anyone who can reach it can take
+    /// upstreams out of rotation, so it shouldn't be reachable by clients.
+    pub admin_bind: Option<String>,
+    /// Size of the buffer request and response bodies are read into, in bytes. Larger buffers
+    /// take fewer reads to transfer large bodies.
+    pub io_buffer_bytes: usize,
+    /// Whether to send identical GET and HEAD requests that arrive while one is in flight to the
+    /// upstream only once, answering them all with its response. Requests carrying credentials
+    /// or asking not to be served from a cache are always sent on their own.
+    pub coalesce_requests: bool,
+    /// Number of upstream responses to cache, answering identical requests with them while they
+    /// are fresh and revalidating them with the upstream once they are stale (0 = no caching).
+    /// Responses are only shared as with `coalesce_requests`.
+    pub response_cache_entries: usize,
+    /// Seconds to remember the response to a request with an Idempotency-Key header for (0 = the
+    /// header isn't looked at). A request with the same key that arrives in that time, or while
+    /// the first is still in flight, is answered with that response instead of being forwarded.
+    /// Keys are per client, method and path, and reusing one with a different body gets a 422.
+    pub idempotency_key_ttl: u64,
+    /// Most idempotency keys to remember at once. Once there are this many, the one whose response
+    /// arrived longest ago is forgotten to make room.
+    pub idempotency_max_keys: usize,
+    /// Whether to decompress gzip and deflate upstream responses for clients whose
+    /// Accept-Encoding doesn't include the upstream's Content-Encoding
+    pub decompress_responses: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind: vec!["0.0.0.0:1100".to_string()],
+            listen_backlog: 1024,
+            tcp_nodelay: false,
+            upstream: Vec::new(),
+            upstream_groups: HashMap::new(),
+            virtual_hosts: HashMap::new(),
+            routes: Vec::new(),
+            active_health_check_interval: 10,
+            active_health_check_path: "/".to_string(),
+            health_check_max_backoff: 0,
+            health_webhook: None,
+            health_webhook_debounce_ms: 1000,
+            max_requests_per_minute: 0,
+            rate_limit_rules: Vec::new(),
+            rate_limit_status: http::StatusCode::TOO_MANY_REQUESTS,
+            rate_limit_body: None,
+            rate_limit_content_type: http::HeaderValue::from_static("text/plain; charset=utf-8"),
+            balancing_strategy: BalancingStrategy::Random,
+            rng_seed: None,
+            drain_on_unhealthy: false,
+            consecutive_errors: 0,
+            upstream_tls: false,
+            upstream_tls_insecure: false,
+            maintenance_page: None,
+            send_proxy_protocol: None,
+            client_idle_timeout: 0,
+            client_read_timeout: 0,
+            max_header_bytes: 8000,
+            keepalive_timeout: 0,
+            max_bytes_per_connection: 0,
+            shed_at_inflight: 0,
+            shed_at_latency_ms: 0,
+            max_upstream_connections: 0,
+            adaptive_concurrency: false,
+            queue_timeout: 0,
+            max_queued: 100,
+            warm_connections: 0,
+            status_rewrites: HashMap::new(),
+            error_pages: HashMap::new(),
+            blocked_content_types: Vec::new(),
+            allowed_methods: Vec::new(),
+            allowed_path_prefixes: Vec::new(),
+            upstream_host_header: None,
+            upstream_host_headers: HashMap::new(),
+            strip_path_prefix: None,
+            add_path_prefix: None,
+            loopback_upstream: None,
+            shutdown_after_requests: 0,
+            shutdown_after_seconds: 0,
+            liveness_path: None,
+            readiness_path: None,
+            tls_cert: None,
+            tls_key: None,
+            client_ca: None,
+            client_cert_header: "x-client-cert-cn".to_string(),
+            admin_bind: None,
+            io_buffer_bytes: 16 * 1024,
+            coalesce_requests: false,
+            response_cache_entries: 0,
+            idempotency_key_ttl: 0,
+            idempotency_max_keys: 10_000,
+            decompress_responses: false,
+        }
+    }
+}
+
+/// A load balancer that has bound its listening sockets and is ready to serve.
+pub struct Proxy {
+    state: ProxyState,
+    listeners: Vec<std::net::TcpListener>,
+    admin_listener: Option<std::net::TcpListener>,
+}
+
+impl Proxy {
+    /// Sets up a proxy for `config`, binding its listening sockets right away so that binding
+    /// errors (and the ports picked for port 0) are known before serving. Nothing is served until
+    /// `run` is polled. Must be called from within a Tokio runtime.
+    pub fn new(config: Config) -> Result<Proxy, std::io::Error> {
+        let state = ProxyState::new(&config)?;
+        let mut listeners = Vec::new();
+        for bind in &config.bind {
+            let listener = bind_listener(bind, config.listen_backlog).map_err(|err| {
+                std::io::Error::new(err.kind(), format!("Could not bind to {}: {}", bind, err))
+            })?;
+            tracing::info!("Listening for requests on {}", listener.local_addr()?);
+            listeners.push(listener);
+        }
+        let admin_listener = match &config.admin_bind {
+            Some(bind) => {
+                let listener = bind_listener(bind, config.listen_backlog).map_err(|err| {
+                    std::io::Error::new(
+                        err.kind(),
+                        format!("Could not bind the admin API to {}: {}", bind, err),
+                    )
+                })?;
+                tracing::info!("Serving the admin API on {}", listener.local_addr()?);
+                Some(listener)
+            }
+            None => None,
+        };
+        Ok(Proxy {
+            state,
+            listeners,
+            admin_listener,
+        })
+    }
+
+    /// Returns the addresses the proxy is listening on, in the order they were given in the config.
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.listeners
+            .iter()
+            .filter_map(|listener| listener.local_addr().ok())
+            .collect()
+    }
+
+    /// Returns the address the admin API is served on, if it is.
+    pub fn admin_addr(&self) -> Option<SocketAddr> {
+        self.admin_listener
+            .as_ref()
+            .and_then(|listener| listener.local_addr().ok())
+    }
+
+    /// Returns the state shared by everything the proxy runs.
+    pub fn state(&self) -> &ProxyState {
+        &self.state
+    }
+
+    /// Serves connections on every listener, along with the health check and rate limit tasks.
+    /// Finishes once the proxy has shut down gracefully (see `ProxyState::shutdown`) and every
+    /// client connection has been closed; drop the future (or abort its task) to stop serving
+    /// immediately instead. Must be polled from within a Tokio runtime.
+    pub async fn run(self) {
+        let state = self.state;
+
+        // do active health check
+        let stat = state.clone();
+        let health_check = tokio::spawn(async move {
+            active_health_check(&stat).await;
+        });
+
+        // do rate limiting check
+        let stat = state.clone();
+        let rate_limit = tokio::spawn(async move {
+            reset_rate_limits(&stat).await;
+        });
+
+        // --shutdown-after-seconds
+        let stat = state.clone();
+        let shutdown_timer = tokio::spawn(async move {
+            if stat.shutdown_after_seconds > 0 {
+                tokio::time::sleep(Duration::from_secs(stat.shutdown_after_seconds)).await;
+                tracing::info!("Served for {}s, shutting down", stat.shutdown_after_seconds);
+                stat.shutdown();
+            }
+        });
+
+        // --warm-connections
+        let stat = state.clone();
+        let warm_up = tokio::spawn(async move {
+            if stat.warm_connections > 0 {
+                for address in &stat.upstream_addresses {
+                    warm_upstream(&stat, address).await;
+                }
+            }
+        });
+
+        // --admin-bind
+        let stat = state.clone();
+        let admin_listener = self.admin_listener;
+        let admin = tokio::spawn(async move {
+            if let Some(listener) = admin_listener {
+                let listener = TcpListener::from_std(listener)
+                    .expect("Error registering listener with the Tokio runtime");
+                admin::serve_admin(listener, stat).await;
+            }
+        });
+
+        // Each listener gets its own accept loop, all sharing the same state
+        let accept_loops: Vec<_> = self
+            .listeners
+            .into_iter()
+            .map(|listener| {
+                let listener = TcpListener::from_std(listener)
+                    .expect("Error registering listener with the Tokio runtime");
+                let state = state.clone();
+                tokio::spawn(async move {
+                    accept_connections(listener, state).await;
+                })
+            })
+            .collect();
+
+        // Stop the background tasks along with the accept loops if this future is dropped
+        let _tasks = AbortOnDrop(
+            accept_loops
+                .iter()
+                .map(|task| task.abort_handle())
+                .chain([
+                    health_check.abort_handle(),
+                    rate_limit.abort_handle(),
+                    shutdown_timer.abort_handle(),
+                    warm_up.abort_handle(),
+                    admin.abort_handle(),
+                ])
+                .collect(),
+        );
+        for accept_loop in accept_loops {
+            accept_loop.await.expect("Accept loop panicked");
+        }
+        // Nobody is left to use them
+        state.parked_connections.lock().clear();
+        tracing::info!("All connections closed, shutdown complete");
+    }
+}
+
+/// Opens a (non-blocking) listening socket on `bind`, trying each address it resolves to in turn
+/// like `std::net::TcpListener::bind`, with room for `backlog` connections waiting to be accepted.
+/// SO_REUSEADDR is set, so that a restarted proxy can bind to its port straight away even while
+/// connections from the previous one linger in TIME_WAIT. Must be called from within a Tokio
+/// runtime.
+fn bind_listener(bind: &str, backlog: u32) -> std::io::Result<std::net::TcpListener> {
+    let listen = |addr: SocketAddr| {
+        let socket = if addr.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        socket.set_reuseaddr(true)?;
+        socket.bind(addr)?;
+        socket.listen(backlog)?.into_std()
+    };
+    let mut last_error = None;
+    for addr in bind.to_socket_addrs()? {
+        match listen(addr) {
+            Ok(listener) => return Ok(listener),
+            Err(err) => last_error = Some(err),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "address resolved to nothing",
+        )
+    }))
+}
+
+/// Aborts the given tasks when dropped.
+struct AbortOnDrop(Vec<tokio::task::AbortHandle>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        for task in &self.0 {
+            task.abort();
+        }
+    }
+}
+
+/// How long to leave an upstream that keeps failing active health checks before checking it again
+/// (see `Config::health_check_max_backoff`).
+struct ProbeBackoff {
+    /// Health checks failed in a row
+    failures: u32,
+    next_probe: Instant,
+}
+
+/// Requests in flight to an upstream, and how many more it may take if it is being drained (see
+/// `ProxyState::drain_upstream`).
+#[derive(Default)]
+struct UpstreamRequests {
+    in_flight: usize,
+    /// None unless the upstream is being drained
+    drain_allowance: Option<usize>,
+}
+
+impl UpstreamRequests {
+    fn drained(&self) -> bool {
+        self.drain_allowance == Some(0) && self.in_flight == 0
+    }
+}
+
+/// Idle upstream connections waiting to be used, keyed by upstream address.
+type ParkedConnections = HashMap<String, Vec<Box<dyn upstream::Stream>>>;
+
+/// Contains information about the state of balancebeam (e.g. what servers we are currently proxying
+/// to, what servers have failed, rate limiting counts, etc.)
+///
+/// It is cheap to clone: every clone shares the same counters and upstream lists.
+#[derive(Clone)]
+pub struct ProxyState {
+    /// How frequently we check whether upstream servers are alive (Milestone 4)
+    #[allow(dead_code)]
+    active_health_check_interval: usize,
+    /// Where we should send requests when doing active health checks (Milestone 4)
+    #[allow(dead_code)]
+    active_health_check_path: String,
+    /// Longest to wait between active health checks of a failing upstream, in seconds (0 = check
+    /// on every interval)
+    health_check_max_backoff: usize,
+    /// When to next check each upstream that failed its last active health check
+    probe_backoffs: Arc<Mutex<HashMap<String, ProbeBackoff>>>,
+    /// Maximum number of requests an individual IP can make in a minute (Milestone 5)
+    #[allow(dead_code)]
+    max_requests_per_minute: usize,
+    /// Rules limiting particular requests in place of `max_requests_per_minute`, in the order
+    /// they are checked
+    rate_limit_rules: Arc<Vec<RateLimitRule>>,
+    /// Status to reply to rate limited requests with
+    rate_limit_status: http::StatusCode,
+    /// Body and Content-Type to reply to rate limited requests with (a canned error if None)
+    rate_limit_body: Option<Arc<(Vec<u8>, http::HeaderValue)>>,
+    /// Whether to close client connections whose upstream has been marked unhealthy
+    drain_on_unhealthy: bool,
+    /// Number of consecutive 5xx responses after which an upstream is taken out of rotation
+    consecutive_errors: usize,
+    /// Whether to connect over TLS to upstreams given without a scheme
+    upstream_tls: bool,
+    /// Used to connect to TLS upstreams (None if there aren't any)
+    tls_connector: Option<tokio_rustls::TlsConnector>,
+    /// Contents of the page to serve when every upstream is down (a plain 502 if None)
+    maintenance_page: Option<Arc<Vec<u8>>>,
+    /// PROXY protocol version to announce client addresses to upstreams with (None to not send it)
+    send_proxy_protocol: Option<upstream::ProxyProtocol>,
+    /// How long a client may take to send each request before we hang up on it (0 = forever)
+    client_idle_timeout: u64,
+    /// How long a client may go without sending anything while sending a request (None = forever)
+    client_read_timeout: Option<Duration>,
+    /// Most bytes a request's request line and headers may take up
+    max_header_bytes: usize,
+    /// How long a client may stay idle between requests before we hang up on it (0 = forever)
+    keepalive_timeout: u64,
+    /// Bytes a client connection may transfer before it is closed (0 = no limit)
+    max_bytes_per_connection: usize,
+    /// Total bytes read from clients over connections that have closed
+    client_bytes_read: Arc<AtomicU64>,
+    /// Total bytes written to clients over connections that have closed
+    client_bytes_written: Arc<AtomicU64>,
+    /// Load is shed while the number of requests in flight and the average upstream latency are
+    /// both above these thresholds. A threshold of 0 is always considered exceeded, unless both
+    /// are 0, in which case load is never shed.
+    shed_at_inflight: usize,
+    shed_at_latency_ms: u64,
+    /// Maximum number of connections open to each upstream at once (0 = no limit)
+    max_upstream_connections: usize,
+    /// Limits on the connections open to each upstream that adapt to its latency, overriding
+    /// `max_upstream_connections` (None unless --adaptive-concurrency is set)
+    concurrency_limits: Option<Arc<ConcurrencyLimits>>,
+    /// How long a client may wait for a free connection slot, in seconds (0 = not at all)
+    queue_timeout: u64,
+    /// Maximum number of clients waiting for a connection slot
+    max_queued: usize,
+    /// Bytes to read request and response bodies in at a time
+    io_buffer_bytes: usize,
+    /// Whether identical requests in flight at the same time share one upstream response
+    coalesce_requests: bool,
+    /// Requests on their way to an upstream that identical ones are waiting on, keyed by
+    /// `coalescing_key`. The response is sent to the waiting requests when it arrives (or None if
+    /// it can't be shared), and dropping the sender tells them to go ahead on their own.
+    coalesced_requests: Arc<Mutex<HashMap<String, broadcast::Sender<SharedResponse>>>>,
+    /// Upstream responses cached for --response-cache-entries, keyed by `sharing_key` (None if
+    /// responses aren't cached)
+    response_cache: Option<Arc<ResponseCache>>,
+    /// Responses to requests with an Idempotency-Key, and the requests in flight that others with
+    /// the same key are waiting on (None unless --idempotency-key-ttl is set)
+    idempotency_keys: Option<Arc<IdempotencyKeys>>,
+    /// Number of connections open to each upstream
+    upstream_connections: Arc<Mutex<HashMap<String, usize>>>,
+    /// Woken whenever an upstream connection is closed, freeing up its slot
+    upstream_slot_freed: Arc<Notify>,
+    /// Number of clients currently waiting for a connection slot
+    queued: Arc<AtomicUsize>,
+    /// Number of idle connections to open to each upstream ahead of time
+    warm_connections: usize,
+    /// Idle connections opened ahead of time for clients to use, keyed by upstream
+    parked_connections: Arc<Mutex<ParkedConnections>>,
+    /// Requests in flight to each upstream, and the drain state of those being drained
+    upstream_requests: Arc<Mutex<HashMap<String, UpstreamRequests>>>,
+    /// Statuses to replace in upstream responses, keyed by the status the upstream sent
+    status_rewrites: Arc<HashMap<http::StatusCode, http::StatusCode>>,
+    /// Pages to replace upstream response bodies with, keyed by the status the upstream sent
+    error_pages: Arc<HashMap<http::StatusCode, Vec<u8>>>,
+    /// Media types of upstream responses that are replaced with a 403
+    blocked_content_types: Arc<Vec<String>>,
+    /// Whether compressed upstream responses are decompressed for clients that can't accept them
+    decompress_responses: bool,
+    /// Whether to set TCP_NODELAY on client and upstream connections
+    tcp_nodelay: bool,
+    /// Methods requests may use (any if empty)
+    allowed_methods: Arc<Vec<http::Method>>,
+    /// Prefixes request paths must start with (any path if empty)
+    allowed_path_prefixes: Arc<Vec<String>>,
+    /// Host header to send to upstreams without one of their own below (the client's if None)
+    upstream_host_header: Option<http::HeaderValue>,
+    /// Host headers to send to particular upstreams
+    upstream_host_headers: Arc<HashMap<String, http::HeaderValue>>,
+    /// Path prefix to remove from requests before forwarding them
+    strip_path_prefix: Option<String>,
+    /// Path prefix to add to requests before forwarding them
+    add_path_prefix: Option<String>,
+    /// Body of the canned response to answer requests with instead of proxying them (None to proxy
+    /// as usual)
+    loopback_body: Option<Arc<Vec<u8>>>,
+    /// Number of requests after which to shut down (0 = never)
+    shutdown_after_requests: usize,
+    /// Seconds after which to shut down (0 = never)
+    shutdown_after_seconds: u64,
+    /// Paths of the liveness and readiness probes the proxy answers itself (None if not served)
+    liveness_path: Option<String>,
+    readiness_path: Option<String>,
+    /// Used to terminate TLS from clients (None to serve plain HTTP)
+    tls_acceptor: Option<tokio_rustls::TlsAcceptor>,
+    /// Header carrying the identity from the client's certificate to upstreams (None unless client
+    /// certificates are required)
+    client_cert_header: Option<http::HeaderName>,
+    /// Number of requests received from clients so far
+    requests_received: Arc<AtomicUsize>,
+    /// ID to give the next request forwarded or answered, to tell requests apart in traces
+    next_request_id: Arc<AtomicUsize>,
+    /// Set to true once the proxy starts shutting down
+    shutting_down: Arc<watch::Sender<bool>>,
+    /// Number of requests currently being proxied
+    in_flight: Arc<AtomicUsize>,
+    /// Moving average of how long upstreams take to respond, in milliseconds (None until the first
+    /// response)
+    upstream_latency_ms: Arc<Mutex<Option<f64>>>,
+    /// Addresses of servers that we are proxying to, in every group
+    upstream_addresses: Vec<String>,
+    /// Upstreams for requests that aren't for one of the virtual hosts
+    default_upstreams: Arc<Vec<String>>,
+    /// Upstreams for requests for each virtual host, keyed by lowercased host name
+    virtual_hosts: Arc<HashMap<String, Vec<String>>>,
+    /// Rules picking upstreams by method or path, each with the upstreams of its groups in order of
+    /// preference
+    routes: Arc<Vec<(RouteMatch, Vec<Vec<String>>)>>,
+    /// living addresses record, read-write-lock has better performance, maybe
+    living_upstream_addresses: Arc<RwLock<HashSet<String>>>,
+    /// rate limiting counter
+    rate_limiter: Arc<RateLimiter>,
+    /// How to pick upstreams
+    balancing_strategy: BalancingStrategy,
+    /// random number generator used to pick upstreams, shared so that a fixed seed gives a
+    /// reproducible selection sequence
+    rng: Arc<Mutex<StdRng>>,
+    /// Number of upstreams picked so far, which says whose turn it is with round-robin balancing
+    next_upstream: Arc<AtomicUsize>,
+    /// consecutive 5xx responses seen from each upstream while proxying (passive health checks)
+    error_streaks: Arc<Mutex<HashMap<String, usize>>>,
+    /// Told whenever an upstream becomes unhealthy or healthy again (None without --health-webhook)
+    health_webhook: Option<Arc<HealthWebhook>>,
+}
+
+impl ProxyState {
+    /// Creates the state for a proxy with the given configuration, setting up TLS if any upstream
+    /// needs it.
+    pub fn new(config: &Config) -> Result<ProxyState, std::io::Error> {
+        let mut virtual_hosts = HashMap::new();
+        for (host, group) in &config.virtual_hosts {
+            let upstreams = config.upstream_groups.get(group).ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "Virtual host {} uses unknown upstream group {}",
+                        host, group
+                    ),
+                )
+            })?;
+            virtual_hosts.insert(host.to_lowercase(), upstreams.clone());
+        }
+        let health_webhook = match &config.health_webhook {
+            Some(url) => Some(Arc::new(
+                HealthWebhook::new(
+                    url.clone(),
+                    Duration::from_millis(config.health_webhook_debounce_ms),
+                )
+                .map_err(|message| {
+                    std::io::Error::new(std::io::ErrorKind::InvalidInput, message)
+                })?,
+            )),
+            None => None,
+        };
+        let mut routes = Vec::new();
+        for (matcher, groups) in &config.routes {
+            let groups = groups
+                .iter()
+                .map(|group| {
+                    config.upstream_groups.get(group).cloned().ok_or_else(|| {
+                        std::io::Error::new(
+                            std::io::ErrorKind::InvalidInput,
+                            format!(
+                                "Route for {:?} uses unknown upstream group {}",
+                                matcher, group
+                            ),
+                        )
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            if groups.is_empty() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Route for {:?} has no upstream groups", matcher),
+                ));
+            }
+            routes.push((matcher.clone(), groups));
+        }
+        // Health is tracked per address, so an upstream in several groups is only checked once
+        let mut upstream_addresses = config.upstream.clone();
+        for address in config.upstream_groups.values().flatten() {
+            if !upstream_addresses.contains(address) {
+                upstream_addresses.push(address.clone());
+            }
+        }
+
+        let tls_connector = if upstream_addresses
+            .iter()
+            .any(|address| upstream::parse_address(address, config.upstream_tls).1)
+        {
+            let connector =
+                upstream::make_tls_connector(config.upstream_tls_insecure).map_err(|err| {
+                    std::io::Error::new(
+                        err.kind(),
+                        format!("Could not set up TLS for upstream connections: {}", err),
+                    )
+                })?;
+            Some(connector)
+        } else {
+            None
+        };
+
+        let tls_acceptor = match (&config.tls_cert, &config.tls_key) {
+            (Some(cert), Some(key)) => Some(
+                tls::make_tls_acceptor(cert, key, config.client_ca.as_deref()).map_err(|err| {
+                    std::io::Error::new(err.kind(), format!("Could not set up TLS: {}", err))
+                })?,
+            ),
+            (None, None) if config.client_ca.is_none() => None,
+            _ => return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Serving TLS needs both a certificate and a key (and so do client certificates)",
+            )),
+        };
+        for prefix in [&config.strip_path_prefix, &config.add_path_prefix]
+            .into_iter()
+            .flatten()
+        {
+            let path = prefix.parse::<http::uri::PathAndQuery>().ok();
+            let valid = prefix.starts_with('/') && path.is_some_and(|path| path.query().is_none());
+            if !valid {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Path prefix {} must be a path starting with /", prefix),
+                ));
+            }
+        }
+        if config.warm_connections > 0 && config.send_proxy_protocol.is_some() {
+            // The PROXY protocol header is sent as soon as we connect, before a client is known
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Warm connections can't be used with the PROXY protocol",
+            ));
+        }
+        let client_cert_header = match &config.client_ca {
+            Some(_) => Some(
+                http::HeaderName::from_bytes(config.client_cert_header.as_bytes()).map_err(
+                    |_| {
+                        std::io::Error::new(
+                            std::io::ErrorKind::InvalidInput,
+                            format!("{} is not a valid header name", config.client_cert_header),
+                        )
+                    },
+                )?,
+            ),
+            None => None,
+        };
+
+        Ok(ProxyState {
+            living_upstream_addresses: Arc::new(RwLock::new(
+                upstream_addresses.iter().cloned().collect(),
+            )),
+            upstream_addresses,
+            default_upstreams: Arc::new(config.upstream.clone()),
+            virtual_hosts: Arc::new(virtual_hosts),
+            routes: Arc::new(routes),
+            active_health_check_interval: config.active_health_check_interval,
+            active_health_check_path: config.active_health_check_path.clone(),
+            health_check_max_backoff: config.health_check_max_backoff,
+            probe_backoffs: Arc::new(Mutex::new(HashMap::new())),
+            max_requests_per_minute: config.max_requests_per_minute,
+            rate_limit_rules: Arc::new(config.rate_limit_rules.clone()),
+            rate_limit_status: config.rate_limit_status,
+            rate_limit_body: config
+                .rate_limit_body
+                .clone()
+                .map(|body| Arc::new((body, config.rate_limit_content_type.clone()))),
+            drain_on_unhealthy: config.drain_on_unhealthy,
+            consecutive_errors: config.consecutive_errors,
+            upstream_tls: config.upstream_tls,
+            tls_connector,
+            maintenance_page: config.maintenance_page.clone().map(Arc::new),
+            send_proxy_protocol: config.send_proxy_protocol,
+            client_idle_timeout: config.client_idle_timeout,
+            client_read_timeout: (config.client_read_timeout > 0)
+                .then(|| Duration::from_secs(config.client_read_timeout)),
+            max_header_bytes: config.max_header_bytes,
+            keepalive_timeout: config.keepalive_timeout,
+            max_bytes_per_connection: config.max_bytes_per_connection,
+            client_bytes_read: Arc::new(AtomicU64::new(0)),
+            client_bytes_written: Arc::new(AtomicU64::new(0)),
+            shed_at_inflight: config.shed_at_inflight,
+            shed_at_latency_ms: config.shed_at_latency_ms,
+            max_upstream_connections: config.max_upstream_connections,
+            concurrency_limits: config
+                .adaptive_concurrency
+                .then(|| Arc::new(ConcurrencyLimits::new(config.max_upstream_connections))),
+            queue_timeout: config.queue_timeout,
+            max_queued: config.max_queued,
+            io_buffer_bytes: config.io_buffer_bytes,
+            coalesce_requests: config.coalesce_requests,
+            coalesced_requests: Arc::new(Mutex::new(HashMap::new())),
+            response_cache: (config.response_cache_entries > 0)
+                .then(|| Arc::new(ResponseCache::new(config.response_cache_entries))),
+            idempotency_keys: (config.idempotency_key_ttl > 0).then(|| {
+                Arc::new(IdempotencyKeys::new(
+                    Duration::from_secs(config.idempotency_key_ttl),
+                    config.idempotency_max_keys,
+                ))
+            }),
+            upstream_connections: Arc::new(Mutex::new(HashMap::new())),
+            upstream_slot_freed: Arc::new(Notify::new()),
+            queued: Arc::new(AtomicUsize::new(0)),
+            warm_connections: config.warm_connections,
+            parked_connections: Arc::new(Mutex::new(HashMap::new())),
+            upstream_requests: Arc::new(Mutex::new(HashMap::new())),
+            status_rewrites: Arc::new(config.status_rewrites.clone()),
+            error_pages: Arc::new(config.error_pages.clone()),
+            blocked_content_types: Arc::new(config.blocked_content_types.clone()),
+            decompress_responses: config.decompress_responses,
+            tcp_nodelay: config.tcp_nodelay,
+            allowed_methods: Arc::new(config.allowed_methods.clone()),
+            allowed_path_prefixes: Arc::new(config.allowed_path_prefixes.clone()),
+            upstream_host_header: config.upstream_host_header.clone(),
+            upstream_host_headers: Arc::new(config.upstream_host_headers.clone()),
+            strip_path_prefix: config.strip_path_prefix.clone(),
+            add_path_prefix: config.add_path_prefix.clone(),
+            loopback_body: config
+                .loopback_upstream
+                .map(|size| Arc::new(vec![b'x'; size])),
+            shutdown_after_requests: config.shutdown_after_requests,
+            shutdown_after_seconds: config.shutdown_after_seconds,
+            liveness_path: config.liveness_path.clone(),
+            readiness_path: config.readiness_path.clone(),
+            tls_acceptor,
+            client_cert_header,
+            requests_received: Arc::new(AtomicUsize::new(0)),
+            next_request_id: Arc::new(AtomicUsize::new(1)),
+            shutting_down: Arc::new(watch::Sender::new(false)),
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            upstream_latency_ms: Arc::new(Mutex::new(None)),
+            rate_limiter: Arc::new(RateLimiter::new()),
+            balancing_strategy: config.balancing_strategy,
+            rng: Arc::new(Mutex::new(match config.rng_seed {
+                Some(seed) => StdRng::seed_from_u64(seed),
+                None => StdRng::from_entropy(),
+            })),
+            next_upstream: Arc::new(AtomicUsize::new(0)),
+            error_streaks: Arc::new(Mutex::new(HashMap::new())),
+            health_webhook,
+        })
+    }
+
+    /// Tells the --health-webhook, if there is one, that `upstream` was just taken out of rotation
+    /// (or put back in, if `healthy`).
+    fn health_changed(&self, upstream: &str, healthy: bool) {
+        if let Some(webhook) = &self.health_webhook {
+            webhook.changed(upstream, healthy);
+        }
+    }
+
+    /// Returns how many clients are currently waiting for a free upstream connection slot.
+    pub fn queue_depth(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
+
+    /// Returns how many idle upstream connections are waiting for clients to use (see
+    /// `Config::warm_connections`).
+    pub fn parked_connections(&self) -> usize {
+        self.parked_connections.lock().values().map(Vec::len).sum()
+    }
+
+    /// Returns whether the upstream at `address` is due an active health check, i.e. it isn't
+    /// backing off after failing one.
+    fn probe_due(&self, address: &str) -> bool {
+        self.probe_backoffs
+            .lock()
+            .get(address)
+            .is_none_or(|backoff| Instant::now() >= backoff.next_probe)
+    }
+
+    /// Decides when to next check the upstream at `address` after an active health check. Passing
+    /// resets it to every interval; each failure in a row doubles the wait, up to
+    /// --health-check-max-backoff.
+    fn schedule_next_probe(&self, address: &str, healthy: bool) {
+        if self.health_check_max_backoff == 0 {
+            return;
+        }
+        let mut backoffs = self.probe_backoffs.lock();
+        if healthy {
+            if backoffs.remove(address).is_some() {
+                tracing::info!(
+                    "Upstream {} passed a health check, ending its backoff",
+                    address
+                );
+            }
+            return;
+        }
+        let backoff = backoffs
+            .entry(address.to_string())
+            .or_insert_with(|| ProbeBackoff {
+                failures: 0,
+                next_probe: Instant::now(),
+            });
+        backoff.failures += 1;
+        let interval = self.active_health_check_interval as u64 * 1000;
+        let ceiling = interval
+            .saturating_mul(1 << backoff.failures.min(32))
+            .min(self.health_check_max_backoff as u64 * 1000)
+            .max(interval);
+        // Wait somewhere between half and all of the ceiling, so that proxies that saw the upstream
+        // fail at the same time don't keep checking it in step
+        let wait = self
+            .rng
+            .lock()
+            .gen_range(ceiling / 2..=ceiling)
+            .max(interval);
+        backoff.next_probe = Instant::now() + Duration::from_millis(wait);
+        tracing::info!(
+            "Upstream {} failed {} health check(s) in a row, checking it again in {:.1}s",
+            address,
+            backoff.failures,
+            wait as f64 / 1000.0
+        );
+    }
+
+    /// Drains the upstream at `address`: no new client connections are sent to it, and clients
+    /// already connected to it may send it at most `max_requests` more requests between them.
+    /// Their requests after that go to other upstreams. Returns false if there is no such upstream.
+    pub fn drain_upstream(&self, address: &str, max_requests: usize) -> bool {
+        if !self
+            .upstream_addresses
+            .iter()
+            .any(|upstream| upstream == address)
+        {
+            return false;
+        }
+        let mut requests = self.upstream_requests.lock();
+        let upstream = requests.entry(address.to_string()).or_default();
+        upstream.drain_allowance = Some(max_requests);
+        tracing::info!(
+            "Draining upstream {} ({} requests in flight, {} more allowed)",
+            address,
+            upstream.in_flight,
+            max_requests
+        );
+        if upstream.drained() {
+            tracing::info!("Upstream {} drained", address);
+        }
+        drop(requests);
+        // Parked connections would be handed to new clients
+        self.parked_connections.lock().remove(address);
+        true
+    }
+
+    /// Returns how many more requests the upstream at `address` may take and how many it has in
+    /// flight, or None if it isn't being drained. The drain is complete once both are 0.
+    pub fn drain_status(&self, address: &str) -> Option<(usize, usize)> {
+        let requests = self.upstream_requests.lock();
+        let upstream = requests.get(address)?;
+        Some((upstream.drain_allowance?, upstream.in_flight))
+    }
+
+    /// Returns how many connections the upstream at `address` may have open at once, or None if
+    /// there is no limit.
+    fn connection_limit(&self, address: &str) -> Option<usize> {
+        match &self.concurrency_limits {
+            Some(limits) => Some(limits.limit(address)),
+            None if self.max_upstream_connections > 0 => Some(self.max_upstream_connections),
+            None => None,
+        }
+    }
+
+    /// Returns the current --adaptive-concurrency limit on the connections to the upstream at
+    /// `address`, or None if limits don't adapt.
+    pub fn concurrency_limit(&self, address: &str) -> Option<usize> {
+        Some(self.concurrency_limits.as_ref()?.limit(address))
+    }
+
+    /// Returns whether the upstream at `address` is being drained.
+    fn is_draining(&self, address: &str) -> bool {
+        self.upstream_requests
+            .lock()
+            .get(address)
+            .is_some_and(|upstream| upstream.drain_allowance.is_some())
+    }
+
+    /// Returns the upstreams that requests for `host` (a Host header, possibly with a port) may be
+    /// sent to, or None if it isn't one of the virtual hosts and there are no others.
+    fn upstreams_for_host(&self, host: Option<&str>) -> Option<&[String]> {
+        if self.virtual_hosts.is_empty() {
+            return Some(&self.default_upstreams);
+        }
+        let name = host.map(|host| match host.rsplit_once(':') {
+            Some((name, port)) if port.bytes().all(|byte| byte.is_ascii_digit()) => name,
+            _ => host,
+        });
+        match name.and_then(|name| self.virtual_hosts.get(&name.to_lowercase())) {
+            Some(upstreams) => Some(upstreams),
+            None if !self.default_upstreams.is_empty() => Some(&self.default_upstreams),
+            None => None,
+        }
+    }
+
+    /// Returns the upstreams that `request` may be sent to: those of the first group with a living
+    /// upstream in the first route it matches, or else those for `host` (see `upstreams_for_host`).
+    async fn upstreams_for_request(
+        &self,
+        request: &http::Request<Vec<u8>>,
+        host: Option<&str>,
+    ) -> Option<&[String]> {
+        let groups = match self
+            .routes
+            .iter()
+            .find(|(matcher, _)| matcher.matches(request))
+        {
+            Some((_, groups)) => groups,
+            None => return self.upstreams_for_host(host),
+        };
+        let living = self.living_upstream_addresses.read().await;
+        let preferred = groups.iter().find(|upstreams| {
+            upstreams
+                .iter()
+                .any(|address| living.contains(address) && !self.is_draining(address))
+        });
+        // With nothing alive, the last group is the one to report the failure for
+        preferred.or(groups.last()).map(Vec::as_slice)
+    }
+
+    /// Returns the Host header to send to `upstream` in place of the client's, if any.
+    fn upstream_host_header(&self, upstream: &str) -> Option<&http::HeaderValue> {
+        self.upstream_host_headers
+            .get(upstream)
+            .or(self.upstream_host_header.as_ref())
+    }
+
+    /// Returns the Host header to send to `upstream` when we decide what it is: the one configured
+    /// for it, or else its own address if that can go in a header.
+    fn upstream_host(&self, upstream: &str) -> http::HeaderValue {
+        let (host_port, _tls) = upstream::parse_address(upstream, self.upstream_tls);
+        match self.upstream_host_header(upstream) {
+            Some(host) => host.clone(),
+            // A socket path isn't a host name, so just say the server is local
+            None if upstream::unix_socket_path(host_port).is_some() => {
+                http::HeaderValue::from_static("localhost")
+            }
+            // Nor can an address with characters no header may hold be sent as one, but there's
+            // no sense in crashing over it
+            None => http::HeaderValue::from_str(host_port)
+                .unwrap_or_else(|_| http::HeaderValue::from_static("localhost")),
+        }
+    }
+
+    /// Returns how many requests clients have sent so far.
+    pub fn requests_received(&self) -> usize {
+        self.requests_received.load(Ordering::SeqCst)
+    }
+
+    /// Returns the total bytes read from and written to clients, over connections that have
+    /// closed.
+    pub fn client_bytes(&self) -> (u64, u64) {
+        (
+            self.client_bytes_read.load(Ordering::SeqCst),
+            self.client_bytes_written.load(Ordering::SeqCst),
+        )
+    }
+
+    /// Starts shutting the proxy down gracefully: it stops accepting connections, closes client
+    /// connections once their current request has been answered, and `Proxy::run` finishes when
+    /// they are all closed.
+    pub fn shutdown(&self) {
+        self.shutting_down.send_replace(true);
+    }
+
+    /// Returns whether the proxy is shutting down.
+    pub fn is_shutting_down(&self) -> bool {
+        *self.shutting_down.borrow()
+    }
+
+    /// Waits until the proxy starts shutting down.
+    async fn shutdown_started(&self) {
+        let mut shutting_down = self.shutting_down.subscribe();
+        // The sender lives as long as self, so this can't fail
+        let _ = shutting_down.wait_for(|shutting_down| *shutting_down).await;
+    }
+
+    /// Returns a new ID for a request, unique for as long as the proxy runs.
+    fn next_request_id(&self) -> usize {
+        self.next_request_id.fetch_add(1, Ordering::SeqCst)
+    }
+
+    /// Counts a request received from a client, starting to shut down if it was the last one
+    /// --shutdown-after-requests allows.
+    fn count_request(&self) {
+        let count = self.requests_received.fetch_add(1, Ordering::SeqCst) + 1;
+        if self.shutdown_after_requests > 0 && count == self.shutdown_after_requests {
+            tracing::info!("Received {} requests, shutting down", count);
+            self.shutdown();
+        }
+    }
+}
+
+/// Accepts connections on `listener`, handling each one in its own task, until the proxy shuts
+/// down. It then stops listening and waits for the connections it accepted to be closed.
+pub async fn accept_connections(listener: TcpListener, state: ProxyState) {
+    let mut connections = tokio::task::JoinSet::new();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                // Handle the connection!
+                if let Ok((stream, client_addr)) = accepted {
+                    let state = state.clone();
+                    let span = tracing::info_span!("connection", client_ip = %client_addr.ip());
+                    connections.spawn(
+                        async move {
+                            handle_connection(stream, &state).await;
+                        }
+                        .instrument(span),
+                    );
+                }
+            }
+            // Reap finished connections so that they don't pile up
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+            _ = state.shutdown_started() => break,
+        }
+    }
+
+    drop(listener);
+    tracing::info!(
+        "No longer accepting connections, waiting for {} to close",
+        connections.len()
+    );
+    while connections.join_next().await.is_some() {}
+}
+
+/// Clears the rate limiting counts every minute, forever.
+pub async fn reset_rate_limits(state: &ProxyState) {
+    let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(60));
+    // The first tick completes immediately, which could wipe out counts for requests that arrived
+    // before this task first ran
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        state.rate_limiter.reset();
+    }
+}
+
+/// simply using fixed window. The first rate limit rule `request` matches decides its limit and
+/// what it is counted by, or else it is limited per IP by --max-requests-per-minute.
+async fn rate_limit_check<S: ClientStream>(
+    state: &ProxyState,
+    client_conn: &mut S,
+    client_ip: &String,
+    request: &http::Request<Vec<u8>>,
+) -> Result<(), std::io::Error> {
+    let rule = state
+        .rate_limit_rules
+        .iter()
+        .enumerate()
+        .find(|(_, rule)| rule.matches.iter().all(|matcher| matcher.matches(request)));
+    let (limit, key) = match rule {
+        // Each rule has counts of its own. Client IPs don't contain spaces, so these keys can't
+        // clash with the ones --max-requests-per-minute counts by.
+        Some((index, rule)) => (
+            rule.max_requests_per_minute,
+            format!("rule {} {}", index, rule.key(request, client_ip)),
+        ),
+        None => (state.max_requests_per_minute, client_ip.clone()),
+    };
+    if limit == 0 {
+        return Ok(());
+    }
+    if state.rate_limiter.count(&key) > limit {
+        let body = state
+            .rate_limit_body
+            .as_ref()
+            .map(|body| (body.0.as_slice(), &body.1));
+        let res = response::make_rate_limit_error(state.rate_limit_status, body);
+        if let Err(err) = response::write_to_stream(&res, client_conn).await {
+            tracing::error!("Failed to response client {}: {}", client_ip, err)
+        }
+        return Err(std::io::Error::other("Too many requests"));
+    }
+    Ok(())
+}
+
+/// Checks every upstream on the configured interval forever, taking failing upstreams out of
+/// rotation and putting recovered ones back. With --health-check-max-backoff, upstreams that keep
+/// failing are checked less and less often.
+pub async fn active_health_check(state: &ProxyState) {
+    loop {
+        tokio::time::sleep(Duration::new(state.active_health_check_interval as u64, 0)).await;
+
+        for upstream_ip in &state.upstream_addresses {
+            if !state.probe_due(upstream_ip) {
+                continue;
+            }
+            let healthy = probe_upstream(state, upstream_ip).await;
+            state.schedule_next_probe(upstream_ip, healthy);
+        }
+    }
+}
+
+/// Runs one active health check of `upstream_ip`, returning whether it passed.
+async fn probe_upstream(state: &ProxyState, upstream_ip: &str) -> bool {
+    // Name-based upstreams need the same Host header as proxied requests to be healthy
+    let request = http::Request::builder()
+        .method(http::Method::GET)
+        .uri(&state.active_health_check_path)
+        .header("Host", state.upstream_host(upstream_ip))
+        .body(Vec::new())
+        .unwrap();
+
+    match open_upstream(state, upstream_ip, None).await {
+        Ok(mut upstream) => {
+            if let Err(err) = request::write_to_stream(&request, &mut upstream).await {
+                tracing::error!("Failed to request upstream {}: {}", upstream_ip, err);
+                return false;
+            }
+
+            match response::read_from_stream(&mut upstream, request.method(), state.io_buffer_bytes)
+                .await
+            {
+                Ok(response) => {
+                    if response.status().as_u16() == 200 {
+                        // If a failed upstream returns HTTP 200, put it back in the rotation of upstream servers.
+                        let recovered = state
+                            .living_upstream_addresses
+                            .write()
+                            .await
+                            .insert(upstream_ip.to_string());
+                        if recovered {
+                            state.health_changed(upstream_ip, true);
+                        }
+                        // Any connections parked before it failed are likely dead
+                        if recovered && state.warm_connections > 0 {
+                            warm_upstream(state, upstream_ip).await;
+                        }
+                        true
+                    } else {
+                        //  If an online upstream returns a non-200 status code, mark that server as failed.
+                        let mut living = state.living_upstream_addresses.write().await;
+                        if living.remove(upstream_ip) {
+                            state.health_changed(upstream_ip, false);
+                        }
+                        false
+                    }
+                }
+                Err(_) => {
+                    //  If an online upstream fails to return a response, mark that server as failed.
+                    tracing::error!("Failed to get response from the upstream {}", upstream_ip);
+                    let mut living = state.living_upstream_addresses.write().await;
+                    if living.remove(upstream_ip) {
+                        state.health_changed(upstream_ip, false);
+                    }
+                    false
+                }
+            }
+        }
+        Err(err) => {
+            // Without this, an upstream that goes away would stay in rotation until a
+            // client request tried it, so --readiness-path would never notice an idle
+            // proxy losing its upstreams
+            tracing::error!("Failed to connect to upstream {}: {}", upstream_ip, err);
+            if state
+                .living_upstream_addresses
+                .write()
+                .await
+                .remove(upstream_ip)
+            {
+                state.health_changed(upstream_ip, false);
+            }
+            false
+        }
+    }
+}
+
+/// Passive health check: counts consecutive 5xx responses from an upstream during normal proxying,
+/// and takes the upstream out of rotation once there have been too many. It is put back by the next
+/// successful active health check.
+async fn record_upstream_status(state: &ProxyState, upstream_ip: &str, status: http::StatusCode) {
+    if state.consecutive_errors == 0 {
+        return;
+    }
+    let ejected = {
+        let mut streaks = state.error_streaks.lock();
+        if status.is_server_error() {
+            let streak = streaks.entry(upstream_ip.to_string()).or_insert(0);
+            *streak += 1;
+            if *streak >= state.consecutive_errors {
+                streaks.remove(upstream_ip);
+                true
+            } else {
+                false
+            }
+        } else {
+            if status.is_success() || status.is_redirection() {
+                streaks.remove(upstream_ip);
+            }
+            false
+        }
+    };
+    if ejected
+        && state
+            .living_upstream_addresses
+            .write()
+            .await
+            .remove(upstream_ip)
+    {
+        tracing::warn!(
+            "Upstream {} returned {} consecutive server errors, taking it out of rotation",
+            upstream_ip,
+            state.consecutive_errors
+        );
+        state.health_changed(upstream_ip, false);
+    }
+}
+
+/// Returns the media type of `response` (lowercased, without parameters) if
+/// --block-response-content-type says it mustn't reach the client.
+fn blocked_content_type(state: &ProxyState, response: &http::Response<Vec<u8>>) -> Option<String> {
+    if state.blocked_content_types.is_empty() {
+        return None;
+    }
+    response
+        .headers()
+        .get_all("content-type")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .map(media_type)
+        .find(|media_type| state.blocked_content_types.contains(media_type))
+}
+
+/// Returns the media type of a Content-Type value, lowercased and without parameters (e.g.
+/// `text/html` for `Text/HTML; charset=utf-8`).
+pub fn media_type(content_type: &str) -> String {
+    let media_type = match content_type.split_once(';') {
+        Some((media_type, _parameters)) => media_type,
+        None => content_type,
+    };
+    media_type.trim().to_ascii_lowercase()
+}
+
+/// Applies --error-page and --rewrite-status to a response to `method` from an upstream. Both match
+/// the status the upstream sent, so a page can be served under a rewritten status.
+fn rewrite_response(
+    state: &ProxyState,
+    method: &http::Method,
+    response: &mut http::Response<Vec<u8>>,
+) {
+    let status = response.status();
+    if let Some(page) = state.error_pages.get(&status) {
+        let headers = response.headers_mut();
+        headers.insert(
+            "content-type",
+            http::HeaderValue::from_static("text/html; charset=utf-8"),
+        );
+        headers.insert("content-length", http::HeaderValue::from(page.len()));
+        headers.remove("content-encoding");
+        // Responses to HEAD requests never have a body, but still say how long it would be
+        *response.body_mut() = if method == http::Method::HEAD {
+            Vec::new()
+        } else {
+            page.clone()
+        };
+    }
+    if let Some(new_status) = state.status_rewrites.get(&status) {
+        *response.status_mut() = *new_status;
+    }
+}
+
+/// Applies --decompress-responses to a response to `request`: if it is encoded with a single
+/// gzip or deflate coding that the request's Accept-Encoding doesn't accept, decodes the body and
+/// removes the Content-Encoding. Returns an error if the body couldn't be decoded.
+fn decompress_response(
+    state: &ProxyState,
+    request: &http::Request<Vec<u8>>,
+    response: &mut http::Response<Vec<u8>>,
+) -> Result<(), std::io::Error> {
+    if !state.decompress_responses {
+        return Ok(());
+    }
+    let mut encodings = response.headers().get_all("content-encoding").iter();
+    // Bodies encoded more than once (or not at all) are left alone
+    let encoding = match (encodings.next(), encodings.next()) {
+        (Some(value), None) => match value.to_str() {
+            Ok(encoding) if !encoding.contains(',') => encoding.trim().to_ascii_lowercase(),
+            _ => return Ok(()),
+        },
+        _ => return Ok(()),
+    };
+    if !response::is_decodable(&encoding) || accepts_encoding(request.headers(), &encoding) {
+        return Ok(());
+    }
+    if response.body().is_empty() {
+        // Responses to HEAD requests (and 204s and 304s) have no body to decode. There's no
+        // telling how long the body of a GET would be, so HEAD responses can't say.
+        response.headers_mut().remove("content-encoding");
+        if request.method() == http::Method::HEAD {
+            response.headers_mut().remove("content-length");
+        }
+        return Ok(());
+    }
+    let body = response::decode_body(&encoding, response.body())?;
+    let headers = response.headers_mut();
+    headers.remove("content-encoding");
+    headers.insert("content-length", http::HeaderValue::from(body.len()));
+    *response.body_mut() = body;
+    Ok(())
+}
+
+/// Returns whether the Accept-Encoding in `headers` accepts the content coding `encoding`. Clients
+/// that don't send Accept-Encoding are taken to accept none, since those that can decompress
+/// generally say so.
+fn accepts_encoding(headers: &http::HeaderMap, encoding: &str) -> bool {
+    let mut wildcard = false;
+    for value in headers.get_all("accept-encoding") {
+        let Ok(value) = value.to_str() else {
+            continue;
+        };
+        for coding in value.split(',') {
+            let mut params = coding.split(';');
+            let name = params
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .to_ascii_lowercase();
+            // A quality of 0 means "not acceptable"
+            let acceptable = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .all(|quality| quality.trim().parse::<f32>().is_ok_and(|q| q > 0.0));
+            let name = name.strip_prefix("x-").unwrap_or(&name);
+            if name == encoding.strip_prefix("x-").unwrap_or(encoding) {
+                return acceptable;
+            }
+            if name == "*" {
+                wildcard = acceptable;
+            }
+        }
+    }
+    wildcard
+}
+
+/// Checks a request against --allow-method and --allow-path-prefix, returning the error response
+/// to send instead of forwarding it if it isn't allowed.
+fn check_request_allowed(
+    state: &ProxyState,
+    request: &http::Request<Vec<u8>>,
+) -> Option<http::Response<Vec<u8>>> {
+    if !state.allowed_methods.is_empty() && !state.allowed_methods.contains(request.method()) {
+        let mut response = response::make_http_error(http::StatusCode::METHOD_NOT_ALLOWED);
+        let allow: Vec<&str> = state.allowed_methods.iter().map(|m| m.as_str()).collect();
+        if let Ok(allow) = http::HeaderValue::from_str(&allow.join(", ")) {
+            response.headers_mut().insert("allow", allow);
+        }
+        return Some(response);
+    }
+    if !state.allowed_path_prefixes.is_empty() {
+        let path = request.uri().path();
+        // A prefix means nothing if the path can climb back out of it with dot segments (which
+        // upstreams may resolve, percent-encoded or not), so refuse those outright
+        let climbs = path.split('/').any(|segment| {
+            let segment = segment.to_ascii_lowercase().replace("%2e", ".");
+            segment == "." || segment == ".."
+        });
+        let allowed = !climbs
+            && state
+                .allowed_path_prefixes
+                .iter()
+                .any(|prefix| path.starts_with(prefix.as_str()));
+        if !allowed {
+            return Some(response::make_http_error(http::StatusCode::FORBIDDEN));
+        }
+    }
+    None
+}
+
+/// Answers requests for --liveness-path and --readiness-path, returning None for any other request.
+/// The proxy is live as long as it can answer at all, and ready while it has an upstream to send
+/// requests to.
+async fn answer_probe(
+    state: &ProxyState,
+    request: &http::Request<Vec<u8>>,
+) -> Option<http::Response<Vec<u8>>> {
+    let path = Some(request.uri().path());
+    let status = if path == state.liveness_path.as_deref() {
+        http::StatusCode::OK
+    } else if path == state.readiness_path.as_deref() {
+        if state.loopback_body.is_some() || !state.living_upstream_addresses.read().await.is_empty()
+        {
+            http::StatusCode::OK
+        } else {
+            http::StatusCode::SERVICE_UNAVAILABLE
+        }
+    } else {
+        return None;
+    };
+    Some(response::make_http_error(status))
+}
+
+/// Makes the response for a client we couldn't find an upstream for.
+fn make_connect_error(state: &ProxyState, error: ConnectError) -> http::Response<Vec<u8>> {
+    match (error, &state.maintenance_page) {
+        (ConnectError::Saturated, _) => response::make_overloaded_error(),
+        // Health checks may bring an upstream back, so suggest retrying after the next one
+        (ConnectError::Unavailable, Some(page)) => {
+            response::make_maintenance_page(page, state.active_health_check_interval)
+        }
+        (ConnectError::Unavailable, None) => {
+            response::make_http_error(http::StatusCode::BAD_GATEWAY)
+        }
+    }
+}
+
+/// Weight given to each new response time in the moving average of upstream latency
+const LATENCY_SMOOTHING: f64 = 0.3;
+
+/// Folds the time an upstream took to respond into the average upstream latency.
+fn record_upstream_latency(state: &ProxyState, elapsed: Duration) {
+    let sample = elapsed.as_secs_f64() * 1000.0;
+    let mut average = state.upstream_latency_ms.lock();
+    *average = Some(match *average {
+        Some(average) => average + LATENCY_SMOOTHING * (sample - average),
+        None => sample,
+    });
+}
+
+/// Adjusts the --adaptive-concurrency limit of the upstream at `address` after it took `elapsed`
+/// to respond. Clients queued for a slot are woken if the limit went up.
+fn record_concurrency_sample(state: &ProxyState, address: &str, elapsed: Duration) {
+    if let Some(limits) = &state.concurrency_limits {
+        if limits.record(address, elapsed) {
+            state.upstream_slot_freed.notify_waiters();
+        }
+    }
+}
+
+/// Returns whether the proxy is overloaded enough that new requests should be turned away.
+fn should_shed_load(state: &ProxyState) -> bool {
+    if state.shed_at_inflight == 0 && state.shed_at_latency_ms == 0 {
+        return false;
+    }
+    let busy = state.shed_at_inflight == 0
+        || state.in_flight.load(Ordering::SeqCst) > state.shed_at_inflight;
+    let slow = state.shed_at_latency_ms == 0
+        || state
+            .upstream_latency_ms
+            .lock()
+            .is_some_and(|average| average > state.shed_at_latency_ms as f64);
+    busy && slow
+}
+
+/// Adds one to a counter (of requests in flight, clients queued, ...) for as long as this is alive.
+struct Counted(Arc<AtomicUsize>);
+
+impl Counted {
+    fn new(counter: &Arc<AtomicUsize>) -> Counted {
+        counter.fetch_add(1, Ordering::SeqCst);
+        Counted(counter.clone())
+    }
+}
+
+impl Drop for Counted {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Opens a connection to the upstream at `address`, over TLS if that upstream uses it.
+/// `client_addresses` are the source and destination of the client connection this upstream
+/// connection is for (None for connections of our own), which are announced to the upstream if
+/// --send-proxy-protocol is set.
+async fn open_upstream(
+    state: &ProxyState,
+    address: &str,
+    client_addresses: Option<(SocketAddr, SocketAddr)>,
+) -> Result<Box<dyn upstream::Stream>, std::io::Error> {
+    let (host_port, tls) = upstream::parse_address(address, state.upstream_tls);
+    let connector = if tls {
+        state.tls_connector.as_ref()
+    } else {
+        None
+    };
+    let proxy_header = state
+        .send_proxy_protocol
+        .map(|version| upstream::proxy_protocol_header(version, client_addresses));
+    upstream::connect(
+        host_port,
+        connector,
+        proxy_header.as_deref(),
+        state.tcp_nodelay,
+    )
+    .await
+}
+
+/// Why no upstream connection could be opened for a client.
+#[derive(Debug)]
+enum ConnectError {
+    /// Every upstream is dead or unreachable
+    Unavailable,
+    /// Every living upstream is at its connection limit, and no slot freed up in time
+    Saturated,
+}
+
+/// A request in flight to an upstream, which stops being counted when this is dropped.
+struct UpstreamRequest {
+    address: String,
+    requests: Arc<Mutex<HashMap<String, UpstreamRequests>>>,
+}
+
+impl Drop for UpstreamRequest {
+    fn drop(&mut self) {
+        let mut requests = self.requests.lock();
+        if let Some(upstream) = requests.get_mut(&self.address) {
+            upstream.in_flight -= 1;
+            if upstream.drained() {
+                tracing::info!("Upstream {} drained", self.address);
+            }
+        }
+    }
+}
+
+/// Counts a request about to be sent to the upstream at `address`, or returns None if the upstream
+/// is being drained and may not take any more.
+fn start_upstream_request(state: &ProxyState, address: &str) -> Option<UpstreamRequest> {
+    let mut requests = state.upstream_requests.lock();
+    let upstream = requests.entry(address.to_string()).or_default();
+    match &mut upstream.drain_allowance {
+        Some(0) => return None,
+        Some(allowance) => *allowance -= 1,
+        None => {}
+    }
+    upstream.in_flight += 1;
+    Some(UpstreamRequest {
+        address: address.to_string(),
+        requests: state.upstream_requests.clone(),
+    })
+}
+
+/// One of an upstream's connection slots, which is freed when this is dropped.
+struct UpstreamSlot {
+    address: String,
+    connections: Arc<Mutex<HashMap<String, usize>>>,
+    freed: Arc<Notify>,
+}
+
+impl Drop for UpstreamSlot {
+    fn drop(&mut self) {
+        let mut connections = self.connections.lock();
+        if let Some(count) = connections.get_mut(&self.address) {
+            *count -= 1;
+            if *count == 0 {
+                connections.remove(&self.address);
+            }
+        }
+        drop(connections);
+        self.freed.notify_waiters();
+    }
+}
+
+/// An upstream connection that holds on to its slot until it is closed.
+struct SlottedStream {
+    stream: Box<dyn upstream::Stream>,
+    _slot: UpstreamSlot,
+}
+
+impl AsyncRead for SlottedStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for SlottedStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+/// Picks one of `candidates` (sorted by address) according to --balancing-strategy, or returns
+/// None if there are none.
+fn choose_upstream<'a>(state: &ProxyState, candidates: &[&'a String]) -> Option<&'a String> {
+    if candidates.is_empty() {
+        return None;
+    }
+    match state.balancing_strategy {
+        BalancingStrategy::Random => candidates.choose(&mut *state.rng.lock()).copied(),
+        // The candidates can change between calls as upstreams die, come back or fill up, so the
+        // turn is taken modulo however many there are now
+        BalancingStrategy::RoundRobin => {
+            let turn = state.next_upstream.fetch_add(1, Ordering::SeqCst);
+            Some(candidates[turn % candidates.len()])
+        }
+    }
+}
+
+/// Picks a living upstream out of `upstreams` with a free connection slot and takes the slot, or
+/// returns None if every living one is saturated. Returns an error if none of them are living.
+async fn reserve_upstream(
+    state: &ProxyState,
+    upstreams: &[String],
+) -> Result<Option<UpstreamSlot>, ConnectError> {
+    let living = state.living_upstream_addresses.read().await;
+    // HashSet iteration order differs between runs, so sort the candidates to keep the selection
+    // reproducible for a given seed (and the round-robin order stable)
+    let mut candidates: Vec<&String> = living
+        .iter()
+        .filter(|address| upstreams.contains(address) && !state.is_draining(address))
+        .collect();
+    if candidates.is_empty() {
+        tracing::error!("Failed to connect upstream: all upstreams are dead");
+        return Err(ConnectError::Unavailable);
+    }
+    candidates.sort();
+    let mut connections = state.upstream_connections.lock();
+    candidates.retain(|address| match state.connection_limit(address) {
+        Some(limit) => connections.get(*address).copied().unwrap_or(0) < limit,
+        None => true,
+    });
+    Ok(choose_upstream(state, &candidates).map(|address| {
+        *connections.entry(address.to_string()).or_insert(0) += 1;
+        UpstreamSlot {
+            address: address.to_string(),
+            connections: state.upstream_connections.clone(),
+            freed: state.upstream_slot_freed.clone(),
+        }
+    }))
+}
+
+/// Takes one of the connection slots of the upstream at `address`, or returns None if it has none
+/// free.
+fn reserve_slot(state: &ProxyState, address: &str) -> Option<UpstreamSlot> {
+    let mut connections = state.upstream_connections.lock();
+    let count = connections.get(address).copied().unwrap_or(0);
+    if state
+        .connection_limit(address)
+        .is_some_and(|limit| count >= limit)
+    {
+        return None;
+    }
+    connections.insert(address.to_string(), count + 1);
+    Some(UpstreamSlot {
+        address: address.to_string(),
+        connections: state.upstream_connections.clone(),
+        freed: state.upstream_slot_freed.clone(),
+    })
+}
+
+/// Opens --warm-connections idle connections to the upstream at `address` (or as many as it has
+/// free slots for) and parks them for clients to use, replacing any parked before.
+async fn warm_upstream(state: &ProxyState, address: &str) {
+    state.parked_connections.lock().remove(address);
+    if state.is_draining(address) {
+        return;
+    }
+    let mut warmed: Vec<Box<dyn upstream::Stream>> = Vec::new();
+    while warmed.len() < state.warm_connections {
+        let slot = match reserve_slot(state, address) {
+            Some(slot) => slot,
+            None => break,
+        };
+        match open_upstream(state, address, None).await {
+            Ok(stream) => warmed.push(Box::new(SlottedStream {
+                stream,
+                _slot: slot,
+            })),
+            Err(err) => {
+                tracing::warn!(
+                    "Failed to warm a connection to upstream {}: {}",
+                    address,
+                    err
+                );
+                break;
+            }
+        }
+    }
+    tracing::info!(
+        "Warmed {} connections to upstream {}",
+        warmed.len(),
+        address
+    );
+    state
+        .parked_connections
+        .lock()
+        .entry(address.to_string())
+        .or_default()
+        .extend(warmed);
+}
+
+/// Takes a parked connection to a living upstream out of `upstreams`, returning it along with the
+/// upstream's address, or None if there are none left. Connections the upstream has closed since
+/// they were parked are thrown away.
+async fn take_parked_connection(
+    state: &ProxyState,
+    upstreams: &[String],
+) -> Option<(Box<dyn upstream::Stream>, String)> {
+    if state.warm_connections == 0 {
+        return None;
+    }
+    let living = state.living_upstream_addresses.read().await;
+    loop {
+        let (mut stream, address) = {
+            let mut parked = state.parked_connections.lock();
+            // Sorted for the same reason as in reserve_upstream
+            let mut candidates: Vec<&String> = parked
+                .iter()
+                .filter(|(address, streams)| {
+                    !streams.is_empty()
+                        && living.contains(*address)
+                        && upstreams.contains(address)
+                        && !state.is_draining(address)
+                })
+                .map(|(address, _)| address)
+                .collect();
+            candidates.sort();
+            let address = choose_upstream(state, &candidates)?.to_string();
+            let stream = parked.get_mut(&address).unwrap().pop().unwrap();
+            (stream, address)
+        };
+        // An idle upstream has nothing to say, so a read that doesn't block means it hung up (or
+        // sent something we can't make sense of). Timing out right away still polls the read once.
+        let mut byte = [0; 1];
+        if tokio::time::timeout(Duration::ZERO, stream.read(&mut byte))
+            .await
+            .is_err()
+        {
+            return Some((stream, address));
+        }
+        tracing::debug!("Parked connection to upstream {} was closed", address);
+    }
+}
+
+/// Connects to a living upstream out of `upstreams` on behalf of the client connection between
+/// `client_addresses`, returning the connection along with the address of the chosen upstream.
+/// If every living upstream is saturated, waits in the queue for up to --queue-timeout for a slot.
+async fn connect_to_upstream(
+    state: &ProxyState,
+    client_addresses: (SocketAddr, SocketAddr),
+    upstreams: &[String],
+) -> Result<(Box<dyn upstream::Stream>, String), ConnectError> {
+    if let Some(parked) = take_parked_connection(state, upstreams).await {
+        return Ok(parked);
+    }
+
+    // Our place in the queue, and when we give up on it (None until we have to queue)
+    let mut queued: Option<(Counted, tokio::time::Instant)> = None;
+    loop {
+        // Start listening before looking for a slot, so that a slot freed in between still
+        // wakes us up
+        let slot_freed = state.upstream_slot_freed.notified();
+        let slot = match reserve_upstream(state, upstreams).await? {
+            Some(slot) => slot,
+            None => {
+                let deadline = match &queued {
+                    Some((_, deadline)) => *deadline,
+                    None => {
+                        let place = Counted::new(&state.queued);
+                        let depth = state.queue_depth();
+                        if state.queue_timeout == 0 || depth > state.max_queued {
+                            tracing::warn!("All upstreams are saturated and the queue is full");
+                            return Err(ConnectError::Saturated);
+                        }
+                        tracing::info!("All upstreams are saturated, queueing ({} waiting)", depth);
+                        let deadline =
+                            tokio::time::Instant::now() + Duration::from_secs(state.queue_timeout);
+                        queued = Some((place, deadline));
+                        deadline
+                    }
+                };
+                if tokio::time::timeout_at(deadline, slot_freed).await.is_err() {
+                    tracing::warn!("Timed out waiting for a free upstream connection");
+                    return Err(ConnectError::Saturated);
+                }
+                continue;
+            }
+        };
+        let upstream_ip = slot.address.clone();
+
+        match open_upstream(state, &upstream_ip, Some(client_addresses)).await {
+            Ok(stream) => {
+                let stream = SlottedStream {
+                    stream,
+                    _slot: slot,
+                };
+                return Ok((Box::new(stream), upstream_ip));
+            }
+            Err(err) => {
+                tracing::error!("Failed to connect to upstream {}: {}", upstream_ip, err);
+
+                let mut living = state.living_upstream_addresses.write().await;
+                if living.remove(&upstream_ip) {
+                    state.health_changed(&upstream_ip, false);
+                }
+
+                if !upstreams.iter().any(|address| living.contains(address)) {
+                    tracing::error!("Failed to connect upstream: all upstreams are dead");
+                    return Err(ConnectError::Unavailable);
+                }
+            }
+        }
+    }
+}
+
+/// A connection from a client, which may or may not be encrypted.
+trait ClientStream: upstream::Stream {
+    /// Returns the TCP connection underneath.
+    fn tcp_stream(&self) -> &TcpStream;
+
+    /// Returns the host name the client asked for in its TLS handshake (SNI), if any.
+    fn server_name(&self) -> Option<&str> {
+        None
+    }
+}
+
+impl ClientStream for TcpStream {
+    fn tcp_stream(&self) -> &TcpStream {
+        self
+    }
+}
+
+/// A client connection that counts the bytes read from and written to it, and refuses to read any
+/// more once --max-bytes-per-connection have gone back and forth.
+struct CountedStream<S> {
+    stream: S,
+    read: usize,
+    written: usize,
+    limit: usize,
+}
+
+impl<S> CountedStream<S> {
+    fn new(stream: S, limit: usize) -> CountedStream<S> {
+        CountedStream {
+            stream,
+            read: 0,
+            written: 0,
+            limit,
+        }
+    }
+
+    /// Returns whether transferring `more` bytes would take the connection to its limit.
+    fn reaches_limit(&self, more: usize) -> bool {
+        self.limit > 0 && self.read + self.written + more >= self.limit
+    }
+}
+
+impl<S: ClientStream> AsyncRead for CountedStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if this.reaches_limit(0) {
+            return Poll::Ready(Err(std::io::Error::other(format!(
+                "connection reached its limit of {} bytes",
+                this.limit
+            ))));
+        }
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut this.stream).poll_read(cx, buf);
+        this.read += buf.filled().len() - filled;
+        result
+    }
+}
+
+impl<S: ClientStream> AsyncWrite for CountedStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.stream).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = result {
+            this.written += written;
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+impl<S: ClientStream> ClientStream for CountedStream<S> {
+    fn tcp_stream(&self) -> &TcpStream {
+        self.stream.tcp_stream()
+    }
+
+    fn server_name(&self) -> Option<&str> {
+        self.stream.server_name()
+    }
+}
+
+impl ClientStream for tokio_rustls::server::TlsStream<TcpStream> {
+    fn tcp_stream(&self) -> &TcpStream {
+        self.get_ref().0
+    }
+
+    fn server_name(&self) -> Option<&str> {
+        self.get_ref().1.server_name()
+    }
+}
+
+/// Returns the IP of the client on `client_conn` to log, or a placeholder if the connection has
+/// already failed (e.g. the client reset it), in which case its address can't be read.
+fn client_ip_for_log(client_conn: &TcpStream) -> String {
+    match client_conn.peer_addr() {
+        Ok(address) => address.ip().to_string(),
+        Err(_) => "(disconnected)".to_string(),
+    }
+}
+
+async fn send_response<S: ClientStream>(client_conn: &mut S, response: &http::Response<Vec<u8>>) {
+    let client_ip = client_ip_for_log(client_conn.tcp_stream());
+    tracing::info!(
+        "{} <- {}",
+        client_ip,
+        response::format_response_line(response)
+    );
+    if let Err(error) = response::write_to_stream(response, client_conn).await {
+        tracing::warn!("Failed to send response to client: {}", error);
+    }
+}
+
+/// Longest to spend discarding what a client is still sending before closing its connection (see
+/// `linger_close`)
+const LINGER_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Closes a connection the client may still be sending a request on, after we have sent our
+/// response. Closing it with unread bytes waiting would reset the connection, which can lose the
+/// response before the client reads it, so stop writing and read (and discard) whatever else the
+/// client sends first, for up to LINGER_TIMEOUT.
+async fn linger_close<S: ClientStream>(client_conn: &mut S) {
+    if client_conn.shutdown().await.is_err() {
+        return;
+    }
+    let mut buffer = [0_u8; 1024];
+    let _ = tokio::time::timeout(LINGER_TIMEOUT, async {
+        while let Ok(1..) = client_conn.read(&mut buffer).await {}
+    })
+    .await;
+}
+
+/// Reads a request from the client, returning None if the client doesn't manage to send one within
+/// --client-idle-timeout. This stops idle or deliberately slow clients from holding a task forever.
+async fn read_client_request<S: ClientStream>(
+    state: &ProxyState,
+    client_conn: &mut S,
+) -> Option<Result<http::Request<Vec<u8>>, request::Error>> {
+    let read = request::read_from_stream(
+        client_conn,
+        state.io_buffer_bytes,
+        state.max_header_bytes,
+        state.client_read_timeout,
+    );
+    let request = if state.client_idle_timeout == 0 {
+        Some(read.await)
+    } else {
+        tokio::time::timeout(Duration::from_secs(state.client_idle_timeout), read)
+            .await
+            .ok()
+    };
+    if let Some(Ok(_)) = request {
+        state.count_request();
+    }
+    request
+}
+
+/// Waits for the client to start sending its next request, returning false if it stays idle for
+/// longer than --keepalive-timeout.
+async fn wait_for_next_request<S: ClientStream>(state: &ProxyState, client_conn: &S) -> bool {
+    if state.keepalive_timeout == 0 {
+        return true;
+    }
+    // Hanging up also makes the connection readable, and is noticed when reading the request
+    tokio::time::timeout(
+        Duration::from_secs(state.keepalive_timeout),
+        client_conn.tcp_stream().readable(),
+    )
+    .await
+    .is_ok()
+}
+
+/// Proxies each request the client sends on `client_conn` until it disconnects, first completing a
+/// TLS handshake if the proxy serves TLS.
+pub async fn handle_connection(client_conn: TcpStream, state: &ProxyState) {
+    if state.tcp_nodelay {
+        if let Err(err) = client_conn.set_nodelay(true) {
+            tracing::warn!("Failed to set TCP_NODELAY on client connection: {}", err);
+        }
+    }
+    let acceptor = match &state.tls_acceptor {
+        Some(acceptor) => acceptor,
+        None => {
+            let mut client_conn = CountedStream::new(client_conn, state.max_bytes_per_connection);
+            serve_client(&mut client_conn, false, None, state).await;
+            record_client_bytes(state, &client_conn);
+            return;
+        }
+    };
+    let client_ip = client_ip_for_log(&client_conn);
+    // Clients get as long to finish the handshake as they would to send a request
+    let handshake = acceptor.accept(client_conn);
+    let handshake = if state.client_idle_timeout == 0 {
+        Ok(handshake.await)
+    } else {
+        tokio::time::timeout(Duration::from_secs(state.client_idle_timeout), handshake).await
+    };
+    match handshake {
+        Ok(Ok(client_conn)) => {
+            let identity = tls::client_identity(client_conn.get_ref().1);
+            let mut client_conn = CountedStream::new(client_conn, state.max_bytes_per_connection);
+            serve_client(&mut client_conn, true, identity, state).await;
+            // Say we're done, so that the client can tell we hung up on purpose rather than being
+            // cut off
+            let _ = client_conn.shutdown().await;
+            record_client_bytes(state, &client_conn);
+        }
+        // Includes clients without an acceptable certificate when client certificates are required
+        Ok(Err(err)) => tracing::info!("TLS handshake with {} failed: {}", client_ip, err),
+        Err(_) => tracing::info!("TLS handshake with {} timed out", client_ip),
+    }
+}
+
+/// Adds the bytes that went over a client connection that has finished to the totals, and logs
+/// them.
+fn record_client_bytes<S: ClientStream>(state: &ProxyState, client_conn: &CountedStream<S>) {
+    state
+        .client_bytes_read
+        .fetch_add(client_conn.read as u64, Ordering::SeqCst);
+    state
+        .client_bytes_written
+        .fetch_add(client_conn.written as u64, Ordering::SeqCst);
+    let client_ip = client_ip_for_log(client_conn.tcp_stream());
+    tracing::info!(
+        "Connection from {} closed after {} bytes in, {} bytes out",
+        client_ip,
+        client_conn.read,
+        client_conn.written
+    );
+}
+
+/// Proxies each request the client sends on `client_conn` until it disconnects. `tls` is whether
+/// the connection was accepted over TLS, and `client_identity` is who the client's certificate says
+/// it is, if it presented one.
+async fn serve_client<S: ClientStream>(
+    client_conn: &mut CountedStream<S>,
+    tls: bool,
+    client_identity: Option<String>,
+    state: &ProxyState,
+) {
+    // A client can reset the connection before we get this far, leaving nothing to serve
+    let client_addresses = match (
+        client_conn.tcp_stream().peer_addr(),
+        client_conn.tcp_stream().local_addr(),
+    ) {
+        (Ok(peer_address), Ok(local_address)) => (peer_address, local_address),
+        (Err(err), _) | (_, Err(err)) => {
+            tracing::info!(
+                "Dropping connection that failed before it was served: {}",
+                err
+            );
+            return;
+        }
+    };
+    let client_ip = client_addresses.0.ip().to_string();
+    tracing::info!("Connection received from {}", client_ip);
+
+    // Probes must be answered without dialing an upstream (which fails when none are ready), so
+    // with probes configured, wait for the first request to be forwarded before connecting. With
+    // virtual hosts or routes, which upstreams we may connect to depends on the request.
+    let connect_lazily = state.liveness_path.is_some()
+        || state.readiness_path.is_some()
+        || !state.virtual_hosts.is_empty()
+        || !state.routes.is_empty();
+
+    // Turn the client away before picking an upstream if we're already overloaded
+    if !connect_lazily && should_shed_load(state) {
+        tracing::warn!("Overloaded, shedding connection from {}", client_ip);
+        let _ = read_client_request(state, client_conn).await;
+        send_response(client_conn, &response::make_overloaded_error()).await;
+        return;
+    }
+
+    // Open a connection to a random destination server. This is None after the upstream hangs up,
+    // in which case we connect again (possibly to a different upstream) for the next request.
+    let (mut upstream_conn, mut upstream_ip) = if state.loopback_body.is_some() {
+        // --loopback-upstream never connects anywhere
+        (None, "loopback".to_string())
+    } else if connect_lazily {
+        (None, "(not connected)".to_string())
+    } else {
+        match connect_to_upstream(state, client_addresses, &state.default_upstreams).await {
+            Ok((upstream, upstream_ip)) => (Some(upstream), upstream_ip),
+            Err(error) => {
+                // Read the request before answering. Closing a socket with unread data resets the
+                // connection, and the client may never see our response
+                let _ = read_client_request(state, client_conn).await;
+                send_response(client_conn, &make_connect_error(state, error)).await;
+                return;
+            }
+        }
+    };
+
+    // The client may now send us one or more requests. Keep trying to read requests until the
+    // client hangs up or we get an error.
+    let mut first_request = true;
+    loop {
+        // Read a request from the client, unless we start shutting down while waiting for one, or
+        // the client keeps the connection idle for too long after its previous request
+        let request = tokio::select! {
+            request = async {
+                if !first_request && !wait_for_next_request(state, client_conn).await {
+                    return None;
+                }
+                Some(read_client_request(state, client_conn).await)
+            } => request,
+            _ = state.shutdown_started() => {
+                tracing::debug!("Shutting down, closing idle connection from {}", client_ip);
+                return;
+            }
+        };
+        first_request = false;
+        let request = match request {
+            Some(request) => request,
+            None => {
+                tracing::info!(
+                    "{} was idle for {}s since its last request, closing connection",
+                    client_ip,
+                    state.keepalive_timeout
+                );
+                return;
+            }
+        };
+        let request = match request {
+            Some(request) => request,
+            None => {
+                tracing::info!(
+                    "No request from {} within {}s, closing connection",
+                    client_ip,
+                    state.client_idle_timeout
+                );
+                let mut response = response::make_http_error(http::StatusCode::REQUEST_TIMEOUT);
+                response
+                    .headers_mut()
+                    .insert("connection", http::HeaderValue::from_static("close"));
+                send_response(client_conn, &response).await;
+                return;
+            }
+        };
+        let request = match request {
+            Ok(request) => request,
+            // Handle case where client closed connection and is no longer sending requests
+            Err(request::Error::IncompleteRequest(0)) => {
+                tracing::debug!("Client finished sending requests. Shutting down connection");
+                return;
+            }
+            // Handle I/O error in reading from the client
+            Err(request::Error::ConnectionError(io_err)) => {
+                tracing::info!("Error reading request from client stream: {}", io_err);
+                return;
+            }
+            Err(error) => {
+                tracing::debug!("Error parsing request: {}", error);
+                let mut response = response::make_http_error(match error {
+                    request::Error::IncompleteRequest(_)
+                    | request::Error::MalformedRequest(_)
+                    | request::Error::InvalidContentLength
+                    | request::Error::ContentLengthMismatch => http::StatusCode::BAD_REQUEST,
+                    request::Error::RequestBodyTooLarge => http::StatusCode::PAYLOAD_TOO_LARGE,
+                    request::Error::HeadersTooLarge => {
+                        http::StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE
+                    }
+                    request::Error::ReadTimedOut => http::StatusCode::REQUEST_TIMEOUT,
+                    request::Error::ConnectionError(_) => http::StatusCode::SERVICE_UNAVAILABLE,
+                });
+                // The rest of the request is still to come, and can't be told apart from the next
+                // one
+                let unfinished = matches!(
+                    error,
+                    request::Error::HeadersTooLarge | request::Error::ReadTimedOut
+                );
+                if unfinished {
+                    response
+                        .headers_mut()
+                        .insert("connection", http::HeaderValue::from_static("close"));
+                }
+                send_response(client_conn, &response).await;
+                if unfinished {
+                    linger_close(client_conn).await;
+                    return;
+                }
+                continue;
+            }
+        };
+
+        let span = tracing::info_span!(
+            "request",
+            id = state.next_request_id(),
+            // Not known yet if the upstream connection will only be opened to forward this request
+            upstream = (upstream_conn.is_some() || state.loopback_body.is_some())
+                .then_some(upstream_ip.as_str()),
+            // Filled in once the request is known to be forwarded
+            trace_id = tracing::field::Empty,
+            span_id = tracing::field::Empty,
+        );
+        let keep_open = serve_request(
+            state,
+            client_conn,
+            client_addresses,
+            tls,
+            client_identity.as_deref(),
+            &mut upstream_conn,
+            &mut upstream_ip,
+            request,
+        )
+        .instrument(span)
+        .await;
+        if !keep_open {
+            return;
+        }
+    }
+}
+
+/// Answers `request` from the client, forwarding it over the client's upstream connection unless it
+/// can be answered here. `tls` is whether the client connected over TLS. Returns whether to keep the
+/// client connection open for more requests.
+#[allow(clippy::too_many_arguments)]
+async fn serve_request<S: ClientStream>(
+    state: &ProxyState,
+    client_conn: &mut CountedStream<S>,
+    client_addresses: (SocketAddr, SocketAddr),
+    tls: bool,
+    client_identity: Option<&str>,
+    upstream_conn: &mut Option<Box<dyn upstream::Stream>>,
+    upstream_ip: &mut String,
+    mut request: http::Request<Vec<u8>>,
+) -> bool {
+    let client_ip = client_addresses.0.ip().to_string();
+    let client_closing = wants_close(request.headers(), request.version());
+    if let Some(mut response) = answer_probe(state, &request).await {
+        tracing::debug!(
+            "Answering {} from {} with {}",
+            request::format_request_line(&request),
+            client_ip,
+            response.status()
+        );
+        let closing = client_closing || state.is_shutting_down();
+        set_connection_header(state, &mut response, request.version(), closing);
+        send_response(client_conn, &response).await;
+        return !closing;
+    }
+
+    tracing::info!(
+        "{} -> {}: {}",
+        client_ip,
+        upstream_ip,
+        request::format_request_line(&request)
+    );
+
+    // Requests that aren't allowed never reach an upstream
+    if let Some(mut response) = check_request_allowed(state, &request) {
+        tracing::info!(
+            "Refusing {} from {}",
+            request::format_request_line(&request),
+            client_ip
+        );
+        let closing = client_closing || state.is_shutting_down();
+        set_connection_header(state, &mut response, request.version(), closing);
+        send_response(client_conn, &response).await;
+        return !closing;
+    }
+
+    // Requests for hosts we don't serve never reach an upstream either. The Host header says which
+    // site the client wants; clients that don't send one may have said in the TLS handshake.
+    let host = request
+        .headers()
+        .get("host")
+        .and_then(|value| value.to_str().ok())
+        .or(client_conn.server_name());
+    let upstreams = match state.upstreams_for_request(&request, host).await {
+        Some(upstreams) => upstreams,
+        None => {
+            tracing::info!("No upstreams for host {:?} from {}", host, client_ip);
+            let mut response = response::make_http_error(http::StatusCode::NOT_FOUND);
+            let closing = client_closing || state.is_shutting_down();
+            set_connection_header(state, &mut response, request.version(), closing);
+            send_response(client_conn, &response).await;
+            return !closing;
+        }
+    };
+
+    // check if too many request
+    if state.max_requests_per_minute > 0 || !state.rate_limit_rules.is_empty() {
+        if let Err(err) = rate_limit_check(state, client_conn, &client_ip, &request).await {
+            tracing::error!("rate limit: {}", err);
+            return !client_closing;
+        }
+    }
+
+    if should_shed_load(state) {
+        tracing::warn!("Overloaded, shedding request from {}", client_ip);
+        let mut response = response::make_overloaded_error();
+        set_connection_header(state, &mut response, request.version(), client_closing);
+        send_response(client_conn, &response).await;
+        return !client_closing;
+    }
+    let _in_flight = Counted::new(&state.in_flight);
+
+    // Add X-Forwarded-For header so that the upstream server knows the client's IP address.
+    // (We're the ones connecting directly to the upstream server, so without this header, the
+    // upstream server will only know our IP, not the client's.)
+    request::extend_header_value(&mut request, "x-forwarded-for", &client_ip);
+    // Likewise, tell the upstream which scheme and host the client originally asked for, so
+    // that it can generate absolute URLs. Connections are https when the proxy terminates TLS.
+    let proto = if tls { "https" } else { "http" };
+    request::extend_header_value(&mut request, "x-forwarded-proto", proto);
+    if let Some(host) = request
+        .headers()
+        .get("host")
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string())
+    {
+        request::extend_header_value(&mut request, "x-forwarded-host", &host);
+    }
+    // Pass on who the client's certificate says it is, making sure the client can't claim to
+    // be someone else by sending the header itself
+    if let Some(header) = &state.client_cert_header {
+        request.headers_mut().remove(header);
+        if let Some(value) =
+            client_identity.and_then(|identity| http::HeaderValue::from_str(identity).ok())
+        {
+            request.headers_mut().insert(header, value);
+        }
+    }
+    // Take part in the client's distributed trace (or start one), so that the hop through the
+    // proxy can be told apart in it
+    let trace = TraceContext::propagate(&mut request);
+    tracing::Span::current()
+        .record("trace_id", trace.trace_id.as_str())
+        .record("span_id", trace.span_id.as_str());
+    tracing::debug!("Forwarding with traceparent {}", trace.traceparent());
+    // Upstreams may serve under a different path than clients ask for
+    request::rewrite_path(
+        &mut request,
+        state.strip_path_prefix.as_deref(),
+        state.add_path_prefix.as_deref(),
+    );
+
+    // --loopback-upstream answers right here, without an upstream round trip
+    let mut response = match &state.loopback_body {
+        Some(body) => response::make_loopback_response(body, request.method()),
+        None => match idempotent_request(
+            state,
+            upstream_conn,
+            upstream_ip,
+            client_addresses,
+            upstreams,
+            &mut request,
+        )
+        .await
+        {
+            Ok(response) => response,
+            Err(response) => {
+                send_response(client_conn, &response).await;
+                return false;
+            }
+        },
+    };
+    response.headers_mut().remove("connection");
+    response.headers_mut().remove("keep-alive");
+
+    if let Some(content_type) = blocked_content_type(state, &response) {
+        tracing::warn!(
+            "Blocking {} response from {} to {}",
+            content_type,
+            upstream_ip,
+            client_ip
+        );
+        response = response::make_http_error(http::StatusCode::FORBIDDEN);
+    } else {
+        rewrite_response(state, request.method(), &mut response);
+    }
+    if let Err(err) = decompress_response(state, &request, &mut response) {
+        tracing::warn!(
+            "Could not decompress response from {} for {}: {}",
+            upstream_ip,
+            client_ip,
+            err
+        );
+        response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
+    }
+
+    // If the upstream was marked unhealthy while we were using it, ask the client to reconnect
+    // so that its next request gets balanced onto a healthy upstream
+    let draining = state.drain_on_unhealthy
+        && state.loopback_body.is_none()
+        && !state
+            .living_upstream_addresses
+            .read()
+            .await
+            .contains(upstream_ip.as_str());
+    // Likewise, ask the client to go away if we're shutting down, or if this response uses up
+    // what's left of --max-bytes-per-connection
+    let shutting_down = state.is_shutting_down();
+    let over_limit = client_conn.reaches_limit(response.body().len());
+    set_connection_header(
+        state,
+        &mut response,
+        request.version(),
+        client_closing || draining || shutting_down || over_limit,
+    );
+
+    // Forward the response to the client
+    send_response(client_conn, &response).await;
+    tracing::debug!("Forwarded response to client");
+
+    if draining {
+        tracing::info!(
+            "Upstream {} is unhealthy, closing connection from {}",
+            upstream_ip,
+            client_ip
+        );
+        return false;
+    }
+    if client_closing {
+        tracing::debug!("Client asked to close the connection");
+        return false;
+    }
+    if shutting_down {
+        tracing::debug!("Shutting down, closing connection from {}", client_ip);
+        return false;
+    }
+    if over_limit {
+        tracing::info!(
+            "{} reached the limit of {} bytes per connection, closing connection",
+            client_ip,
+            state.max_bytes_per_connection
+        );
+        return false;
+    }
+    true
+}
+
+/// An upstream response shared between coalesced requests (None if it can't be shared).
+type SharedResponse = Option<Arc<http::Response<Vec<u8>>>>;
+
+/// Returns the key identifying requests that would get the same response as `request` with
+/// --coalesce-requests, or None if it mustn't be coalesced with others (see `sharing_key`).
+fn coalescing_key(state: &ProxyState, request: &http::Request<Vec<u8>>) -> Option<String> {
+    if !state.coalesce_requests {
+        return None;
+    }
+    sharing_key(request)
+}
+
+/// Returns the key to cache the response to `request` under with --response-cache-entries, or
+/// None if it mustn't be answered from the cache: it mustn't be shared (see `sharing_key`), or the
+/// client made it conditional or asked for part of the response, so it wants an answer only the
+/// upstream can give.
+fn cache_key(state: &ProxyState, request: &http::Request<Vec<u8>>) -> Option<String> {
+    let headers = request.headers();
+    if state.response_cache.is_none()
+        || [
+            "if-none-match",
+            "if-modified-since",
+            "if-match",
+            "if-unmodified-since",
+            "range",
+        ]
+        .iter()
+        .any(|name| headers.contains_key(*name))
+    {
+        return None;
+    }
+    sharing_key(request)
+}
+
+/// Returns the key identifying requests that would get the same response as `request`, or None if
+/// its response mustn't be shared with others: it isn't a GET or HEAD, it carries a body or
+/// credentials, or the client asked for a fresh response.
+fn sharing_key(request: &http::Request<Vec<u8>>) -> Option<String> {
+    if !(request.method() == http::Method::GET || request.method() == http::Method::HEAD)
+        || !request.body().is_empty()
+    {
+        return None;
+    }
+    let headers = request.headers();
+    if headers.contains_key("authorization") || headers.contains_key("cookie") {
+        return None;
+    }
+    let no_cache = headers
+        .get_all("cache-control")
+        .iter()
+        .chain(headers.get_all("pragma"))
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|directive| {
+            let directive = directive.trim();
+            directive.eq_ignore_ascii_case("no-cache") || directive.eq_ignore_ascii_case("no-store")
+        });
+    if no_cache {
+        return None;
+    }
+    // The upstream may pick a different representation for clients that negotiate differently,
+    // and answer conditional and partial requests differently
+    let header = |name| {
+        headers
+            .get(name)
+            .and_then(|value: &http::HeaderValue| value.to_str().ok())
+            .unwrap_or("")
+    };
+    Some(format!(
+        "{} {} {}\n{}\n{}\n{}\n{}\n{}\n{}",
+        request.method(),
+        header("host"),
+        request.uri(),
+        header("accept"),
+        header("accept-encoding"),
+        header("accept-language"),
+        header("if-none-match"),
+        header("if-modified-since"),
+        header("range")
+    ))
+}
+
+/// Returns whether `response` may be given to clients other than the one it was meant for.
+fn is_shareable(response: &http::Response<Vec<u8>>) -> bool {
+    let headers = response.headers();
+    !headers.contains_key("set-cookie")
+        && !headers
+            .get_all("cache-control")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|directive| {
+                let directive = directive.trim();
+                directive.eq_ignore_ascii_case("private")
+                    || directive.eq_ignore_ascii_case("no-store")
+            })
+}
+
+/// A request that identical requests are waiting on (see `ProxyState::coalesced_requests`). If it
+/// is dropped before `finish` is called, they go ahead on their own.
+struct CoalescedRequest<'a> {
+    state: &'a ProxyState,
+    key: String,
+    finished: bool,
+}
+
+impl CoalescedRequest<'_> {
+    /// Hands `response` to the requests waiting on this one. Requests arriving after this go to the
+    /// upstream again.
+    fn finish(mut self, response: SharedResponse) {
+        self.finished = true;
+        if let Some(sender) = self.state.coalesced_requests.lock().remove(&self.key) {
+            // Nobody may be waiting
+            let _ = sender.send(response);
+        }
+    }
+}
+
+impl Drop for CoalescedRequest<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.state.coalesced_requests.lock().remove(&self.key);
+        }
+    }
+}
+
+/// Forwards `request` like `cached_request`, except that with --idempotency-key-ttl, a request
+/// with the same Idempotency-Key as one already forwarded is answered with that one's response
+/// (once it arrives, if it is still in flight) instead of being sent to the upstream again. Keys
+/// only match requests from the same client with the same method and path, and one reused with a
+/// different request is answered with a 422.
+async fn idempotent_request(
+    state: &ProxyState,
+    upstream_conn: &mut Option<Box<dyn upstream::Stream>>,
+    upstream_ip: &mut String,
+    client_addresses: (SocketAddr, SocketAddr),
+    upstreams: &[String],
+    request: &mut http::Request<Vec<u8>>,
+) -> Result<http::Response<Vec<u8>>, http::Response<Vec<u8>>> {
+    let key = request
+        .headers()
+        .get("idempotency-key")
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|key| !key.is_empty())
+        .map(str::to_string);
+    // Clients can't see or clash with each other's keys
+    let key = key.map(|key| {
+        format!(
+            "{} {} {} {}",
+            client_addresses.0.ip(),
+            request.method(),
+            request.uri().path(),
+            key
+        )
+    });
+    let (keys, key) = match (&state.idempotency_keys, key) {
+        (Some(keys), Some(key)) => (keys, key),
+        _ => {
+            return cached_request(
+                state,
+                upstream_conn,
+                upstream_ip,
+                client_addresses,
+                upstreams,
+                request,
+            )
+            .await
+        }
+    };
+    let fingerprint = idempotency::fingerprint(request);
+    loop {
+        match keys.begin(&key, fingerprint) {
+            KeyState::Answered(response) => {
+                tracing::debug!("Answering with the response to an earlier request with this key");
+                return Ok(response);
+            }
+            KeyState::InFlight(mut receiver) => {
+                if let Ok(Some(response)) = receiver.recv().await {
+                    tracing::debug!("Answering with the response to the request with this key");
+                    return Ok(response::clone_response(&response));
+                }
+                // The upstream never answered it, so the key is free to be forwarded again
+                tracing::debug!("Request with this idempotency key failed, trying again");
+            }
+            KeyState::First(pending) => {
+                let result = cached_request(
+                    state,
+                    upstream_conn,
+                    upstream_ip,
+                    client_addresses,
+                    upstreams,
+                    request,
+                )
+                .await;
+                pending.finish(result.as_ref().ok());
+                return result;
+            }
+            KeyState::Mismatch => {
+                tracing::warn!("Idempotency key reused for a different request, rejecting it");
+                return Ok(response::make_http_error(
+                    http::StatusCode::UNPROCESSABLE_ENTITY,
+                ));
+            }
+            KeyState::Untracked => {
+                tracing::warn!("Too many idempotency keys in flight, forwarding without one");
+                return cached_request(
+                    state,
+                    upstream_conn,
+                    upstream_ip,
+                    client_addresses,
+                    upstreams,
+                    request,
+                )
+                .await;
+            }
+        }
+    }
+}
+
+/// Forwards `request` like `coalesce_request`, except that with --response-cache-entries, it is
+/// answered with a cached response while that is fresh. Once the cached response is stale, the
+/// upstream is asked whether it is still current: a 304 makes it fresh again and it is answered
+/// with, while a new response replaces it.
+async fn cached_request(
+    state: &ProxyState,
+    upstream_conn: &mut Option<Box<dyn upstream::Stream>>,
+    upstream_ip: &mut String,
+    client_addresses: (SocketAddr, SocketAddr),
+    upstreams: &[String],
+    request: &mut http::Request<Vec<u8>>,
+) -> Result<http::Response<Vec<u8>>, http::Response<Vec<u8>>> {
+    let (cache, key) = match (&state.response_cache, cache_key(state, request)) {
+        (Some(cache), Some(key)) => (cache, key),
+        _ => {
+            return coalesce_request(
+                state,
+                upstream_conn,
+                upstream_ip,
+                client_addresses,
+                upstreams,
+                request,
+            )
+            .await
+        }
+    };
+    let conditions = match cache.lookup(&key) {
+        Lookup::Fresh(response) => {
+            tracing::debug!("Answering from the cache");
+            return Ok(response);
+        }
+        Lookup::Stale(conditions) => conditions,
+        Lookup::Miss => Vec::new(),
+    };
+    for (name, value) in &conditions {
+        request.headers_mut().insert(*name, value.clone());
+    }
+    let mut response = coalesce_request(
+        state,
+        upstream_conn,
+        upstream_ip,
+        client_addresses,
+        upstreams,
+        request,
+    )
+    .await?;
+    if !conditions.is_empty() && response.status() == http::StatusCode::NOT_MODIFIED {
+        if let Some(cached) = cache.revalidated(&key, &response) {
+            tracing::debug!("Cached response is still current, answering with it");
+            return Ok(cached);
+        }
+        // The cached response was evicted while we asked, and the client didn't ask for a 304
+        tracing::debug!("Cached response is gone, asking again without conditions");
+        for (name, _) in &conditions {
+            request.headers_mut().remove(*name);
+        }
+        response = coalesce_request(
+            state,
+            upstream_conn,
+            upstream_ip,
+            client_addresses,
+            upstreams,
+            request,
+        )
+        .await?;
+    }
+    if !(is_shareable(&response) && cache.store(&key, &response)) {
+        cache.remove(&key);
+    }
+    Ok(response)
+}
+
+/// Forwards `request` like `forward_request`, except that with --coalesce-requests, a request
+/// identical to one already on its way to an upstream waits for that one's response and answers
+/// with a copy of it instead.
+async fn coalesce_request(
+    state: &ProxyState,
+    upstream_conn: &mut Option<Box<dyn upstream::Stream>>,
+    upstream_ip: &mut String,
+    client_addresses: (SocketAddr, SocketAddr),
+    upstreams: &[String],
+    request: &mut http::Request<Vec<u8>>,
+) -> Result<http::Response<Vec<u8>>, http::Response<Vec<u8>>> {
+    let key = match coalescing_key(state, request) {
+        Some(key) => key,
+        None => {
+            return forward_request(
+                state,
+                upstream_conn,
+                upstream_ip,
+                client_addresses,
+                upstreams,
+                request,
+            )
+            .await
+        }
+    };
+    // Subscribe while holding the lock, so that the response can't be sent in between
+    let waiting = {
+        let mut coalesced = state.coalesced_requests.lock();
+        match coalesced.get(&key) {
+            Some(sender) => Some(sender.subscribe()),
+            None => {
+                coalesced.insert(key.clone(), broadcast::channel(1).0);
+                None
+            }
+        }
+    };
+
+    match waiting {
+        Some(mut receiver) => {
+            if let Ok(Some(response)) = receiver.recv().await {
+                tracing::debug!("Answering with the response to an identical request");
+                return Ok(response::clone_response(&response));
+            }
+            // The request we waited on failed, or its response was only for that client
+            tracing::debug!("Identical request got no shareable response, sending our own");
+            forward_request(
+                state,
+                upstream_conn,
+                upstream_ip,
+                client_addresses,
+                upstreams,
+                request,
+            )
+            .await
+        }
+        None => {
+            let coalesced = CoalescedRequest {
+                state,
+                key,
+                finished: false,
+            };
+            let result = forward_request(
+                state,
+                upstream_conn,
+                upstream_ip,
+                client_addresses,
+                upstreams,
+                request,
+            )
+            .await;
+            coalesced.finish(match &result {
+                Ok(response) if is_shareable(response) => {
+                    Some(Arc::new(response::clone_response(response)))
+                }
+                _ => None,
+            });
+            result
+        }
+    }
+}
+
+/// Forwards `request` over the client's upstream connection, reconnecting (possibly to a different
+/// upstream out of `upstreams`) if the upstream closed it after the previous response or isn't one
+/// of `upstreams`. Returns the upstream's response, or the error response to send the client
+/// before hanging up.
+async fn forward_request(
+    state: &ProxyState,
+    upstream_conn: &mut Option<Box<dyn upstream::Stream>>,
+    upstream_ip: &mut String,
+    client_addresses: (SocketAddr, SocketAddr),
+    upstreams: &[String],
+    request: &mut http::Request<Vec<u8>>,
+) -> Result<http::Response<Vec<u8>>, http::Response<Vec<u8>>> {
+    // The client's previous request may have been for a virtual host served by other upstreams
+    if upstream_conn.is_some() && !upstreams.contains(upstream_ip) {
+        tracing::debug!(
+            "Upstream {} doesn't serve this host, moving the client to another",
+            upstream_ip
+        );
+        *upstream_conn = None;
+    }
+    let _request = loop {
+        // Reconnect if the upstream closed the connection after the previous response
+        if upstream_conn.is_none() {
+            match connect_to_upstream(state, client_addresses, upstreams).await {
+                Ok((upstream, ip)) => {
+                    tracing::Span::current().record("upstream", ip.as_str());
+                    *upstream_ip = ip;
+                    *upstream_conn = Some(upstream);
+                }
+                Err(error) => return Err(make_connect_error(state, error)),
+            }
+        }
+        match start_upstream_request(state, upstream_ip) {
+            Some(request) => break request,
+            None => {
+                tracing::debug!(
+                    "Upstream {} is drained, moving the client to another",
+                    upstream_ip
+                );
+                *upstream_conn = None;
+            }
+        }
+    };
+    let upstream = upstream_conn.as_mut().unwrap();
+
+    // Name-based upstreams may expect a different Host than the client asked for. The client's
+    // is still in X-Forwarded-Host. HTTP/1.0 clients may not send a Host at all, which HTTP/1.1
+    // upstreams are entitled to reject, so make one up for them.
+    if state.upstream_host_header(upstream_ip).is_some() || !request.headers().contains_key("host")
+    {
+        request
+            .headers_mut()
+            .insert("host", state.upstream_host(upstream_ip));
+    }
+
+    // Forward the request to the server
+    let sent_at = Instant::now();
+    if let Err(error) = request::write_to_stream(request, upstream).await {
+        tracing::error!(
+            "Failed to send request to upstream {}: {}",
+            upstream_ip,
+            error
+        );
+        return Err(response::make_http_error(http::StatusCode::BAD_GATEWAY));
+    }
+    tracing::debug!("Forwarded request to server");
+
+    // Read the server's response
+    let response =
+        match response::read_from_stream(upstream, request.method(), state.io_buffer_bytes).await {
+            Ok(response) => response,
+            Err(error) => {
+                tracing::error!("Error reading response from server: {}", error);
+                return Err(response::make_http_error(http::StatusCode::BAD_GATEWAY));
+            }
+        };
+
+    let elapsed = sent_at.elapsed();
+    record_upstream_latency(state, elapsed);
+    record_concurrency_sample(state, upstream_ip, elapsed);
+    record_upstream_status(state, upstream_ip, response.status()).await;
+
+    // Connection headers only apply to a single hop: the upstream closing its connection to us
+    // doesn't mean the client has to close its connection, and vice versa
+    if wants_close(response.headers(), response.version()) {
+        tracing::debug!("Upstream {} closed the connection", upstream_ip);
+        *upstream_conn = None;
+    }
+    Ok(response)
+}
+
+/// Tells the client whether the connection stays open after `response`, which answers a request
+/// using HTTP `version`, and how long it may then stay idle. HTTP/1.0 clients expect it to be closed
+/// unless told otherwise.
+fn set_connection_header(
+    state: &ProxyState,
+    response: &mut http::Response<Vec<u8>>,
+    version: http::Version,
+    closing: bool,
+) {
+    if closing {
+        response
+            .headers_mut()
+            .insert("connection", http::HeaderValue::from_static("close"));
+        return;
+    }
+    if version <= http::Version::HTTP_10 {
+        response
+            .headers_mut()
+            .insert("connection", http::HeaderValue::from_static("keep-alive"));
+    }
+    // Let the client know when we'll close the connection, so that it doesn't send its next
+    // request just as we do
+    if state.keepalive_timeout > 0 {
+        response.headers_mut().insert(
+            "keep-alive",
+            http::HeaderValue::from_str(&format!("timeout={}", state.keepalive_timeout)).unwrap(),
+        );
+    }
+}
+
+/// Returns whether a message with these headers means the sender will close the connection after
+/// it: either it says `Connection: close`, or it is HTTP/1.0 and doesn't ask for keep-alive.
+fn wants_close(headers: &http::HeaderMap, version: http::Version) -> bool {
+    let has_token = |token: &str| {
+        headers
+            .get_all("connection")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|value| value.trim().eq_ignore_ascii_case(token))
+    };
+    if version <= http::Version::HTTP_10 {
+        !has_token("keep-alive")
+    } else {
+        has_token("close")
+    }
+}
@@ -1,333 +1,716 @@
-mod request;
-mod response;
-
-use std::{
-    collections::{HashMap, HashSet},
-    sync::Arc,
-    time::Duration,
+use balancebeam::{
+    tls, upstream, BalancingStrategy, Config, Proxy, RateLimitKey, RateLimitRule, RouteMatch,
 };
-
 use clap::Parser;
-use rand::{seq::IteratorRandom, SeedableRng};
-use tokio::{
-    net::{TcpListener, TcpStream},
-    sync::RwLock,
-};
+use std::collections::HashMap;
+use std::io::IsTerminal;
 
 /// Contains information parsed from the command-line invocation of balancebeam. The Clap macros
 /// provide a fancy way to automatically construct a command-line argument parser.
 #[derive(Parser, Debug)]
 #[command(about = "Fun with load balancing")]
 struct CmdOptions {
-    /// "IP/port to bind to"
+    /// "IP/port to bind to (may be given more than once)"
     #[arg(short, long, default_value = "0.0.0.0:1100")]
-    bind: String,
-    /// "Upstream host to forward requests to"
+    bind: Vec<String>,
+    /// "Let this many connections wait to be accepted on each listening socket"
+    #[arg(long, default_value = "1024")]
+    listen_backlog: u32,
+    /// "Set TCP_NODELAY on client and upstream connections, sending small writes right away"
+    #[arg(long)]
+    tcp_nodelay: bool,
+    /// "Upstream host to forward requests to (host:port, or unix:<path> for a Unix socket)"
     #[arg(short, long)]
     upstream: Vec<String>,
+    /// "Add an upstream to a named group for --virtual-host and --route, given as <group>=<upstream> (may be
+    /// given more than once)"
+    #[arg(long, value_parser = parse_upstream_group)]
+    upstream_group: Vec<(String, String)>,
+    /// "Send requests for a host to an --upstream-group, given as <host>=<group> (may be given more
+    /// than once; other hosts go to --upstream, or get a 404 without it)"
+    #[arg(long, value_parser = parse_virtual_host)]
+    virtual_host: Vec<(String, String)>,
+    /// "Send requests using a method or for a path prefix to the first --upstream-group in a list
+    /// with a living upstream, given as <METHOD or /prefix>=<group>[,<group>...] (may be given
+    /// more than once; the first match wins)"
+    #[arg(long, value_parser = parse_route)]
+    route: Vec<(RouteMatch, Vec<String>)>,
     /// "Perform active health checks on this interval (in seconds)"
     #[arg(long, default_value = "10")]
     active_health_check_interval: usize,
     /// "Path to send request to for active health checks"
     #[arg(long, default_value = "/")]
     active_health_check_path: String,
+    /// "Longest to wait between health checks of an upstream that keeps failing them, in seconds
+    /// (the wait doubles after each failure; 0 = check on every interval)"
+    #[arg(long, default_value = "0")]
+    health_check_max_backoff: usize,
+    /// "http:// URL to POST {upstream, state, timestamp} JSON to whenever an upstream becomes
+    /// unhealthy or healthy again"
+    #[arg(long)]
+    health_webhook: Option<http::Uri>,
+    /// "Only tell --health-webhook about an upstream's new state once it has held this long, in
+    /// milliseconds"
+    #[arg(long, default_value = "1000")]
+    health_webhook_debounce_ms: u64,
     /// "Maximum number of requests to accept per IP per minute (0 = unlimited)"
     #[arg(long, default_value = "0")]
     max_requests_per_minute: usize,
+    /// "Limit requests using a method and/or for a path prefix separately, given as
+    /// <METHOD or /prefix>[,...]=<limit per minute>[/ip|/all|/header:<name>], counted per client
+    /// IP by default (may be given more than once; the first match wins, and requests matching
+    /// none are limited by --max-requests-per-minute; a limit of 0 means unlimited)"
+    #[arg(long, value_parser = parse_rate_limit_rule)]
+    rate_limit_rule: Vec<RateLimitRule>,
+    /// "Status to reply to requests over --max-requests-per-minute with"
+    #[arg(long, default_value = "429", value_parser = parse_status)]
+    rate_limit_status: http::StatusCode,
+    /// "Body to reply to requests over --max-requests-per-minute with"
+    #[arg(long, conflicts_with = "rate_limit_body_file")]
+    rate_limit_body: Option<String>,
+    /// "File holding the body to reply to requests over --max-requests-per-minute with"
+    #[arg(long)]
+    rate_limit_body_file: Option<String>,
+    /// "Content-Type of --rate-limit-body (e.g. application/json)"
+    #[arg(long, default_value = "text/plain; charset=utf-8", value_parser = parse_content_type)]
+    rate_limit_content_type: http::HeaderValue,
+    /// "How to pick the upstream for each new upstream connection"
+    #[arg(long, value_enum, default_value = "random")]
+    balancing_strategy: BalancingStrategy,
+    /// "Seed for the random number generator used to pick upstreams (random if unset)"
+    #[arg(long)]
+    rng_seed: Option<u64>,
+    /// "Close client connections bound to an upstream once it is marked unhealthy"
+    #[arg(long)]
+    drain_on_unhealthy: bool,
+    /// "Take an upstream out of rotation after this many consecutive 5xx responses (0 = never)"
+    #[arg(long, default_value = "0")]
+    consecutive_errors: usize,
+    /// "Connect to upstreams over TLS (upstreams can also opt in individually with https://)"
+    #[arg(long)]
+    upstream_tls: bool,
+    /// "Don't verify the certificates of TLS upstreams"
+    #[arg(long)]
+    upstream_tls_insecure: bool,
+    /// "HTML file to serve (with a 503) when every upstream is down"
+    #[arg(long)]
+    maintenance_page: Option<String>,
+    /// "Send a PROXY protocol header with the client's address when connecting to upstreams"
+    #[arg(long, value_enum)]
+    send_proxy_protocol: Option<upstream::ProxyProtocol>,
+    /// "Seconds a client may take to send each request before it is disconnected (0 = no limit)"
+    #[arg(long, default_value = "0")]
+    client_idle_timeout: u64,
+    /// "Seconds a client may go without sending anything partway through a request before it is
+    /// disconnected (0 = no limit)"
+    #[arg(long, default_value = "0")]
+    client_read_timeout: u64,
+    /// "Most bytes the request line and headers of a request may take up (more get a 431)"
+    #[arg(long, default_value = "8000")]
+    max_header_bytes: usize,
+    /// "Seconds a client may leave its connection idle between requests before it is closed (0 =
+    /// no limit)"
+    #[arg(long, default_value = "0")]
+    keepalive_timeout: u64,
+    /// "Close client connections once this many bytes have been read from and written to them in
+    /// total, after the response that reaches the limit (0 = no limit)"
+    #[arg(long, default_value = "0")]
+    max_bytes_per_connection: usize,
+    /// "Reply 503 to new requests while more than this many are in flight (0 = no limit)"
+    #[arg(long, default_value = "0")]
+    shed_at_inflight: usize,
+    /// "Reply 503 to new requests while upstreams average more than this many ms (0 = no limit)"
+    #[arg(long, default_value = "0")]
+    shed_at_latency_ms: u64,
+    /// "Open at most this many connections to each upstream at once (0 = no limit)"
+    #[arg(long, default_value = "0")]
+    max_upstream_connections: usize,
+    /// "Adapt each upstream's connection limit to its latency, lowering it when the latency climbs
+    /// (capped by --max-upstream-connections)"
+    #[arg(long)]
+    adaptive_concurrency: bool,
+    /// "Seconds a client may wait for a connection slot when every upstream is full (0 = no wait)"
+    #[arg(long, default_value = "0")]
+    queue_timeout: u64,
+    /// "Maximum number of clients that may wait for a connection slot at once"
+    #[arg(long, default_value = "100")]
+    max_queued: usize,
+    /// "Open this many idle connections to each upstream at startup, for the first requests to use"
+    #[arg(long, default_value = "0", conflicts_with = "send_proxy_protocol")]
+    warm_connections: usize,
+    /// "Rewrite upstream responses with status <from> to status <to>, given as <from>:<to> (may be
+    /// given more than once)"
+    #[arg(long, value_parser = parse_status_rewrite)]
+    rewrite_status: Vec<(http::StatusCode, http::StatusCode)>,
+    /// "Serve an HTML file in place of the body of upstream responses with status <code>, given as
+    /// <code>:<path> (may be given more than once)"
+    #[arg(long, value_parser = parse_error_page)]
+    error_page: Vec<(http::StatusCode, String)>,
+    /// "Replace upstream responses with this Content-Type with a 403 (may be given more than once)"
+    #[arg(long)]
+    block_response_content_type: Vec<String>,
+    /// "Decompress gzip and deflate upstream responses for clients whose Accept-Encoding doesn't
+    /// include the response's Content-Encoding"
+    #[arg(long)]
+    decompress_responses: bool,
+    /// "Only forward requests using this method (may be given more than once; default: any)"
+    #[arg(long, value_parser = parse_method)]
+    allow_method: Vec<http::Method>,
+    /// "Only forward requests for paths starting with this (may be given more than once)"
+    #[arg(long)]
+    allow_path_prefix: Vec<String>,
+    /// "Send this Host header to upstreams instead of the client's, given as <host> for every
+    /// upstream or <upstream>=<host> for one (may be given more than once)"
+    #[arg(long, value_parser = parse_upstream_host_header)]
+    upstream_host_header: Vec<(Option<String>, http::HeaderValue)>,
+    /// "Remove this prefix from the paths of requests that start with it before forwarding them"
+    #[arg(long, value_parser = parse_path_prefix)]
+    strip_path_prefix: Option<String>,
+    /// "Add this prefix to the paths of requests before forwarding them (after
+    /// --strip-path-prefix)"
+    #[arg(long, value_parser = parse_path_prefix)]
+    add_path_prefix: Option<String>,
+    /// "Answer every request with a canned 200 instead of proxying it (for benchmarking)"
+    #[arg(long)]
+    loopback_upstream: bool,
+    /// "Size in bytes of the body of --loopback-upstream responses"
+    #[arg(long, default_value = "64")]
+    loopback_body_size: usize,
+    /// "Shut down gracefully after receiving this many requests (0 = never)"
+    #[arg(long, default_value = "0", conflicts_with = "shutdown_after_seconds")]
+    shutdown_after_requests: usize,
+    /// "Shut down gracefully after serving for this many seconds (0 = never)"
+    #[arg(long, default_value = "0")]
+    shutdown_after_seconds: u64,
+    /// "Answer requests for this path with a 200 for as long as balancebeam is running"
+    #[arg(long)]
+    liveness_path: Option<String>,
+    /// "Answer requests for this path with a 200 while any upstream is alive, and a 503 otherwise"
+    #[arg(long)]
+    readiness_path: Option<String>,
+    /// "Serve clients over TLS with this PEM certificate chain"
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<String>,
+    /// "PEM private key for --tls-cert"
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<String>,
+    /// "Require clients to present a certificate signed by one of the CAs in this PEM file"
+    #[arg(long, requires = "tls_cert")]
+    client_ca: Option<String>,
+    /// "Header to tell upstreams the name in the client's certificate with (see --client-ca)"
+    #[arg(long, default_value = "X-Client-Cert-CN")]
+    client_cert_header: String,
+    /// "Serve the admin API (e.g. for draining upstreams) on this address, which clients shouldn't
+    /// be able to reach"
+    #[arg(long)]
+    admin_bind: Option<String>,
+    /// "Read request and response bodies this many bytes at a time"
+    #[arg(long, default_value = "16384", value_parser = parse_buffer_size)]
+    io_buffer_bytes: usize,
+    /// "Send identical GET and HEAD requests that arrive while one is already in flight to the
+    /// upstream only once, answering them all with its response"
+    #[arg(long)]
+    coalesce_requests: bool,
+    /// "Cache up to this many upstream responses, answering requests with them while they are
+    /// fresh and revalidating them with the upstream once they are stale (0 = no caching)"
+    #[arg(long, default_value = "0")]
+    response_cache_entries: usize,
+    /// "Answer requests whose Idempotency-Key was already seen in the last this many seconds with
+    /// the response to the first of them, instead of forwarding them again (0 = ignore the header)"
+    #[arg(long, default_value = "0")]
+    idempotency_key_ttl: u64,
+    /// "Remember at most this many idempotency keys, forgetting the one answered longest ago to
+    /// make room for another"
+    #[arg(long, default_value = "10000")]
+    idempotency_max_keys: usize,
+    /// "How to print traces: human-readable lines, or one JSON object per line"
+    #[arg(long, value_enum, default_value = "pretty")]
+    trace_format: TraceFormat,
+    /// "Check the configuration and report any problems, then exit without serving"
+    #[arg(long)]
+    check_config: bool,
 }
 
-/// Contains information about the state of balancebeam (e.g. what servers we are currently proxying
-/// to, what servers have failed, rate limiting counts, etc.)
-///
-/// You should add fields to this struct in later milestones.
-#[derive(Clone)]
-struct ProxyState {
-    /// How frequently we check whether upstream servers are alive (Milestone 4)
-    #[allow(dead_code)]
-    active_health_check_interval: usize,
-    /// Where we should send requests when doing active health checks (Milestone 4)
-    #[allow(dead_code)]
-    active_health_check_path: String,
-    /// Maximum number of requests an individual IP can make in a minute (Milestone 5)
-    #[allow(dead_code)]
-    max_requests_per_minute: usize,
-    /// Addresses of servers that we are proxying to
-    upstream_addresses: Vec<String>,
-    /// living addresses record, read-write-lock has better performance, maybe
-    living_upstream_addresses: Arc<RwLock<HashSet<String>>>,
-    /// rate limiting counter
-    rate_limiter: Arc<RwLock<HashMap<String, usize>>>,
+/// Formats for the traces balancebeam prints (see --trace-format).
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+enum TraceFormat {
+    Pretty,
+    Json,
 }
 
 #[tokio::main]
 async fn main() {
-    // Initialize the logging library. You can print log messages using the `log` macros:
-    // https://docs.rs/log/0.4.8/log/ You are welcome to continue using print! statements; this
-    // just looks a little prettier.
-    if std::env::var("RUST_LOG").is_err() {
-        std::env::set_var("RUST_LOG", "debug");
-    }
-    pretty_env_logger::init();
-
     // Parse the command line arguments passed to this program
     let options = CmdOptions::parse();
-    if options.upstream.is_empty() {
-        log::error!("At least one upstream server must be specified using the --upstream option.");
+
+    // Print traces to stderr. Each connection and each request gets a span (see the `tracing`
+    // crate: https://docs.rs/tracing), so every event logged while handling a request carries the
+    // client's IP, the request's ID and the upstream it went to. Records from crates that log with
+    // the `log` macros are passed through as events too.
+    let filter = tracing_subscriber::EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("debug"));
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        // Colors would only clutter log files
+        .with_ansi(std::io::stderr().is_terminal())
+        .with_writer(std::io::stderr);
+    match options.trace_format {
+        TraceFormat::Pretty => subscriber.init(),
+        TraceFormat::Json => subscriber.json().init(),
+    }
+
+    if options.check_config {
+        let valid = check_config(&options).await;
+        std::process::exit(if valid { 0 } else { 1 });
+    }
+    if options.upstream.is_empty()
+        && options.upstream_group.is_empty()
+        && !options.loopback_upstream
+    {
+        tracing::error!(
+            "At least one upstream server must be specified using the --upstream option."
+        );
         std::process::exit(1);
     }
 
-    // Start listening for connections
-    let listener = match TcpListener::bind(&options.bind).await {
-        Ok(listener) => listener,
-        Err(err) => {
-            log::error!("Could not bind to {}: {}", options.bind, err);
-            std::process::exit(1);
+    let maintenance_page = match &options.maintenance_page {
+        Some(path) => match std::fs::read(path) {
+            Ok(page) => Some(page),
+            Err(err) => {
+                tracing::error!("Could not read maintenance page {}: {}", path, err);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let rate_limit_body = match &options.rate_limit_body_file {
+        Some(path) => match std::fs::read(path) {
+            Ok(body) => Some(body),
+            Err(err) => {
+                tracing::error!("Could not read rate limit body {}: {}", path, err);
+                std::process::exit(1);
+            }
+        },
+        None => options.rate_limit_body.clone().map(String::into_bytes),
+    };
+
+    let mut error_pages = HashMap::new();
+    for (status, path) in &options.error_page {
+        match std::fs::read(path) {
+            Ok(page) => {
+                error_pages.insert(*status, page);
+            }
+            Err(err) => {
+                tracing::error!("Could not read error page {}: {}", path, err);
+                std::process::exit(1);
+            }
         }
+    }
+
+    let read_pem = |path: &Option<String>, what: &str| {
+        path.as_ref().map(|path| {
+            std::fs::read(path).unwrap_or_else(|err| {
+                tracing::error!("Could not read {} {}: {}", what, path, err);
+                std::process::exit(1);
+            })
+        })
     };
-    log::info!("Listening for requests on {}", options.bind);
+    let tls_cert = read_pem(&options.tls_cert, "TLS certificate");
+    let tls_key = read_pem(&options.tls_key, "TLS key");
+    let client_ca = read_pem(&options.client_ca, "client CA");
+
+    let mut upstream_host_header = None;
+    let mut upstream_host_headers = HashMap::new();
+    for (upstream, host) in options.upstream_host_header {
+        match upstream {
+            Some(upstream) => {
+                upstream_host_headers.insert(upstream, host);
+            }
+            None => upstream_host_header = Some(host),
+        }
+    }
 
-    // Handle incoming connections
-    let state = ProxyState {
-        upstream_addresses: options.upstream.clone(),
+    let mut upstream_groups: HashMap<String, Vec<String>> = HashMap::new();
+    for (group, upstream) in options.upstream_group {
+        upstream_groups.entry(group).or_default().push(upstream);
+    }
+
+    let config = Config {
+        bind: options.bind,
+        listen_backlog: options.listen_backlog,
+        tcp_nodelay: options.tcp_nodelay,
+        upstream: options.upstream,
+        upstream_groups,
+        virtual_hosts: options.virtual_host.into_iter().collect(),
+        routes: options.route,
         active_health_check_interval: options.active_health_check_interval,
         active_health_check_path: options.active_health_check_path,
+        health_check_max_backoff: options.health_check_max_backoff,
+        health_webhook: options.health_webhook,
+        health_webhook_debounce_ms: options.health_webhook_debounce_ms,
         max_requests_per_minute: options.max_requests_per_minute,
-        living_upstream_addresses: Arc::new(RwLock::new(options.upstream.into_iter().collect())),
-        rate_limiter: Arc::new(RwLock::new(HashMap::new())),
+        rate_limit_rules: options.rate_limit_rule,
+        rate_limit_status: options.rate_limit_status,
+        rate_limit_body,
+        rate_limit_content_type: options.rate_limit_content_type,
+        balancing_strategy: options.balancing_strategy,
+        rng_seed: options.rng_seed,
+        drain_on_unhealthy: options.drain_on_unhealthy,
+        consecutive_errors: options.consecutive_errors,
+        upstream_tls: options.upstream_tls,
+        upstream_tls_insecure: options.upstream_tls_insecure,
+        maintenance_page,
+        send_proxy_protocol: options.send_proxy_protocol,
+        client_idle_timeout: options.client_idle_timeout,
+        client_read_timeout: options.client_read_timeout,
+        max_header_bytes: options.max_header_bytes,
+        keepalive_timeout: options.keepalive_timeout,
+        max_bytes_per_connection: options.max_bytes_per_connection,
+        shed_at_inflight: options.shed_at_inflight,
+        shed_at_latency_ms: options.shed_at_latency_ms,
+        max_upstream_connections: options.max_upstream_connections,
+        adaptive_concurrency: options.adaptive_concurrency,
+        queue_timeout: options.queue_timeout,
+        max_queued: options.max_queued,
+        warm_connections: options.warm_connections,
+        status_rewrites: options.rewrite_status.into_iter().collect(),
+        error_pages,
+        blocked_content_types: options
+            .block_response_content_type
+            .iter()
+            .map(|content_type| balancebeam::media_type(content_type))
+            .collect(),
+        allowed_methods: options.allow_method,
+        allowed_path_prefixes: options.allow_path_prefix,
+        upstream_host_header,
+        upstream_host_headers,
+        strip_path_prefix: options.strip_path_prefix,
+        add_path_prefix: options.add_path_prefix,
+        loopback_upstream: options
+            .loopback_upstream
+            .then_some(options.loopback_body_size),
+        shutdown_after_requests: options.shutdown_after_requests,
+        shutdown_after_seconds: options.shutdown_after_seconds,
+        liveness_path: options.liveness_path,
+        readiness_path: options.readiness_path,
+        tls_cert,
+        tls_key,
+        client_ca,
+        client_cert_header: options.client_cert_header,
+        admin_bind: options.admin_bind,
+        io_buffer_bytes: options.io_buffer_bytes,
+        coalesce_requests: options.coalesce_requests,
+        response_cache_entries: options.response_cache_entries,
+        idempotency_key_ttl: options.idempotency_key_ttl,
+        idempotency_max_keys: options.idempotency_max_keys,
+        decompress_responses: options.decompress_responses,
     };
-
-    // do active health check
-    let stat = state.clone();
-    tokio::spawn(async move {
-        active_health_check(&stat).await;
-    });
-
-    // do rate limiting check
-    let stat = state.clone();
-    tokio::spawn(async move {
-        let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(60));
-        loop {
-            interval.tick().await;
-            let mut limiter = stat.rate_limiter.write().await;
-            limiter.clear();
-        }
-    });
-
-    // Handle the connection!
-    loop {
-        if let Ok((stream, _)) = listener.accept().await {
-            let state = state.clone();
-            tokio::spawn(async move {
-                handle_connection(stream, &state).await;
-            });
+    match Proxy::new(config) {
+        Ok(proxy) => proxy.run().await,
+        Err(err) => {
+            tracing::error!("{}", err);
+            std::process::exit(1);
         }
     }
 }
 
-/// simply using fixed window
-async fn rate_limit_check(
-    state: &ProxyState,
-    client_conn: &mut TcpStream,
-    client_ip: &String,
-) -> Result<(), std::io::Error> {
-    let mut rate = state.rate_limiter.write().await;
-    let count = rate.entry(client_ip.to_string()).or_insert(0);
-    *count += 1;
-    if *count > state.max_requests_per_minute {
-        let res = response::make_http_error(http::StatusCode::TOO_MANY_REQUESTS);
-        if let Err(err) = response::write_to_stream(&res, client_conn).await {
-            log::error!("Failed to response client {}: {}", client_ip, err)
+/// Checks everything about the configuration that we can without serving (for --check-config),
+/// printing a line for each check. Returns whether balancebeam would be able to start.
+async fn check_config(options: &CmdOptions) -> bool {
+    let mut problems = 0;
+    let mut report = |result: Result<String, String>| match result {
+        Ok(message) => println!("ok: {}", message),
+        Err(message) => {
+            println!("error: {}", message);
+            problems += 1;
         }
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            "Too many requests",
-        ));
+    };
+
+    for bind in &options.bind {
+        report(match resolve(bind).await {
+            Ok(()) => Ok(format!("bind address {}", bind)),
+            Err(err) => Err(format!("bind address {}: {}", bind, err)),
+        });
+    }
+    if let Some(bind) = &options.admin_bind {
+        report(match resolve(bind).await {
+            Ok(()) => Ok(format!("admin bind address {}", bind)),
+            Err(err) => Err(format!("admin bind address {}: {}", bind, err)),
+        });
+    }
+    if options.upstream.is_empty()
+        && options.upstream_group.is_empty()
+        && !options.loopback_upstream
+    {
+        report(Err("no upstream servers given (use --upstream)".to_string()));
+    }
+    for (host, group) in &options.virtual_host {
+        if !options.upstream_group.iter().any(|(name, _)| name == group) {
+            report(Err(format!(
+                "virtual host {}: no upstream group {}",
+                host, group
+            )));
+        }
+    }
+    for (matcher, groups) in &options.route {
+        for group in groups {
+            if !options.upstream_group.iter().any(|(name, _)| name == group) {
+                report(Err(format!(
+                    "route for {:?}: no upstream group {}",
+                    matcher, group
+                )));
+            }
+        }
+    }
+    let upstreams: Vec<&String> = options
+        .upstream
+        .iter()
+        .chain(options.upstream_group.iter().map(|(_, address)| address))
+        .collect();
+    for address in &upstreams {
+        let (host_port, _tls) = upstream::parse_address(address, options.upstream_tls);
+        let reachable = match upstream::unix_socket_path(host_port) {
+            Some(path) => check_socket(path),
+            None => resolve(host_port).await,
+        };
+        report(match reachable {
+            Ok(()) => Ok(format!("upstream {}", address)),
+            Err(err) => Err(format!("upstream {}: {}", address, err)),
+        });
+    }
+    if upstreams
+        .iter()
+        .any(|address| upstream::parse_address(address, options.upstream_tls).1)
+    {
+        report(
+            match upstream::make_tls_connector(options.upstream_tls_insecure) {
+                Ok(_) => Ok("TLS setup for upstream connections".to_string()),
+                Err(err) => Err(format!("TLS setup for upstream connections: {}", err)),
+            },
+        );
+    }
+    if let Some(path) = &options.maintenance_page {
+        report(match std::fs::read(path) {
+            Ok(_) => Ok(format!("maintenance page {}", path)),
+            Err(err) => Err(format!("maintenance page {}: {}", path, err)),
+        });
+    }
+    if let Some(path) = &options.rate_limit_body_file {
+        report(match std::fs::read(path) {
+            Ok(_) => Ok(format!("rate limit body {}", path)),
+            Err(err) => Err(format!("rate limit body {}: {}", path, err)),
+        });
+    }
+
+    if let (Some(cert_path), Some(key_path)) = (&options.tls_cert, &options.tls_key) {
+        let read = |path: &String| std::fs::read(path).map_err(|err| format!("{}: {}", path, err));
+        let acceptor = read(cert_path).and_then(|cert| {
+            let key = read(key_path)?;
+            let client_ca = options.client_ca.as_ref().map(read).transpose()?;
+            tls::make_tls_acceptor(&cert, &key, client_ca.as_deref()).map_err(|err| err.to_string())
+        });
+        report(match acceptor {
+            Ok(_) => Ok("TLS setup for client connections".to_string()),
+            Err(err) => Err(format!("TLS setup for client connections: {}", err)),
+        });
+    }
+
+    for (status, path) in &options.error_page {
+        report(match std::fs::read(path) {
+            Ok(_) => Ok(format!("error page for {} {}", status.as_u16(), path)),
+            Err(err) => Err(format!(
+                "error page for {} {}: {}",
+                status.as_u16(),
+                path,
+                err
+            )),
+        });
     }
-    Ok(())
-}
 
-async fn active_health_check(state: &ProxyState) {
-    loop {
-        tokio::time::sleep(Duration::new(state.active_health_check_interval as u64, 0)).await;
-
-        for upstream_ip in &state.upstream_addresses {
-            let request = http::Request::builder()
-                .method(http::Method::GET)
-                .uri(&state.active_health_check_path)
-                .header("Host", upstream_ip)
-                .body(Vec::new())
-                .unwrap();
-
-            match TcpStream::connect(upstream_ip).await {
-                Ok(mut upstream) => {
-                    if let Err(err) = request::write_to_stream(&request, &mut upstream).await {
-                        log::error!("Failed to request upstream {}: {}", upstream_ip, err);
-                        continue;
-                    }
-
-                    match response::read_from_stream(&mut upstream, request.method()).await {
-                        Ok(response) => {
-                            if response.status().as_u16() == 200 {
-                                // If a failed upstream returns HTTP 200, put it back in the rotation of upstream servers.
-                                let mut living = state.living_upstream_addresses.write().await;
-                                if !living.contains(upstream_ip) {
-                                    living.insert(upstream_ip.to_string());
-                                }
-                            } else {
-                                //  If an online upstream returns a non-200 status code, mark that server as failed.
-                                let mut living = state.living_upstream_addresses.write().await;
-                                if living.contains(upstream_ip) {
-                                    living.remove(upstream_ip);
-                                }
-                            }
-                        }
-                        Err(_) => {
-                            //  If an online upstream fails to return a response, mark that server as failed.
-                            log::error!("Failed to get response from the upstream {}", upstream_ip);
-                            let mut living = state.living_upstream_addresses.write().await;
-                            if living.contains(upstream_ip) {
-                                living.remove(upstream_ip);
-                            }
-                        }
-                    }
-                }
-                Err(err) => {
-                    log::error!("Failed to connect to upstream {}: {}", upstream_ip, err);
-                }
+    for (upstream, _host) in &options.upstream_host_header {
+        if let Some(upstream) = upstream {
+            if !upstreams.contains(&upstream) {
+                report(Err(format!(
+                    "upstream Host header for {}: not one of the upstreams",
+                    upstream
+                )));
             }
         }
     }
+
+    if problems == 0 {
+        println!("Configuration OK");
+        true
+    } else {
+        println!("Configuration has {} problem(s)", problems);
+        false
+    }
 }
 
-async fn connect_to_upstream(state: &ProxyState) -> Result<TcpStream, std::io::Error> {
-    loop {
-        let living = state.living_upstream_addresses.read().await;
-        let mut rng = rand::rngs::StdRng::from_entropy();
-        let upstream_ip = &living.iter().choose(&mut rng).unwrap().clone();
-        drop(living);
+/// Checks that a `host:port` address is well-formed and resolves to at least one IP address.
+async fn resolve(host_port: &str) -> Result<(), std::io::Error> {
+    match tokio::net::lookup_host(host_port).await?.next() {
+        Some(_) => Ok(()),
+        None => Err(std::io::Error::other("does not resolve to any address")),
+    }
+}
 
-        match TcpStream::connect(upstream_ip).await {
-            Ok(stream) => {
-                return Ok(stream);
-            }
-            Err(err) => {
-                log::error!("Failed to connect to upstream {}: {}", upstream_ip, err);
+/// Checks that there is a Unix socket at `path` (though not that anything is listening on it).
+fn check_socket(path: &str) -> Result<(), std::io::Error> {
+    use std::os::unix::fs::FileTypeExt;
+    if std::fs::metadata(path)?.file_type().is_socket() {
+        Ok(())
+    } else {
+        Err(std::io::Error::other("not a Unix socket"))
+    }
+}
 
-                let mut living = state.living_upstream_addresses.write().await;
-                living.remove(upstream_ip);
+/// Parses an HTTP method given on the command line, which may be in any case.
+fn parse_method(method: &str) -> Result<http::Method, String> {
+    http::Method::from_bytes(method.to_ascii_uppercase().as_bytes())
+        .map_err(|_| format!("invalid HTTP method {}", method))
+}
 
-                if living.is_empty() {
-                    log::error!("Failed to connect upstream: all upstreams are dead");
-                    return Err(err);
-                }
-            }
-        }
+/// Parses a buffer size given on the command line, which must be at least one byte.
+fn parse_buffer_size(value: &str) -> Result<usize, String> {
+    match value.parse() {
+        Ok(size) if size > 0 => Ok(size),
+        _ => Err(format!(
+            "invalid buffer size {} (must be a positive number of bytes)",
+            value
+        )),
     }
 }
 
-async fn send_response(client_conn: &mut TcpStream, response: &http::Response<Vec<u8>>) {
-    let client_ip = client_conn.peer_addr().unwrap().ip().to_string();
-    log::info!(
-        "{} <- {}",
-        client_ip,
-        response::format_response_line(response)
-    );
-    if let Err(error) = response::write_to_stream(response, client_conn).await {
-        log::warn!("Failed to send response to client: {}", error);
+/// Parses an HTTP status code given on the command line.
+fn parse_status(code: &str) -> Result<http::StatusCode, String> {
+    http::StatusCode::from_bytes(code.trim().as_bytes())
+        .map_err(|_| format!("invalid HTTP status code {}", code))
+}
+
+/// Parses a --rewrite-status value, e.g. `404:410`.
+fn parse_status_rewrite(value: &str) -> Result<(http::StatusCode, http::StatusCode), String> {
+    match value.split_once(':') {
+        Some((from, to)) => Ok((parse_status(from)?, parse_status(to)?)),
+        None => Err(format!("expected <from>:<to>, got {}", value)),
     }
 }
 
-async fn handle_connection(mut client_conn: TcpStream, state: &ProxyState) {
-    let client_ip = client_conn.peer_addr().unwrap().ip().to_string();
-    log::info!("Connection received from {}", client_ip);
-
-    // Open a connection to a random destination server
-    let mut upstream_conn = match connect_to_upstream(state).await {
-        Ok(stream) => stream,
-        Err(_error) => {
-            let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-            send_response(&mut client_conn, &response).await;
-            return;
-        }
+/// Parses a --rate-limit-content-type value.
+fn parse_content_type(value: &str) -> Result<http::HeaderValue, String> {
+    http::HeaderValue::from_str(value).map_err(|_| format!("invalid Content-Type {}", value))
+}
+
+/// Parses an --upstream-host-header value, e.g. `www.example.com` or
+/// `10.0.0.1:8080=www.example.com`.
+fn parse_upstream_host_header(value: &str) -> Result<(Option<String>, http::HeaderValue), String> {
+    let (upstream, host) = match value.split_once('=') {
+        Some((upstream, host)) => (Some(upstream.to_string()), host),
+        None => (None, value),
     };
-    let upstream_ip = upstream_conn.peer_addr().unwrap().ip().to_string();
-
-    // The client may now send us one or more requests. Keep trying to read requests until the
-    // client hangs up or we get an error.
-    loop {
-        // Read a request from the client
-        let mut request = match request::read_from_stream(&mut client_conn).await {
-            Ok(request) => request,
-            // Handle case where client closed connection and is no longer sending requests
-            Err(request::Error::IncompleteRequest(0)) => {
-                log::debug!("Client finished sending requests. Shutting down connection");
-                return;
-            }
-            // Handle I/O error in reading from the client
-            Err(request::Error::ConnectionError(io_err)) => {
-                log::info!("Error reading request from client stream: {}", io_err);
-                return;
-            }
-            Err(error) => {
-                log::debug!("Error parsing request: {:?}", error);
-                let response = response::make_http_error(match error {
-                    request::Error::IncompleteRequest(_)
-                    | request::Error::MalformedRequest(_)
-                    | request::Error::InvalidContentLength
-                    | request::Error::ContentLengthMismatch => http::StatusCode::BAD_REQUEST,
-                    request::Error::RequestBodyTooLarge => http::StatusCode::PAYLOAD_TOO_LARGE,
-                    request::Error::ConnectionError(_) => http::StatusCode::SERVICE_UNAVAILABLE,
-                });
-                send_response(&mut client_conn, &response).await;
-                continue;
-            }
-        };
-        log::info!(
-            "{} -> {}: {}",
-            client_ip,
-            upstream_ip,
-            request::format_request_line(&request)
-        );
+    match http::HeaderValue::from_str(host) {
+        Ok(host) if !host.is_empty() => Ok((upstream, host)),
+        _ => Err(format!("invalid Host header {}", host)),
+    }
+}
 
-        // check if too many request
-        if state.max_requests_per_minute > 0 {
-            if let Err(err) = rate_limit_check(state, &mut client_conn, &client_ip).await {
-                log::error!("rate limit: {}", err);
-                continue;
-            }
+/// Parses a --strip-path-prefix or --add-path-prefix value, which must be a path.
+fn parse_path_prefix(value: &str) -> Result<String, String> {
+    match value.parse::<http::uri::PathAndQuery>() {
+        Ok(path) if value.starts_with('/') && path.query().is_none() => Ok(value.to_string()),
+        _ => Err(format!("expected a path starting with /, got {}", value)),
+    }
+}
+
+/// Parses an --upstream-group value, e.g. `blog=10.0.0.1:8080`.
+fn parse_upstream_group(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((group, upstream)) if !group.is_empty() && !upstream.is_empty() => {
+            Ok((group.to_string(), upstream.to_string()))
         }
+        _ => Err(format!("expected <group>=<upstream>, got {}", value)),
+    }
+}
 
-        // Add X-Forwarded-For header so that the upstream server knows the client's IP address.
-        // (We're the ones connecting directly to the upstream server, so without this header, the
-        // upstream server will only know our IP, not the client's.)
-        request::extend_header_value(&mut request, "x-forwarded-for", &client_ip);
-
-        // Forward the request to the server
-        if let Err(error) = request::write_to_stream(&request, &mut upstream_conn).await {
-            log::error!(
-                "Failed to send request to upstream {}: {}",
-                upstream_ip,
-                error
-            );
-            let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-            send_response(&mut client_conn, &response).await;
-            return;
+/// Parses a --virtual-host value, e.g. `blog.example.com=blog`.
+fn parse_virtual_host(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((host, group)) if !host.is_empty() && !group.is_empty() => {
+            Ok((host.to_string(), group.to_string()))
         }
-        log::debug!("Forwarded request to server");
-
-        // Read the server's response
-        let response = match response::read_from_stream(&mut upstream_conn, request.method()).await
-        {
-            Ok(response) => response,
-            Err(error) => {
-                log::error!("Error reading response from server: {:?}", error);
-                let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-                send_response(&mut client_conn, &response).await;
-                return;
+        _ => Err(format!("expected <host>=<group>, got {}", value)),
+    }
+}
+
+/// Parses a --route value, e.g. `GET=replicas,primary` or `/admin=primary`.
+fn parse_route(value: &str) -> Result<(RouteMatch, Vec<String>), String> {
+    let (matcher, groups) = value.split_once('=').ok_or_else(|| {
+        format!(
+            "expected <METHOD or /prefix>=<group>[,<group>...], got {}",
+            value
+        )
+    })?;
+    let matcher = if matcher.starts_with('/') {
+        RouteMatch::PathPrefix(matcher.to_string())
+    } else {
+        RouteMatch::Method(parse_method(matcher)?)
+    };
+    let groups: Vec<String> = groups.split(',').map(str::to_string).collect();
+    if groups.iter().any(String::is_empty) {
+        return Err(format!("empty upstream group name in {}", value));
+    }
+    Ok((matcher, groups))
+}
+
+/// Parses a --rate-limit-rule value, e.g. `/login=5`, `/static=0` or
+/// `POST,/api=100/header:x-api-key`.
+fn parse_rate_limit_rule(value: &str) -> Result<RateLimitRule, String> {
+    let (matchers, limit) = value.split_once('=').ok_or_else(|| {
+        format!(
+            "expected <METHOD or /prefix>[,...]=<limit>[/ip|/all|/header:<name>], got {}",
+            value
+        )
+    })?;
+    let matches = matchers
+        .split(',')
+        .map(|matcher| {
+            if matcher.starts_with('/') {
+                Ok(RouteMatch::PathPrefix(matcher.to_string()))
+            } else {
+                Ok(RouteMatch::Method(parse_method(matcher)?))
             }
-        };
-        // Forward the response to the client
-        send_response(&mut client_conn, &response).await;
-        log::debug!("Forwarded response to client");
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    let (limit, key) = match limit.split_once('/') {
+        Some((limit, key)) => (limit, key),
+        None => (limit, "ip"),
+    };
+    let max_requests_per_minute = limit
+        .parse()
+        .map_err(|_| format!("invalid limit {} in {}", limit, value))?;
+    let key = match key {
+        "ip" => RateLimitKey::ClientIp,
+        "all" => RateLimitKey::All,
+        _ => match key.strip_prefix("header:") {
+            Some(name) => RateLimitKey::Header(
+                http::HeaderName::from_bytes(name.as_bytes())
+                    .map_err(|_| format!("invalid header name {} in {}", name, value))?,
+            ),
+            None => return Err(format!("unknown rate limit key {} in {}", key, value)),
+        },
+    };
+    Ok(RateLimitRule {
+        matches,
+        max_requests_per_minute,
+        key,
+    })
+}
+
+/// Parses an --error-page value, e.g. `404:/var/www/not_found.html`.
+fn parse_error_page(value: &str) -> Result<(http::StatusCode, String), String> {
+    match value.split_once(':') {
+        Some((code, path)) if !path.is_empty() => Ok((parse_status(code)?, path.to_string())),
+        _ => Err(format!("expected <code>:<path>, got {}", value)),
     }
 }
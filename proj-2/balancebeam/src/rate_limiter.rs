@@ -0,0 +1,46 @@
+//! Counts requests per client for --max-requests-per-minute. The counts are split into shards with
+//! a lock each, so that requests from different clients rarely wait on each other.
+
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+
+use parking_lot::Mutex;
+
+/// Number of shards. Clients are spread over them by a hash of their key, so with this many,
+/// requests from two clients only contend for a lock about 1 time in 64.
+const SHARDS: usize = 64;
+
+pub struct RateLimiter {
+    shards: Vec<Mutex<HashMap<String, usize>>>,
+    /// Picks a client's shard. Randomly keyed, so that clients can't choose IPs that all land in
+    /// the same shard.
+    hasher: RandomState,
+}
+
+impl RateLimiter {
+    pub fn new() -> RateLimiter {
+        RateLimiter {
+            shards: (0..SHARDS).map(|_| Mutex::new(HashMap::new())).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    /// Counts a request from `client`, returning how many requests it has made since the counts
+    /// were last reset (including this one).
+    pub fn count(&self, client: &str) -> usize {
+        let shard = self.hasher.hash_one(client) as usize % SHARDS;
+        let mut counts = self.shards[shard].lock();
+        let count = counts.entry(client.to_string()).or_insert(0);
+        *count += 1;
+        *count
+    }
+
+    /// Forgets every client's count. Each shard is cleared in turn, without stopping requests
+    /// being counted in the others.
+    pub fn reset(&self) {
+        for shard in &self.shards {
+            shard.lock().clear();
+        }
+    }
+}
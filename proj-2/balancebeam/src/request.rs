@@ -1,12 +1,14 @@
 use std::cmp::min;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
-const MAX_HEADERS_SIZE: usize = 8000;
+/// Most bytes of the request line and headers to read at a time
+const HEADER_READ_BYTES: usize = 1024;
 const MAX_BODY_SIZE: usize = 10000000;
 const MAX_NUM_HEADERS: usize = 32;
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     /// Client hung up before sending a complete request. IncompleteRequest contains the number of
     /// bytes that were successfully read before the client hung up
@@ -19,10 +21,31 @@ pub enum Error {
     ContentLengthMismatch,
     /// The request body is bigger than MAX_BODY_SIZE
     RequestBodyTooLarge,
-    /// Encountered an I/O error when reading/writing a TcpStream
+    /// The request line and headers didn't end within the header size limit
+    HeadersTooLarge,
+    /// The client went longer than the read timeout without sending anything
+    ReadTimedOut,
+    /// Encountered an I/O error when reading/writing a stream
     ConnectionError(std::io::Error),
 }
 
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::IncompleteRequest(read) => {
+                write!(f, "client hung up after {} bytes of a request", read)
+            }
+            Error::MalformedRequest(err) => write!(f, "malformed request: {}", err),
+            Error::InvalidContentLength => write!(f, "invalid Content-Length"),
+            Error::ContentLengthMismatch => write!(f, "body doesn't match the Content-Length"),
+            Error::RequestBodyTooLarge => write!(f, "request body too large"),
+            Error::HeadersTooLarge => write!(f, "request headers too large"),
+            Error::ReadTimedOut => write!(f, "timed out waiting for the request"),
+            Error::ConnectionError(err) => write!(f, "{}", err),
+        }
+    }
+}
+
 /// Extracts the Content-Length header value from the provided request. Returns Ok(Some(usize)) if
 /// the Content-Length is present and valid, Ok(None) if Content-Length is not present, or
 /// Err(Error) if Content-Length is present but invalid.
@@ -66,6 +89,35 @@ pub fn extend_header_value(
         .insert(name, http::HeaderValue::from_bytes(&new_value).unwrap());
 }
 
+/// Rewrites the path of `request`: `strip` is removed from the front of it if it starts with it, as
+/// whole segments (so `/api` is removed from `/api/foo` and `/api`, but not `/apiary`), and then
+/// `add` is put in front. Trailing slashes on the prefixes make no difference, and the query is
+/// kept. Paths that aren't absolute (as in `OPTIONS *`) are left alone.
+pub fn rewrite_path(request: &mut http::Request<Vec<u8>>, strip: Option<&str>, add: Option<&str>) {
+    let path = request.uri().path();
+    if !path.starts_with('/') || (strip.is_none() && add.is_none()) {
+        return;
+    }
+    let mut new_path = path;
+    if let Some(rest) = strip.and_then(|strip| path.strip_prefix(strip.trim_end_matches('/'))) {
+        if rest.is_empty() {
+            new_path = "/";
+        } else if rest.starts_with('/') {
+            new_path = rest;
+        }
+    }
+    let new_path = format!("{}{}", add.unwrap_or("").trim_end_matches('/'), new_path);
+    let path_and_query = match request.uri().query() {
+        Some(query) => format!("{}?{}", new_path, query),
+        None => new_path,
+    };
+    let mut parts = request.uri().clone().into_parts();
+    parts.path_and_query = path_and_query.parse().ok();
+    if let Ok(uri) = http::Uri::from_parts(parts) {
+        *request.uri_mut() = uri;
+    }
+}
+
 /// Attempts to parse the data in the supplied buffer as an HTTP request. Returns one of the
 /// following:
 ///
@@ -74,6 +126,7 @@ pub fn extend_header_value(
 /// * If there is data in the buffer that is definitely not a valid HTTP request, returns Err(Error)
 ///
 /// You won't need to touch this function.
+#[allow(clippy::type_complexity)]
 fn parse_request(buffer: &[u8]) -> Result<Option<(http::Request<Vec<u8>>, usize)>, Error> {
     let mut headers = [httparse::EMPTY_HEADER; MAX_NUM_HEADERS];
     let mut req = httparse::Request::new(&mut headers);
@@ -83,7 +136,10 @@ fn parse_request(buffer: &[u8]) -> Result<Option<(http::Request<Vec<u8>>, usize)
         let mut request = http::Request::builder()
             .method(req.method.unwrap())
             .uri(req.path.unwrap())
-            .version(http::Version::HTTP_11);
+            .version(match req.version {
+                Some(0) => http::Version::HTTP_10,
+                _ => http::Version::HTTP_11,
+            });
         for header in req.headers {
             request = request.header(header.name, header.value);
         }
@@ -94,68 +150,96 @@ fn parse_request(buffer: &[u8]) -> Result<Option<(http::Request<Vec<u8>>, usize)
     }
 }
 
+/// Reads whatever the client has sent into `buffer`, like AsyncReadExt::read, but gives up with
+/// Error::ReadTimedOut if nothing arrives within `timeout` (if there is one).
+async fn read_some<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    buffer: &mut [u8],
+    timeout: Option<Duration>,
+) -> Result<usize, Error> {
+    let read = stream.read(buffer);
+    let result = match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, read)
+            .await
+            .map_err(|_| Error::ReadTimedOut)?,
+        None => read.await,
+    };
+    result.map_err(Error::ConnectionError)
+}
+
 /// Reads an HTTP request from the provided stream, waiting until a complete set of headers is sent.
 /// This function only reads the request line and headers; the read_body function can subsequently
 /// be called in order to read the request body (for a POST request).
 ///
-/// Returns Ok(http::Request) if a valid request is received, or Error if not.
+/// Returns Ok(http::Request) if a valid request is received, or Error if not. The request line and
+/// headers may take up at most `max_header_bytes`, however many reads they arrive in.
 ///
 /// You will need to modify this function in Milestone 2.
-async fn read_headers(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>, Error> {
+async fn read_headers<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    max_header_bytes: usize,
+    read_timeout: Option<Duration>,
+) -> Result<http::Request<Vec<u8>>, Error> {
     // Try reading the headers from the request. We may not receive all the headers in one shot
     // (e.g. we might receive the first few bytes of a request, and then the rest follows later).
     // Try parsing repeatedly until we read a valid HTTP request
-    let mut request_buffer = [0_u8; MAX_HEADERS_SIZE];
-    let mut bytes_read = 0;
+    let mut request_buffer = Vec::new();
+    let mut read_buffer = [0_u8; HEADER_READ_BYTES];
     loop {
-        // Read bytes from the connection into the buffer, starting at position bytes_read
-        let new_bytes = stream
-            .read(&mut request_buffer[bytes_read..])
-            .await
-            .map_err(Error::ConnectionError)?;
+        // Never read past the limit, so that a request whose headers fit is never turned away
+        let room = max_header_bytes - request_buffer.len();
+        if room == 0 {
+            return Err(Error::HeadersTooLarge);
+        }
+        let new_bytes = read_some(
+            stream,
+            &mut read_buffer[..min(room, HEADER_READ_BYTES)],
+            read_timeout,
+        )
+        .await?;
         if new_bytes == 0 {
             // We didn't manage to read a complete request
-            return Err(Error::IncompleteRequest(bytes_read));
+            return Err(Error::IncompleteRequest(request_buffer.len()));
         }
-        bytes_read += new_bytes;
+        request_buffer.extend_from_slice(&read_buffer[..new_bytes]);
 
         // See if we've read a valid request so far
-        if let Some((mut request, headers_len)) = parse_request(&request_buffer[..bytes_read])? {
+        if let Some((mut request, headers_len)) = parse_request(&request_buffer)? {
             // We've read a complete set of headers. However, if this was a POST request, a request
             // body might have been included as well, and we might have read part of the body out of
             // the stream into header_buffer. We need to add those bytes to the Request body so that
             // we don't lose them
             request
                 .body_mut()
-                .extend_from_slice(&request_buffer[headers_len..bytes_read]);
+                .extend_from_slice(&request_buffer[headers_len..]);
             return Ok(request);
         }
     }
 }
 
 /// This function reads the body for a request from the stream. The client only sends a body if the
-/// Content-Length header is present; this function reads that number of bytes from the stream. It
-/// returns Ok(()) if successful, or Err(Error) if Content-Length bytes couldn't be read.
+/// Content-Length header is present; this function reads that number of bytes from the stream,
+/// `buffer_size` bytes at a time. It returns Ok(()) if successful, or Err(Error) if Content-Length
+/// bytes couldn't be read.
 ///
 /// You will need to modify this function in Milestone 2.
-async fn read_body(
-    stream: &mut TcpStream,
+async fn read_body<S: AsyncRead + Unpin>(
+    stream: &mut S,
     request: &mut http::Request<Vec<u8>>,
     content_length: usize,
+    buffer_size: usize,
+    read_timeout: Option<Duration>,
 ) -> Result<(), Error> {
+    // Read up to buffer_size bytes at a time. (If the client only sent a small body, then only
+    // allocate space to read that body.)
+    let mut buffer = vec![0_u8; min(buffer_size, content_length)];
     // Keep reading data until we read the full body length, or until we hit an error.
     while request.body().len() < content_length {
-        // Read up to 512 bytes at a time. (If the client only sent a small body, then only allocate
-        // space to read that body.)
-        let mut buffer = vec![0_u8; min(512, content_length)];
-        let bytes_read = stream
-            .read(&mut buffer)
-            .await
-            .map_err(Error::ConnectionError)?;
+        let bytes_read = read_some(stream, &mut buffer, read_timeout).await?;
 
         // Make sure the client is still sending us bytes
         if bytes_read == 0 {
-            log::debug!(
+            tracing::debug!(
                 "Client hung up after sending a body of length {}, even though it said the content \
                 length is {}",
                 request.body().len(),
@@ -166,7 +250,7 @@ async fn read_body(
 
         // Make sure the client didn't send us *too many* bytes
         if request.body().len() + bytes_read > content_length {
-            log::debug!(
+            tracing::debug!(
                 "Client sent more bytes than we expected based on the given content length!"
             );
             return Err(Error::ContentLengthMismatch);
@@ -179,18 +263,33 @@ async fn read_body(
 }
 
 /// This function reads and returns an HTTP request from a stream, returning an Error if the client
-/// closes the connection prematurely or sends an invalid request.
+/// closes the connection prematurely or sends an invalid request. The body is read `buffer_size`
+/// bytes at a time. The request line and headers may take up at most `max_header_bytes`, and each
+/// read must get something within `read_timeout` (if there is one), so that a client sending its
+/// request a trickle at a time can only tie the connection up for so long.
 ///
 /// You will need to modify this function in Milestone 2.
-pub async fn read_from_stream(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>, Error> {
+pub async fn read_from_stream<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    buffer_size: usize,
+    max_header_bytes: usize,
+    read_timeout: Option<Duration>,
+) -> Result<http::Request<Vec<u8>>, Error> {
     // Read headers
-    let mut request = read_headers(stream).await?;
+    let mut request = read_headers(stream, max_header_bytes, read_timeout).await?;
     // Read body if the client supplied the Content-Length header (which it does for POST requests)
     if let Some(content_length) = get_content_length(&request)? {
         if content_length > MAX_BODY_SIZE {
             return Err(Error::RequestBodyTooLarge);
         } else {
-            read_body(stream, &mut request, content_length).await?;
+            read_body(
+                stream,
+                &mut request,
+                content_length,
+                buffer_size,
+                read_timeout,
+            )
+            .await?;
         }
     }
     Ok(request)
@@ -199,26 +298,27 @@ pub async fn read_from_stream(stream: &mut TcpStream) -> Result<http::Request<Ve
 /// This function serializes a request to bytes and writes those bytes to the provided stream.
 ///
 /// You will need to modify this function in Milestone 2.
-#[allow(clippy::unused_io_amount)]
-pub async fn write_to_stream(
+pub async fn write_to_stream<S: AsyncWrite + Unpin>(
     request: &http::Request<Vec<u8>>,
-    stream: &mut TcpStream,
+    stream: &mut S,
 ) -> Result<(), std::io::Error> {
     stream
-        .write(&format_request_line(request).into_bytes())
+        .write_all(&format_request_line(request).into_bytes())
         .await?;
-    stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
+    stream.write_all(b"\r\n").await?;
     for (header_name, header_value) in request.headers() {
         stream
-            .write(&format!("{}: ", header_name).as_bytes())
+            .write_all(format!("{}: ", header_name).as_bytes())
             .await?;
-        stream.write(header_value.as_bytes()).await?;
-        stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
+        stream.write_all(header_value.as_bytes()).await?;
+        stream.write_all(b"\r\n").await?;
     }
-    stream.write(&['\r' as u8, '\n' as u8]).await?;
-    if request.body().len() > 0 {
-        stream.write(request.body()).await?;
+    stream.write_all(b"\r\n").await?;
+    if !request.body().is_empty() {
+        stream.write_all(request.body()).await?;
     }
+    // Encrypted streams buffer what is written to them
+    stream.flush().await?;
     Ok(())
 }
 
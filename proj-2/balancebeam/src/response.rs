@@ -1,11 +1,12 @@
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use std::io::Read;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 const MAX_HEADERS_SIZE: usize = 8000;
 const MAX_BODY_SIZE: usize = 10000000;
 const MAX_NUM_HEADERS: usize = 32;
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     /// Client hung up before sending a complete request
     IncompleteResponse,
@@ -17,10 +18,23 @@ pub enum Error {
     ContentLengthMismatch,
     /// The request body is bigger than MAX_BODY_SIZE
     ResponseBodyTooLarge,
-    /// Encountered an I/O error when reading/writing a TcpStream
+    /// Encountered an I/O error when reading/writing a stream
     ConnectionError(std::io::Error),
 }
 
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::IncompleteResponse => write!(f, "server hung up before the end of its response"),
+            Error::MalformedResponse(err) => write!(f, "malformed response: {}", err),
+            Error::InvalidContentLength => write!(f, "invalid Content-Length"),
+            Error::ContentLengthMismatch => write!(f, "body doesn't match the Content-Length"),
+            Error::ResponseBodyTooLarge => write!(f, "response body too large"),
+            Error::ConnectionError(err) => write!(f, "{}", err),
+        }
+    }
+}
+
 /// Extracts the Content-Length header value from the provided response. Returns Ok(Some(usize)) if
 /// the Content-Length is present and valid, Ok(None) if Content-Length is not present, or
 /// Err(Error) if Content-Length is present but invalid.
@@ -52,6 +66,7 @@ fn get_content_length(response: &http::Response<Vec<u8>>) -> Result<Option<usize
 ///   Err(Error)
 ///
 /// You won't need to touch this function.
+#[allow(clippy::type_complexity)]
 fn parse_response(buffer: &[u8]) -> Result<Option<(http::Response<Vec<u8>>, usize)>, Error> {
     let mut headers = [httparse::EMPTY_HEADER; MAX_NUM_HEADERS];
     let mut resp = httparse::Response::new(&mut headers);
@@ -60,7 +75,10 @@ fn parse_response(buffer: &[u8]) -> Result<Option<(http::Response<Vec<u8>>, usiz
     if let httparse::Status::Complete(len) = res {
         let mut response = http::Response::builder()
             .status(resp.code.unwrap())
-            .version(http::Version::HTTP_11);
+            .version(match resp.version {
+                Some(0) => http::Version::HTTP_10,
+                _ => http::Version::HTTP_11,
+            });
         for header in resp.headers {
             response = response.header(header.name, header.value);
         }
@@ -78,7 +96,9 @@ fn parse_response(buffer: &[u8]) -> Result<Option<(http::Response<Vec<u8>>, usiz
 /// Returns Ok(http::Response) if a valid response is received, or Error if not.
 ///
 /// You will need to modify this function in Milestone 2.
-async fn read_headers(stream: &mut TcpStream) -> Result<http::Response<Vec<u8>>, Error> {
+async fn read_headers<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> Result<http::Response<Vec<u8>>, Error> {
     // Try reading the headers from the response. We may not receive all the headers in one shot
     // (e.g. we might receive the first few bytes of a response, and then the rest follows later).
     // Try parsing repeatedly until we read a valid HTTP response
@@ -111,19 +131,21 @@ async fn read_headers(stream: &mut TcpStream) -> Result<http::Response<Vec<u8>>,
 
 /// This function reads the body for a response from the stream. If the Content-Length header is
 /// present, it reads that many bytes; otherwise, it reads bytes until the connection is closed.
+/// Bytes are read `buffer_size` at a time.
 ///
 /// You will need to modify this function in Milestone 2.
-async fn read_body(
-    stream: &mut TcpStream,
+async fn read_body<S: AsyncRead + Unpin>(
+    stream: &mut S,
     response: &mut http::Response<Vec<u8>>,
+    buffer_size: usize,
 ) -> Result<(), Error> {
     // The response may or may not supply a Content-Length header. If it provides the header, then
     // we want to read that number of bytes; if it does not, we want to keep reading bytes until
     // the connection is closed.
     let content_length = get_content_length(response)?;
 
+    let mut buffer = vec![0_u8; buffer_size];
     while content_length.is_none() || response.body().len() < content_length.unwrap() {
-        let mut buffer = [0_u8; 512];
         let bytes_read = stream
             .read(&mut buffer)
             .await
@@ -158,12 +180,14 @@ async fn read_body(
 }
 
 /// This function reads and returns an HTTP response from a stream, returning an Error if the server
-/// closes the connection prematurely or sends an invalid response.
+/// closes the connection prematurely or sends an invalid response. The body is read `buffer_size`
+/// bytes at a time.
 ///
 /// You will need to modify this function in Milestone 2.
-pub async fn read_from_stream(
-    stream: &mut TcpStream,
+pub async fn read_from_stream<S: AsyncRead + Unpin>(
+    stream: &mut S,
     request_method: &http::Method,
+    buffer_size: usize,
 ) -> Result<http::Response<Vec<u8>>, Error> {
     let mut response = read_headers(stream).await?;
     // A response may have a body as long as it is not responding to a HEAD request and as long as
@@ -173,34 +197,66 @@ pub async fn read_from_stream(
         || response.status() == http::StatusCode::NO_CONTENT
         || response.status() == http::StatusCode::NOT_MODIFIED)
     {
-        read_body(stream, &mut response).await?;
+        read_body(stream, &mut response, buffer_size).await?;
     }
     Ok(response)
 }
 
+/// Returns whether `decode_body` can decode bodies with the given (lowercase) Content-Encoding.
+pub fn is_decodable(encoding: &str) -> bool {
+    matches!(encoding, "gzip" | "x-gzip" | "deflate")
+}
+
+/// Decodes a body sent with the given (lowercase) Content-Encoding, which must be one that
+/// `is_decodable`. Fails if the body isn't validly encoded, or decodes to more than MAX_BODY_SIZE
+/// bytes (so a small body can't blow up into an enormous one).
+pub fn decode_body(encoding: &str, body: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+    let read_limited = |decoder: &mut dyn Read| {
+        let mut decoded = Vec::new();
+        decoder
+            .take(MAX_BODY_SIZE as u64 + 1)
+            .read_to_end(&mut decoded)?;
+        if decoded.len() > MAX_BODY_SIZE {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "decoded body is too large",
+            ));
+        }
+        Ok(decoded)
+    };
+    if encoding == "deflate" {
+        // deflate is meant to be zlib-wrapped, but some servers send raw deflate data instead
+        read_limited(&mut flate2::read::ZlibDecoder::new(body))
+            .or_else(|_| read_limited(&mut flate2::read::DeflateDecoder::new(body)))
+    } else {
+        read_limited(&mut flate2::read::MultiGzDecoder::new(body))
+    }
+}
+
 /// This function serializes a response to bytes and writes those bytes to the provided stream.
 ///
 /// You will need to modify this function in Milestone 2.
-#[allow(clippy::unused_io_amount)]
-pub async fn write_to_stream(
+pub async fn write_to_stream<S: AsyncWrite + Unpin>(
     response: &http::Response<Vec<u8>>,
-    stream: &mut TcpStream,
+    stream: &mut S,
 ) -> Result<(), std::io::Error> {
     stream
-        .write(&format_response_line(response).into_bytes())
+        .write_all(&format_response_line(response).into_bytes())
         .await?;
-    stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
+    stream.write_all(b"\r\n").await?;
     for (header_name, header_value) in response.headers() {
         stream
-            .write(&format!("{}: ", header_name).as_bytes())
+            .write_all(format!("{}: ", header_name).as_bytes())
             .await?;
-        stream.write(header_value.as_bytes()).await?;
-        stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
+        stream.write_all(header_value.as_bytes()).await?;
+        stream.write_all(b"\r\n").await?;
     }
-    stream.write(&['\r' as u8, '\n' as u8]).await?;
+    stream.write_all(b"\r\n").await?;
     if !response.body().is_empty() {
-        stream.write(response.body()).await?;
+        stream.write_all(response.body()).await?;
     }
+    // Encrypted streams buffer what is written to them
+    stream.flush().await?;
     Ok(())
 }
 
@@ -213,6 +269,87 @@ pub fn format_response_line(response: &http::Response<Vec<u8>>) -> String {
     )
 }
 
+/// Copies `response` (http::Response isn't Clone, because of its extensions, which we don't use).
+pub fn clone_response(response: &http::Response<Vec<u8>>) -> http::Response<Vec<u8>> {
+    let mut copy = http::Response::new(response.body().clone());
+    *copy.status_mut() = response.status();
+    *copy.version_mut() = response.version();
+    *copy.headers_mut() = response.headers().clone();
+    copy
+}
+
+/// Makes a 503 response carrying the given HTML page, asking the client to come back after
+/// `retry_after` seconds.
+pub fn make_maintenance_page(page: &[u8], retry_after: usize) -> http::Response<Vec<u8>> {
+    http::Response::builder()
+        .status(http::StatusCode::SERVICE_UNAVAILABLE)
+        .header("Content-Type", "text/html; charset=utf-8")
+        .header("Content-Length", page.len().to_string())
+        .header("Retry-After", retry_after.to_string())
+        .version(http::Version::HTTP_11)
+        .body(page.to_vec())
+        .unwrap()
+}
+
+/// Makes the canned 200 response --loopback-upstream answers every request with. Responses to HEAD
+/// requests leave out the body, but still say how long it would be.
+pub fn make_loopback_response(body: &[u8], method: &http::Method) -> http::Response<Vec<u8>> {
+    http::Response::builder()
+        .status(http::StatusCode::OK)
+        .header("Content-Type", "text/plain")
+        .header("Content-Length", body.len().to_string())
+        .version(http::Version::HTTP_11)
+        .body(if method == http::Method::HEAD {
+            Vec::new()
+        } else {
+            body.to_vec()
+        })
+        .unwrap()
+}
+
+/// Makes the 503 response sent to clients whose requests are shed because the proxy is overloaded.
+/// Load usually drops off quickly, so they are asked to retry after a second.
+pub fn make_overloaded_error() -> http::Response<Vec<u8>> {
+    let mut response = make_http_error(http::StatusCode::SERVICE_UNAVAILABLE);
+    response
+        .headers_mut()
+        .insert("Retry-After", http::HeaderValue::from_static("1"));
+    response
+}
+
+/// Makes the response sent to clients over --max-requests-per-minute: an error with the given
+/// status, carrying `body` (with its Content-Type) if given. The request count resets every minute,
+/// so clients are asked to retry after that long.
+pub fn make_rate_limit_error(
+    status: http::StatusCode,
+    body: Option<(&[u8], &http::HeaderValue)>,
+) -> http::Response<Vec<u8>> {
+    let mut response = make_http_error(status);
+    if let Some((body, content_type)) = body {
+        response
+            .headers_mut()
+            .insert("Content-Type", content_type.clone());
+        response
+            .headers_mut()
+            .insert("Content-Length", http::HeaderValue::from(body.len()));
+        *response.body_mut() = body.to_vec();
+    }
+    response
+        .headers_mut()
+        .insert("Retry-After", http::HeaderValue::from_static("60"));
+    response
+}
+
+/// Makes a response with the given status and a plain text body.
+pub fn make_text_response(status: http::StatusCode, text: &str) -> http::Response<Vec<u8>> {
+    let mut response = make_http_error(status);
+    response
+        .headers_mut()
+        .insert("Content-Length", http::HeaderValue::from(text.len()));
+    *response.body_mut() = text.as_bytes().to_vec();
+    response
+}
+
 /// This is a helper function that creates an http::Response containing an HTTP error that can be
 /// sent to a client.
 pub fn make_http_error(status: http::StatusCode) -> http::Response<Vec<u8>> {
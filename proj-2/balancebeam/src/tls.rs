@@ -0,0 +1,85 @@
+use std::sync::Arc;
+
+use tokio_rustls::rustls;
+
+/// Builds the TLS acceptor used to terminate client connections, from a PEM certificate chain and
+/// the PEM private key that goes with it. If `client_ca` is given (as PEM certificates), clients
+/// must present a certificate signed by one of them, and the handshake fails if they don't.
+pub fn make_tls_acceptor(
+    cert: &[u8],
+    key: &[u8],
+    client_ca: Option<&[u8]>,
+) -> Result<tokio_rustls::TlsAcceptor, std::io::Error> {
+    let certs = read_certs(cert)?;
+    if certs.is_empty() {
+        return Err(invalid("no certificates found in the TLS certificate"));
+    }
+    let key = rustls_pemfile::read_all(&mut &key[..])?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(rustls::PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| invalid("no private key found in the TLS key"))?;
+
+    let builder = rustls::ServerConfig::builder().with_safe_defaults();
+    let builder = match client_ca {
+        Some(client_ca) => {
+            let mut roots = rustls::RootCertStore::empty();
+            for cert in read_certs(client_ca)? {
+                roots
+                    .add(&cert)
+                    .map_err(|err| invalid(&format!("bad client CA certificate: {}", err)))?;
+            }
+            if roots.is_empty() {
+                return Err(invalid("no certificates found in the client CA"));
+            }
+            builder.with_client_cert_verifier(
+                rustls::server::AllowAnyAuthenticatedClient::new(roots).boxed(),
+            )
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder
+        .with_single_cert(certs, key)
+        .map_err(|err| invalid(&err.to_string()))?;
+    Ok(tokio_rustls::TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Returns who the client's certificate says it is: the subject's common name, or failing that the
+/// first DNS name or email address among its subject alternative names. Returns None if the client
+/// didn't present a certificate or it names nobody.
+pub fn client_identity(connection: &rustls::ServerConnection) -> Option<String> {
+    let cert = connection.peer_certificates()?.first()?;
+    let (_, cert) = x509_parser::parse_x509_certificate(&cert.0).ok()?;
+    if let Some(common_name) = cert
+        .subject()
+        .iter_common_name()
+        .find_map(|name| name.as_str().ok())
+    {
+        return Some(common_name.to_string());
+    }
+    let alt_names = cert.subject_alternative_name().ok()??;
+    alt_names
+        .value
+        .general_names
+        .iter()
+        .find_map(|name| match name {
+            x509_parser::extensions::GeneralName::DNSName(name)
+            | x509_parser::extensions::GeneralName::RFC822Name(name) => Some(name.to_string()),
+            _ => None,
+        })
+}
+
+fn read_certs(pem: &[u8]) -> Result<Vec<rustls::Certificate>, std::io::Error> {
+    Ok(rustls_pemfile::certs(&mut &pem[..])?
+        .into_iter()
+        .map(rustls::Certificate)
+        .collect())
+}
+
+fn invalid(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, message)
+}
@@ -0,0 +1,96 @@
+//! W3C trace context propagation (https://www.w3.org/TR/trace-context/). balancebeam takes part in
+//! the client's trace as a hop of its own, so upstreams see the proxy as their parent span, and
+//! starts a new trace for clients that don't send one.
+
+use rand::Rng;
+
+/// The trace a request belongs to, and the ID of the proxy's span in it.
+pub struct TraceContext {
+    /// 32 lowercase hex digits identifying the whole trace
+    pub trace_id: String,
+    /// 16 lowercase hex digits identifying the proxy's hop, which upstreams get as their parent
+    pub span_id: String,
+    /// Trace flags (such as whether the trace is sampled), as 2 hex digits
+    flags: String,
+}
+
+impl TraceContext {
+    /// Works out the trace context of `request` and sets its `traceparent` header to send upstream.
+    /// A valid `traceparent` from the client is continued with a new span ID, and its `tracestate`
+    /// is passed on unchanged. Otherwise a new trace is started, and any `tracestate` is dropped,
+    /// since it only means something within the trace it came with.
+    pub fn propagate(request: &mut http::Request<Vec<u8>>) -> TraceContext {
+        let mut parents = request.headers().get_all("traceparent").iter();
+        // A request with several traceparents is as good as one without any
+        let parent = match (parents.next(), parents.next()) {
+            (Some(value), None) => value.to_str().ok().and_then(parse_traceparent),
+            _ => None,
+        };
+        let context = match parent {
+            Some((trace_id, flags)) => TraceContext {
+                trace_id,
+                span_id: random_id(8),
+                flags,
+            },
+            None => {
+                request.headers_mut().remove("tracestate");
+                TraceContext {
+                    trace_id: random_id(16),
+                    span_id: random_id(8),
+                    // Every request is logged, so new traces are sampled
+                    flags: "01".to_string(),
+                }
+            }
+        };
+        request.headers_mut().insert(
+            "traceparent",
+            http::HeaderValue::from_str(&context.traceparent()).unwrap(),
+        );
+        context
+    }
+
+    /// Returns the `traceparent` header value naming the proxy's span as the parent.
+    pub fn traceparent(&self) -> String {
+        format!("00-{}-{}-{}", self.trace_id, self.span_id, self.flags)
+    }
+}
+
+/// Parses a `traceparent` header value, returning its trace ID and flags if it is valid.
+fn parse_traceparent(value: &str) -> Option<(String, String)> {
+    let mut fields = value.trim().split('-');
+    let version = fields.next()?;
+    let trace_id = fields.next()?;
+    let parent_id = fields.next()?;
+    let flags = fields.next()?;
+    // Later versions may add fields after these, which we don't know what to do with but can
+    // ignore, since we only send version 00 on
+    let valid = is_hex(version, 2)
+        && version != "ff"
+        && (version != "00" || fields.next().is_none())
+        && is_hex(trace_id, 32)
+        && is_hex(parent_id, 16)
+        && is_hex(flags, 2)
+        // All-zero IDs are invalid
+        && trace_id.bytes().any(|digit| digit != b'0')
+        && parent_id.bytes().any(|digit| digit != b'0');
+    valid.then(|| (trace_id.to_string(), flags.to_string()))
+}
+
+/// Returns whether `field` is `len` lowercase hex digits.
+fn is_hex(field: &str, len: usize) -> bool {
+    field.len() == len
+        && field
+            .bytes()
+            .all(|digit| digit.is_ascii_digit() || (b'a'..=b'f').contains(&digit))
+}
+
+/// Returns a random ID of `bytes` bytes as lowercase hex digits, which is never all zeros.
+fn random_id(bytes: usize) -> String {
+    let mut rng = rand::thread_rng();
+    loop {
+        let id: Vec<u8> = (0..bytes).map(|_| rng.gen()).collect();
+        if id.iter().any(|&byte| byte != 0) {
+            return id.iter().map(|byte| format!("{:02x}", byte)).collect();
+        }
+    }
+}
@@ -0,0 +1,185 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UnixStream};
+use tokio_rustls::rustls;
+
+/// A connection to an upstream server, which may or may not be encrypted.
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// Splits an upstream address as given on the command line into the `host:port` to connect to and
+/// whether to use TLS. Addresses may start with `https://` or `http://`; addresses without a scheme
+/// use TLS only if `tls_by_default` is set. Unix socket addresses (`unix:<path>`) are kept whole
+/// and never use TLS.
+pub fn parse_address(address: &str, tls_by_default: bool) -> (&str, bool) {
+    if unix_socket_path(address).is_some() {
+        (address, false)
+    } else if let Some(host_port) = address.strip_prefix("https://") {
+        (host_port, true)
+    } else if let Some(host_port) = address.strip_prefix("http://") {
+        (host_port, false)
+    } else {
+        (address, tls_by_default)
+    }
+}
+
+/// Returns the path of the socket an upstream listens on if it was given as `unix:<path>`, or None
+/// if it listens on TCP.
+pub fn unix_socket_path(address: &str) -> Option<&str> {
+    address.strip_prefix("unix:")
+}
+
+/// Builds the TLS connector used to talk to HTTPS upstreams. Certificates are verified against the
+/// system's root certificates unless `insecure` is set, in which case they aren't verified at all.
+pub fn make_tls_connector(insecure: bool) -> Result<tokio_rustls::TlsConnector, std::io::Error> {
+    let builder = rustls::ClientConfig::builder().with_safe_defaults();
+    let config = if insecure {
+        builder
+            .with_custom_certificate_verifier(Arc::new(NoCertificateVerification))
+            .with_no_client_auth()
+    } else {
+        let mut roots = rustls::RootCertStore::empty();
+        for cert in rustls_native_certs::load_native_certs()? {
+            // Skip certificates rustls can't parse rather than refusing to start
+            if let Err(err) = roots.add(&rustls::Certificate(cert.0)) {
+                tracing::warn!("Ignoring unparseable system root certificate: {}", err);
+            }
+        }
+        builder.with_root_certificates(roots).with_no_client_auth()
+    };
+    Ok(tokio_rustls::TlsConnector::from(Arc::new(config)))
+}
+
+/// Opens a TCP connection to `host_port`, with TCP_NODELAY set if `nodelay` is true.
+pub async fn connect_tcp(host_port: &str, nodelay: bool) -> Result<TcpStream, std::io::Error> {
+    let stream = TcpStream::connect(host_port).await?;
+    if nodelay {
+        stream.set_nodelay(true)?;
+    }
+    Ok(stream)
+}
+
+/// Opens a connection to `host_port` (or to a Unix socket, for `unix:<path>`), performing a TLS
+/// handshake over it if `tls` is given. If `proxy_header` is given, it is sent as soon as the
+/// connection is open (before the handshake). TCP connections get TCP_NODELAY if `nodelay` is true.
+pub async fn connect(
+    host_port: &str,
+    tls: Option<&tokio_rustls::TlsConnector>,
+    proxy_header: Option<&[u8]>,
+    nodelay: bool,
+) -> Result<Box<dyn Stream>, std::io::Error> {
+    if let Some(path) = unix_socket_path(host_port) {
+        let mut stream = UnixStream::connect(path).await?;
+        if let Some(header) = proxy_header {
+            stream.write_all(header).await?;
+        }
+        return Ok(Box::new(stream));
+    }
+    let mut stream = connect_tcp(host_port, nodelay).await?;
+    if let Some(header) = proxy_header {
+        stream.write_all(header).await?;
+    }
+    let connector = match tls {
+        Some(connector) => connector,
+        None => return Ok(Box::new(stream)),
+    };
+    // Strip the port (taking care of bracketed IPv6 addresses) to get the name to verify
+    let host = match host_port.rsplit_once(':') {
+        Some((host, _port)) => host,
+        None => host_port,
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let server_name = rustls::ServerName::try_from(host).map_err(|_| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Invalid TLS server name {}", host),
+        )
+    })?;
+    Ok(Box::new(connector.connect(server_name, stream).await?))
+}
+
+/// Versions of the HAProxy PROXY protocol balancebeam can use to tell upstreams where a connection
+/// came from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum ProxyProtocol {
+    /// Human-readable text header
+    V1,
+    /// Binary header
+    V2,
+}
+
+/// Every v2 header starts with this signature.
+const PROXY_V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Builds the PROXY protocol header for a connection from `source` to balancebeam's address
+/// `destination`. Without addresses (for connections balancebeam makes on its own behalf, such as
+/// health checks) the header says so, and the upstream should use the real connection addresses.
+pub fn proxy_protocol_header(
+    version: ProxyProtocol,
+    addresses: Option<(SocketAddr, SocketAddr)>,
+) -> Vec<u8> {
+    // Both addresses must belong to the same family; anything else can only be sent as unknown
+    let addresses =
+        addresses.filter(|(source, destination)| source.is_ipv4() == destination.is_ipv4());
+    match version {
+        ProxyProtocol::V1 => match addresses {
+            Some((source, destination)) => format!(
+                "PROXY {} {} {} {} {}\r\n",
+                if source.is_ipv4() { "TCP4" } else { "TCP6" },
+                source.ip(),
+                destination.ip(),
+                source.port(),
+                destination.port()
+            )
+            .into_bytes(),
+            None => b"PROXY UNKNOWN\r\n".to_vec(),
+        },
+        ProxyProtocol::V2 => {
+            let mut header = PROXY_V2_SIGNATURE.to_vec();
+            let (source, destination) = match addresses {
+                Some(addresses) => addresses,
+                None => {
+                    // Version 2, LOCAL command, unspecified family, no address block
+                    header.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]);
+                    return header;
+                }
+            };
+            let mut address_block = Vec::new();
+            for address in [source, destination] {
+                match address.ip() {
+                    IpAddr::V4(ip) => address_block.extend_from_slice(&ip.octets()),
+                    IpAddr::V6(ip) => address_block.extend_from_slice(&ip.octets()),
+                }
+            }
+            address_block.extend_from_slice(&source.port().to_be_bytes());
+            address_block.extend_from_slice(&destination.port().to_be_bytes());
+            // Version 2, PROXY command, then TCP over IPv4 or IPv6
+            header.push(0x21);
+            header.push(if source.is_ipv4() { 0x11 } else { 0x21 });
+            header.extend_from_slice(&(address_block.len() as u16).to_be_bytes());
+            header.extend_from_slice(&address_block);
+            header
+        }
+    }
+}
+
+/// Accepts any certificate the upstream presents (for --upstream-tls-insecure).
+struct NoCertificateVerification;
+
+impl rustls::client::ServerCertVerifier for NoCertificateVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+        Ok(rustls::client::ServerCertVerified::assertion())
+    }
+}
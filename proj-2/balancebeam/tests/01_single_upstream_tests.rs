@@ -1,7 +1,13 @@
 mod common;
 
-use common::{init_logging, BalanceBeam, EchoServer, Server};
+use common::{
+    init_logging, BalanceBeam, ClosingServer, EchoServer, ErrorServer, ProxyProtocolServer, Server,
+};
+use rand::Rng;
+use std::io::Read;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
 async fn setup() -> (BalanceBeam, EchoServer) {
     init_logging();
@@ -45,6 +51,76 @@ async fn test_simple_connections() {
     log::info!("All done :)");
 }
 
+/// Make sure the upstream is told which scheme and host the client originally requested, and that
+/// any values set by an earlier proxy are preserved.
+#[tokio::test]
+async fn test_forwarded_proto_and_host() {
+    let (balancebeam, upstream) = setup().await;
+
+    log::info!("Sending a GET request");
+    let response_text = balancebeam
+        .get("/forwarded")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("x-forwarded-proto: http\n"));
+    assert!(response_text.contains(&format!("x-forwarded-host: {}\n", balancebeam.address)));
+
+    log::info!("Sending a GET request that already passed through another proxy");
+    let client = reqwest::Client::new();
+    let response_text = client
+        .get(format!("http://{}/forwarded", balancebeam.address))
+        .header("x-forwarded-proto", "https")
+        .header("x-forwarded-host", "example.com")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .text()
+        .await
+        .expect("Balancebeam replied with a malformed response");
+    assert!(response_text.contains("x-forwarded-proto: https, http\n"));
+    assert!(response_text.contains(&format!(
+        "x-forwarded-host: example.com, {}\n",
+        balancebeam.address
+    )));
+
+    log::info!("Sending a GET request to a balancebeam that terminates TLS");
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])
+        .expect("Error generating certificate");
+    let tmpdir = std::path::Path::new(env!("CARGO_TARGET_TMPDIR"));
+    let cert_path = tmpdir.join("forwarded_cert.pem");
+    let key_path = tmpdir.join("forwarded_key.pem");
+    std::fs::write(&cert_path, cert.serialize_pem().unwrap()).expect("Error writing certificate");
+    std::fs::write(&key_path, cert.serialize_private_key_pem()).expect("Error writing key");
+    let tls_balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &[
+            "--tls-cert",
+            cert_path.to_str().unwrap(),
+            "--tls-key",
+            key_path.to_str().unwrap(),
+        ],
+    )
+    .await;
+    let client = reqwest::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .unwrap();
+    let response_text = client
+        .get(format!("https://{}/forwarded", tls_balancebeam.address))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .text()
+        .await
+        .expect("Balancebeam replied with a malformed response");
+    assert!(response_text.contains("x-forwarded-proto: https\n"));
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
 /// Test handling of multiple HTTP requests per connection to the server. Open three concurrent
 /// connections, and send four requests on each.
 #[tokio::test]
@@ -69,7 +145,7 @@ async fn test_multiple_requests_per_connection() {
                 );
                 let path = format!("/conn-{}/req-{}", task_num, req_num);
                 let response_text = client
-                    .get(&format!("http://{}{}", balancebeam_shared.address, path))
+                    .get(format!("http://{}{}", balancebeam_shared.address, path))
                     .header("x-sent-by", "balancebeam-tests")
                     .send()
                     .await
@@ -99,3 +175,1205 @@ async fn test_multiple_requests_per_connection() {
 
     log::info!("All done :)");
 }
+
+/// Proxy to an upstream that only speaks HTTPS. Its certificate is self-signed, so balancebeam
+/// should only reach it when certificate verification is turned off.
+#[tokio::test]
+async fn test_tls_upstream() {
+    init_logging();
+    let upstream = EchoServer::new_tls().await;
+
+    log::info!("Sending a request to an upstream given with an https:// address");
+    let upstream_url = format!("https://{}", upstream.address);
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream_url], None, None, &["--upstream-tls-insecure"])
+            .await;
+    let response_text = balancebeam
+        .get("/over-tls")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("GET /over-tls HTTP/1.1"));
+    assert!(response_text.contains("x-sent-by: balancebeam-tests"));
+    drop(balancebeam);
+
+    log::info!("Checking that untrusted certificates are rejected by default");
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], None, None, &["--upstream-tls"]).await;
+    let client = reqwest::Client::new();
+    let response = client
+        .get(format!("http://{}/untrusted", balancebeam.address))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 502);
+
+    log::info!("All done :)");
+    assert_eq!(Box::new(upstream).stop().await, 1);
+}
+
+/// Bodies much bigger than a TLS record (or a socket buffer) should make it through a TLS upstream
+/// and back intact, however the writes get split up.
+#[tokio::test]
+async fn test_large_body_through_tls_upstream() {
+    init_logging();
+    let upstream = EchoServer::new_tls().await;
+    let upstream_url = format!("https://{}", upstream.address);
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream_url], None, None, &["--upstream-tls-insecure"])
+            .await;
+
+    log::info!("Sending a 5 MB body to be echoed back");
+    let body: Vec<u8> = (0..5_000_000).map(|i| (i % 251) as u8).collect();
+    // A body cut short leaves the upstream waiting for the rest, so don't wait forever either
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(30))
+        .build()
+        .unwrap();
+    let response = client
+        .post(format!("http://{}/large", balancebeam.address))
+        .body(body.clone())
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 200);
+    let echoed = response
+        .bytes()
+        .await
+        .expect("Balancebeam replied with a malformed response");
+    assert!(echoed.starts_with(b"POST /large HTTP/1.1"));
+    assert!(
+        echoed.ends_with(&body),
+        "Only got {} bytes back for a {} byte body",
+        echoed.len(),
+        body.len()
+    );
+
+    log::info!("All done :)");
+    assert_eq!(Box::new(upstream).stop().await, 1);
+}
+
+/// Once the only upstream is down, clients should get the maintenance page instead of a bare 502
+#[tokio::test]
+async fn test_maintenance_page() {
+    init_logging();
+    let page = "<html><body><h1>Down for maintenance</h1></body></html>";
+    let page_path = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("maintenance.html");
+    std::fs::write(&page_path, page).expect("Error writing maintenance page");
+
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        Some(5),
+        None,
+        &["--maintenance-page", page_path.to_str().unwrap()],
+    )
+    .await;
+
+    log::info!("Stopping the upstream");
+    Box::new(upstream).stop().await;
+
+    log::info!("Sending a request with every upstream down");
+    let client = reqwest::Client::new();
+    let response = client
+        .get(format!("http://{}/anything", balancebeam.address))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 503);
+    assert_eq!(
+        response
+            .headers()
+            .get("retry-after")
+            .map(|value| value.to_str().unwrap()),
+        Some("5")
+    );
+    assert_eq!(response.text().await.unwrap(), page);
+    log::info!("All done :)");
+}
+
+/// balancebeam should accept connections on every address given with --bind
+#[tokio::test]
+async fn test_multiple_bind_addresses() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let second_address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--bind", &second_address],
+    )
+    .await;
+
+    for address in [&balancebeam.address, &second_address] {
+        log::info!("Sending a request to {}", address);
+        let response_text = reqwest::get(format!("http://{}/via-{}", address, address))
+            .await
+            .expect("Error sending request to balancebeam")
+            .text()
+            .await
+            .unwrap();
+        assert!(response_text.contains(&format!("GET /via-{} HTTP/1.1", address)));
+    }
+
+    log::info!("All done :)");
+    assert_eq!(Box::new(upstream).stop().await, 2);
+}
+
+/// An upstream that hangs up after every response shouldn't break the client's keep-alive
+/// connection: balancebeam should connect to the upstream again for each request.
+#[tokio::test]
+async fn test_upstream_connection_close() {
+    init_logging();
+    let upstream = ClosingServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    // One client, so all the requests share a keep-alive connection to balancebeam
+    let client = reqwest::Client::new();
+    for i in 0..3 {
+        log::info!("Sending request {}", i);
+        let response = client
+            .get(format!("http://{}/request-{}", balancebeam.address, i))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.status().as_u16(), 200);
+        assert!(
+            response.headers().get("connection").is_none(),
+            "The upstream's Connection header was passed on to the client"
+        );
+        assert_eq!(
+            response.text().await.unwrap(),
+            format!("GET /request-{} HTTP/1.1", i)
+        );
+    }
+
+    log::info!("All done :)");
+    assert_eq!(Box::new(upstream).stop().await, 3);
+}
+
+/// When the client sends `Connection: close`, balancebeam should hang up after responding
+#[tokio::test]
+async fn test_client_connection_close() {
+    let (balancebeam, upstream) = setup().await;
+
+    log::info!("Sending a request with Connection: close");
+    let mut stream = tokio::net::TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Error connecting to balancebeam");
+    stream
+        .write_all(b"GET /closing HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut response))
+        .await
+        .expect("balancebeam didn't close the connection")
+        .unwrap();
+    let response = String::from_utf8_lossy(&response);
+    assert!(response.starts_with("HTTP/1.1 200"));
+    assert!(response.contains("connection: close"));
+    assert!(response.contains("GET /closing HTTP/1.1"));
+
+    log::info!("All done :)");
+    Box::new(upstream).stop().await;
+}
+
+/// Reads one response from `stream`, using its Content-Length to tell where it ends
+async fn read_response(stream: &mut tokio::net::TcpStream) -> String {
+    let mut response = Vec::new();
+    loop {
+        let text = String::from_utf8_lossy(&response).to_string();
+        if let Some(headers_end) = text.find("\r\n\r\n") {
+            let content_length: usize = text[..headers_end]
+                .lines()
+                .find_map(|line| line.strip_prefix("content-length: "))
+                .expect("Response has no Content-Length")
+                .parse()
+                .unwrap();
+            if response.len() >= headers_end + 4 + content_length {
+                return text;
+            }
+        }
+        let mut buf = [0; 1024];
+        let n = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf))
+            .await
+            .expect("Timed out waiting for a response")
+            .unwrap();
+        assert!(n > 0, "Connection closed in the middle of a response");
+        response.extend_from_slice(&buf[..n]);
+    }
+}
+
+/// An HTTP/1.0 request without a Host header should reach the upstream with one, and balancebeam
+/// should close the connection after responding, since HTTP/1.0 clients don't expect keep-alive
+#[tokio::test]
+async fn test_http10_request() {
+    let (balancebeam, upstream) = setup().await;
+
+    let mut stream = tokio::net::TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Error connecting to balancebeam");
+    stream
+        .write_all(b"GET /legacy HTTP/1.0\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut response))
+        .await
+        .expect("balancebeam didn't close the connection")
+        .unwrap();
+    let response = String::from_utf8_lossy(&response);
+    assert!(response.contains(" 200 "), "{}", response);
+    assert!(response.contains("connection: close"), "{}", response);
+    assert!(response.contains("GET /legacy HTTP/1.0"), "{}", response);
+    assert!(
+        response.contains(&format!("host: {}", upstream.address)),
+        "{}",
+        response
+    );
+
+    log::info!("All done :)");
+    Box::new(upstream).stop().await;
+}
+
+/// An HTTP/1.0 client asking for keep-alive should be told it got it, and be able to send more
+/// requests on the same connection
+#[tokio::test]
+async fn test_http10_keep_alive() {
+    let (balancebeam, upstream) = setup().await;
+
+    let mut stream = tokio::net::TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Error connecting to balancebeam");
+    for path in ["/first", "/second"] {
+        stream
+            .write_all(
+                format!("GET {} HTTP/1.0\r\nConnection: keep-alive\r\n\r\n", path).as_bytes(),
+            )
+            .await
+            .unwrap();
+        let response = read_response(&mut stream).await;
+        assert!(response.contains(" 200 "), "{}", response);
+        assert!(response.contains("connection: keep-alive"), "{}", response);
+        assert!(
+            response.contains(&format!("GET {} HTTP/1.0", path)),
+            "{}",
+            response
+        );
+    }
+
+    // Without keep-alive, the last request closes the connection
+    stream
+        .write_all(b"GET /last HTTP/1.0\r\n\r\n")
+        .await
+        .unwrap();
+    let response = read_response(&mut stream).await;
+    assert!(response.contains("connection: close"), "{}", response);
+    let mut rest = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut rest))
+        .await
+        .expect("balancebeam didn't close the connection")
+        .unwrap();
+    assert!(rest.is_empty());
+
+    log::info!("All done :)");
+    assert_eq!(Box::new(upstream).stop().await, 3);
+}
+
+/// A client that connects and never sends a request should be disconnected after
+/// --client-idle-timeout instead of holding its connection open forever
+#[tokio::test]
+async fn test_client_idle_timeout() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--client-idle-timeout", "1"],
+    )
+    .await;
+
+    log::info!("Connecting without sending a request");
+    let mut stream = tokio::net::TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Error connecting to balancebeam");
+    let mut response = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut response))
+        .await
+        .expect("balancebeam didn't close the idle connection")
+        .unwrap();
+    let response = String::from_utf8_lossy(&response);
+    assert!(response.starts_with("HTTP/1.1 408"), "{}", response);
+
+    log::info!("All done :)");
+    assert_eq!(Box::new(upstream).stop().await, 0);
+}
+
+/// A client sending its request a byte at a time should get a response as usual, even if the
+/// whole request takes longer than --client-read-timeout, but one that stops sending partway
+/// through should be disconnected with a 408 after that timeout
+#[tokio::test]
+async fn test_client_read_timeout() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--client-read-timeout", "1"],
+    )
+    .await;
+
+    log::info!("Sending a request a byte at a time");
+    let mut stream = tokio::net::TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Error connecting to balancebeam");
+    stream.set_nodelay(true).unwrap();
+    let request = b"GET /dribble HTTP/1.1\r\nHost: example.com\r\nX-Slow: yes\r\n\r\n";
+    let started = Instant::now();
+    for byte in request {
+        stream.write_all(&[*byte]).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(25)).await;
+    }
+    assert!(started.elapsed() > Duration::from_secs(1));
+    let response = read_response(&mut stream).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    assert!(response.contains("GET /dribble HTTP/1.1"), "{}", response);
+    assert!(response.contains("x-slow: yes"), "{}", response);
+
+    log::info!("Stopping partway through a request");
+    stream
+        .write_all(b"GET /stalled HTTP/1.1\r\nHo")
+        .await
+        .unwrap();
+    let mut response = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut response))
+        .await
+        .expect("balancebeam didn't close the stalled connection")
+        .unwrap();
+    let response = String::from_utf8_lossy(&response);
+    assert!(response.starts_with("HTTP/1.1 408"), "{}", response);
+    assert!(response.contains("connection: close"), "{}", response);
+
+    log::info!("All done :)");
+    assert_eq!(Box::new(upstream).stop().await, 1);
+}
+
+/// Headers within --max-header-bytes should be accepted however many reads they take to arrive,
+/// while a request with more should get a 431 and be disconnected
+#[tokio::test]
+async fn test_max_header_bytes() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--max-header-bytes", "1024"],
+    )
+    .await;
+
+    log::info!("Sending headers just within the limit, a byte at a time");
+    let mut stream = tokio::net::TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Error connecting to balancebeam");
+    stream.set_nodelay(true).unwrap();
+    let mut request = b"GET /fits HTTP/1.1\r\nHost: example.com\r\nX-Padding: ".to_vec();
+    request.resize(1024 - 4, b'a');
+    request.extend_from_slice(b"\r\n\r\n");
+    for byte in &request {
+        stream.write_all(&[*byte]).await.unwrap();
+        // Give balancebeam a chance to read each byte on its own now and then
+        if rand::thread_rng().gen_ratio(1, 10) {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    }
+    let response = read_response(&mut stream).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    assert!(response.contains("GET /fits HTTP/1.1"), "{}", response);
+
+    log::info!("Sending headers over the limit");
+    let mut request = b"GET /too-big HTTP/1.1\r\nHost: example.com\r\nX-Padding: ".to_vec();
+    request.resize(2000, b'a');
+    request.extend_from_slice(b"\r\n\r\n");
+    stream.write_all(&request).await.unwrap();
+    let mut response = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut response))
+        .await
+        .expect("balancebeam didn't close the connection")
+        .unwrap();
+    let response = String::from_utf8_lossy(&response);
+    assert!(response.starts_with("HTTP/1.1 431"), "{}", response);
+
+    log::info!("All done :)");
+    assert_eq!(Box::new(upstream).stop().await, 1);
+}
+
+/// A keep-alive connection left idle after a request should be closed after --keepalive-timeout,
+/// without sending anything more
+#[tokio::test]
+async fn test_keepalive_timeout() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--keepalive-timeout", "1", "--client-idle-timeout", "10"],
+    )
+    .await;
+
+    log::info!("Sending a request, then idling");
+    let mut stream = tokio::net::TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Error connecting to balancebeam");
+    stream
+        .write_all(b"GET /first HTTP/1.1\r\nHost: example.com\r\n\r\n")
+        .await
+        .unwrap();
+    let response = read_response(&mut stream).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    assert!(
+        response.contains("keep-alive: timeout=1\r\n"),
+        "{}",
+        response
+    );
+    let idle_since = Instant::now();
+    let mut rest = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut rest))
+        .await
+        .expect("balancebeam didn't close the idle connection")
+        .unwrap();
+    assert!(rest.is_empty(), "{}", String::from_utf8_lossy(&rest));
+    assert!(idle_since.elapsed() >= Duration::from_millis(900));
+
+    log::info!("All done :)");
+    assert_eq!(Box::new(upstream).stop().await, 1);
+}
+
+/// --check-config should accept a usable configuration and reject a broken one, without serving
+#[tokio::test]
+async fn test_check_config() {
+    init_logging();
+    let bind_address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+
+    log::info!("Checking a valid configuration");
+    let (status, output) =
+        BalanceBeam::check_config(&["--bind", &bind_address, "--upstream", "127.0.0.1:8080"]).await;
+    assert!(status.success());
+    assert!(output.contains("Configuration OK"));
+    // Nothing should be listening on the bind address
+    assert!(tokio::net::TcpStream::connect(&bind_address).await.is_err());
+
+    log::info!("Checking a Host header for an upstream that is only in a group");
+    let (status, output) = BalanceBeam::check_config(&[
+        "--bind",
+        &bind_address,
+        "--upstream-group",
+        "api=127.0.0.1:8081",
+        "--virtual-host",
+        "api.example.com=api",
+        "--upstream-host-header",
+        "127.0.0.1:8081=api.internal",
+    ])
+    .await;
+    assert!(status.success(), "{}", output);
+    assert!(output.contains("Configuration OK"));
+
+    log::info!("Checking an invalid configuration");
+    let (status, output) = BalanceBeam::check_config(&[
+        "--bind",
+        &bind_address,
+        "--upstream",
+        "no-port-here",
+        "--maintenance-page",
+        "/nonexistent/maintenance.html",
+        "--upstream-host-header",
+        "127.0.0.1:8080=api.internal",
+    ])
+    .await;
+    assert!(!status.success());
+    assert!(output.contains("error: upstream no-port-here"));
+    assert!(output.contains("error: maintenance page /nonexistent/maintenance.html"));
+    assert!(output.contains("error: upstream Host header for 127.0.0.1:8080"));
+    assert!(output.contains("Configuration has 3 problem(s)"));
+
+    log::info!("Checking a configuration without upstreams");
+    let (status, _output) = BalanceBeam::check_config(&["--bind", &bind_address]).await;
+    assert!(!status.success());
+    log::info!("All done :)");
+}
+
+/// Requests with a method or path outside the allowlists should be refused without reaching the
+/// upstream
+#[tokio::test]
+async fn test_method_and_path_allowlist() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &[
+            "--allow-method",
+            "GET",
+            "--allow-method",
+            "head",
+            "--allow-path-prefix",
+            "/public/",
+        ],
+    )
+    .await;
+    let client = reqwest::Client::new();
+    let url = |path: &str| format!("http://{}{}", balancebeam.address, path);
+
+    log::info!("Sending an allowed GET request");
+    let response_text = balancebeam
+        .get("/public/index.html")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("GET /public/index.html HTTP/1.1"));
+
+    log::info!("Sending a POST request");
+    let response = client
+        .post(url("/public/form"))
+        .body("Hello world!")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status(), 405);
+    assert_eq!(response.headers()["allow"], "GET, HEAD");
+
+    log::info!("Sending a request for a path outside the allowed prefix");
+    let response = client
+        .get(url("/private/secrets"))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status(), 403);
+    drop(client);
+
+    // reqwest resolves dot segments itself, so send these by hand
+    for path in [
+        "/public/../private/secrets",
+        "/public/%2E%2E/private/secrets",
+    ] {
+        log::info!("Sending a request for {}", path);
+        let mut stream = tokio::net::TcpStream::connect(&balancebeam.address)
+            .await
+            .expect("Error connecting to balancebeam");
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+            path
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut response))
+            .await
+            .expect("balancebeam didn't close the connection")
+            .unwrap();
+        assert!(
+            response.starts_with(b"HTTP/1.1 403"),
+            "{} wasn't refused",
+            path
+        );
+    }
+
+    log::info!("All done :)");
+    assert_eq!(Box::new(upstream).stop().await, 1);
+}
+
+/// Upstream responses with a matching status should get the configured page and/or status, and
+/// other responses should pass through untouched
+#[tokio::test]
+async fn test_error_pages_and_status_rewrites() {
+    init_logging();
+    let page = "<html><body><h1>Nothing here</h1></body></html>";
+    let page_path = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("not_found.html");
+    std::fs::write(&page_path, page).expect("Error writing error page");
+    let error_page = format!("404:{}", page_path.to_str().unwrap());
+    let upstream = ErrorServer::new_with_status(http::StatusCode::NOT_FOUND).await;
+
+    let cases: [(&[&str], u16, &str); 3] = [
+        (&["--error-page", &error_page], 404, page),
+        (
+            &["--error-page", &error_page, "--rewrite-status", "404:410"],
+            410,
+            page,
+        ),
+        (&["--rewrite-status", "500:503"], 404, ""),
+    ];
+    for (args, status, body) in cases {
+        log::info!("Sending a request with {:?}", args);
+        let balancebeam = BalanceBeam::new_with_args(&[&upstream.address], None, None, args).await;
+        let response = reqwest::Client::new()
+            .get(format!("http://{}/missing", balancebeam.address))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.status(), status);
+        if !body.is_empty() {
+            assert_eq!(
+                response.headers()["content-type"],
+                "text/html; charset=utf-8"
+            );
+        }
+        assert_eq!(response.text().await.unwrap(), body);
+    }
+
+    log::info!("All done :)");
+    assert_eq!(Box::new(upstream).stop().await, 3);
+}
+
+/// Responses with a blocked Content-Type should be replaced with a 403, whatever the case and
+/// parameters, while other content types are forwarded
+#[tokio::test]
+async fn test_block_response_content_type() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &[
+            "--block-response-content-type",
+            "application/x-msdownload",
+            "--block-response-content-type",
+            "Application/Octet-Stream; charset=binary",
+        ],
+    )
+    .await;
+
+    let client = reqwest::Client::new();
+    for (content_type, status) in [
+        ("application/x-msdownload", 403),
+        ("APPLICATION/X-MSDOWNLOAD ; name=setup.exe", 403),
+        ("application/octet-stream", 403),
+        ("text/plain; charset=utf-8", 200),
+        ("application/x-msdownloads", 200),
+    ] {
+        log::info!("Requesting a {} response", content_type);
+        let response = client
+            .get(format!("http://{}/download", balancebeam.address))
+            .header("x-echo-content-type", content_type)
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.status(), status, "for {}", content_type);
+        let body = response.text().await.unwrap();
+        // Blocked bodies must not leak through
+        assert_eq!(body.contains("GET /download"), status == 200);
+    }
+    drop(client);
+
+    log::info!("All done :)");
+    assert_eq!(Box::new(upstream).stop().await, 5);
+}
+
+/// With --decompress-responses, compressed upstream responses should be decompressed for clients
+/// whose Accept-Encoding doesn't include the encoding, and passed through to those whose does
+#[tokio::test]
+async fn test_decompress_responses() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--decompress-responses"],
+    )
+    .await;
+
+    let client = reqwest::Client::new();
+    for (encoding, accept_encoding, decompressed) in [
+        ("gzip", None, true),
+        ("deflate", Some("gzip"), true),
+        ("gzip", Some("br, gzip;q=0"), true),
+        ("gzip", Some("deflate, GZIP"), false),
+        ("deflate", Some("*"), false),
+    ] {
+        log::info!(
+            "Requesting {} with Accept-Encoding {:?}",
+            encoding,
+            accept_encoding
+        );
+        let mut request = client
+            .get(format!("http://{}/compressed", balancebeam.address))
+            .header("x-echo-content-encoding", encoding);
+        if let Some(accept_encoding) = accept_encoding {
+            request = request.header("accept-encoding", accept_encoding);
+        }
+        let response = request
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.status(), 200);
+        let headers = response.headers().clone();
+        let body = response.bytes().await.unwrap().to_vec();
+        let body = if decompressed {
+            assert!(headers.get("content-encoding").is_none());
+            assert_eq!(headers["content-length"], body.len().to_string());
+            body
+        } else {
+            assert_eq!(headers["content-encoding"], encoding);
+            let mut decoded = Vec::new();
+            if encoding == "gzip" {
+                flate2::read::GzDecoder::new(&body[..]).read_to_end(&mut decoded)
+            } else {
+                flate2::read::ZlibDecoder::new(&body[..]).read_to_end(&mut decoded)
+            }
+            .expect("Response should be validly encoded");
+            decoded
+        };
+        assert!(String::from_utf8(body)
+            .unwrap()
+            .starts_with("GET /compressed HTTP/1.1\n"));
+    }
+    drop(client);
+
+    log::info!("All done :)");
+    assert_eq!(Box::new(upstream).stop().await, 5);
+}
+
+/// With --response-cache-entries, a fresh cached response should be answered with, and a stale one
+/// revalidated: a 304 should make it fresh again and serve the cached body, and a 200 replace it
+#[tokio::test]
+async fn test_response_cache_revalidation() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--response-cache-entries", "10"],
+    )
+    .await;
+
+    let client = reqwest::Client::new();
+    // The echoed body includes a traceparent that differs for every request that reaches the
+    // upstream, so identical bodies must have come from the cache
+    let get = |etag: &'static str| {
+        let client = client.clone();
+        let address = balancebeam.address.clone();
+        async move {
+            let response = client
+                .get(format!("http://{}/document", address))
+                .header("x-echo-etag", etag)
+                .header("x-echo-cache-control", "max-age=1")
+                .send()
+                .await
+                .expect("Error sending request to balancebeam");
+            assert_eq!(response.status(), 200);
+            assert_eq!(response.headers()["etag"], etag);
+            response.text().await.unwrap()
+        }
+    };
+
+    let original = get("\"v1\"").await;
+    assert!(!original.contains("if-none-match"), "{}", original);
+    assert_eq!(get("\"v1\"").await, original);
+    assert_eq!(upstream.requests_received(), 1);
+
+    log::info!("Revalidating an unchanged response");
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    assert_eq!(get("\"v1\"").await, original);
+    assert_eq!(upstream.requests_received(), 2);
+    // The 304 made it fresh again
+    assert_eq!(get("\"v1\"").await, original);
+    assert_eq!(upstream.requests_received(), 2);
+
+    log::info!("Revalidating a changed response");
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    let replacement = get("\"v2\"").await;
+    assert_ne!(replacement, original);
+    assert!(
+        replacement.contains("if-none-match: \"v1\"\n"),
+        "{}",
+        replacement
+    );
+    assert_eq!(upstream.requests_received(), 3);
+    assert_eq!(get("\"v2\"").await, replacement);
+    assert_eq!(upstream.requests_received(), 3);
+    drop(client);
+
+    log::info!("All done :)");
+    assert_eq!(Box::new(upstream).stop().await, 3);
+}
+
+/// Sends a GET request to balancebeam on a new connection and returns the response status.
+async fn get_status(balancebeam: &BalanceBeam, path: &str) -> reqwest::StatusCode {
+    reqwest::Client::new()
+        .get(format!("http://{}{}", balancebeam.address, path))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .status()
+}
+
+/// With a slow upstream, new requests should be turned away with a 503 while too many are in
+/// flight, and accepted again once the load drops
+#[tokio::test]
+async fn test_load_shedding() {
+    init_logging();
+    let upstream = EchoServer::new_slow(Duration::from_secs(1)).await;
+    let balancebeam = Arc::new(
+        BalanceBeam::new_with_args(
+            &[&upstream.address],
+            None,
+            None,
+            &["--shed-at-inflight", "2", "--shed-at-latency-ms", "500"],
+        )
+        .await,
+    );
+
+    log::info!("Sending a request to measure the upstream latency");
+    assert_eq!(get_status(&balancebeam, "/warmup").await, 200);
+
+    log::info!("Sending a request while three others are in flight");
+    // Set up the clients first: on this single-threaded runtime, setting one up holds up the
+    // others' requests, and can take long enough to make the requests arrive out of order
+    let mut clients: Vec<reqwest::Client> = (0..4).map(|_| reqwest::Client::new()).collect();
+    let shed_client = clients.pop().unwrap();
+    let mut in_flight = Vec::new();
+    for (i, client) in clients.into_iter().enumerate() {
+        let url = format!("http://{}/busy/{}", balancebeam.address, i);
+        in_flight.push(tokio::spawn(async move {
+            client
+                .get(url)
+                .send()
+                .await
+                .expect("Error sending request to balancebeam")
+                .status()
+        }));
+    }
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    let shed_status = shed_client
+        .get(format!("http://{}/shed", balancebeam.address))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .status();
+    assert_eq!(shed_status, 503);
+    for request in in_flight {
+        assert_eq!(request.await.unwrap(), 200);
+    }
+
+    log::info!("Sending a request after the load has dropped");
+    assert_eq!(get_status(&balancebeam, "/recovered").await, 200);
+
+    log::info!("All done :)");
+    assert_eq!(Box::new(upstream).stop().await, 5);
+}
+
+/// With --shutdown-after-requests, balancebeam should finish the requests it has already received
+/// and then exit cleanly once it has received that many
+#[tokio::test]
+async fn test_shutdown_after_requests() {
+    init_logging();
+    let upstream = EchoServer::new_slow(Duration::from_secs(1)).await;
+    let mut balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--shutdown-after-requests", "3"],
+    )
+    .await;
+    let address = balancebeam.address.clone();
+
+    log::info!("Sending two requests over a kept-alive connection");
+    let client = reqwest::Client::new();
+    for i in 0..2 {
+        let response = client
+            .get(format!("http://{}/before/{}", address, i))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.status(), 200);
+        assert!(response.headers().get("connection").is_none());
+    }
+
+    log::info!("Sending the last request while another connection is idle");
+    let last = reqwest::Client::new()
+        .get(format!("http://{}/last", address))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(last.status(), 200);
+    assert_eq!(last.headers().get("connection").unwrap(), "close");
+    drop(last);
+
+    log::info!("Checking that balancebeam exits");
+    let status = balancebeam
+        .wait_for_exit(Duration::from_secs(5))
+        .await
+        .expect("balancebeam didn't exit after the last request");
+    assert!(status.success(), "balancebeam exited with {}", status);
+    assert!(tokio::net::TcpStream::connect(&address).await.is_err());
+    drop(client);
+
+    log::info!("All done :)");
+    assert_eq!(Box::new(upstream).stop().await, 3);
+}
+
+/// Sends a request to balancebeam at `address` over a fresh connection, returning the response body
+/// along with the client address of that connection.
+async fn get_over_new_connection(address: &str) -> (String, std::net::SocketAddr) {
+    let mut stream = tokio::net::TcpStream::connect(address)
+        .await
+        .expect("Error connecting to balancebeam");
+    let client_address = stream.local_addr().unwrap();
+    stream
+        .write_all(b"GET /proxied HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut response))
+        .await
+        .expect("balancebeam didn't close the connection")
+        .unwrap();
+    let response = String::from_utf8_lossy(&response).to_string();
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    let body = response.split("\r\n\r\n").nth(1).unwrap_or("").to_string();
+    (body, client_address)
+}
+
+/// With --send-proxy-protocol, upstreams should be told the real address of IPv4 and IPv6 clients
+async fn check_proxy_protocol(version: &str) {
+    init_logging();
+    let upstream = ProxyProtocolServer::new().await;
+    let ipv6_address = format!("[::1]:{}", rand::thread_rng().gen_range(1024..65535));
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--bind", &ipv6_address, "--send-proxy-protocol", version],
+    )
+    .await;
+
+    for address in [&balancebeam.address, &ipv6_address] {
+        log::info!("Sending a request to {}", address);
+        let (body, client_address) = get_over_new_connection(address).await;
+        assert_eq!(body, client_address.to_string());
+    }
+
+    log::info!("All done :)");
+    assert_eq!(Box::new(upstream).stop().await, 2);
+}
+
+#[tokio::test]
+async fn test_send_proxy_protocol_v1() {
+    check_proxy_protocol("v1").await;
+}
+
+#[tokio::test]
+async fn test_send_proxy_protocol_v2() {
+    check_proxy_protocol("v2").await;
+}
+
+/// With --max-bytes-per-connection, a client should be cut off once its connection has carried that
+/// many bytes: after the response that reaches the limit, or in the middle of a request that does
+#[tokio::test]
+async fn test_max_bytes_per_connection() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--max-bytes-per-connection", "2000"],
+    )
+    .await;
+
+    log::info!("Sending small requests until the limit is reached");
+    let mut stream = tokio::net::TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Error connecting to balancebeam");
+    let mut responses = 0;
+    loop {
+        stream
+            .write_all(b"GET /small HTTP/1.1\r\nHost: example.com\r\n\r\n")
+            .await
+            .unwrap();
+        let response = read_response(&mut stream).await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        responses += 1;
+        if response.contains("connection: close\r\n") {
+            break;
+        }
+        assert!(responses < 10, "The connection was never closed");
+    }
+    assert!(
+        responses > 1,
+        "The first response already reached the limit"
+    );
+    let mut rest = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut rest))
+        .await
+        .expect("balancebeam didn't close the connection")
+        .unwrap();
+    assert!(rest.is_empty(), "{}", String::from_utf8_lossy(&rest));
+
+    log::info!("Streaming a body past the limit");
+    let mut stream = tokio::net::TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Error connecting to balancebeam");
+    stream
+        .write_all(b"POST /large HTTP/1.1\r\nHost: example.com\r\nContent-Length: 100000\r\n\r\n")
+        .await
+        .unwrap();
+    // balancebeam may hang up before the body is all written
+    for _ in 0..100 {
+        if stream.write_all(&[b'x'; 1000]).await.is_err() {
+            break;
+        }
+    }
+    let mut rest = Vec::new();
+    let _ = tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut rest))
+        .await
+        .expect("balancebeam didn't close the connection");
+    assert!(rest.is_empty(), "{}", String::from_utf8_lossy(&rest));
+
+    log::info!("All done :)");
+    assert_eq!(Box::new(upstream).stop().await, responses);
+}
+
+/// With --strip-path-prefix and --add-path-prefix, upstreams should see the rewritten path (with
+/// the query kept), while paths outside the stripped prefix only get the added one.
+#[tokio::test]
+async fn test_rewrite_path_prefix() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--strip-path-prefix", "/api/", "--add-path-prefix", "/v1"],
+    )
+    .await;
+
+    for (path, forwarded) in [
+        ("/api/users?id=7", "/v1/users?id=7"),
+        ("/api", "/v1/"),
+        ("/api/", "/v1/"),
+        ("/apiary", "/v1/apiary"),
+        ("/static/app.js", "/v1/static/app.js"),
+    ] {
+        log::info!("Requesting {}", path);
+        let response_text = balancebeam
+            .get(path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(
+            response_text.starts_with(&format!("GET {} HTTP/1.1", forwarded)),
+            "{} was forwarded as {}",
+            path,
+            response_text.lines().next().unwrap_or_default()
+        );
+    }
+
+    log::info!("All done :)");
+    assert_eq!(Box::new(upstream).stop().await, 5);
+}
+
+/// Returns the value of header `name` in a request echoed back by an EchoServer.
+fn echoed_header<'a>(response_text: &'a str, name: &str) -> Option<&'a str> {
+    response_text
+        .lines()
+        .find_map(|line| line.strip_prefix(&format!("{}: ", name)))
+}
+
+/// Checks that `traceparent` is a valid version 00 traceparent, returning its trace ID and parent
+/// (span) ID.
+fn parse_traceparent(traceparent: &str) -> (&str, &str) {
+    let fields: Vec<&str> = traceparent.split('-').collect();
+    assert_eq!(fields.len(), 4, "Invalid traceparent {}", traceparent);
+    assert_eq!(fields[0], "00", "Invalid traceparent {}", traceparent);
+    for (field, len) in fields[1..].iter().zip([32, 16, 2]) {
+        assert_eq!(field.len(), len, "Invalid traceparent {}", traceparent);
+        assert!(
+            field
+                .bytes()
+                .all(|digit| digit.is_ascii_hexdigit() && !digit.is_ascii_uppercase()),
+            "Invalid traceparent {}",
+            traceparent
+        );
+    }
+    assert!(fields[1].bytes().any(|digit| digit != b'0'));
+    assert!(fields[2].bytes().any(|digit| digit != b'0'));
+    (fields[1], fields[2])
+}
+
+/// Upstreams should get a traceparent continuing the client's trace with a span for the proxy (and
+/// the client's tracestate), or starting a new trace if the client didn't send one, and the trace
+/// and span should be logged.
+#[tokio::test]
+async fn test_trace_context() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+    let client = reqwest::Client::new();
+
+    log::info!("Sending a request that is part of a trace");
+    let trace_id = "4bf92f3577b34da6a3ce929d0e0e4736";
+    let response_text = client
+        .get(format!("http://{}/traced", balancebeam.address))
+        .header(
+            "traceparent",
+            format!("00-{}-00f067aa0ba902b7-01", trace_id),
+        )
+        .header("tracestate", "congo=t61rcWkgMzE")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .text()
+        .await
+        .expect("Error reading response from balancebeam");
+    let traceparent = echoed_header(&response_text, "traceparent").expect("No traceparent sent");
+    let (forwarded_trace_id, span_id) = parse_traceparent(traceparent);
+    assert_eq!(forwarded_trace_id, trace_id);
+    assert_ne!(span_id, "00f067aa0ba902b7");
+    assert!(traceparent.ends_with("-01"));
+    assert_eq!(
+        echoed_header(&response_text, "tracestate"),
+        Some("congo=t61rcWkgMzE")
+    );
+    let logged = format!("trace_id=\"{}\" span_id=\"{}\"", trace_id, span_id);
+    assert!(
+        balancebeam
+            .output()
+            .iter()
+            .any(|line| line.contains(&logged)),
+        "{} wasn't logged",
+        logged
+    );
+
+    log::info!("Sending requests that aren't part of a trace (or not validly)");
+    for traceparent in [
+        None,
+        Some("00-00000000000000000000000000000000-00f067aa0ba902b7-01"),
+    ] {
+        let mut request = client
+            .get(format!("http://{}/untraced", balancebeam.address))
+            .header("tracestate", "congo=t61rcWkgMzE");
+        if let Some(traceparent) = traceparent {
+            request = request.header("traceparent", traceparent);
+        }
+        let response_text = request
+            .send()
+            .await
+            .expect("Error sending request to balancebeam")
+            .text()
+            .await
+            .expect("Error reading response from balancebeam");
+        let (new_trace_id, _) = parse_traceparent(
+            echoed_header(&response_text, "traceparent").expect("No traceparent sent"),
+        );
+        assert_ne!(new_trace_id, trace_id);
+        assert_eq!(echoed_header(&response_text, "tracestate"), None);
+    }
+
+    log::info!("All done :)");
+    assert_eq!(Box::new(upstream).stop().await, 3);
+}
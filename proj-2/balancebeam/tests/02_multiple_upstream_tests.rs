@@ -2,19 +2,50 @@ mod common;
 
 use common::{init_logging, BalanceBeam, EchoServer, ErrorServer, Server};
 
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::time::sleep;
 
 async fn setup_with_params(
     n_upstreams: usize,
     active_health_check_interval: Option<usize>,
     max_requests_per_minute: Option<usize>,
+) -> (BalanceBeam, Vec<Box<dyn Server>>) {
+    setup_with_args(
+        n_upstreams,
+        active_health_check_interval,
+        max_requests_per_minute,
+        &[],
+    )
+    .await
+}
+
+async fn setup_with_args(
+    n_upstreams: usize,
+    active_health_check_interval: Option<usize>,
+    max_requests_per_minute: Option<usize>,
+    extra_args: &[&str],
 ) -> (BalanceBeam, Vec<Box<dyn Server>>) {
     init_logging();
     let mut upstreams: Vec<Box<dyn Server>> = Vec::new();
     for _ in 0..n_upstreams {
         upstreams.push(Box::new(EchoServer::new().await));
     }
+    let balancebeam = start_balancebeam(
+        &upstreams,
+        active_health_check_interval,
+        max_requests_per_minute,
+        extra_args,
+    )
+    .await;
+    (balancebeam, upstreams)
+}
+
+async fn start_balancebeam(
+    upstreams: &[Box<dyn Server>],
+    active_health_check_interval: Option<usize>,
+    max_requests_per_minute: Option<usize>,
+    extra_args: &[&str],
+) -> BalanceBeam {
     let upstream_addresses: Vec<String> = upstreams
         .iter()
         .map(|upstream| upstream.address())
@@ -23,13 +54,35 @@ async fn setup_with_params(
         .iter()
         .map(|addr| addr.as_str())
         .collect();
-    let balancebeam = BalanceBeam::new(
+    BalanceBeam::new_with_args(
         &upstream_addresses,
         active_health_check_interval,
         max_requests_per_minute,
+        extra_args,
     )
-    .await;
-    (balancebeam, upstreams)
+    .await
+}
+
+/// Sends a request through balancebeam and returns the index of the upstream that received it
+async fn send_and_locate(
+    balancebeam: &BalanceBeam,
+    upstreams: &[Box<dyn Server>],
+    path: &str,
+) -> usize {
+    let before: Vec<usize> = upstreams
+        .iter()
+        .map(|upstream| upstream.requests_received())
+        .collect();
+    let response_text = balancebeam
+        .get(path)
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    upstreams
+        .iter()
+        .zip(before)
+        .position(|(upstream, count)| upstream.requests_received() > count)
+        .expect("No upstream received the request")
 }
 
 async fn setup(n_upstreams: usize) -> (BalanceBeam, Vec<Box<dyn Server>>) {
@@ -271,7 +324,7 @@ async fn test_rate_limiting() {
     for i in 0..num_extra_requests {
         let client = reqwest::Client::new();
         let response = client
-            .get(&format!("http://{}/overboard-{}", balancebeam.address, i))
+            .get(format!("http://{}/overboard-{}", balancebeam.address, i))
             .header("x-sent-by", "balancebeam-tests")
             .send()
             .await
@@ -294,3 +347,422 @@ async fn test_rate_limiting() {
 
     log::info!("All done :)");
 }
+
+/// Requests over the rate limit should get the status and body given with --rate-limit-status and
+/// --rate-limit-body, instead of the default 429
+#[tokio::test]
+async fn test_custom_rate_limit_response() {
+    let rate_limit_threshold = 2;
+    let body = r#"{"error": "slow down"}"#;
+    let (balancebeam, mut upstreams) = setup_with_args(
+        1,
+        None,
+        Some(rate_limit_threshold),
+        &[
+            "--rate-limit-status",
+            "503",
+            "--rate-limit-body",
+            body,
+            "--rate-limit-content-type",
+            "application/json",
+        ],
+    )
+    .await;
+
+    for i in 0..rate_limit_threshold {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    log::info!("Sending a request over the limit");
+    let response = reqwest::get(format!("http://{}/overboard", balancebeam.address))
+        .await
+        .expect("Error sending rate limited request to balancebeam");
+    assert_eq!(response.status().as_u16(), 503);
+    assert_eq!(response.headers()["content-type"], "application/json");
+    assert_eq!(response.headers()["retry-after"], "60");
+    assert_eq!(response.text().await.unwrap(), body);
+
+    let upstream = upstreams.pop().unwrap();
+    assert_eq!(upstream.stop().await, rate_limit_threshold);
+    log::info!("All done :)");
+}
+
+/// Fix the RNG seed and make sure two balancebeam instances pick upstreams in the same order
+#[tokio::test]
+async fn test_seeded_upstream_selection() {
+    let n_upstreams = 3;
+    let n_requests = 12;
+    let (first_balancebeam, upstreams) =
+        setup_with_args(n_upstreams, None, None, &["--rng-seed", "110"]).await;
+    let second_balancebeam =
+        start_balancebeam(&upstreams, None, None, &["--rng-seed", "110"]).await;
+
+    let mut sequences = Vec::new();
+    for balancebeam in [&first_balancebeam, &second_balancebeam] {
+        let mut sequence = Vec::new();
+        for i in 0..n_requests {
+            let path = format!("/seeded-{}", i);
+            sequence.push(send_and_locate(balancebeam, &upstreams, &path).await);
+        }
+        log::info!("Upstream selection sequence: {:?}", sequence);
+        sequences.push(sequence);
+    }
+    assert_eq!(
+        sequences[0], sequences[1],
+        "Upstream selection differed between two runs with the same seed"
+    );
+    assert!(
+        sequences[0].iter().any(|idx| *idx != sequences[0][0]),
+        "Seeded selection always picked the same upstream"
+    );
+
+    for upstream in upstreams {
+        upstream.stop().await;
+    }
+    log::info!("All done :)");
+}
+
+/// With --balancing-strategy round-robin, requests should go to each upstream in turn, in the same
+/// order every time round
+#[tokio::test]
+async fn test_round_robin_selection() {
+    let n_upstreams = 3;
+    let (balancebeam, upstreams) = setup_with_args(
+        n_upstreams,
+        None,
+        None,
+        &["--balancing-strategy", "round-robin"],
+    )
+    .await;
+
+    let mut sequence = Vec::new();
+    for i in 0..n_upstreams * 3 {
+        let path = format!("/round-robin-{}", i);
+        sequence.push(send_and_locate(&balancebeam, &upstreams, &path).await);
+    }
+    log::info!("Upstream selection sequence: {:?}", sequence);
+    let mut first_cycle = sequence[..n_upstreams].to_vec();
+    first_cycle.sort();
+    assert_eq!(
+        first_cycle,
+        (0..n_upstreams).collect::<Vec<_>>(),
+        "The first {} requests didn't go to every upstream: {:?}",
+        n_upstreams,
+        sequence
+    );
+    for (i, upstream) in sequence.iter().enumerate() {
+        assert_eq!(
+            *upstream,
+            sequence[i % n_upstreams],
+            "Selection didn't repeat the same cycle: {:?}",
+            sequence
+        );
+    }
+
+    for upstream in upstreams {
+        upstream.stop().await;
+    }
+    log::info!("All done :)");
+}
+
+/// With --drain-on-unhealthy, a keep-alive connection bound to an upstream that gets marked
+/// unhealthy should be closed after its in-flight request, so the client's next request lands on a
+/// healthy upstream:
+///
+/// * Open keep-alive connections until one is bound to an upstream returning Error 500s
+/// * Wait for the active health check to mark that upstream unhealthy
+/// * The next request on the connection should be answered with `Connection: close`
+/// * The request after that should reach the healthy upstream
+#[tokio::test]
+async fn test_drain_on_unhealthy() {
+    init_logging();
+    let healthy: Box<dyn Server> = Box::new(EchoServer::new().await);
+    let unhealthy: Box<dyn Server> = Box::new(ErrorServer::new().await);
+    let upstreams = vec![healthy, unhealthy];
+    let balancebeam = start_balancebeam(&upstreams, Some(3), None, &["--drain-on-unhealthy"]).await;
+
+    log::info!("Opening connections until one is bound to the failing upstream");
+    let mut bound_client = None;
+    for i in 0..20 {
+        let client = reqwest::Client::new();
+        let response = client
+            .get(format!("http://{}/find-failing-{}", balancebeam.address, i))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        if response.status().as_u16() == 500 {
+            bound_client = Some(client);
+            break;
+        }
+    }
+    let client = bound_client.expect("Never got connected to the failing upstream");
+
+    log::info!("Waiting for the active health check to mark the failing upstream unhealthy...");
+    sleep(Duration::from_secs(4)).await;
+
+    log::info!("Sending a request on the connection bound to the unhealthy upstream");
+    let response = client
+        .get(format!("http://{}/in-flight", balancebeam.address))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 500);
+    assert_eq!(
+        response
+            .headers()
+            .get("connection")
+            .map(|value| value.to_str().unwrap()),
+        Some("close"),
+        "balancebeam didn't ask the client to close its connection to the unhealthy upstream"
+    );
+
+    log::info!("Sending another request, which should land on the healthy upstream");
+    let response = client
+        .get(format!("http://{}/after-drain", balancebeam.address))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 200);
+    let response_text = response.text().await.unwrap();
+    assert!(response_text.contains("GET /after-drain HTTP/1.1"));
+
+    for upstream in upstreams {
+        upstream.stop().await;
+    }
+    log::info!("All done :)");
+}
+
+/// Passive ejection test:
+/// * Start one healthy upstream and one that always returns Error 500
+/// * Send requests until the failing upstream has returned `--consecutive-errors` 500s in a row
+/// * The failing upstream should then be out of rotation, before any active health check runs
+#[tokio::test]
+async fn test_consecutive_errors_eject_upstream() {
+    init_logging();
+    let healthy: Box<dyn Server> = Box::new(EchoServer::new().await);
+    let failing: Box<dyn Server> = Box::new(ErrorServer::new().await);
+    let upstreams = vec![healthy, failing];
+    let balancebeam =
+        start_balancebeam(&upstreams, Some(60), None, &["--consecutive-errors", "3"]).await;
+
+    log::info!("Sending requests until the failing upstream has returned 3 errors");
+    let mut n_requests = 0;
+    while upstreams[1].requests_received() < 3 {
+        n_requests += 1;
+        assert!(
+            n_requests < 100,
+            "The failing upstream never got 3 requests"
+        );
+        balancebeam
+            .get(&format!("/before-ejection-{}", n_requests))
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+
+    log::info!("Checking that the failing upstream no longer receives requests");
+    for i in 0..20 {
+        let path = format!("/after-ejection-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+    assert_eq!(upstreams[1].requests_received(), 3);
+
+    for upstream in upstreams {
+        upstream.stop().await;
+    }
+    log::info!("All done :)");
+}
+
+/// With --upstream-host-header, upstreams should see the configured Host (with per-upstream
+/// overrides taking precedence), while the client's original Host is kept in X-Forwarded-Host
+#[tokio::test]
+async fn test_upstream_host_header() {
+    init_logging();
+    let upstreams: Vec<Box<dyn Server>> = vec![
+        Box::new(EchoServer::new().await),
+        Box::new(EchoServer::new().await),
+    ];
+    let api_override = format!("{}=api.internal", upstreams[0].address());
+    let balancebeam = start_balancebeam(
+        &upstreams,
+        None,
+        None,
+        &[
+            "--upstream-host-header",
+            "www.internal",
+            "--upstream-host-header",
+            &api_override,
+        ],
+    )
+    .await;
+
+    let mut seen = [false, false];
+    for i in 0..20 {
+        let before: Vec<usize> = upstreams
+            .iter()
+            .map(|upstream| upstream.requests_received())
+            .collect();
+        let response_text = balancebeam
+            .get(&format!("/host-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+        let index = upstreams
+            .iter()
+            .zip(before)
+            .position(|(upstream, count)| upstream.requests_received() > count)
+            .expect("No upstream received the request");
+        seen[index] = true;
+        let expected_host = if index == 0 {
+            "api.internal"
+        } else {
+            "www.internal"
+        };
+        assert!(
+            response_text.contains(&format!("\nhost: {}\n", expected_host)),
+            "upstream {} didn't get Host {}:\n{}",
+            index,
+            expected_host,
+            response_text
+        );
+        assert!(response_text.contains(&format!("x-forwarded-host: {}\n", balancebeam.address)));
+    }
+    assert_eq!(seen, [true, true], "Not every upstream received a request");
+
+    for upstream in upstreams {
+        upstream.stop().await;
+    }
+    log::info!("All done :)");
+}
+
+/// Sends a GET request for `path` straight to balancebeam and returns the response status
+async fn get_status(balancebeam: &BalanceBeam, path: &str) -> reqwest::StatusCode {
+    reqwest::Client::new()
+        .get(format!("http://{}{}", balancebeam.address, path))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .status()
+}
+
+/// The readiness probe should fail while every upstream is dead and pass again once one recovers,
+/// while the liveness probe passes throughout
+#[tokio::test]
+async fn test_liveness_and_readiness_probes() {
+    let (balancebeam, mut upstreams) = setup_with_args(
+        1,
+        Some(1),
+        None,
+        &["--liveness-path", "/livez", "--readiness-path", "/readyz"],
+    )
+    .await;
+    let address = upstreams[0].address();
+    assert_eq!(get_status(&balancebeam, "/livez").await, 200);
+    assert_eq!(get_status(&balancebeam, "/readyz").await, 200);
+
+    log::info!("Killing the only upstream");
+    upstreams.pop().unwrap().stop().await;
+    sleep(Duration::from_secs(3)).await;
+    assert_eq!(get_status(&balancebeam, "/readyz").await, 503);
+    assert_eq!(get_status(&balancebeam, "/livez").await, 200);
+    assert_eq!(get_status(&balancebeam, "/anything-else").await, 502);
+
+    log::info!("Bringing the upstream back");
+    upstreams.push(Box::new(EchoServer::new_at_address(address).await));
+    sleep(Duration::from_secs(3)).await;
+    assert_eq!(get_status(&balancebeam, "/readyz").await, 200);
+    assert_eq!(get_status(&balancebeam, "/livez").await, 200);
+
+    log::info!("Checking that other requests are still forwarded");
+    send_and_locate(&balancebeam, &upstreams, "/after-recovery").await;
+
+    for upstream in upstreams {
+        upstream.stop().await;
+    }
+    log::info!("All done :)");
+}
+
+/// Waits until `upstream` has received `count` requests in all, returning how long after `start`
+/// each new one arrived
+async fn wait_for_requests(
+    upstream: &dyn Server,
+    count: usize,
+    start: Instant,
+    timeout: Duration,
+) -> Vec<Duration> {
+    let mut seen = upstream.requests_received();
+    let mut arrivals = Vec::new();
+    while seen < count {
+        assert!(
+            start.elapsed() < timeout,
+            "Only {} of {} health checks arrived",
+            seen,
+            count
+        );
+        sleep(Duration::from_millis(20)).await;
+        let received = upstream.requests_received();
+        for _ in seen..received {
+            arrivals.push(start.elapsed());
+        }
+        seen = received;
+    }
+    arrivals
+}
+
+/// An upstream failing health checks should be checked less and less often, up to
+/// --health-check-max-backoff, and every interval again once it passes one
+#[tokio::test]
+async fn test_health_check_backoff() {
+    init_logging();
+    let failing = ErrorServer::new().await;
+    let address = failing.address();
+    let upstreams: Vec<Box<dyn Server>> = vec![Box::new(failing)];
+    let start = Instant::now();
+    let balancebeam = start_balancebeam(
+        &upstreams,
+        Some(1),
+        None,
+        &["--health-check-max-backoff", "8"],
+    )
+    .await;
+
+    log::info!("Timing health checks of a failing upstream");
+    let arrivals = wait_for_requests(&*upstreams[0], 4, start, Duration::from_secs(30)).await;
+    let gaps: Vec<Duration> = arrivals.windows(2).map(|pair| pair[1] - pair[0]).collect();
+    log::info!("Health checks {:?} apart", gaps);
+    // Each failure doubles the longest wait (1s interval, then up to 2s, 4s, 8s), and the actual
+    // wait is at least half of that
+    assert!(gaps[0] >= Duration::from_millis(900), "{:?}", gaps);
+    assert!(gaps[0] <= Duration::from_millis(2500), "{:?}", gaps);
+    assert!(gaps[1] >= Duration::from_millis(1900), "{:?}", gaps);
+    assert!(gaps[2] >= Duration::from_millis(3900), "{:?}", gaps);
+    assert!(gaps[2] <= Duration::from_millis(9500), "{:?}", gaps);
+
+    log::info!("Letting the upstream pass its next health check");
+    for upstream in upstreams {
+        upstream.stop().await;
+    }
+    let upstreams: Vec<Box<dyn Server>> = vec![Box::new(EchoServer::new_at_address(address).await)];
+    let start = Instant::now();
+    let arrivals = wait_for_requests(&*upstreams[0], 3, start, Duration::from_secs(15)).await;
+    let gaps: Vec<Duration> = arrivals.windows(2).map(|pair| pair[1] - pair[0]).collect();
+    log::info!("Health checks {:?} apart", gaps);
+    assert!(
+        gaps.iter().all(|gap| *gap <= Duration::from_millis(1500)),
+        "{:?}",
+        gaps
+    );
+
+    drop(balancebeam);
+    for upstream in upstreams {
+        upstream.stop().await;
+    }
+    log::info!("All done :)");
+}
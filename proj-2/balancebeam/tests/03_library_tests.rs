@@ -0,0 +1,1237 @@
+mod common;
+
+use balancebeam::{Config, Proxy, ProxyState, RateLimitKey, RateLimitRule, RouteMatch};
+use common::{init_logging, EchoServer, Server};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// The proxy should be usable in-process: bind an ephemeral port, serve from a spawned task, and
+/// stop when that task is aborted.
+#[tokio::test]
+async fn test_embedded_proxy() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let proxy = Proxy::new(Config {
+        bind: vec!["127.0.0.1:0".to_string()],
+        upstream: vec![upstream.address.clone()],
+        ..Config::default()
+    })
+    .expect("Error setting up the proxy");
+    let address = proxy.local_addrs()[0];
+    assert_ne!(address.port(), 0);
+    let proxy_task = tokio::spawn(proxy.run());
+
+    log::info!("Sending a GET request");
+    let client = reqwest::Client::new();
+    let response_text = client
+        .get(format!("http://{}/embedded", address))
+        .header("x-sent-by", "balancebeam-tests")
+        .send()
+        .await
+        .expect("Error sending request to the proxy")
+        .text()
+        .await
+        .expect("Error reading response from the proxy");
+    assert!(response_text.contains("GET /embedded HTTP/1.1"));
+    assert!(response_text.contains("x-forwarded-for: 127.0.0.1"));
+    drop(client);
+
+    log::info!("Stopping the proxy");
+    proxy_task.abort();
+    assert!(proxy_task.await.unwrap_err().is_cancelled());
+    assert!(tokio::net::TcpStream::connect(address).await.is_err());
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// A restarted proxy should be able to bind to its port straight away, even though the connections
+/// the previous one closed are still in TIME_WAIT.
+#[tokio::test]
+async fn test_rebind_after_restart() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let config = |bind: String| Config {
+        bind: vec![bind],
+        upstream: vec![upstream.address.clone()],
+        ..Config::default()
+    };
+    let proxy = Proxy::new(config("127.0.0.1:0".to_string())).expect("Error setting up the proxy");
+    let address = proxy.local_addrs()[0];
+    let proxy_task = tokio::spawn(proxy.run());
+
+    log::info!("Leaving a connection open to the proxy");
+    let mut client = tokio::net::TcpStream::connect(address).await.unwrap();
+    client
+        .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .await
+        .unwrap();
+    let mut buf = [0; 1024];
+    assert!(client.read(&mut buf).await.unwrap() > 0);
+
+    log::info!("Stopping the proxy, which closes the connection first");
+    proxy_task.abort();
+    assert!(proxy_task.await.unwrap_err().is_cancelled());
+    while client.read(&mut buf).await.unwrap_or(0) > 0 {}
+    drop(client);
+
+    log::info!("Starting a new proxy on the same port");
+    let proxy = Proxy::new(config(address.to_string())).expect("Error rebinding the proxy");
+    assert_eq!(proxy.local_addrs(), vec![address]);
+    tokio::spawn(proxy.run());
+    assert_eq!(get_status(address, "/").await, 200);
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// With --tcp-nodelay, upstream connections should have TCP_NODELAY set, and only then.
+#[tokio::test]
+async fn test_tcp_nodelay() {
+    init_logging();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let listening = listener.local_addr().unwrap().to_string();
+    for nodelay in [false, true] {
+        let stream = balancebeam::upstream::connect_tcp(&listening, nodelay)
+            .await
+            .expect("Error connecting to the listener");
+        assert_eq!(stream.nodelay().unwrap(), nodelay);
+    }
+
+    log::info!("Proxying with TCP_NODELAY");
+    let upstream = EchoServer::new().await;
+    let (address, _) = start_proxy(Config {
+        upstream: vec![upstream.address.clone()],
+        tcp_nodelay: true,
+        ..Config::default()
+    });
+    assert_eq!(get_status(address, "/").await, 200);
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// A client that resets its connection before the proxy gets to it should have the connection
+/// dropped, rather than panicking the task serving it
+#[tokio::test]
+async fn test_connection_reset_before_serving() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let (address, state) = start_proxy(Config {
+        upstream: vec![upstream.address.clone()],
+        ..Config::default()
+    });
+
+    log::info!("Resetting a connection before it is served");
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let client = tokio::net::TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (server_conn, _) = listener.accept().await.unwrap();
+    // Closing with a zero linger time sends a RST instead of a FIN
+    client.set_zero_linger().unwrap();
+    drop(client);
+    let reset = Instant::now();
+    while server_conn.peer_addr().is_ok() {
+        assert!(
+            reset.elapsed() < Duration::from_secs(5),
+            "Connection never reset"
+        );
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    // Panics in here would fail the test
+    balancebeam::handle_connection(server_conn, &state).await;
+
+    log::info!("Checking the proxy still serves other clients");
+    assert_eq!(get_status(address, "/").await, 200);
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// Starts a proxy for `config` on an ephemeral port, returning its address and state.
+fn start_proxy(config: Config) -> (SocketAddr, ProxyState) {
+    let proxy = Proxy::new(Config {
+        bind: vec!["127.0.0.1:0".to_string()],
+        ..config
+    })
+    .expect("Error setting up the proxy");
+    let address = proxy.local_addrs()[0];
+    let state = proxy.state().clone();
+    tokio::spawn(proxy.run());
+    (address, state)
+}
+
+/// Sends a GET request on a new connection (closed afterwards) and returns the response status.
+async fn get_status(address: SocketAddr, path: &str) -> reqwest::StatusCode {
+    reqwest::Client::new()
+        .get(format!("http://{}{}", address, path))
+        .send()
+        .await
+        .expect("Error sending request to the proxy")
+        .status()
+}
+
+/// With every upstream connection slot taken, clients should wait in the queue until a slot frees
+/// up, and get a 503 if none frees up within the queue timeout.
+#[tokio::test]
+async fn test_queue_for_saturated_upstreams() {
+    init_logging();
+    let upstream = EchoServer::new_slow(Duration::from_secs(2)).await;
+    let config = |queue_timeout| Config {
+        upstream: vec![upstream.address.clone()],
+        max_upstream_connections: 1,
+        queue_timeout,
+        ..Config::default()
+    };
+
+    log::info!("Queueing behind a slow request");
+    let (address, state) = start_proxy(config(5));
+    let start = Instant::now();
+    let first = tokio::spawn(get_status(address, "/first"));
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    let second = tokio::spawn(get_status(address, "/second"));
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert_eq!(state.queue_depth(), 1);
+    assert_eq!(first.await.unwrap(), 200);
+    assert_eq!(second.await.unwrap(), 200);
+    // The second request can only start once the first is done
+    assert!(start.elapsed() >= Duration::from_secs(4));
+    assert_eq!(state.queue_depth(), 0);
+
+    log::info!("Timing out in the queue");
+    let (address, state) = start_proxy(config(1));
+    let first = tokio::spawn(get_status(address, "/first"));
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    let start = Instant::now();
+    assert_eq!(get_status(address, "/second").await, 503);
+    assert!(start.elapsed() >= Duration::from_secs(1));
+    assert_eq!(state.queue_depth(), 0);
+    assert_eq!(first.await.unwrap(), 200);
+
+    assert_eq!(Box::new(upstream).stop().await, 3);
+    log::info!("All done :)");
+}
+
+/// With adaptive concurrency, an upstream whose latency climbs should have its connection limit cut
+#[tokio::test]
+async fn test_adaptive_concurrency() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let (address, state) = start_proxy(Config {
+        upstream: vec![upstream.address.clone()],
+        adaptive_concurrency: true,
+        ..Config::default()
+    });
+    let client = reqwest::Client::new();
+    let get_with_delay = |delay_ms: u64| {
+        client
+            .get(format!("http://{}/", address))
+            .header("x-echo-delay-ms", delay_ms)
+            .send()
+    };
+
+    log::info!("Sending requests while the upstream is fast");
+    for _ in 0..20 {
+        let response = get_with_delay(0)
+            .await
+            .expect("Error sending request to the proxy");
+        assert_eq!(response.status(), 200);
+    }
+    let steady_limit = state.concurrency_limit(&upstream.address).unwrap();
+    assert!(steady_limit >= 20, "{}", steady_limit);
+
+    log::info!("Sending requests while the upstream slows down");
+    let mut limits = vec![steady_limit];
+    for delay_ms in [200, 400, 600, 800] {
+        let response = get_with_delay(delay_ms)
+            .await
+            .expect("Error sending request to the proxy");
+        assert_eq!(response.status(), 200);
+        limits.push(state.concurrency_limit(&upstream.address).unwrap());
+    }
+    log::info!("Limits: {:?}", limits);
+    assert!(
+        limits.windows(2).all(|pair| pair[1] < pair[0]),
+        "{:?}",
+        limits
+    );
+    assert!(limits[limits.len() - 1] >= 1);
+
+    assert_eq!(Box::new(upstream).stop().await, 24);
+    log::info!("All done :)");
+}
+
+/// With a loopback upstream, every request should get the canned response without the proxy
+/// dialing an upstream, while still going through rate limiting
+#[tokio::test]
+async fn test_loopback_upstream() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let (address, _state) = start_proxy(Config {
+        upstream: vec![upstream.address.clone()],
+        loopback_upstream: Some(100),
+        max_requests_per_minute: 3,
+        ..Config::default()
+    });
+
+    log::info!("Sending requests up to the rate limit");
+    let client = reqwest::Client::new();
+    for i in 0..3 {
+        let response = client
+            .get(format!("http://{}/loopback/{}", address, i))
+            .send()
+            .await
+            .expect("Error sending request to the proxy");
+        assert_eq!(response.status(), 200);
+        let body = response.text().await.unwrap();
+        assert_eq!(body, "x".repeat(100));
+    }
+    log::info!("Sending one request too many");
+    let response = client
+        .get(format!("http://{}/loopback/limited", address))
+        .send()
+        .await
+        .expect("Error sending request to the proxy");
+    assert_eq!(response.status(), 429);
+    drop(client);
+
+    assert_eq!(Box::new(upstream).stop().await, 0);
+    log::info!("All done :)");
+}
+
+/// Under parallel load from many clients, each client should get exactly its quota of requests
+/// through before being rate limited
+#[tokio::test]
+async fn test_rate_limit_parallel_clients() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let (address, _state) = start_proxy(Config {
+        upstream: vec![upstream.address.clone()],
+        loopback_upstream: Some(10),
+        max_requests_per_minute: 10,
+        ..Config::default()
+    });
+
+    log::info!("Sending requests from 8 clients at once");
+    // Every 127.x.x.x address is loopback, so each of these is a different client to the proxy
+    let client_ips: Vec<std::net::IpAddr> = (2..10)
+        .map(|i| std::net::Ipv4Addr::new(127, 0, 0, i).into())
+        .collect();
+    let mut requests = Vec::new();
+    for &client_ip in &client_ips {
+        let client = reqwest::Client::builder()
+            .local_address(client_ip)
+            .build()
+            .unwrap();
+        for i in 0..25 {
+            let client = client.clone();
+            requests.push(tokio::spawn(async move {
+                let status = client
+                    .get(format!("http://{}/parallel/{}", address, i))
+                    .send()
+                    .await
+                    .expect("Error sending request to the proxy")
+                    .status();
+                (client_ip, status)
+            }));
+        }
+    }
+    let mut passed: HashMap<std::net::IpAddr, usize> = HashMap::new();
+    for request in requests {
+        let (client_ip, status) = request.await.unwrap();
+        match status.as_u16() {
+            200 => *passed.entry(client_ip).or_insert(0) += 1,
+            429 => {}
+            status => panic!("Unexpected status {} for {}", status, client_ip),
+        }
+    }
+    for client_ip in &client_ips {
+        assert_eq!(passed.get(client_ip), Some(&10), "{:?}", passed);
+    }
+
+    assert_eq!(Box::new(upstream).stop().await, 0);
+    log::info!("All done :)");
+}
+
+/// Requests matching a rate limit rule should be limited by it, with counts of their own, while
+/// other requests are limited by --max-requests-per-minute
+#[tokio::test]
+async fn test_rate_limit_rules() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let (address, _state) = start_proxy(Config {
+        upstream: vec![upstream.address.clone()],
+        loopback_upstream: Some(10),
+        max_requests_per_minute: 3,
+        rate_limit_rules: vec![
+            RateLimitRule {
+                matches: vec![RouteMatch::PathPrefix("/login".to_string())],
+                max_requests_per_minute: 2,
+                key: RateLimitKey::ClientIp,
+            },
+            RateLimitRule {
+                matches: vec![RouteMatch::PathPrefix("/static".to_string())],
+                max_requests_per_minute: 0,
+                key: RateLimitKey::ClientIp,
+            },
+            RateLimitRule {
+                matches: vec![RouteMatch::PathPrefix("/shared".to_string())],
+                max_requests_per_minute: 4,
+                key: RateLimitKey::All,
+            },
+        ],
+        ..Config::default()
+    });
+    let client = reqwest::Client::new();
+    let get = |path: &str| {
+        let request = client.get(format!("http://{}{}", address, path));
+        async move {
+            request
+                .send()
+                .await
+                .expect("Error sending request to the proxy")
+                .status()
+                .as_u16()
+        }
+    };
+
+    log::info!("Logging in past the rule's limit");
+    assert_eq!(get("/login").await, 200);
+    assert_eq!(get("/login/again").await, 200);
+    assert_eq!(get("/login").await, 429);
+
+    log::info!("Fetching unlimited static files");
+    for i in 0..10 {
+        assert_eq!(get(&format!("/static/{}", i)).await, 200);
+    }
+
+    log::info!("Sending other requests up to the global limit");
+    for i in 0..3 {
+        assert_eq!(get(&format!("/other/{}", i)).await, 200);
+    }
+    assert_eq!(get("/other/limited").await, 429);
+
+    log::info!("Sharing a limit between clients");
+    let mut statuses = Vec::new();
+    for i in 2..7 {
+        let client = reqwest::Client::builder()
+            .local_address(std::net::IpAddr::from(std::net::Ipv4Addr::new(
+                127, 0, 0, i,
+            )))
+            .build()
+            .unwrap();
+        statuses.push(
+            client
+                .get(format!("http://{}/shared", address))
+                .send()
+                .await
+                .expect("Error sending request to the proxy")
+                .status()
+                .as_u16(),
+        );
+    }
+    assert_eq!(statuses, vec![200, 200, 200, 200, 429]);
+    drop(client);
+
+    assert_eq!(Box::new(upstream).stop().await, 0);
+    log::info!("All done :)");
+}
+
+/// Makes a certificate for `common_name`, signed by `ca` (or self-signed if None). CAs can sign
+/// other certificates; other certificates are for clients.
+fn make_cert(common_name: &str, ca: Option<&rcgen::Certificate>) -> (rcgen::Certificate, String) {
+    let mut params = rcgen::CertificateParams::new(vec!["localhost".to_string()]);
+    params
+        .distinguished_name
+        .push(rcgen::DnType::CommonName, common_name);
+    match ca {
+        None => params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained),
+        Some(_) => params.extended_key_usages = vec![rcgen::ExtendedKeyUsagePurpose::ClientAuth],
+    }
+    let cert = rcgen::Certificate::from_params(params).expect("Error generating certificate");
+    let pem = match ca {
+        Some(ca) => cert.serialize_pem_with_signer(ca),
+        None => cert.serialize_pem(),
+    }
+    .expect("Error serializing certificate");
+    (cert, pem)
+}
+
+/// Sends a GET request over TLS (presenting `client_cert` if given) and returns the whole
+/// response, or an error if the proxy refuses the handshake.
+async fn tls_get(
+    address: SocketAddr,
+    server_cert: &rcgen::Certificate,
+    client_cert: Option<(&rcgen::Certificate, &rcgen::Certificate)>,
+    extra_header: &str,
+) -> std::io::Result<String> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::rustls;
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots
+        .add(&rustls::Certificate(server_cert.serialize_der().unwrap()))
+        .unwrap();
+    let builder = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots);
+    let config = match client_cert {
+        Some((cert, ca)) => builder
+            .with_client_auth_cert(
+                vec![rustls::Certificate(
+                    cert.serialize_der_with_signer(ca).unwrap(),
+                )],
+                rustls::PrivateKey(cert.serialize_private_key_der()),
+            )
+            .unwrap(),
+        None => builder.with_no_client_auth(),
+    };
+    let stream = tokio::net::TcpStream::connect(address).await?;
+    let mut stream = tokio_rustls::TlsConnector::from(std::sync::Arc::new(config))
+        .connect("localhost".try_into().unwrap(), stream)
+        .await?;
+    stream
+        .write_all(
+            format!(
+                "GET /mtls HTTP/1.1\r\nHost: localhost\r\n{}Connection: close\r\n\r\n",
+                extra_header
+            )
+            .as_bytes(),
+        )
+        .await?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+    Ok(response)
+}
+
+/// With --client-ca, clients with a certificate from that CA should be let through with their name
+/// passed on to the upstream, while clients with no certificate or one from another CA are refused
+#[tokio::test]
+async fn test_client_certificates() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let server_cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let server_pem = server_cert.serialize_pem().unwrap();
+    let (ca, ca_pem) = make_cert("Test CA", None);
+    let (other_ca, _) = make_cert("Other CA", None);
+    let (alice, _) = make_cert("alice", Some(&ca));
+    let (mallory, _) = make_cert("mallory", Some(&other_ca));
+    let (address, _state) = start_proxy(Config {
+        upstream: vec![upstream.address.clone()],
+        tls_cert: Some(server_pem.into_bytes()),
+        tls_key: Some(server_cert.serialize_private_key_pem().into_bytes()),
+        client_ca: Some(ca_pem.into_bytes()),
+        ..Config::default()
+    });
+
+    log::info!("Connecting with a trusted certificate");
+    let response = tls_get(address, &server_cert, Some((&alice, &ca)), "")
+        .await
+        .expect("Error sending request with a trusted certificate");
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    assert!(response.contains("GET /mtls HTTP/1.1"));
+    assert!(response.contains("x-client-cert-cn: alice"), "{}", response);
+
+    log::info!("Claiming to be someone else with the header");
+    let response = tls_get(
+        address,
+        &server_cert,
+        Some((&alice, &ca)),
+        "X-Client-Cert-CN: bob\r\n",
+    )
+    .await
+    .expect("Error sending request with a trusted certificate");
+    assert!(response.contains("x-client-cert-cn: alice"), "{}", response);
+    assert!(!response.contains("bob"), "{}", response);
+
+    log::info!("Connecting with an untrusted certificate, and with none");
+    let untrusted = tls_get(address, &server_cert, Some((&mallory, &other_ca)), "").await;
+    assert!(untrusted.is_err(), "{:?}", untrusted);
+    let anonymous = tls_get(address, &server_cert, None, "").await;
+    assert!(anonymous.is_err(), "{:?}", anonymous);
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// Upstreams listening on Unix sockets should be health checked, proxied to and balanced across
+/// like TCP ones
+#[tokio::test]
+async fn test_unix_socket_upstream() {
+    init_logging();
+    let unix_upstream = EchoServer::new_unix().await;
+    let tcp_upstream = EchoServer::new().await;
+    assert!(unix_upstream.address.starts_with("unix:"));
+    let (address, _state) = start_proxy(Config {
+        upstream: vec![unix_upstream.address.clone(), tcp_upstream.address.clone()],
+        rng_seed: Some(1),
+        active_health_check_interval: 1,
+        ..Config::default()
+    });
+    // Health checks must reach the Unix socket too, or they would take it out of rotation
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    let unix_before = unix_upstream.requests_received();
+    let tcp_before = tcp_upstream.requests_received();
+
+    // Each request gets a new connection, so that it can be balanced onto either upstream
+    log::info!("Sending requests to both upstreams");
+    let client = reqwest::Client::new();
+    for i in 0..20 {
+        let path = format!("/unix/{}", i);
+        let response_text = client
+            .get(format!("http://{}{}", address, path))
+            .header("connection", "close")
+            .send()
+            .await
+            .expect("Error sending request to the proxy")
+            .text()
+            .await
+            .expect("Error reading response from the proxy");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+        assert!(response_text.contains("x-forwarded-for: 127.0.0.1"));
+    }
+
+    drop(client);
+
+    // Health checks may have run in the meantime, but they only ever add one request per upstream
+    let unix_requests = unix_upstream.requests_received() - unix_before;
+    let tcp_requests = tcp_upstream.requests_received() - tcp_before;
+    assert!((20..=22).contains(&(unix_requests + tcp_requests)));
+    assert!(
+        unix_requests > 0,
+        "The Unix socket upstream never got a request"
+    );
+    assert!(tcp_requests > 0, "The TCP upstream never got a request");
+    Box::new(unix_upstream).stop().await;
+    Box::new(tcp_upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// An upstream whose address can't be sent as a Host header shouldn't bring down health checks
+/// (or the proxy), just fail them
+#[tokio::test]
+async fn test_upstream_address_not_a_header_value() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let (address, _state) = start_proxy(Config {
+        upstream: vec!["bad\u{1}host:80".to_string(), upstream.address.clone()],
+        active_health_check_interval: 1,
+        ..Config::default()
+    });
+    tokio::time::sleep(Duration::from_millis(2500)).await;
+    assert!(
+        upstream.requests_received() >= 2,
+        "The good upstream stopped being health checked"
+    );
+
+    log::info!("Sending a request once the bad upstream is out of rotation");
+    assert_eq!(get_status(address, "/").await, reqwest::StatusCode::OK);
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Collects everything written by a tracing subscriber, for inspecting traces.
+#[derive(Clone, Default)]
+struct CapturedTraces(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+impl std::io::Write for CapturedTraces {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl CapturedTraces {
+    /// Returns each event traced so far.
+    fn events(&self) -> Vec<serde_json::Value> {
+        String::from_utf8(self.0.lock().unwrap().clone())
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).expect("Trace line isn't JSON"))
+            .collect()
+    }
+}
+
+/// Events logged while handling a request should be inside spans carrying the client's IP, the
+/// request's ID and the upstream the request went to
+#[tokio::test]
+async fn test_request_spans() {
+    // The subscriber only covers this thread, which is the only one the test runtime runs tasks on
+    let traces = CapturedTraces::default();
+    let writer = traces.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .json()
+        .with_max_level(tracing::Level::DEBUG)
+        .with_writer(move || writer.clone())
+        .finish();
+    let _subscriber = tracing::subscriber::set_default(subscriber);
+
+    let upstream = EchoServer::new().await;
+    let (address, _state) = start_proxy(Config {
+        upstream: vec![upstream.address.clone()],
+        ..Config::default()
+    });
+    assert_eq!(get_status(address, "/traced/1").await, 200);
+    assert_eq!(get_status(address, "/traced/2").await, 200);
+
+    let events = traces.events();
+    let spans_of = |message: &str| {
+        let event = events
+            .iter()
+            .find(|event| {
+                event["fields"]["message"]
+                    .as_str()
+                    .is_some_and(|text| text.contains(message))
+            })
+            .unwrap_or_else(|| panic!("No event mentioning {}", message));
+        event["spans"].as_array().unwrap().clone()
+    };
+    let mut request_ids = Vec::new();
+    for path in ["/traced/1", "/traced/2"] {
+        let spans = spans_of(path);
+        assert_eq!(spans.len(), 2);
+        assert_eq!(spans[0]["name"], "connection");
+        assert_eq!(spans[0]["client_ip"], "127.0.0.1");
+        assert_eq!(spans[1]["name"], "request");
+        assert_eq!(spans[1]["upstream"], upstream.address.as_str());
+        request_ids.push(spans[1]["id"].as_u64().expect("Request span has no ID"));
+    }
+    assert_ne!(request_ids[0], request_ids[1]);
+
+    // Events from deeper down are attributed to the request too
+    let spans = spans_of("Forwarded request to server");
+    assert_eq!(spans[1]["name"], "request");
+    assert!(spans[1]["id"].is_u64());
+
+    Box::new(upstream).stop().await;
+}
+
+/// With warm connections, the proxy should open idle connections to each upstream at startup (no
+/// more than --max-upstream-connections allows), and the first request should use one of them
+#[tokio::test]
+async fn test_warm_connections() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let (address, state) = start_proxy(Config {
+        upstream: vec![upstream.address.clone()],
+        warm_connections: 2,
+        ..Config::default()
+    });
+    let deadline = Instant::now() + Duration::from_secs(2);
+    while state.parked_connections() < 2 && Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    assert_eq!(state.parked_connections(), 2);
+    assert_eq!(upstream.connections_received(), 2);
+
+    log::info!("Sending a request, which should go over a warm connection");
+    assert_eq!(get_status(address, "/warm").await, 200);
+    assert_eq!(state.parked_connections(), 1);
+    assert_eq!(upstream.connections_received(), 2);
+    assert_eq!(upstream.requests_received(), 1);
+
+    log::info!("Checking that warming respects the connection cap");
+    let capped_upstream = EchoServer::new().await;
+    let (_, capped_state) = start_proxy(Config {
+        upstream: vec![capped_upstream.address.clone()],
+        warm_connections: 2,
+        max_upstream_connections: 1,
+        ..Config::default()
+    });
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert_eq!(capped_state.parked_connections(), 1);
+    assert_eq!(capped_upstream.connections_received(), 1);
+
+    // The upstreams can't stop until the proxies close their parked connections
+    state.shutdown();
+    capped_state.shutdown();
+    let deadline = Instant::now() + Duration::from_secs(2);
+    while state.parked_connections() + capped_state.parked_connections() > 0
+        && Instant::now() < deadline
+    {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    Box::new(upstream).stop().await;
+    Box::new(capped_upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Draining an upstream through the admin API should let its in-flight request finish while every
+/// new request goes to the other upstream.
+#[tokio::test]
+async fn test_drain_upstream() {
+    init_logging();
+    let upstreams = vec![
+        EchoServer::new_slow(Duration::from_secs(2)).await,
+        EchoServer::new_slow(Duration::from_secs(2)).await,
+    ];
+    let proxy = Proxy::new(Config {
+        bind: vec!["127.0.0.1:0".to_string()],
+        upstream: upstreams.iter().map(|u| u.address.clone()).collect(),
+        admin_bind: Some("127.0.0.1:0".to_string()),
+        ..Config::default()
+    })
+    .expect("Error setting up the proxy");
+    let address = proxy.local_addrs()[0];
+    let admin = proxy
+        .admin_addr()
+        .expect("The admin API should be listening");
+    let state = proxy.state().clone();
+    tokio::spawn(proxy.run());
+
+    log::info!("Starting a slow request");
+    let in_flight = tokio::spawn(get_status(address, "/in-flight"));
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    let (draining, other) = if upstreams[0].requests_received() == 1 {
+        (&upstreams[0], &upstreams[1])
+    } else {
+        (&upstreams[1], &upstreams[0])
+    };
+    assert_eq!(draining.requests_received(), 1);
+
+    log::info!("Draining {}", draining.address);
+    let client = reqwest::Client::new();
+    let drain_url = format!("http://{}/upstreams/{}/drain", admin, draining.address);
+    let response = client
+        .post(format!("{}?max=0", drain_url))
+        .send()
+        .await
+        .expect("Error sending request to the admin API");
+    assert_eq!(response.status(), 200);
+    assert_eq!(state.drain_status(&draining.address), Some((0, 1)));
+
+    log::info!("Sending requests while the upstream drains");
+    let requests: Vec<_> = (0..4)
+        .map(|_| tokio::spawn(get_status(address, "/new")))
+        .collect();
+    for request in requests {
+        assert_eq!(request.await.unwrap(), 200);
+    }
+    assert_eq!(in_flight.await.unwrap(), 200);
+    assert_eq!(draining.requests_received(), 1);
+    assert_eq!(other.requests_received(), 4);
+
+    let response_text = client
+        .get(&drain_url)
+        .send()
+        .await
+        .expect("Error sending request to the admin API")
+        .text()
+        .await
+        .expect("Error reading response from the admin API");
+    assert!(response_text.contains("is drained"));
+    assert_eq!(
+        client
+            .post(format!("http://{}/upstreams/127.0.0.1:1/drain", admin))
+            .send()
+            .await
+            .expect("Error sending request to the admin API")
+            .status(),
+        404
+    );
+
+    for upstream in upstreams {
+        Box::new(upstream).stop().await;
+    }
+    log::info!("All done :)");
+}
+
+/// Large bodies should make it through the proxy intact whatever size of buffer they are read in.
+#[tokio::test]
+async fn test_io_buffer_sizes() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let body: Vec<u8> = (0..200_000_u32).map(|i| (i * 7 % 251) as u8).collect();
+    for io_buffer_bytes in [1, 4096, 64 * 1024] {
+        log::info!("Sending a large body with {} byte buffers", io_buffer_bytes);
+        let (address, _) = start_proxy(Config {
+            upstream: vec![upstream.address.clone()],
+            io_buffer_bytes,
+            ..Config::default()
+        });
+        let response = reqwest::Client::new()
+            .post(format!("http://{}/upload", address))
+            .body(body.clone())
+            .send()
+            .await
+            .expect("Error sending request to the proxy");
+        assert_eq!(response.status(), 200);
+        let response_body = response
+            .bytes()
+            .await
+            .expect("Error reading response from the proxy");
+        // The echo server sends the request line and headers before the body
+        assert!(response_body.starts_with(b"POST /upload HTTP/1.1"));
+        assert!(response_body.ends_with(&body));
+    }
+
+    assert_eq!(Box::new(upstream).stop().await, 3);
+    log::info!("All done :)");
+}
+
+/// Requests should go to the upstream group of the host they are for, even when a client asks for
+/// different hosts over the same connection, and requests for unknown hosts should get a 404.
+#[tokio::test]
+async fn test_virtual_hosts() {
+    init_logging();
+    let blog_upstream = EchoServer::new().await;
+    let shop_upstream = EchoServer::new().await;
+    let (address, _state) = start_proxy(Config {
+        upstream_groups: HashMap::from([
+            ("blog".to_string(), vec![blog_upstream.address.clone()]),
+            ("shop".to_string(), vec![shop_upstream.address.clone()]),
+        ]),
+        virtual_hosts: HashMap::from([
+            ("blog.example.com".to_string(), "blog".to_string()),
+            ("Shop.Example.com".to_string(), "shop".to_string()),
+        ]),
+        ..Config::default()
+    });
+
+    log::info!("Alternating requests between the two hosts");
+    let client = reqwest::Client::new();
+    for (i, host) in [
+        "blog.example.com",
+        "shop.example.com:1100",
+        "blog.example.com",
+        "SHOP.example.com",
+    ]
+    .iter()
+    .enumerate()
+    {
+        let response_text = client
+            .get(format!("http://{}/page/{}", address, i))
+            .header("Host", *host)
+            .send()
+            .await
+            .expect("Error sending request to the proxy")
+            .text()
+            .await
+            .expect("Error reading response from the proxy");
+        assert!(response_text.starts_with(&format!("GET /page/{} HTTP/1.1", i)));
+        assert!(response_text.contains(&format!("host: {}", host)));
+    }
+
+    log::info!("Sending a request for a host nobody serves");
+    let response = client
+        .get(format!("http://{}/page", address))
+        .header("Host", "other.example.com")
+        .send()
+        .await
+        .expect("Error sending request to the proxy");
+    assert_eq!(response.status(), 404);
+
+    assert_eq!(Box::new(blog_upstream).stop().await, 2);
+    assert_eq!(Box::new(shop_upstream).stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// Routes should send GETs to the replicas and POSTs to the primary, and GETs should fall back to
+/// the primary once the replicas are down.
+#[tokio::test]
+async fn test_routes() {
+    init_logging();
+    let replica = EchoServer::new().await;
+    let primary = EchoServer::new().await;
+    let (address, _state) = start_proxy(Config {
+        upstream_groups: HashMap::from([
+            ("replicas".to_string(), vec![replica.address.clone()]),
+            ("primary".to_string(), vec![primary.address.clone()]),
+        ]),
+        routes: vec![
+            (
+                RouteMatch::Method(http::Method::GET),
+                vec!["replicas".to_string(), "primary".to_string()],
+            ),
+            (
+                RouteMatch::Method(http::Method::POST),
+                vec!["primary".to_string()],
+            ),
+        ],
+        active_health_check_interval: 1,
+        ..Config::default()
+    });
+
+    log::info!("Sending a GET and a POST");
+    let client = reqwest::Client::new();
+    for method in [reqwest::Method::GET, reqwest::Method::POST] {
+        let response = client
+            .request(method, format!("http://{}/routed", address))
+            .send()
+            .await
+            .expect("Error sending request to the proxy");
+        assert_eq!(response.status(), 200);
+    }
+    assert_eq!(replica.requests_received(), 1);
+    assert_eq!(primary.requests_received(), 1);
+
+    log::info!("Stopping the replica and waiting for a health check to notice");
+    Box::new(replica).stop().await;
+    tokio::time::sleep(Duration::from_secs(3)).await;
+
+    log::info!("Sending a GET, which should go to the primary");
+    let response_text = client
+        .get(format!("http://{}/fallback", address))
+        .send()
+        .await
+        .expect("Error sending request to the proxy")
+        .text()
+        .await
+        .expect("Error reading response from the proxy");
+    assert!(response_text.starts_with("GET /fallback HTTP/1.1"));
+
+    // The primary has answered health checks too by now
+    assert!(Box::new(primary).stop().await >= 2);
+    log::info!("All done :)");
+}
+
+/// With request coalescing, identical GETs that arrive while one is in flight should reach the
+/// upstream only once and all get its response, while POSTs are still sent one by one.
+#[tokio::test]
+async fn test_coalesce_requests() {
+    init_logging();
+    let upstream = EchoServer::new_slow(Duration::from_secs(1)).await;
+    let (address, _state) = start_proxy(Config {
+        upstream: vec![upstream.address.clone()],
+        coalesce_requests: true,
+        ..Config::default()
+    });
+
+    log::info!("Sending identical GETs at once");
+    let send_all = |method: reqwest::Method, count: usize| {
+        let tasks: Vec<_> = (0..count)
+            .map(|_| {
+                let method = method.clone();
+                tokio::spawn(async move {
+                    // A client each, so that every request comes in on its own connection
+                    let response = reqwest::Client::new()
+                        .request(method, format!("http://{}/popular", address))
+                        .send()
+                        .await
+                        .expect("Error sending request to the proxy");
+                    assert_eq!(response.status(), 200);
+                    response
+                        .text()
+                        .await
+                        .expect("Error reading response from the proxy")
+                })
+            })
+            .collect();
+        async move {
+            let mut bodies = Vec::new();
+            for task in tasks {
+                bodies.push(task.await.expect("Request task panicked"));
+            }
+            bodies
+        }
+    };
+    let bodies = send_all(reqwest::Method::GET, 10).await;
+    assert!(bodies[0].starts_with("GET /popular HTTP/1.1"));
+    assert!(bodies.iter().all(|body| *body == bodies[0]));
+
+    log::info!("Sending POSTs at once");
+    let bodies = send_all(reqwest::Method::POST, 3).await;
+    assert!(bodies
+        .iter()
+        .all(|body| body.starts_with("POST /popular HTTP/1.1")));
+
+    assert_eq!(Box::new(upstream).stop().await, 4);
+    log::info!("All done :)");
+}
+
+/// POSTs `body` to `path` through the proxy at `address` with an Idempotency-Key, from the
+/// loopback address `from`, on a new connection. Returns the response's status and body.
+async fn post_with_idempotency_key(
+    address: SocketAddr,
+    key: &str,
+    path: &str,
+    body: &'static str,
+    from: &str,
+) -> (reqwest::StatusCode, String) {
+    let response = reqwest::Client::builder()
+        .local_address(from.parse::<std::net::IpAddr>().unwrap())
+        .build()
+        .unwrap()
+        .post(format!("http://{}{}", address, path))
+        .header("Idempotency-Key", key)
+        .body(body)
+        .send()
+        .await
+        .expect("Error sending request to the proxy");
+    let status = response.status();
+    let text = response
+        .text()
+        .await
+        .expect("Error reading response from the proxy");
+    (status, text)
+}
+
+/// With --idempotency-key-ttl, POSTs retried with the same Idempotency-Key should reach the
+/// upstream only once, whether the first is still in flight or has already been answered. The key
+/// only covers requests from the same client to the same path, and reusing it for a different
+/// request should be refused.
+#[tokio::test]
+async fn test_idempotency_key() {
+    init_logging();
+    let upstream = EchoServer::new_slow(Duration::from_secs(1)).await;
+    let (address, _state) = start_proxy(Config {
+        upstream: vec![upstream.address.clone()],
+        idempotency_key_ttl: 60,
+        ..Config::default()
+    });
+    let key = "8e03978e-40d5-43e8-bc93-6894a57f9324";
+    let post = move |path: &'static str, body: &'static str, from: &'static str| {
+        post_with_idempotency_key(address, key, path, body, from)
+    };
+
+    log::info!("Sending a POST and a retry of it while it is in flight");
+    let first = tokio::spawn(post("/payments", "charge 1", "127.0.0.1"));
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let retry = post("/payments", "charge 1", "127.0.0.1").await;
+    let (status, first) = first.await.expect("Request task panicked");
+    assert_eq!(status, 200);
+    assert!(first.starts_with("POST /payments HTTP/1.1"));
+    assert!(first.ends_with("charge 1"));
+    assert_eq!(retry, (status, first.clone()));
+
+    log::info!("Retrying after the response arrived");
+    assert_eq!(post("/payments", "charge 1", "127.0.0.1").await.1, first);
+
+    log::info!("Reusing the key for a different request");
+    let (status, _) = post("/payments", "charge 2", "127.0.0.1").await;
+    assert_eq!(status, 422);
+
+    log::info!("Using the key for another path, and from another client");
+    let (status, refund) = post("/refunds", "charge 1", "127.0.0.1").await;
+    assert_eq!(status, 200);
+    assert!(refund.starts_with("POST /refunds HTTP/1.1"));
+    let (status, other_client) = post("/payments", "charge 1", "127.0.0.2").await;
+    assert_eq!(status, 200);
+    assert!(other_client.contains("x-forwarded-for: 127.0.0.2"));
+
+    assert_eq!(Box::new(upstream).stop().await, 3);
+    log::info!("All done :)");
+}
+
+/// Once --idempotency-max-keys keys are remembered, the oldest answered one should be forgotten to
+/// make room, so that a retry with it is forwarded again
+#[tokio::test]
+async fn test_idempotency_max_keys() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let (address, _state) = start_proxy(Config {
+        upstream: vec![upstream.address.clone()],
+        idempotency_key_ttl: 60,
+        idempotency_max_keys: 2,
+        ..Config::default()
+    });
+
+    for key in ["first", "second", "second", "third", "second", "first"] {
+        let (status, _) =
+            post_with_idempotency_key(address, key, "/payments", "charge", "127.0.0.1").await;
+        assert_eq!(status, 200);
+    }
+    // "third" pushed out "first", so only the repeats of "second" were answered by the proxy
+    assert_eq!(Box::new(upstream).stop().await, 4);
+    log::info!("All done :)");
+}
+
+/// Accepts POSTs to a mock --health-webhook, answering each with a 200 and passing its JSON body
+/// on.
+async fn mock_health_webhook() -> (
+    String,
+    tokio::sync::mpsc::UnboundedReceiver<serde_json::Value>,
+) {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/hooks/health", listener.local_addr().unwrap());
+    let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let mut received = Vec::new();
+            let mut buffer = [0; 1024];
+            // Read up to the end of the headers, then the rest of the body
+            let header_end = loop {
+                let n = stream.read(&mut buffer).await.unwrap();
+                assert_ne!(n, 0, "Webhook request was cut off");
+                received.extend_from_slice(&buffer[..n]);
+                if let Some(i) = received.windows(4).position(|w| w == b"\r\n\r\n") {
+                    break i + 4;
+                }
+            };
+            let head = String::from_utf8_lossy(&received[..header_end]).to_lowercase();
+            assert!(head.starts_with("post /hooks/health http/1.1"));
+            assert!(head.contains("content-type: application/json"));
+            let length: usize = head
+                .lines()
+                .find_map(|line| line.strip_prefix("content-length: "))
+                .expect("Webhook request has no Content-Length")
+                .trim()
+                .parse()
+                .unwrap();
+            while received.len() < header_end + length {
+                let n = stream.read(&mut buffer).await.unwrap();
+                assert_ne!(n, 0, "Webhook request body was cut off");
+                received.extend_from_slice(&buffer[..n]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                .await
+                .unwrap();
+            let event = serde_json::from_slice(&received[header_end..]).unwrap();
+            let _ = sender.send(event);
+        }
+    });
+    (url, receiver)
+}
+
+/// The --health-webhook should be told once when an upstream stops answering health checks, and
+/// once more when it comes back, but not about upstreams that stay healthy.
+#[tokio::test]
+async fn test_health_webhook() {
+    init_logging();
+    let (url, mut events) = mock_health_webhook().await;
+    let upstream = EchoServer::new().await;
+    // Nothing is listening here until the upstream is "restarted" below
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let flaky_address = listener.local_addr().unwrap().to_string();
+    drop(listener);
+    let (_address, _state) = start_proxy(Config {
+        upstream: vec![upstream.address.clone(), flaky_address.clone()],
+        active_health_check_interval: 1,
+        health_webhook: Some(url.parse().unwrap()),
+        health_webhook_debounce_ms: 100,
+        ..Config::default()
+    });
+
+    log::info!("Waiting to hear that the stopped upstream is unhealthy");
+    let event = tokio::time::timeout(Duration::from_secs(5), events.recv())
+        .await
+        .expect("Webhook was never told about the stopped upstream")
+        .unwrap();
+    assert_eq!(event["upstream"], flaky_address.as_str());
+    assert_eq!(event["state"], "unhealthy");
+    assert!(event["timestamp"].as_u64().unwrap() > 0);
+
+    log::info!("Starting the upstream and waiting to hear that it's healthy again");
+    let restarted = EchoServer::new_at_address(flaky_address.clone()).await;
+    let event = tokio::time::timeout(Duration::from_secs(5), events.recv())
+        .await
+        .expect("Webhook was never told about the restarted upstream")
+        .unwrap();
+    assert_eq!(event["upstream"], flaky_address.as_str());
+    assert_eq!(event["state"], "healthy");
+
+    // The upstream that stayed up is never reported on
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    assert!(events.try_recv().is_err());
+    Box::new(upstream).stop().await;
+    Box::new(restarted).stop().await;
+    log::info!("All done :)");
+}
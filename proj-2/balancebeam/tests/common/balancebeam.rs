@@ -1,13 +1,15 @@
 use rand::Rng;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio::process::{Child, Command};
 use tokio::time::sleep;
 
 pub struct BalanceBeam {
-    #[allow(dead_code)]
     child: Child, // process is killed when dropped (Command::kill_on_drop)
     pub address: String,
+    /// Lines balancebeam has printed to stdout and stderr so far
+    output: Arc<Mutex<Vec<String>>>,
 }
 
 impl BalanceBeam {
@@ -19,10 +21,27 @@ impl BalanceBeam {
         path
     }
 
+    #[allow(dead_code)]
     pub async fn new(
         upstreams: &[&str],
         active_health_check_interval: Option<usize>,
         max_requests_per_minute: Option<usize>,
+    ) -> BalanceBeam {
+        BalanceBeam::new_with_args(
+            upstreams,
+            active_health_check_interval,
+            max_requests_per_minute,
+            &[],
+        )
+        .await
+    }
+
+    /// Like `new`, but passes `extra_args` through to the balancebeam command line as well.
+    pub async fn new_with_args(
+        upstreams: &[&str],
+        active_health_check_interval: Option<usize>,
+        max_requests_per_minute: Option<usize>,
+        extra_args: &[&str],
     ) -> BalanceBeam {
         let mut rng = rand::thread_rng();
         let address = format!("127.0.0.1:{}", rng.gen_range(1024..65535));
@@ -39,21 +58,26 @@ impl BalanceBeam {
             cmd.arg("--max-requests-per-minute")
                 .arg(max_requests_per_minute.to_string());
         }
+        cmd.args(extra_args);
         cmd.kill_on_drop(true);
         cmd.stdout(std::process::Stdio::piped());
         cmd.stderr(std::process::Stdio::piped());
-        let mut child = cmd.spawn().expect(&format!(
-            "Could not execute balancebeam binary {}",
-            BalanceBeam::target_bin_path().to_str().unwrap()
-        ));
+        let mut child = cmd.spawn().unwrap_or_else(|_| {
+            panic!(
+                "Could not execute balancebeam binary {}",
+                BalanceBeam::target_bin_path().to_str().unwrap()
+            )
+        });
 
         // Print output from the child. We want to intercept and log this output (instead of letting
         // the child inherit stderr and print directly to the terminal) so that the output can be
         // suppressed if the test passes and displayed if it fails.
+        let output = Arc::new(Mutex::new(Vec::new()));
         let stdout = child
             .stdout
             .take()
             .expect("Child process somehow missing stdout pipe!");
+        let stdout_output = output.clone();
         tokio::spawn(async move {
             let mut stdout_reader = BufReader::new(stdout).lines();
             while let Some(line) = stdout_reader
@@ -62,12 +86,14 @@ impl BalanceBeam {
                 .expect("I/O error reading from child stdout")
             {
                 println!("Balancebeam output: {}", line);
+                stdout_output.lock().unwrap().push(line);
             }
         });
         let stderr = child
             .stderr
             .take()
             .expect("Child process somehow missing stderr pipe!");
+        let stderr_output = output.clone();
         tokio::spawn(async move {
             let mut stderr_reader = BufReader::new(stderr).lines();
             while let Some(line) = stderr_reader
@@ -76,19 +102,55 @@ impl BalanceBeam {
                 .expect("I/O error reading from child stderr")
             {
                 println!("Balancebeam output: {}", line);
+                stderr_output.lock().unwrap().push(line);
             }
         });
 
         // Hack: wait for executable to start running
         sleep(Duration::from_secs(1)).await;
-        BalanceBeam { child, address }
+        BalanceBeam {
+            child,
+            address,
+            output,
+        }
+    }
+
+    /// Returns the lines balancebeam has printed so far.
+    #[allow(dead_code)]
+    pub fn output(&self) -> Vec<String> {
+        self.output.lock().unwrap().clone()
+    }
+
+    /// Runs `balancebeam --check-config` with the given arguments, returning its exit status and
+    /// what it printed to stdout.
+    #[allow(dead_code)]
+    pub async fn check_config(args: &[&str]) -> (std::process::ExitStatus, String) {
+        let output = Command::new(BalanceBeam::target_bin_path())
+            .arg("--check-config")
+            .args(args)
+            .output()
+            .await
+            .expect("Could not execute balancebeam binary");
+        let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+        println!("Balancebeam output: {}", stdout);
+        (output.status, stdout)
+    }
+
+    /// Waits up to `timeout` for balancebeam to exit on its own, returning its exit status (or None
+    /// if it is still running).
+    #[allow(dead_code)]
+    pub async fn wait_for_exit(&mut self, timeout: Duration) -> Option<std::process::ExitStatus> {
+        tokio::time::timeout(timeout, self.child.wait())
+            .await
+            .ok()
+            .map(|status| status.expect("Error waiting for balancebeam to exit"))
     }
 
     #[allow(dead_code)]
     pub async fn get(&self, path: &str) -> Result<String, reqwest::Error> {
         let client = reqwest::Client::new();
         client
-            .get(&format!("http://{}{}", self.address, path))
+            .get(format!("http://{}{}", self.address, path))
             .header("x-sent-by", "balancebeam-tests")
             .send()
             .await?
@@ -100,7 +162,7 @@ impl BalanceBeam {
     pub async fn post(&self, path: &str, body: &str) -> Result<String, reqwest::Error> {
         let client = reqwest::Client::new();
         client
-            .post(&format!("http://{}{}", self.address, path))
+            .post(format!("http://{}{}", self.address, path))
             .header("x-sent-by", "balancebeam-tests")
             .body(body.to_string())
             .send()
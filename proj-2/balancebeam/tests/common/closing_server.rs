@@ -0,0 +1,102 @@
+use crate::common::server::Server;
+use async_trait::async_trait;
+use rand::Rng;
+use std::sync::{atomic, Arc};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+
+#[derive(Debug)]
+struct ServerState {
+    pub requests_received: atomic::AtomicUsize,
+}
+
+/// Answers a single request with `Connection: close` and then hangs up. The response body is the
+/// request line, so tests can tell which request was answered.
+async fn answer_once(mut stream: TcpStream, server_state: Arc<ServerState>) {
+    let mut request = Vec::new();
+    let mut buffer = [0_u8; 512];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        match stream.read(&mut buffer).await {
+            Ok(0) | Err(_) => return,
+            Ok(bytes_read) => request.extend_from_slice(&buffer[..bytes_read]),
+        }
+    }
+    server_state
+        .requests_received
+        .fetch_add(1, atomic::Ordering::SeqCst);
+
+    let request = String::from_utf8_lossy(&request);
+    let body = request.lines().next().unwrap_or("");
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    let _ = stream.write_all(response.as_bytes()).await;
+}
+
+/// An upstream that closes every connection after one response.
+pub struct ClosingServer {
+    shutdown_signal_sender: oneshot::Sender<()>,
+    server_task: tokio::task::JoinHandle<()>,
+    pub address: String,
+    state: Arc<ServerState>,
+}
+
+impl ClosingServer {
+    #[allow(dead_code)]
+    pub async fn new() -> ClosingServer {
+        let mut rng = rand::thread_rng();
+        let bind_addr_string = format!("127.0.0.1:{}", rng.gen_range(1024..65535));
+        let listener = TcpListener::bind(&bind_addr_string)
+            .await
+            .expect("Error binding ClosingServer");
+        let (shutdown_tx, mut shutdown_rx) = oneshot::channel::<()>();
+
+        let server_state = Arc::new(ServerState {
+            requests_received: atomic::AtomicUsize::new(0),
+        });
+        let server_task_state = server_state.clone();
+        let server_task = tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    accepted = listener.accept() => match accepted {
+                        Ok((stream, _)) => {
+                            tokio::spawn(answer_once(stream, server_task_state.clone()));
+                        }
+                        Err(e) => log::error!("Error in ClosingServer: {}", e),
+                    },
+                    _ = &mut shutdown_rx => break,
+                }
+            }
+        });
+
+        ClosingServer {
+            shutdown_signal_sender: shutdown_tx,
+            server_task,
+            state: server_state,
+            address: bind_addr_string,
+        }
+    }
+}
+
+#[async_trait]
+impl Server for ClosingServer {
+    async fn stop(self: Box<Self>) -> usize {
+        let _ = self.shutdown_signal_sender.send(());
+        self.server_task
+            .await
+            .expect("ClosingServer server task panicked");
+
+        self.state.requests_received.load(atomic::Ordering::SeqCst)
+    }
+
+    fn requests_received(&self) -> usize {
+        self.state.requests_received.load(atomic::Ordering::SeqCst)
+    }
+
+    fn address(&self) -> String {
+        self.address.clone()
+    }
+}
@@ -3,12 +3,19 @@ use async_trait::async_trait;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Request, Response};
 use rand::Rng;
+use std::io::Write;
 use std::sync::{atomic, Arc};
+use std::time::Duration;
+use tokio::net::{TcpListener, UnixListener};
 use tokio::sync::oneshot;
+use tokio_rustls::rustls;
 
 #[derive(Debug)]
 struct ServerState {
     pub requests_received: atomic::AtomicUsize,
+    pub connections_received: atomic::AtomicUsize,
+    /// How long to wait before answering each request
+    pub delay: Duration,
 }
 
 async fn echo(
@@ -18,6 +25,14 @@ async fn echo(
     server_state
         .requests_received
         .fetch_add(1, atomic::Ordering::SeqCst);
+    // Tests can make particular requests slower still
+    let extra_delay = req
+        .headers()
+        .get("x-echo-delay-ms")
+        .and_then(|value| value.to_str().ok()?.parse().ok())
+        .map(Duration::from_millis)
+        .unwrap_or_default();
+    tokio::time::sleep(server_state.delay + extra_delay).await;
     let mut req_text = format!("{} {} {:?}\n", req.method(), req.uri(), req.version());
     for (header_name, header_value) in req.headers() {
         req_text += &format!(
@@ -27,9 +42,67 @@ async fn echo(
         );
     }
     req_text += "\n";
+    // Tests can give the response an ETag and Cache-Control, in which case requests that already
+    // have the ETag get a 304
+    let etag = req.headers().get("x-echo-etag").cloned();
+    let cache_control = req.headers().get("x-echo-cache-control").cloned();
+    let not_modified = etag.is_some() && req.headers().get("if-none-match") == etag.as_ref();
+    // Tests can choose the Content-Type and Content-Encoding (gzip or deflate) of the response
+    let content_type = req.headers().get("x-echo-content-type").cloned();
+    let content_encoding = req.headers().get("x-echo-content-encoding").cloned();
     let mut req_as_bytes = req_text.into_bytes();
     req_as_bytes.extend(hyper::body::to_bytes(req.into_body()).await?);
-    Ok(Response::new(Body::from(req_as_bytes)))
+    if let Some(encoding) = &content_encoding {
+        req_as_bytes = encode(encoding.to_str().unwrap(), &req_as_bytes);
+    }
+    let mut response = if not_modified {
+        Response::builder()
+            .status(hyper::StatusCode::NOT_MODIFIED)
+            .body(Body::empty())
+            .unwrap()
+    } else {
+        Response::new(Body::from(req_as_bytes))
+    };
+    if let Some(etag) = etag {
+        response.headers_mut().insert(hyper::header::ETAG, etag);
+    }
+    if let Some(cache_control) = cache_control {
+        response
+            .headers_mut()
+            .insert(hyper::header::CACHE_CONTROL, cache_control);
+    }
+    if not_modified {
+        return Ok(response);
+    }
+    if let Some(content_type) = content_type {
+        response
+            .headers_mut()
+            .insert(hyper::header::CONTENT_TYPE, content_type);
+    }
+    if let Some(content_encoding) = content_encoding {
+        response
+            .headers_mut()
+            .insert(hyper::header::CONTENT_ENCODING, content_encoding);
+    }
+    Ok(response)
+}
+
+/// Compresses `body` with the given Content-Encoding.
+fn encode(encoding: &str, body: &[u8]) -> Vec<u8> {
+    let compression = flate2::Compression::default();
+    match encoding {
+        "gzip" => {
+            let mut encoder = flate2::write::GzEncoder::new(Vec::new(), compression);
+            encoder.write_all(body).unwrap();
+            encoder.finish().unwrap()
+        }
+        "deflate" => {
+            let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), compression);
+            encoder.write_all(body).unwrap();
+            encoder.finish().unwrap()
+        }
+        _ => panic!("EchoServer can't encode {}", encoding),
+    }
 }
 
 pub struct EchoServer {
@@ -46,6 +119,17 @@ impl EchoServer {
     }
 
     pub async fn new_at_address(bind_addr_string: String) -> EchoServer {
+        EchoServer::new_with_delay(bind_addr_string, Duration::ZERO).await
+    }
+
+    /// Starts an echo server that takes `delay` to answer each request, like a slow application.
+    #[allow(dead_code)]
+    pub async fn new_slow(delay: Duration) -> EchoServer {
+        let mut rng = rand::thread_rng();
+        EchoServer::new_with_delay(format!("127.0.0.1:{}", rng.gen_range(1024..65535)), delay).await
+    }
+
+    async fn new_with_delay(bind_addr_string: String, delay: Duration) -> EchoServer {
         let bind_addr = bind_addr_string.parse().unwrap();
         // Create a one-shot channel that can be used to tell the server to shut down
         let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
@@ -53,11 +137,16 @@ impl EchoServer {
         // Start a separate server task
         let server_state = Arc::new(ServerState {
             requests_received: atomic::AtomicUsize::new(0),
+            connections_received: atomic::AtomicUsize::new(0),
+            delay,
         });
         let server_task_state = server_state.clone();
         let server_task = tokio::spawn(async move {
             let service = make_service_fn(|_| {
                 let server_task_state = server_task_state.clone();
+                server_task_state
+                    .connections_received
+                    .fetch_add(1, atomic::Ordering::SeqCst);
                 async move {
                     Ok::<_, hyper::Error>(service_fn(move |req| {
                         let server_task_state = server_task_state.clone();
@@ -83,6 +172,142 @@ impl EchoServer {
             address: bind_addr_string,
         }
     }
+
+    /// Starts an echo server listening on a Unix socket in the temporary directory. Its address is
+    /// `unix:<path>`, as given to balancebeam.
+    #[allow(dead_code)]
+    pub async fn new_unix() -> EchoServer {
+        let path = std::env::temp_dir().join(format!(
+            "balancebeam-echo-{}.sock",
+            rand::thread_rng().gen::<u64>()
+        ));
+        let listener = UnixListener::bind(&path).expect("Error binding Unix echo server");
+
+        let (shutdown_tx, mut shutdown_rx) = oneshot::channel::<()>();
+        let server_state = Arc::new(ServerState {
+            requests_received: atomic::AtomicUsize::new(0),
+            connections_received: atomic::AtomicUsize::new(0),
+            delay: Duration::ZERO,
+        });
+        let server_task_state = server_state.clone();
+        let socket_path = path.clone();
+        let server_task = tokio::spawn(async move {
+            loop {
+                let stream = tokio::select! {
+                    accepted = listener.accept() => match accepted {
+                        Ok((stream, _)) => stream,
+                        Err(e) => {
+                            log::error!("Error accepting connection in EchoServer: {}", e);
+                            continue;
+                        }
+                    },
+                    _ = &mut shutdown_rx => break,
+                };
+                server_task_state
+                    .connections_received
+                    .fetch_add(1, atomic::Ordering::SeqCst);
+                let server_task_state = server_task_state.clone();
+                tokio::spawn(async move {
+                    let service = service_fn(move |req| echo(server_task_state.clone(), req));
+                    if let Err(e) = hyper::server::conn::Http::new()
+                        .serve_connection(stream, service)
+                        .await
+                    {
+                        log::error!("Error in EchoServer: {}", e);
+                    }
+                });
+            }
+            let _ = std::fs::remove_file(socket_path);
+        });
+
+        EchoServer {
+            shutdown_signal_sender: shutdown_tx,
+            server_task,
+            state: server_state,
+            address: format!("unix:{}", path.display()),
+        }
+    }
+
+    /// Starts an echo server that only speaks HTTPS, using a freshly generated self-signed
+    /// certificate for 127.0.0.1.
+    #[allow(dead_code)]
+    pub async fn new_tls() -> EchoServer {
+        let mut rng = rand::thread_rng();
+        let bind_addr_string = format!("127.0.0.1:{}", rng.gen_range(1024..65535));
+        let listener = TcpListener::bind(&bind_addr_string)
+            .await
+            .expect("Error binding TLS echo server");
+
+        let cert = rcgen::generate_simple_self_signed(vec!["127.0.0.1".to_string()])
+            .expect("Error generating certificate");
+        let config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![rustls::Certificate(cert.serialize_der().unwrap())],
+                rustls::PrivateKey(cert.serialize_private_key_der()),
+            )
+            .expect("Error configuring TLS echo server");
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(config));
+
+        let (shutdown_tx, mut shutdown_rx) = oneshot::channel::<()>();
+        let server_state = Arc::new(ServerState {
+            requests_received: atomic::AtomicUsize::new(0),
+            connections_received: atomic::AtomicUsize::new(0),
+            delay: Duration::ZERO,
+        });
+        let server_task_state = server_state.clone();
+        let server_task = tokio::spawn(async move {
+            loop {
+                let stream = tokio::select! {
+                    accepted = listener.accept() => match accepted {
+                        Ok((stream, _)) => stream,
+                        Err(e) => {
+                            log::error!("Error accepting connection in EchoServer: {}", e);
+                            continue;
+                        }
+                    },
+                    _ = &mut shutdown_rx => break,
+                };
+                server_task_state
+                    .connections_received
+                    .fetch_add(1, atomic::Ordering::SeqCst);
+                let acceptor = acceptor.clone();
+                let server_task_state = server_task_state.clone();
+                tokio::spawn(async move {
+                    let stream = match acceptor.accept(stream).await {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            log::info!("TLS handshake failed in EchoServer: {}", e);
+                            return;
+                        }
+                    };
+                    let service = service_fn(move |req| echo(server_task_state.clone(), req));
+                    if let Err(e) = hyper::server::conn::Http::new()
+                        .serve_connection(stream, service)
+                        .await
+                    {
+                        log::error!("Error in EchoServer: {}", e);
+                    }
+                });
+            }
+        });
+
+        EchoServer {
+            shutdown_signal_sender: shutdown_tx,
+            server_task,
+            state: server_state,
+            address: bind_addr_string,
+        }
+    }
+
+    /// Returns how many connections the server has accepted.
+    #[allow(dead_code)]
+    pub fn connections_received(&self) -> usize {
+        self.state
+            .connections_received
+            .load(atomic::Ordering::SeqCst)
+    }
 }
 
 #[async_trait]
@@ -98,6 +323,10 @@ impl Server for EchoServer {
         self.state.requests_received.load(atomic::Ordering::SeqCst)
     }
 
+    fn requests_received(&self) -> usize {
+        self.state.requests_received.load(atomic::Ordering::SeqCst)
+    }
+
     fn address(&self) -> String {
         self.address.clone()
     }
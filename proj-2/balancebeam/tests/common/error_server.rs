@@ -9,12 +9,13 @@ use tokio::sync::oneshot;
 #[derive(Debug)]
 struct ServerState {
     pub requests_received: atomic::AtomicUsize,
+    pub status: http::StatusCode,
 }
 
 #[allow(dead_code)]
-async fn return_error() -> Result<Response<Body>, hyper::Error> {
+async fn return_error(status: http::StatusCode) -> Result<Response<Body>, hyper::Error> {
     Ok(Response::builder()
-        .status(http::StatusCode::INTERNAL_SERVER_ERROR)
+        .status(status)
         .body(Body::empty())
         .unwrap())
 }
@@ -22,6 +23,7 @@ async fn return_error() -> Result<Response<Body>, hyper::Error> {
 pub struct ErrorServer {
     shutdown_signal_sender: oneshot::Sender<()>,
     server_task: tokio::task::JoinHandle<()>,
+    #[allow(dead_code)]
     pub address: String,
     state: Arc<ServerState>,
 }
@@ -33,8 +35,20 @@ impl ErrorServer {
         ErrorServer::new_at_address(format!("127.0.0.1:{}", rng.gen_range(1024..65535))).await
     }
 
+    /// Starts a server that answers every request with `status` instead of a 500.
+    #[allow(dead_code)]
+    pub async fn new_with_status(status: http::StatusCode) -> ErrorServer {
+        let mut rng = rand::thread_rng();
+        ErrorServer::new_with_state(format!("127.0.0.1:{}", rng.gen_range(1024..65535)), status)
+            .await
+    }
+
     #[allow(dead_code)]
     pub async fn new_at_address(bind_addr_string: String) -> ErrorServer {
+        ErrorServer::new_with_state(bind_addr_string, http::StatusCode::INTERNAL_SERVER_ERROR).await
+    }
+
+    async fn new_with_state(bind_addr_string: String, status: http::StatusCode) -> ErrorServer {
         let bind_addr = bind_addr_string.parse().unwrap();
         // Create a one-shot channel that can be used to tell the server to shut down
         let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
@@ -42,6 +56,7 @@ impl ErrorServer {
         // Start a separate server task
         let server_state = Arc::new(ServerState {
             requests_received: atomic::AtomicUsize::new(0),
+            status,
         });
         let server_task_state = server_state.clone();
         let server_task = tokio::spawn(async move {
@@ -52,7 +67,7 @@ impl ErrorServer {
                         server_task_state
                             .requests_received
                             .fetch_add(1, atomic::Ordering::SeqCst);
-                        return_error()
+                        return_error(server_task_state.status)
                     }))
                 }
             });
@@ -89,6 +104,10 @@ impl Server for ErrorServer {
         self.state.requests_received.load(atomic::Ordering::SeqCst)
     }
 
+    fn requests_received(&self) -> usize {
+        self.state.requests_received.load(atomic::Ordering::SeqCst)
+    }
+
     fn address(&self) -> String {
         self.address.clone()
     }
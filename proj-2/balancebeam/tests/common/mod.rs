@@ -1,13 +1,21 @@
 mod balancebeam;
+mod closing_server;
 mod echo_server;
 mod error_server;
+mod proxy_protocol_server;
 mod server;
 
 use std::sync;
 
-pub use balancebeam::BalanceBeam;
+#[allow(unused_imports)]
+pub use self::balancebeam::BalanceBeam;
+#[allow(unused_imports)]
+pub use closing_server::ClosingServer;
 pub use echo_server::EchoServer;
+#[allow(unused_imports)]
 pub use error_server::ErrorServer;
+#[allow(unused_imports)]
+pub use proxy_protocol_server::ProxyProtocolServer;
 pub use server::Server;
 
 static INIT_TESTS: sync::Once = sync::Once::new();
@@ -0,0 +1,181 @@
+use crate::common::server::Server;
+use async_trait::async_trait;
+use rand::Rng;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{atomic, Arc};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+
+const PROXY_V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+#[derive(Debug)]
+struct ServerState {
+    pub requests_received: atomic::AtomicUsize,
+}
+
+/// Reads a PROXY protocol header (either version) from the start of `stream`. Returns the source
+/// address it announces, None if it doesn't announce one (e.g. for health checks), or an error
+/// message if the header is missing or malformed.
+async fn read_proxy_header(stream: &mut TcpStream) -> Result<Option<SocketAddr>, String> {
+    let mut first = [0_u8; 1];
+    stream
+        .read_exact(&mut first)
+        .await
+        .map_err(|e| e.to_string())?;
+    if first[0] == b'P' {
+        // Version 1: a single text line
+        let mut line = first.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() > 107 {
+                return Err("v1 header too long".to_string());
+            }
+            line.push(stream.read_u8().await.map_err(|e| e.to_string())?);
+        }
+        let line = String::from_utf8(line).map_err(|e| e.to_string())?;
+        let fields: Vec<&str> = line.trim_end().split(' ').collect();
+        return match fields.as_slice() {
+            ["PROXY", "UNKNOWN", ..] => Ok(None),
+            ["PROXY", "TCP4" | "TCP6", source, _destination, source_port, _destination_port] => {
+                let ip: IpAddr = source.parse().map_err(|_| "bad v1 source address")?;
+                let port: u16 = source_port.parse().map_err(|_| "bad v1 source port")?;
+                Ok(Some(SocketAddr::new(ip, port)))
+            }
+            _ => Err(format!("malformed v1 header {:?}", line)),
+        };
+    }
+
+    // Version 2: a fixed 16-byte preamble followed by the address block
+    let mut preamble = [0_u8; 16];
+    preamble[0] = first[0];
+    stream
+        .read_exact(&mut preamble[1..])
+        .await
+        .map_err(|e| e.to_string())?;
+    if &preamble[..12] != PROXY_V2_SIGNATURE {
+        return Err("missing PROXY protocol header".to_string());
+    }
+    let mut address_block = vec![0_u8; u16::from_be_bytes([preamble[14], preamble[15]]) as usize];
+    stream
+        .read_exact(&mut address_block)
+        .await
+        .map_err(|e| e.to_string())?;
+    match (preamble[12], preamble[13]) {
+        (0x20, _) => Ok(None),
+        (0x21, 0x11) if address_block.len() >= 12 => {
+            let ip: [u8; 4] = address_block[..4].try_into().unwrap();
+            let port = u16::from_be_bytes([address_block[8], address_block[9]]);
+            Ok(Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port)))
+        }
+        (0x21, 0x21) if address_block.len() >= 36 => {
+            let ip: [u8; 16] = address_block[..16].try_into().unwrap();
+            let port = u16::from_be_bytes([address_block[32], address_block[33]]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port)))
+        }
+        (command, family) => Err(format!(
+            "unexpected v2 command {:#x} / family {:#x}",
+            command, family
+        )),
+    }
+}
+
+/// Reads the PROXY header and a single request, then replies with the client address the header
+/// announced ("unknown" if it didn't announce one) and hangs up.
+async fn answer_once(mut stream: TcpStream, server_state: Arc<ServerState>) {
+    let source = match read_proxy_header(&mut stream).await {
+        Ok(Some(source)) => source.to_string(),
+        Ok(None) => "unknown".to_string(),
+        Err(err) => {
+            log::error!("ProxyProtocolServer got a bad PROXY header: {}", err);
+            let _ = stream
+                .write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n")
+                .await;
+            return;
+        }
+    };
+
+    let mut request = Vec::new();
+    let mut buffer = [0_u8; 512];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        match stream.read(&mut buffer).await {
+            Ok(0) | Err(_) => return,
+            Ok(bytes_read) => request.extend_from_slice(&buffer[..bytes_read]),
+        }
+    }
+    server_state
+        .requests_received
+        .fetch_add(1, atomic::Ordering::SeqCst);
+
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}",
+        source.len(),
+        source
+    );
+    let _ = stream.write_all(response.as_bytes()).await;
+}
+
+/// An upstream that expects every connection to start with a PROXY protocol header, and answers
+/// each request with the client address from that header.
+pub struct ProxyProtocolServer {
+    shutdown_signal_sender: oneshot::Sender<()>,
+    server_task: tokio::task::JoinHandle<()>,
+    pub address: String,
+    state: Arc<ServerState>,
+}
+
+impl ProxyProtocolServer {
+    #[allow(dead_code)]
+    pub async fn new() -> ProxyProtocolServer {
+        let mut rng = rand::thread_rng();
+        let bind_addr_string = format!("127.0.0.1:{}", rng.gen_range(1024..65535));
+        let listener = TcpListener::bind(&bind_addr_string)
+            .await
+            .expect("Error binding ProxyProtocolServer");
+        let (shutdown_tx, mut shutdown_rx) = oneshot::channel::<()>();
+
+        let server_state = Arc::new(ServerState {
+            requests_received: atomic::AtomicUsize::new(0),
+        });
+        let server_task_state = server_state.clone();
+        let server_task = tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    accepted = listener.accept() => match accepted {
+                        Ok((stream, _)) => {
+                            tokio::spawn(answer_once(stream, server_task_state.clone()));
+                        }
+                        Err(e) => log::error!("Error in ProxyProtocolServer: {}", e),
+                    },
+                    _ = &mut shutdown_rx => break,
+                }
+            }
+        });
+
+        ProxyProtocolServer {
+            shutdown_signal_sender: shutdown_tx,
+            server_task,
+            state: server_state,
+            address: bind_addr_string,
+        }
+    }
+}
+
+#[async_trait]
+impl Server for ProxyProtocolServer {
+    async fn stop(self: Box<Self>) -> usize {
+        let _ = self.shutdown_signal_sender.send(());
+        self.server_task
+            .await
+            .expect("ProxyProtocolServer server task panicked");
+
+        self.state.requests_received.load(atomic::Ordering::SeqCst)
+    }
+
+    fn requests_received(&self) -> usize {
+        self.state.requests_received.load(atomic::Ordering::SeqCst)
+    }
+
+    fn address(&self) -> String {
+        self.address.clone()
+    }
+}
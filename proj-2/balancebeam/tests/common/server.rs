@@ -3,5 +3,8 @@ use async_trait::async_trait;
 #[async_trait]
 pub trait Server {
     async fn stop(self: Box<Self>) -> usize;
+    #[allow(dead_code)]
+    fn requests_received(&self) -> usize;
+    #[allow(dead_code)]
     fn address(&self) -> String;
 }
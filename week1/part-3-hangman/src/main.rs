@@ -18,19 +18,27 @@ use std::fs;
 use std::io;
 use std::io::Write;
 use std::iter::FromIterator;
-use std::vec;
 
 const NUM_INCORRECT_GUESSES: u32 = 5;
 const WORDS_PATH: &str = "words.txt";
+// Used instead of WORDS_PATH when run with --phrases
+const PHRASES_PATH: &str = "phrases.txt";
+// Typed instead of a letter to reveal one, at the cost of a guess
+const HINT: char = '?';
 
-fn pick_a_random_word() -> String {
-    let file_string = fs::read_to_string(WORDS_PATH).expect("Unable to read file.");
+fn pick_a_random_word(path: &str) -> String {
+    let file_string = fs::read_to_string(path).expect("Unable to read file.");
     let words: Vec<&str> = file_string.split('\n').collect();
     String::from(words[rand::thread_rng().gen_range(0, words.len())].trim())
 }
 
 fn main() {
-    let secret_word = pick_a_random_word();
+    let path = if std::env::args().any(|arg| arg == "--phrases") {
+        PHRASES_PATH
+    } else {
+        WORDS_PATH
+    };
+    let secret_word = pick_a_random_word(path);
     // Note: given what you know about Rust so far, it's easier to pull characters out of a
     // vector than it is to pull them out of a string. You can get the ith character of
     // secret_word by doing secret_word_chars[i].
@@ -42,12 +50,13 @@ fn main() {
     println!("Welcome to CS110L Hangman!");
 
     let mut chances = NUM_INCORRECT_GUESSES;
-    let mut rest = secret_word_chars.len();
+    let (mut correct, mut rest) = hide_secret(&secret_word_chars);
     let mut guessed: Vec<char> = Vec::new();
-    let mut correct: Vec<char> = vec!['-'; rest];
     while chances > 0 && rest > 0 {
         let letter = do_guess(chances, &mut guessed, &correct);
-        if !check_guess(letter, &mut correct, &secret_word_chars) {
+        if letter == HINT {
+            take_hint(&mut chances, &mut rest, &mut correct, &secret_word_chars);
+        } else if !check_guess(letter, &mut correct, &secret_word_chars) {
             chances -= 1;
         } else {
             rest -= 1;
@@ -60,18 +69,75 @@ fn main() {
             secret_word
         );
     } else {
+        println!(
+            "{}",
+            render_gallows(NUM_INCORRECT_GUESSES, NUM_INCORRECT_GUESSES)
+        );
         println!("Sorry, you ran out of guesses! ");
     }
 }
 
+// Returns the secret as shown before any guesses, and how many letters are left to guess. Only
+// letters are hidden; spaces and punctuation in phrases are shown from the start.
+fn hide_secret(secret: &[char]) -> (Vec<char>, usize) {
+    let correct: Vec<char> = secret
+        .iter()
+        .map(|&c| if c.is_alphabetic() { '-' } else { c })
+        .collect();
+    let rest = correct.iter().filter(|&&c| c == '-').count();
+    (correct, rest)
+}
+
+// The empty gallows, and where each body part is drawn on it (row, column, character), in the
+// order they're added
+const GALLOWS: [&str; 6] = [
+    "  +---+",
+    "  |   |",
+    "      |",
+    "      |",
+    "      |",
+    "=========",
+];
+const BODY_PARTS: [(usize, usize, char); 6] = [
+    (2, 2, 'O'),
+    (3, 2, '|'),
+    (3, 1, '/'),
+    (3, 3, '\\'),
+    (4, 1, '/'),
+    (4, 3, '\\'),
+];
+
+// Draws the gallows after `misses` of `allowed` incorrect guesses. The body parts are spread over
+// the allowed guesses, so the drawing is complete on the last one however many there are.
+fn render_gallows(misses: u32, allowed: u32) -> String {
+    let parts = if allowed == 0 {
+        BODY_PARTS.len()
+    } else {
+        (misses.min(allowed) as usize * BODY_PARTS.len()).div_ceil(allowed as usize)
+    };
+    let mut rows: Vec<Vec<char>> = GALLOWS.iter().map(|row| row.chars().collect()).collect();
+    for &(row, column, c) in &BODY_PARTS[..parts] {
+        rows[row][column] = c;
+    }
+    let rows: Vec<String> = rows
+        .iter()
+        .map(|row| String::from_iter(row.iter()))
+        .collect();
+    rows.join("\n")
+}
+
 fn do_guess(chances: u32, guess: &mut Vec<char>, correct: &[char]) -> char {
+    println!(
+        "{}",
+        render_gallows(NUM_INCORRECT_GUESSES - chances, NUM_INCORRECT_GUESSES)
+    );
     println!("The word so far is {}", String::from_iter(correct.iter()));
     println!(
         "You have guessed the following letters: {}",
         String::from_iter(guess.iter())
     );
     println!("You have {chances} guesses left");
-    print!("Please guess a letter: ");
+    print!("Please guess a letter (or {} for a hint): ", HINT);
 
     io::stdout().flush().expect("Error flushing stdout.");
 
@@ -81,16 +147,39 @@ fn do_guess(chances: u32, guess: &mut Vec<char>, correct: &[char]) -> char {
         .expect("Error reading line.");
 
     let letter: char = input.chars().next().unwrap();
-    guess.push(letter);
+    if letter != HINT {
+        guess.push(letter);
+    }
 
     letter
 }
 
-fn check_guess(letter: char, correct: &mut [char], secret: &Vec<char>) -> bool {
+// Reveals a random letter that hasn't been guessed yet, using up one of the remaining chances.
+// Hints aren't given on the last chance, since they would end the game. Returns whether a letter
+// was revealed.
+fn take_hint(chances: &mut u32, rest: &mut usize, correct: &mut [char], secret: &[char]) -> bool {
+    if *chances <= 1 {
+        println!("Sorry, you can't take a hint on your last guess");
+        return false;
+    }
+    let hidden: Vec<usize> = (0..correct.len()).filter(|&i| correct[i] == '-').collect();
+    if hidden.is_empty() {
+        return false;
+    }
+    let i = hidden[rand::thread_rng().gen_range(0, hidden.len())];
+    correct[i] = secret[i];
+    println!("Hint: the word has a {}", secret[i]);
+    *chances -= 1;
+    *rest -= 1;
+    true
+}
+
+// Guesses are case-insensitive, so "s" reveals the "S" in "Star Wars"
+fn check_guess(letter: char, correct: &mut [char], secret: &[char]) -> bool {
     let mut i = 0;
     while i < secret.len() {
         let c = secret[i];
-        if c == letter && correct[i] == '-' {
+        if c.to_lowercase().eq(letter.to_lowercase()) && correct[i] == '-' {
             correct[i] = c;
             return true;
         }
@@ -100,3 +189,60 @@ fn check_guess(letter: char, correct: &mut [char], secret: &Vec<char>) -> bool {
 
     false
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_phrase() {
+        let secret: Vec<char> = "Star Wars".chars().collect();
+        let (mut correct, mut rest) = hide_secret(&secret);
+        assert_eq!(String::from_iter(correct.iter()), "---- ----");
+        assert_eq!(rest, 8);
+
+        for letter in "STARwars".chars() {
+            assert!(check_guess(letter, &mut correct, &secret));
+            rest -= 1;
+        }
+        assert_eq!(String::from_iter(correct.iter()), "Star Wars");
+        assert_eq!(rest, 0);
+        assert!(!check_guess('a', &mut correct, &secret));
+    }
+
+    #[test]
+    fn test_hint() {
+        let secret: Vec<char> = "ox".chars().collect();
+        let (mut correct, mut rest) = hide_secret(&secret);
+        let mut chances = 2;
+        assert!(take_hint(&mut chances, &mut rest, &mut correct, &secret));
+        assert_eq!((chances, rest), (1, 1));
+        let revealed: Vec<usize> = (0..2).filter(|&i| correct[i] != '-').collect();
+        assert_eq!(revealed.len(), 1);
+        assert_eq!(correct[revealed[0]], secret[revealed[0]]);
+
+        // No hints on the last chance
+        assert!(!take_hint(&mut chances, &mut rest, &mut correct, &secret));
+        assert_eq!((chances, rest), (1, 1));
+    }
+
+    #[test]
+    fn test_render_gallows() {
+        assert_eq!(
+            render_gallows(0, 5),
+            "  +---+\n  |   |\n      |\n      |\n      |\n========="
+        );
+        // 2 of 5 misses shows 3 of the 6 parts (rounding up)
+        assert_eq!(
+            render_gallows(2, 5),
+            "  +---+\n  |   |\n  O   |\n /|   |\n      |\n========="
+        );
+        assert_eq!(
+            render_gallows(5, 5),
+            "  +---+\n  |   |\n  O   |\n /|\\  |\n / \\  |\n========="
+        );
+        // With more allowed guesses than body parts, some misses don't add one
+        assert_eq!(render_gallows(1, 12), render_gallows(2, 12));
+        assert_eq!(render_gallows(12, 12), render_gallows(5, 5));
+    }
+}
@@ -1,13 +1,22 @@
 use std::fmt;
+use std::iter::FromIterator;
 use std::option::Option;
+use std::ptr::NonNull;
 
-#[derive(Clone, PartialEq)]
 pub struct LinkedList<T> {
     head: Option<Box<Node<T>>>,
+    /// The last node of the chain owned by `head`, so that pushing to the back doesn't have to walk
+    /// the list. It is None exactly when the list is empty, and it must be updated whenever the last
+    /// node is unlinked or a new one is added after it.
+    tail: Option<NonNull<Node<T>>>,
     size: usize,
 }
 
-#[derive(Clone, PartialEq)]
+// The tail pointer only ever points into nodes the list owns, so the list can cross threads
+// whenever its values can (NonNull would otherwise opt it out).
+unsafe impl<T: Send> Send for LinkedList<T> {}
+unsafe impl<T: Sync> Sync for LinkedList<T> {}
+
 struct Node<T> {
     value: T,
     next: Option<Box<Node<T>>>,
@@ -29,6 +38,7 @@ impl<T> LinkedList<T> {
     pub fn new() -> LinkedList<T> {
         LinkedList {
             head: None,
+            tail: None,
             size: 0,
         }
     }
@@ -42,7 +52,10 @@ impl<T> LinkedList<T> {
     }
 
     pub fn push_front(&mut self, value: T) {
-        let new_node: Box<Node<T>> = Box::new(Node::new(value, self.head.take()));
+        let mut new_node: Box<Node<T>> = Box::new(Node::new(value, self.head.take()));
+        if self.tail.is_none() {
+            self.tail = Some(NonNull::from(&mut *new_node));
+        }
         self.head = Some(new_node);
         self.size += 1;
     }
@@ -50,9 +63,202 @@ impl<T> LinkedList<T> {
     pub fn pop_front(&mut self) -> Option<T> {
         let node: Box<Node<T>> = self.head.take()?;
         self.head = node.next;
+        if self.head.is_none() {
+            // That was the last node, which the tail pointed to
+            self.tail = None;
+        }
         self.size -= 1;
         Some(node.value)
     }
+
+    /// Returns the first element, or None if the list is empty.
+    pub fn front(&self) -> Option<&T> {
+        self.head.as_ref().map(|node| &node.value)
+    }
+
+    /// Returns the first element mutably, or None if the list is empty.
+    pub fn front_mut(&mut self) -> Option<&mut T> {
+        self.head.as_mut().map(|node| &mut node.value)
+    }
+
+    /// Returns the last element, or None if the list is empty.
+    pub fn back(&self) -> Option<&T> {
+        // Safety: the tail points to the last node of the chain we own, and the borrow of self
+        // keeps the chain from changing while the reference lives
+        self.tail.map(|tail| unsafe { &(*tail.as_ptr()).value })
+    }
+
+    /// Returns the last element mutably, or None if the list is empty.
+    pub fn back_mut(&mut self) -> Option<&mut T> {
+        // Safety: as in back, and the mutable borrow of self makes this the only reference
+        self.tail.map(|tail| unsafe { &mut (*tail.as_ptr()).value })
+    }
+
+    /// Adds `value` to the end of the list in constant time.
+    pub fn push_back(&mut self, value: T) {
+        let mut new_node: Box<Node<T>> = Box::new(Node::new(value, None));
+        let new_tail = NonNull::from(&mut *new_node);
+        match self.tail {
+            // Safety: the tail points to the last node of the chain we own, and we hold &mut self,
+            // so nothing else can be using it
+            Some(mut tail) => unsafe { tail.as_mut().next = Some(new_node) },
+            None => self.head = Some(new_node),
+        }
+        self.tail = Some(new_tail);
+        self.size += 1;
+    }
+
+    /// Rotates the list in place so that the first `mid` elements move to the end, like
+    /// `VecDeque::rotate_left`. The nodes are relinked rather than moved, walking the list once.
+    ///
+    /// Panics if `mid` is greater than the size of the list.
+    pub fn rotate_left(&mut self, mid: usize) {
+        assert!(
+            mid <= self.size,
+            "can't rotate {} elements of a list of size {}",
+            mid,
+            self.size
+        );
+        if mid == 0 || mid == self.size {
+            return;
+        }
+        // The node before the new head becomes the tail
+        let (new_head, new_tail) = {
+            let mut node = self.head.as_mut().unwrap();
+            for _ in 1..mid {
+                node = node.next.as_mut().unwrap();
+            }
+            (node.next.take(), NonNull::from(&mut **node))
+        };
+        let old_head = std::mem::replace(&mut self.head, new_head);
+        if let Some(mut tail) = self.tail {
+            // Safety: the old tail is still the last node of the chain we own (moving the boxes
+            // around doesn't move the nodes), and we hold &mut self
+            unsafe { tail.as_mut().next = old_head };
+        }
+        self.tail = Some(new_tail);
+    }
+
+    /// Rotates the list in place so that the last `k` elements move to the front, like
+    /// `VecDeque::rotate_right`.
+    ///
+    /// Panics if `k` is greater than the size of the list.
+    pub fn rotate_right(&mut self, k: usize) {
+        assert!(
+            k <= self.size,
+            "can't rotate {} elements of a list of size {}",
+            k,
+            self.size
+        );
+        self.rotate_left(self.size - k);
+    }
+
+    /// Returns a cursor pointing at the first element (or at the "ghost" position past the end,
+    /// if the list is empty), which can edit the list in place as it moves along.
+    pub fn cursor_front_mut(&mut self) -> CursorMut<'_, T> {
+        CursorMut {
+            link: NonNull::from(&mut self.head),
+            prev: None,
+            list: self,
+        }
+    }
+
+    /// Returns an iterator that unlinks the elements for which `f` returns true and yields them in
+    /// order, like `Vec::extract_if`. The other elements stay in the list, in the same order.
+    /// Elements are only looked at as the iterator reaches them, so if it is dropped early, the
+    /// rest of the list is left as it was.
+    pub fn extract_if<F: FnMut(&T) -> bool>(&mut self, f: F) -> ExtractIf<'_, T, F> {
+        ExtractIf {
+            cursor: self.cursor_front_mut(),
+            pred: f,
+        }
+    }
+
+    /// Consumes the list, returning a new list with `f` applied to every element.
+    pub fn map<U, F: FnMut(T) -> U>(mut self, mut f: F) -> LinkedList<U> {
+        let mut mapped = LinkedList::new();
+        while let Some(value) = self.pop_front() {
+            mapped.push_back(f(value));
+        }
+        mapped
+    }
+
+    /// Consumes the list, returning a new list with only the elements for which `f` returns true.
+    pub fn filter<F: FnMut(&T) -> bool>(mut self, mut f: F) -> LinkedList<T> {
+        let mut kept = LinkedList::new();
+        while let Some(value) = self.pop_front() {
+            if f(&value) {
+                kept.push_back(value);
+            }
+        }
+        kept
+    }
+
+    /// Consumes the list, folding every element into an accumulator from front to back.
+    pub fn fold<B, F: FnMut(B, T) -> B>(mut self, init: B, mut f: F) -> B {
+        let mut acc = init;
+        while let Some(value) = self.pop_front() {
+            acc = f(acc, value);
+        }
+        acc
+    }
+}
+
+impl<T: Ord> LinkedList<T> {
+    /// Sorts the list in place with a (stable) merge sort, relinking the nodes rather than moving
+    /// the values around.
+    pub fn sort(&mut self) {
+        let head = self.head.take();
+        self.head = merge_sort(head, self.size);
+        // Sorting relinks the nodes, so the old tail may now be anywhere in the chain
+        self.tail = last_node(&mut self.head);
+    }
+}
+
+/// Returns the last node of the chain starting at `head`.
+fn last_node<T>(head: &mut Option<Box<Node<T>>>) -> Option<NonNull<Node<T>>> {
+    let mut last = None;
+    let mut current = head;
+    while let Some(node) = current {
+        last = Some(NonNull::from(&mut **node));
+        current = &mut node.next;
+    }
+    last
+}
+
+/// Sorts the first `len` nodes starting at `head`, which must be the whole chain.
+fn merge_sort<T: Ord>(mut head: Option<Box<Node<T>>>, len: usize) -> Option<Box<Node<T>>> {
+    if len <= 1 {
+        return head;
+    }
+    let mid = len / 2;
+    let mut cursor = &mut head;
+    for _ in 0..mid {
+        cursor = &mut cursor.as_mut().unwrap().next;
+    }
+    let back = cursor.take();
+    merge(merge_sort(head, mid), merge_sort(back, len - mid))
+}
+
+/// Merges two sorted chains, taking from `left` on ties so that equal elements keep their order.
+fn merge<T: Ord>(
+    mut left: Option<Box<Node<T>>>,
+    mut right: Option<Box<Node<T>>>,
+) -> Option<Box<Node<T>>> {
+    let mut head = None;
+    let mut tail = &mut head;
+    while let (Some(l), Some(r)) = (&left, &right) {
+        let source = if l.value <= r.value {
+            &mut left
+        } else {
+            &mut right
+        };
+        let mut node = source.take().unwrap();
+        *source = node.next.take();
+        tail = &mut tail.insert(node).next;
+    }
+    *tail = if left.is_some() { left } else { right };
+    head
 }
 
 impl<T: fmt::Display> fmt::Display for LinkedList<T> {
@@ -67,8 +273,34 @@ impl<T: fmt::Display> fmt::Display for LinkedList<T> {
     }
 }
 
+// Derived impls would recurse once per node and overflow the stack on long lists (and a derived
+// PartialEq would compare the tail pointers too), so walk the chains iteratively instead.
+impl<T: Clone> Clone for LinkedList<T> {
+    fn clone(&self) -> Self {
+        self.into_iter().collect()
+    }
+}
+
+impl<T: PartialEq> PartialEq for LinkedList<T> {
+    /// Walks both chains in step, so lists of different lengths compare unequal even if a cached
+    /// `size` is wrong.
+    fn eq(&self, other: &Self) -> bool {
+        let mut left = &self.head;
+        let mut right = &other.head;
+        while let (Some(l), Some(r)) = (left, right) {
+            if l.value != r.value {
+                return false;
+            }
+            left = &l.next;
+            right = &r.next;
+        }
+        left.is_none() && right.is_none()
+    }
+}
+
 impl<T> Drop for LinkedList<T> {
     fn drop(&mut self) {
+        self.tail = None;
         let mut current = self.head.take();
         while let Some(mut node) = current {
             current = node.next.take();
@@ -76,6 +308,22 @@ impl<T> Drop for LinkedList<T> {
     }
 }
 
+impl<T> FromIterator<T> for LinkedList<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        let mut list = LinkedList::new();
+        list.extend(iter);
+        list
+    }
+}
+
+impl<T> Extend<T> for LinkedList<T> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        for value in iter {
+            self.push_back(value);
+        }
+    }
+}
+
 impl<T> Iterator for LinkedList<T> {
     type Item = T;
     fn next(&mut self) -> Option<T> {
@@ -83,6 +331,50 @@ impl<T> Iterator for LinkedList<T> {
     }
 }
 
+// Lists are serialized as a plain sequence of their values, front first.
+#[cfg(feature = "serde")]
+impl<T: serde::Serialize> serde::Serialize for LinkedList<T> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeSeq;
+        let mut seq = serializer.serialize_seq(Some(self.size))?;
+        let mut current = &self.head;
+        while let Some(node) = current {
+            seq.serialize_element(&node.value)?;
+            current = &node.next;
+        }
+        seq.end()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, T: serde::Deserialize<'de>> serde::Deserialize<'de> for LinkedList<T> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ListVisitor<T>(std::marker::PhantomData<T>);
+
+        impl<'de, T: serde::Deserialize<'de>> serde::de::Visitor<'de> for ListVisitor<T> {
+            type Value = LinkedList<T>;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a sequence")
+            }
+
+            fn visit_seq<A: serde::de::SeqAccess<'de>>(
+                self,
+                mut seq: A,
+            ) -> Result<Self::Value, A::Error> {
+                // Appending at the tail keeps this linear, however long the list
+                let mut list = LinkedList::new();
+                while let Some(value) = seq.next_element()? {
+                    list.push_back(value);
+                }
+                Ok(list)
+            }
+        }
+
+        deserializer.deserialize_seq(ListVisitor(std::marker::PhantomData))
+    }
+}
+
 pub struct LinkedListIter<'a, T> {
     current: &'a Option<Box<Node<T>>>,
 }
@@ -111,6 +403,111 @@ impl<'a, T: Clone> IntoIterator for &'a LinkedList<T> {
     }
 }
 
+/// A cursor over a `LinkedList` that can insert and remove elements where it points, like
+/// `std::collections::linked_list::CursorMut` but only moving forwards. Past the last element is a
+/// "ghost" position with no element, from which moving next wraps around to the front.
+pub struct CursorMut<'a, T> {
+    list: &'a mut LinkedList<T>,
+    /// The link holding the current node: the list's head or some node's next. It holds None when
+    /// the cursor is at the ghost position.
+    link: NonNull<Option<Box<Node<T>>>>,
+    /// The node whose next is `link` (None if `link` is the head), which becomes the tail if the
+    /// current node is the last one and gets removed
+    prev: Option<NonNull<Node<T>>>,
+}
+
+impl<T> CursorMut<'_, T> {
+    /// Moves to the next element, or to the ghost position after the last one. Moving on from the
+    /// ghost position goes back to the front.
+    pub fn move_next(&mut self) {
+        // Safety: the link is in the chain the list owns, and the cursor holds the only borrow of
+        // the list, so nothing else can have freed or moved it
+        match unsafe { self.link.as_mut() } {
+            Some(node) => {
+                self.prev = Some(NonNull::from(&mut **node));
+                self.link = NonNull::from(&mut node.next);
+            }
+            None => {
+                self.prev = None;
+                self.link = NonNull::from(&mut self.list.head);
+            }
+        }
+    }
+
+    /// Returns the element the cursor points at, or None at the ghost position.
+    pub fn current(&mut self) -> Option<&mut T> {
+        // Safety: as in move_next, and the borrow of self stops the cursor from changing the list
+        // while the reference lives
+        unsafe { self.link.as_mut() }
+            .as_mut()
+            .map(|node| &mut node.value)
+    }
+
+    /// Inserts `value` after the current element, leaving the cursor where it is. At the ghost
+    /// position, `value` is inserted at the front of the list instead.
+    pub fn insert_after(&mut self, value: T) {
+        // Safety: as in move_next
+        match unsafe { self.link.as_mut() } {
+            Some(node) => {
+                let mut new_node = Box::new(Node::new(value, node.next.take()));
+                if new_node.next.is_none() {
+                    self.list.tail = Some(NonNull::from(&mut *new_node));
+                }
+                node.next = Some(new_node);
+                self.list.size += 1;
+            }
+            None => {
+                let was_empty = self.list.is_empty();
+                self.list.push_front(value);
+                if was_empty {
+                    // The ghost position was the head, which now holds the new node, so move the
+                    // cursor along to stay at the ghost position
+                    let head = self.list.head.as_mut().unwrap();
+                    self.prev = Some(NonNull::from(&mut **head));
+                    self.link = NonNull::from(&mut head.next);
+                }
+            }
+        }
+    }
+
+    /// Removes the current element and returns it, leaving the cursor pointing at the element that
+    /// came after it. Returns None (and changes nothing) at the ghost position.
+    pub fn remove_current(&mut self) -> Option<T> {
+        // Safety: as in move_next
+        let link = unsafe { self.link.as_mut() };
+        let mut node = link.take()?;
+        *link = node.next.take();
+        if link.is_none() {
+            // That was the last node, so the one before it (if any) is the last now
+            self.list.tail = self.prev;
+        }
+        self.list.size -= 1;
+        Some(node.value)
+    }
+}
+
+/// Iterator returned by `LinkedList::extract_if`.
+pub struct ExtractIf<'a, T, F> {
+    /// Points at the next element to look at, and stops at the ghost position
+    cursor: CursorMut<'a, T>,
+    pred: F,
+}
+
+impl<T, F: FnMut(&T) -> bool> Iterator for ExtractIf<'_, T, F> {
+    type Item = T;
+    fn next(&mut self) -> Option<T> {
+        // Removing an element leaves the cursor on the one after it, so only kept elements need
+        // stepping over
+        while let Some(value) = self.cursor.current() {
+            if (self.pred)(value) {
+                return self.cursor.remove_current();
+            }
+            self.cursor.move_next();
+        }
+        None
+    }
+}
+
 pub trait ComputeNorm {
     fn compute_norm(&self) -> f64{
         0.0
@@ -122,3 +519,469 @@ impl ComputeNorm for LinkedList<f64>{
         self.into_iter().map(|x| {x * x}).sum::<f64>().sqrt()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn list_of(values: &[i32]) -> LinkedList<i32> {
+        let mut list = LinkedList::new();
+        for value in values.iter().rev() {
+            list.push_front(*value);
+        }
+        list
+    }
+
+    /// Checks that the tail points at the last node of the chain (and is None for an empty list).
+    fn assert_tail_consistent<T>(list: &mut LinkedList<T>) {
+        let expected = last_node(&mut list.head);
+        assert_eq!(list.tail, expected);
+        assert_eq!(list.tail.is_none(), list.is_empty());
+    }
+
+    fn to_vec(list: &LinkedList<i32>) -> Vec<i32> {
+        list.into_iter().collect()
+    }
+
+    #[test]
+    fn test_map() {
+        let doubled = list_of(&[1, 2, 3, 4]).map(|x| x * 2);
+        assert_eq!(to_vec(&doubled), vec![2, 4, 6, 8]);
+        assert_eq!(doubled.get_size(), 4);
+    }
+
+    #[test]
+    fn test_filter() {
+        let evens = list_of(&[1, 2, 3, 4, 5, 6]).filter(|x| x % 2 == 0);
+        assert_eq!(to_vec(&evens), vec![2, 4, 6]);
+        assert_eq!(evens.get_size(), 3);
+    }
+
+    #[test]
+    fn test_fold() {
+        assert_eq!(list_of(&[1, 2, 3, 4]).fold(0, |acc, x| acc + x), 10);
+        assert_eq!(LinkedList::<i32>::new().fold(7, |acc, x| acc + x), 7);
+    }
+
+    #[test]
+    fn test_sort_reversed() {
+        let mut list = list_of(&[5, 4, 3, 2, 1]);
+        list.sort();
+        assert_eq!(to_vec(&list), vec![1, 2, 3, 4, 5]);
+        assert_eq!(list.get_size(), 5);
+    }
+
+    #[test]
+    fn test_sort_already_sorted() {
+        let mut list = list_of(&[1, 2, 3, 4, 5, 6]);
+        list.sort();
+        assert_eq!(to_vec(&list), vec![1, 2, 3, 4, 5, 6]);
+
+        let mut empty = LinkedList::<i32>::new();
+        empty.sort();
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn test_sort_duplicates_is_stable() {
+        // Only the key takes part in comparisons, so the tag shows whether equal keys kept their
+        // original order
+        #[derive(Debug, PartialEq, Eq)]
+        struct Tagged(i32, &'static str);
+        impl PartialOrd for Tagged {
+            fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+                Some(self.cmp(other))
+            }
+        }
+        impl Ord for Tagged {
+            fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+                self.0.cmp(&other.0)
+            }
+        }
+
+        let mut list = LinkedList::new();
+        for item in [
+            Tagged(1, "e"),
+            Tagged(3, "d"),
+            Tagged(1, "c"),
+            Tagged(2, "b"),
+            Tagged(3, "a"),
+        ] {
+            list.push_front(item);
+        }
+        list.sort();
+        assert_eq!(list.get_size(), 5);
+        let sorted: Vec<Tagged> = list.collect();
+        assert_eq!(
+            sorted,
+            vec![
+                Tagged(1, "c"),
+                Tagged(1, "e"),
+                Tagged(2, "b"),
+                Tagged(3, "a"),
+                Tagged(3, "d"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rotate_left() {
+        for (mid, expected) in [
+            (0, vec![1, 2, 3, 4, 5]),
+            (2, vec![3, 4, 5, 1, 2]),
+            (4, vec![5, 1, 2, 3, 4]),
+            (5, vec![1, 2, 3, 4, 5]),
+        ] {
+            let mut list = list_of(&[1, 2, 3, 4, 5]);
+            list.rotate_left(mid);
+            assert_eq!(to_vec(&list), expected);
+            assert_eq!(list.get_size(), 5);
+            assert_tail_consistent(&mut list);
+            // The relinked list must still grow at the right end
+            list.push_back(6);
+            assert_eq!(list.back(), Some(&6));
+            assert_tail_consistent(&mut list);
+        }
+
+        let mut empty = LinkedList::<i32>::new();
+        empty.rotate_left(0);
+        assert!(empty.is_empty());
+        assert_tail_consistent(&mut empty);
+    }
+
+    #[test]
+    fn test_rotate_right() {
+        for (k, expected) in [
+            (0, vec![1, 2, 3, 4, 5]),
+            (1, vec![5, 1, 2, 3, 4]),
+            (3, vec![3, 4, 5, 1, 2]),
+            (5, vec![1, 2, 3, 4, 5]),
+        ] {
+            let mut list = list_of(&[1, 2, 3, 4, 5]);
+            list.rotate_right(k);
+            assert_eq!(to_vec(&list), expected);
+            assert_eq!(list.get_size(), 5);
+            assert_tail_consistent(&mut list);
+        }
+
+        let mut single = list_of(&[7]);
+        single.rotate_right(1);
+        assert_eq!(to_vec(&single), vec![7]);
+        assert_tail_consistent(&mut single);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_rotate_past_size() {
+        list_of(&[1, 2, 3]).rotate_left(4);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_rotate_right_past_size() {
+        list_of(&[1, 2, 3]).rotate_right(4);
+    }
+
+    #[test]
+    fn test_clone_long_list() {
+        let n = 1_000_000;
+        let mut list = LinkedList::new();
+        for i in (0..n).rev() {
+            list.push_front(i);
+        }
+        let copy = list.clone();
+        assert_eq!(copy.get_size(), n as usize);
+        assert!(list.zip(&copy).all(|(a, b)| a == b));
+        assert_eq!((&copy).into_iter().last(), Some(n - 1));
+    }
+
+    #[test]
+    fn test_map_non_clone() {
+        let mut list = LinkedList::new();
+        list.push_front(String::from("b"));
+        list.push_front(String::from("a"));
+        let shouted = list.map(|s| s + "!");
+        assert_eq!(shouted.to_string(), " a! b!");
+    }
+
+    #[test]
+    fn test_push_back() {
+        let mut list = LinkedList::new();
+        assert_tail_consistent(&mut list);
+        for i in 1..=4 {
+            list.push_back(i);
+            assert_tail_consistent(&mut list);
+        }
+        list.push_front(0);
+        assert_tail_consistent(&mut list);
+        assert_eq!(to_vec(&list), vec![0, 1, 2, 3, 4]);
+        assert_eq!(list.get_size(), 5);
+    }
+
+    #[test]
+    fn test_push_back_pop_front_interleaved() {
+        // Mirror every operation on a VecDeque, including draining the list completely so that
+        // later pushes have to start a new chain
+        let mut list = LinkedList::new();
+        let mut model = std::collections::VecDeque::new();
+        let mut next = 0;
+        for round in 0..200 {
+            for _ in 0..round % 7 {
+                list.push_back(next);
+                model.push_back(next);
+                next += 1;
+                assert_tail_consistent(&mut list);
+            }
+            if round % 5 == 0 {
+                list.push_front(next);
+                model.push_front(next);
+                next += 1;
+                assert_tail_consistent(&mut list);
+            }
+            for _ in 0..round % 4 + 1 {
+                assert_eq!(list.pop_front(), model.pop_front());
+                assert_tail_consistent(&mut list);
+            }
+            assert_eq!(list.get_size(), model.len());
+        }
+        while let Some(value) = model.pop_front() {
+            assert_eq!(list.pop_front(), Some(value));
+        }
+        assert!(list.is_empty());
+        assert_tail_consistent(&mut list);
+        list.push_back(42);
+        assert_tail_consistent(&mut list);
+        assert_eq!(to_vec(&list), vec![42]);
+    }
+
+    #[test]
+    fn test_tail_after_sort_and_clone() {
+        let mut list = list_of(&[3, 1, 2]);
+        list.sort();
+        assert_tail_consistent(&mut list);
+        list.push_back(4);
+        assert_eq!(to_vec(&list), vec![1, 2, 3, 4]);
+
+        // The copy must get its own tail rather than sharing the original's
+        let mut copy = list.clone();
+        assert_tail_consistent(&mut copy);
+        copy.push_back(5);
+        list.push_back(6);
+        assert_eq!(to_vec(&copy), vec![1, 2, 3, 4, 5]);
+        assert_eq!(to_vec(&list), vec![1, 2, 3, 4, 6]);
+        assert!(copy != list);
+    }
+
+    #[test]
+    fn test_collect_and_extend() {
+        let mut list: LinkedList<i32> = (1..=3).collect();
+        assert_tail_consistent(&mut list);
+        list.extend(vec![4, 5]);
+        assert_tail_consistent(&mut list);
+        assert_eq!(to_vec(&list), vec![1, 2, 3, 4, 5]);
+        assert_eq!(list.get_size(), 5);
+        assert!(list == list_of(&[1, 2, 3, 4, 5]));
+    }
+
+    #[test]
+    fn test_front_and_back_empty() {
+        let mut list = LinkedList::<i32>::new();
+        assert_eq!(list.front(), None);
+        assert_eq!(list.back(), None);
+        assert_eq!(list.front_mut(), None);
+        assert_eq!(list.back_mut(), None);
+        assert!(list.is_empty());
+    }
+
+    #[test]
+    fn test_front_and_back() {
+        let mut list = list_of(&[1, 2, 3]);
+        assert_eq!(list.front(), Some(&1));
+        assert_eq!(list.back(), Some(&3));
+        assert_eq!(list.get_size(), 3);
+
+        *list.front_mut().unwrap() = 10;
+        *list.back_mut().unwrap() += 20;
+        assert_eq!(to_vec(&list), vec![10, 2, 23]);
+        assert_eq!(list.get_size(), 3);
+
+        // A single element is both the front and the back
+        let mut single = list_of(&[7]);
+        *single.front_mut().unwrap() += 1;
+        assert_eq!(single.back(), Some(&8));
+        assert_eq!(single.pop_front(), Some(8));
+        assert_eq!(single.back(), None);
+        single.push_back(9);
+        assert_eq!(single.front(), Some(&9));
+        assert_eq!(single.back(), Some(&9));
+    }
+
+    #[test]
+    fn test_eq() {
+        assert!(list_of(&[1, 2, 3]) == list_of(&[1, 2, 3]));
+        assert!(LinkedList::<i32>::new() == LinkedList::new());
+        assert!(list_of(&[1, 2, 3]) != list_of(&[1, 2, 4]));
+        assert!(list_of(&[1, 2, 3]) != list_of(&[1, 2]));
+        assert!(list_of(&[1, 2]) != list_of(&[1, 2, 3]));
+        assert!(list_of(&[1]) != LinkedList::new());
+    }
+
+    #[test]
+    fn test_eq_ignores_desynced_size() {
+        // Same cached size, but one chain is a prefix of the other
+        let long = list_of(&[1, 2, 3]);
+        let mut short = list_of(&[1, 2]);
+        short.size = 3;
+        assert!(long != short);
+        assert!(short != long);
+
+        let mut empty = LinkedList::new();
+        empty.size = 1;
+        assert!(list_of(&[1]) != empty);
+        assert!(empty != list_of(&[1]));
+
+        // Equal chains are equal whatever the cached sizes say
+        let mut stale = list_of(&[1, 2]);
+        stale.size = 5;
+        assert!(stale == list_of(&[1, 2]));
+        assert!(list_of(&[1, 2]) == stale);
+    }
+
+    #[test]
+    fn test_cursor_remove_every_other() {
+        let mut list = list_of(&[1, 2, 3, 4, 5, 6, 7]);
+        let mut cursor = list.cursor_front_mut();
+        while cursor.current().is_some() {
+            // Keep this one and remove the next
+            cursor.move_next();
+            cursor.remove_current();
+        }
+        assert_eq!(to_vec(&list), vec![1, 3, 5, 7]);
+        assert_eq!(list.get_size(), 4);
+        assert_tail_consistent(&mut list);
+
+        // Removing the last element should leave the tail on the one before it
+        let mut list = list_of(&[1, 2, 3, 4, 5, 6]);
+        let mut cursor = list.cursor_front_mut();
+        while cursor.current().is_some() {
+            cursor.move_next();
+            assert!(cursor.remove_current().unwrap() % 2 == 0);
+        }
+        assert_eq!(to_vec(&list), vec![1, 3, 5]);
+        assert_eq!(list.get_size(), 3);
+        assert_tail_consistent(&mut list);
+        list.push_back(7);
+        assert_eq!(to_vec(&list), vec![1, 3, 5, 7]);
+    }
+
+    #[test]
+    fn test_cursor_remove_all() {
+        let mut list = list_of(&[1, 2, 3]);
+        let mut cursor = list.cursor_front_mut();
+        let mut removed = Vec::new();
+        while let Some(value) = cursor.remove_current() {
+            removed.push(value);
+        }
+        assert_eq!(removed, vec![1, 2, 3]);
+        assert!(list.is_empty());
+        assert_tail_consistent(&mut list);
+    }
+
+    #[test]
+    fn test_cursor_insert_after() {
+        let mut list = list_of(&[1, 3, 5]);
+        let mut cursor = list.cursor_front_mut();
+        while let Some(value) = cursor.current() {
+            *value *= 10;
+            let next = *value + 5;
+            cursor.insert_after(next);
+            // Skip over the element just inserted
+            cursor.move_next();
+            cursor.move_next();
+        }
+        assert_eq!(to_vec(&list), vec![10, 15, 30, 35, 50, 55]);
+        assert_eq!(list.get_size(), 6);
+        assert_tail_consistent(&mut list);
+    }
+
+    #[test]
+    fn test_extract_if_odd() {
+        let mut list = list_of(&[1, 2, 3, 4, 5, 6, 7]);
+        let odd: Vec<i32> = list.extract_if(|value| value % 2 == 1).collect();
+        assert_eq!(odd, vec![1, 3, 5, 7]);
+        assert_eq!(to_vec(&list), vec![2, 4, 6]);
+        assert_eq!(list.get_size(), 3);
+        assert_tail_consistent(&mut list);
+        list.push_back(8);
+        assert_eq!(to_vec(&list), vec![2, 4, 6, 8]);
+
+        // Extracting everything leaves an empty list with no tail
+        let mut list = list_of(&[1, 3, 5]);
+        assert_eq!(list.extract_if(|_| true).count(), 3);
+        assert!(list.is_empty());
+        assert_tail_consistent(&mut list);
+    }
+
+    #[test]
+    fn test_extract_if_dropped_early() {
+        // Elements after the last one yielded are left alone
+        let mut list = list_of(&[1, 2, 3, 4, 5]);
+        {
+            let mut extracted = list.extract_if(|value| value % 2 == 1);
+            assert_eq!(extracted.next(), Some(1));
+            assert_eq!(extracted.next(), Some(3));
+        }
+        assert_eq!(to_vec(&list), vec![2, 4, 5]);
+        assert_eq!(list.get_size(), 3);
+        assert_tail_consistent(&mut list);
+    }
+
+    #[test]
+    fn test_cursor_ghost_position() {
+        // From the ghost position, inserting goes to the front and moving wraps around
+        let mut list = LinkedList::new();
+        let mut cursor = list.cursor_front_mut();
+        assert!(cursor.current().is_none());
+        assert!(cursor.remove_current().is_none());
+        cursor.insert_after(2);
+        cursor.insert_after(1);
+        assert!(cursor.current().is_none());
+        cursor.move_next();
+        assert_eq!(cursor.current(), Some(&mut 1));
+        cursor.move_next();
+        cursor.move_next();
+        assert!(cursor.current().is_none());
+        cursor.move_next();
+        assert_eq!(cursor.current(), Some(&mut 1));
+        assert_eq!(to_vec(&list), vec![1, 2]);
+        assert_eq!(list.get_size(), 2);
+        assert_tail_consistent(&mut list);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_json_round_trip() {
+        let list = list_of(&[3, 1, 4, 1, 5]);
+        let json = serde_json::to_string(&list).unwrap();
+        assert_eq!(json, "[3,1,4,1,5]");
+        let mut decoded: LinkedList<i32> = serde_json::from_str(&json).unwrap();
+        assert!(decoded == list);
+        assert_eq!(decoded.get_size(), 5);
+        assert_tail_consistent(&mut decoded);
+
+        let mut empty: LinkedList<i32> = serde_json::from_str("[]").unwrap();
+        assert!(empty.is_empty());
+        assert_tail_consistent(&mut empty);
+        assert!(serde_json::from_str::<LinkedList<i32>>("{}").is_err());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_json_round_trip_long_list() {
+        // Long enough that anything recursing per node would overflow the stack
+        let list: LinkedList<u32> = (0..1_000_000).collect();
+        let json = serde_json::to_string(&list).unwrap();
+        let decoded: LinkedList<u32> = serde_json::from_str(&json).unwrap();
+        assert!(decoded == list);
+    }
+}
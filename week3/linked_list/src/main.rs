@@ -15,7 +15,8 @@ fn main() {
     println!("top element: {}", list.pop_front().unwrap());
     println!("{}", list);
     println!("size: {}", list.get_size());
-    println!("{}", list.to_string()); // ToString impl for anything impl Display
+    let list_string = list.to_string(); // ToString impl for anything impl Display
+    println!("{}", list_string);
 
     let mut list2 = list.clone();
     println!("list == list2: {}", list == list2);
@@ -1,104 +1,621 @@
+use crossbeam_deque::{Steal, Stealer, Worker};
 use std::collections::VecDeque;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
 #[allow(unused_imports)]
 use std::sync::{Arc, Mutex};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 #[allow(unused_imports)]
 use std::{env, process, thread};
 
-/// Determines whether a number is prime. This function is taken from CS 110 factor.py.
+/// How many trial divisions to do between looking at the clock. Reading it takes much longer than
+/// a division, so checking on every one would slow factoring right down.
+const BUDGET_CHECK_INTERVAL: u32 = 4096;
+
+/// The time allowed for factoring one number, if there is a limit.
+struct Budget {
+    start: Instant,
+    timeout: Option<Duration>,
+}
+
+impl Budget {
+    fn new(timeout: Option<Duration>) -> Budget {
+        Budget {
+            start: Instant::now(),
+            timeout,
+        }
+    }
+
+    /// Returns whether the time is up, which is only checked every `BUDGET_CHECK_INTERVAL` steps
+    /// (numbers too small to get that far always finish).
+    fn exhausted(&self, step: u32) -> bool {
+        step.is_multiple_of(BUDGET_CHECK_INTERVAL)
+            && self
+                .timeout
+                .is_some_and(|timeout| self.start.elapsed() > timeout)
+    }
+}
+
+/// Determines whether a number is prime, or returns None if `budget` runs out first. This function
+/// is taken from CS 110 factor.py.
 ///
 /// You don't need to read or understand this code.
 #[allow(dead_code)]
-fn is_prime(num: u32) -> bool {
+fn is_prime(num: u32, budget: &Budget) -> Option<bool> {
     if num <= 1 {
-        return false;
+        return Some(false);
     }
     for factor in 2..((num as f64).sqrt().floor() as u32) {
-        if num % factor == 0 {
-            return false;
+        if budget.exhausted(factor) {
+            return None;
+        }
+        if num.is_multiple_of(factor) {
+            return Some(false);
+        }
+    }
+    Some(true)
+}
+
+/// The prime factors of a number, along with how long it took to find them.
+#[derive(Debug, Clone, PartialEq)]
+struct Factorization {
+    number: u32,
+    factors: Vec<u32>,
+    time: Duration,
+    /// Whether factoring was given up on after --per-number-timeout (leaving `factors` empty)
+    timed_out: bool,
+}
+
+impl Factorization {
+    /// Formats the result as the human-readable line farm has always printed.
+    fn to_text(&self) -> String {
+        if self.timed_out {
+            return format!("{} timed out [time: {:?}]", self.number, self.time);
+        }
+        format!(
+            "{} = {} [time: {:?}]",
+            self.number,
+            join_factors(&self.factors, " * "),
+            self.time
+        )
+    }
+
+    /// Formats the result as a `number,factors` CSV row, with the factors separated by spaces.
+    fn to_csv(&self) -> String {
+        if self.timed_out {
+            return format!("{},timed out", self.number);
+        }
+        format!("{},{}", self.number, join_factors(&self.factors, " "))
+    }
+
+    /// Formats the result as a JSON object.
+    fn to_json(&self) -> String {
+        if self.timed_out {
+            return format!(
+                "{{\"number\": {}, \"timed_out\": true, \"time_ms\": {:.3}}}",
+                self.number,
+                self.time.as_secs_f64() * 1000.0
+            );
         }
+        format!(
+            "{{\"number\": {}, \"factors\": [{}], \"time_ms\": {:.3}}}",
+            self.number,
+            join_factors(&self.factors, ", "),
+            self.time.as_secs_f64() * 1000.0
+        )
     }
-    true
 }
 
-/// Determines the prime factors of a number and prints them to stdout. This function is taken
-/// from CS 110 factor.py.
+fn join_factors(factors: &[u32], separator: &str) -> String {
+    factors
+        .iter()
+        .map(|f| f.to_string())
+        .collect::<Vec<String>>()
+        .join(separator)
+}
+
+/// Determines the prime factors of a number, giving up once it has taken longer than `timeout`.
+/// This function is taken from CS 110 factor.py.
 ///
 /// You don't need to read or understand this code.
 #[allow(dead_code)]
-fn factor_number(num: u32) {
+fn factor_number(num: u32, timeout: Option<Duration>) -> Factorization {
     let start = Instant::now();
+    let budget = Budget::new(timeout);
+    let timed_out = || Factorization {
+        number: num,
+        factors: Vec::new(),
+        time: start.elapsed(),
+        timed_out: true,
+    };
 
-    if num == 1 || is_prime(num) {
-        println!("{} = {} [time: {:?}]", num, num, start.elapsed());
-        return;
+    let prime = match is_prime(num, &budget) {
+        Some(prime) => prime,
+        None => return timed_out(),
+    };
+    if num == 1 || prime {
+        return Factorization {
+            number: num,
+            factors: vec![num],
+            time: start.elapsed(),
+            timed_out: false,
+        };
     }
 
     let mut factors = Vec::new();
     let mut curr_num = num;
     for factor in 2..num {
-        while curr_num % factor == 0 {
+        if budget.exhausted(factor) {
+            return timed_out();
+        }
+        while curr_num.is_multiple_of(factor) {
             factors.push(factor);
             curr_num /= factor;
         }
     }
     factors.sort();
-    let factors_str = factors
-        .into_iter()
-        .map(|f| f.to_string())
-        .collect::<Vec<String>>()
-        .join(" * ");
-    println!("{} = {} [time: {:?}]", num, factors_str, start.elapsed());
+    Factorization {
+        number: num,
+        factors,
+        time: start.elapsed(),
+        timed_out: false,
+    }
+}
+
+/// How results are printed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum OutputFormat {
+    /// One human-readable line per number, printed as soon as it is factored
+    Text,
+    /// A `number,factors` header followed by one row per number, in input order
+    Csv,
+    /// A JSON array with one object per number, in input order
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "csv" => Ok(OutputFormat::Csv),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(format!(
+                "{} is not a valid output format (expected text, csv or json)",
+                s
+            )),
+        }
+    }
 }
 
-/// Returns a list of numbers supplied via argv.
+/// Settings given on the command line.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Options {
+    format: OutputFormat,
+    /// Longest to spend factoring any one number before reporting it as timed out (no limit if
+    /// None)
+    per_number_timeout: Option<Duration>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            format: OutputFormat::Text,
+            per_number_timeout: None,
+        }
+    }
+}
+
+/// Returns the options, the numbers supplied via argv, and any arguments that weren't valid
+/// numbers.
 #[allow(dead_code)]
-fn get_input_numbers() -> VecDeque<u32> {
+fn get_input_numbers() -> (Options, VecDeque<u32>, Vec<String>) {
+    parse_args(env::args().skip(1)).unwrap_or_else(|err| {
+        println!("{}", err);
+        process::exit(1);
+    })
+}
+
+/// Parses the command-line arguments (excluding the program name). Arguments that aren't valid
+/// numbers are collected rather than treated as fatal, so that the rest can still be factored; an
+/// invalid option is an error.
+fn parse_args<I: Iterator<Item = String>>(
+    mut args: I,
+) -> Result<(Options, VecDeque<u32>, Vec<String>), String> {
+    let mut options = Options::default();
     let mut numbers = VecDeque::new();
-    for arg in env::args().skip(1) {
-        if let Ok(val) = arg.parse::<u32>() {
+    let mut invalid = Vec::new();
+    while let Some(arg) = args.next() {
+        // Options may be given as `--name value` or `--name=value`
+        let mut value_of = |name: &str| {
+            if arg == name {
+                Some(args.next().unwrap_or_default())
+            } else {
+                arg.strip_prefix(name)
+                    .and_then(|rest| rest.strip_prefix('='))
+                    .map(str::to_string)
+            }
+        };
+        if let Some(format_arg) = value_of("--output-format") {
+            options.format = format_arg.parse()?;
+        } else if let Some(timeout_arg) = value_of("--per-number-timeout") {
+            let millis: u64 = timeout_arg.parse().map_err(|_| {
+                format!(
+                    "{} is not a valid timeout (expected a number of milliseconds)",
+                    timeout_arg
+                )
+            })?;
+            options.per_number_timeout = Some(Duration::from_millis(millis));
+        } else if let Ok(val) = arg.parse::<u32>() {
             numbers.push_back(val);
         } else {
-            println!("{} is not a valid number", arg);
-            process::exit(1);
+            invalid.push(arg);
         }
     }
-    numbers
+    Ok((options, numbers, invalid))
 }
 
+/// Renders the results of a whole run in the given format.
+fn render(results: &[Factorization], format: OutputFormat) -> String {
+    match format {
+        OutputFormat::Text => results
+            .iter()
+            .map(|result| result.to_text() + "\n")
+            .collect(),
+        OutputFormat::Csv => {
+            let mut csv = String::from("number,factors\n");
+            for result in results {
+                csv += &result.to_csv();
+                csv += "\n";
+            }
+            csv
+        }
+        OutputFormat::Json => {
+            let objects: Vec<String> = results
+                .iter()
+                .map(|result| format!("  {}", result.to_json()))
+                .collect();
+            if objects.is_empty() {
+                "[]\n".to_string()
+            } else {
+                format!("[\n{}\n]\n", objects.join(",\n"))
+            }
+        }
+    }
+}
+
+/// Prints a status message. These go to stderr when printing results in a machine-readable format,
+/// so that they don't get mixed into the results.
+fn report(format: OutputFormat, message: &str) {
+    if format == OutputFormat::Text {
+        println!("{}", message);
+    } else {
+        eprintln!("{}", message);
+    }
+}
+
+/// How often progress is reported while factoring
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
 fn main() {
+    let (options, numbers, invalid) = get_input_numbers();
+    let format = options.format;
+    for arg in &invalid {
+        eprintln!("{} is not a valid number", arg);
+    }
     let num_threads = num_cpus::get();
-    println!("Farm starting on {} CPUs", num_threads);
+    report(format, &format!("Farm starting on {} CPUs", num_threads));
     let start = Instant::now();
 
-    let number_queue = Arc::new(Mutex::new(get_input_numbers()));
+    let results = factor_all(numbers, num_threads, options);
+    // Text results were already printed as they came in
+    if format != OutputFormat::Text {
+        print!("{}", render(&results, format));
+    }
+
+    let elapsed = start.elapsed();
+    report(
+        format,
+        &format!(
+            "Factored {} numbers in {:?} ({:.1} numbers/s)",
+            results.len(),
+            elapsed,
+            throughput(results.len(), elapsed)
+        ),
+    );
+    if !invalid.is_empty() {
+        process::exit(1);
+    }
+}
+
+/// Factors every number in `numbers` using `num_threads` threads, periodically reporting progress.
+/// In text format, each result is printed as soon as it is ready. Returns the results in the same
+/// order as `numbers`, whatever order they were computed in.
+fn factor_all(numbers: VecDeque<u32>, num_threads: usize, options: Options) -> Vec<Factorization> {
+    let format = options.format;
+    let total = numbers.len();
+    // Deal the numbers out to one deque per thread. Threads that run out of numbers steal from the
+    // others, so nobody sits idle while there is still work, but no lock is shared by every number
+    let queues: Vec<Worker<(usize, u32)>> = (0..num_threads).map(|_| Worker::new_fifo()).collect();
+    for (index, number) in numbers.into_iter().enumerate() {
+        queues[index % num_threads].push((index, number));
+    }
+    let stealers: Arc<Vec<Stealer<(usize, u32)>>> =
+        Arc::new(queues.iter().map(Worker::stealer).collect());
+    let results = Arc::new(Mutex::new(vec![None; total]));
+    let processed = Arc::new(AtomicUsize::new(0));
+
+    // Report progress until told that all the worker threads are done
+    let (done_sender, done_receiver) = mpsc::channel::<()>();
+    let reporter = {
+        let processed = processed.clone();
+        let start = Instant::now();
+        thread::spawn(move || {
+            while let Err(mpsc::RecvTimeoutError::Timeout) =
+                done_receiver.recv_timeout(PROGRESS_INTERVAL)
+            {
+                let count = processed.load(Ordering::SeqCst);
+                report(
+                    format,
+                    &format!(
+                        "Progress: {}/{} numbers factored ({:.1} numbers/s)",
+                        count,
+                        total,
+                        throughput(count, start.elapsed())
+                    ),
+                );
+            }
+        })
+    };
 
     // factor_number() until the queue is empty
     let mut threads = Vec::new();
-    for _ in 0..num_threads {
-        let handle = number_queue.clone();
+    for queue in queues {
+        let stealers = stealers.clone();
+        let results = results.clone();
+        let processed = processed.clone();
         threads.push(thread::spawn(move || {
-            factor_agent(handle);
+            factor_agent(queue, stealers, results, processed, options);
         }))
     }
 
     for thread in threads {
         thread.join().expect("Panic occurred in thread!");
     }
+    // The reporter stops once the sender is gone
+    drop(done_sender);
+    reporter.join().expect("Panic occurred in thread!");
 
-    println!("Total execution time: {:?}", start.elapsed());
+    let results = std::mem::take(&mut *results.lock().unwrap());
+    results
+        .into_iter()
+        .map(|result| result.expect("Number was never factored"))
+        .collect()
 }
 
-fn factor_agent(number_queue: Arc<Mutex<VecDeque<u32>>>) {
-    while let Some(number) = get_factor_number(&number_queue) {
-        factor_number(number);
+/// Returns how many numbers per second were factored.
+fn throughput(count: usize, elapsed: Duration) -> f64 {
+    if elapsed.as_secs_f64() == 0.0 {
+        return 0.0;
     }
+    count as f64 / elapsed.as_secs_f64()
 }
 
-fn get_factor_number(number_queue: &Arc<Mutex<VecDeque<u32>>>) -> Option<u32> {
-    let mut queue_ref = number_queue.lock().unwrap();
-    if (*queue_ref).is_empty() {
-        return None;
+fn factor_agent(
+    queue: Worker<(usize, u32)>,
+    stealers: Arc<Vec<Stealer<(usize, u32)>>>,
+    results: Arc<Mutex<Vec<Option<Factorization>>>>,
+    processed: Arc<AtomicUsize>,
+    options: Options,
+) {
+    while let Some((index, number)) = get_factor_number(&queue, &stealers) {
+        let result = factor_number(number, options.per_number_timeout);
+        if options.format == OutputFormat::Text {
+            println!("{}", result.to_text());
+        }
+        results.lock().unwrap()[index] = Some(result);
+        processed.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// Takes the next number from this thread's own deque, or steals a batch from another thread's once
+/// it is empty. Returns None once every deque is empty. No numbers are added after the threads
+/// start, so there is nothing to wait for at that point.
+fn get_factor_number(
+    queue: &Worker<(usize, u32)>,
+    stealers: &[Stealer<(usize, u32)>],
+) -> Option<(usize, u32)> {
+    if let Some(entry) = queue.pop() {
+        return Some(entry);
+    }
+    loop {
+        let steal: Steal<(usize, u32)> = stealers
+            .iter()
+            .map(|stealer| stealer.steal_batch_and_pop(queue))
+            .collect();
+        match steal {
+            Steal::Success(entry) => return Some(entry),
+            Steal::Empty => return None,
+            // Lost a race with another thread; look again
+            Steal::Retry => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Options that don't print results as they come in
+    const CSV: Options = Options {
+        format: OutputFormat::Csv,
+        per_number_timeout: None,
+    };
+
+    #[test]
+    fn test_factor_agents_count_every_number() {
+        // One agent starts with all the numbers and the other has to steal them, as in factor_all
+        let busy = Worker::new_fifo();
+        for (index, number) in (2..200).enumerate() {
+            busy.push((index, number));
+        }
+        let queues = vec![busy, Worker::new_fifo()];
+        let stealers = Arc::new(queues.iter().map(Worker::stealer).collect::<Vec<_>>());
+        let results = Arc::new(Mutex::new(vec![None; 198]));
+        let processed = Arc::new(AtomicUsize::new(0));
+        let threads: Vec<_> = queues
+            .into_iter()
+            .map(|queue| {
+                let (stealers, results) = (stealers.clone(), results.clone());
+                let processed = processed.clone();
+                thread::spawn(move || factor_agent(queue, stealers, results, processed, CSV))
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(processed.load(Ordering::SeqCst), 198);
+        assert!(results.lock().unwrap().iter().all(Option::is_some));
+    }
+
+    #[test]
+    fn test_factor_all_empty() {
+        assert!(factor_all(VecDeque::new(), 4, CSV).is_empty());
+    }
+
+    #[test]
+    fn test_factor_all_keeps_input_order() {
+        let numbers: VecDeque<u32> = (2..200).rev().collect();
+        let results = factor_all(numbers.clone(), 4, CSV);
+        let factored: Vec<u32> = results.iter().map(|result| result.number).collect();
+        assert_eq!(factored, Vec::from(numbers));
+    }
+
+    /// The known input 12 and 7, with the timings zeroed so that the output is deterministic
+    fn known_results() -> Vec<Factorization> {
+        let mut results = factor_all(vec![12, 7].into(), 2, CSV);
+        for result in &mut results {
+            result.time = Duration::from_secs(0);
+        }
+        results
+    }
+
+    #[test]
+    fn test_get_factor_number_steals_when_empty() {
+        let busy = Worker::new_fifo();
+        let idle = Worker::new_fifo();
+        for index in 0..100 {
+            busy.push((index, index as u32));
+        }
+        let stealers = vec![busy.stealer(), idle.stealer()];
+
+        // The idle thread should take work off the busy one, and between them every number should
+        // come out exactly once
+        let mut taken = vec![get_factor_number(&idle, &stealers).unwrap()];
+        while let Some(entry) = get_factor_number(&busy, &stealers) {
+            taken.push(entry);
+            taken.extend(get_factor_number(&idle, &stealers));
+        }
+        assert!(get_factor_number(&idle, &stealers).is_none());
+        taken.sort();
+        assert_eq!(taken, (0..100).map(|i| (i, i as u32)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_factor_all_more_threads_than_numbers() {
+        let results = factor_all(vec![12, 7].into(), 8, CSV);
+        let factored: Vec<u32> = results.iter().map(|result| result.number).collect();
+        assert_eq!(factored, vec![12, 7]);
+    }
+
+    #[test]
+    fn test_render_csv() {
+        assert_eq!(
+            render(&known_results(), OutputFormat::Csv),
+            "number,factors\n12,2 2 3\n7,7\n"
+        );
+    }
+
+    #[test]
+    fn test_render_json() {
+        assert_eq!(
+            render(&known_results(), OutputFormat::Json),
+            "[\n  {\"number\": 12, \"factors\": [2, 2, 3], \"time_ms\": 0.000},\n  \
+             {\"number\": 7, \"factors\": [7], \"time_ms\": 0.000}\n]\n"
+        );
+        assert_eq!(render(&[], OutputFormat::Json), "[]\n");
+    }
+
+    #[test]
+    fn test_parse_output_format() {
+        assert_eq!("csv".parse(), Ok(OutputFormat::Csv));
+        assert_eq!("json".parse(), Ok(OutputFormat::Json));
+        assert_eq!("text".parse(), Ok(OutputFormat::Text));
+        assert!("xml".parse::<OutputFormat>().is_err());
+    }
+
+    fn args(args: &[&str]) -> impl Iterator<Item = String> {
+        args.iter()
+            .map(|arg| arg.to_string())
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    #[test]
+    fn test_parse_args_keeps_valid_numbers() {
+        let (options, numbers, invalid) =
+            parse_args(args(&["12", "abc", "--output-format=csv", "7", "-3", "4x"])).unwrap();
+        assert_eq!(options.format, OutputFormat::Csv);
+        assert_eq!(options.per_number_timeout, None);
+        assert_eq!(numbers, VecDeque::from(vec![12, 7]));
+        assert_eq!(invalid, vec!["abc", "-3", "4x"]);
+    }
+
+    #[test]
+    fn test_parse_args_rejects_bad_format() {
+        assert!(parse_args(args(&["12", "--output-format", "xml"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_args_per_number_timeout() {
+        let (options, numbers, _) =
+            parse_args(args(&["--per-number-timeout", "250", "12"])).unwrap();
+        assert_eq!(options.per_number_timeout, Some(Duration::from_millis(250)));
+        assert_eq!(numbers, VecDeque::from(vec![12]));
+        let (options, _, _) = parse_args(args(&["--per-number-timeout=5"])).unwrap();
+        assert_eq!(options.per_number_timeout, Some(Duration::from_millis(5)));
+        assert!(parse_args(args(&["--per-number-timeout", "soon"])).is_err());
+    }
+
+    #[test]
+    fn test_per_number_timeout() {
+        // The largest prime that fits in a u32, and twice a large prime, which trial division
+        // takes billions of steps to factor
+        let numbers = vec![4294967291, 12, 4294967294].into();
+        let options = Options {
+            per_number_timeout: Some(Duration::from_nanos(1)),
+            ..CSV
+        };
+        let start = Instant::now();
+        let results = factor_all(numbers, 2, options);
+        assert!(start.elapsed() < Duration::from_secs(5));
+        let timed_out: Vec<bool> = results.iter().map(|result| result.timed_out).collect();
+        assert_eq!(timed_out, vec![true, false, true]);
+        assert_eq!(results[1].factors, vec![2, 2, 3]);
+        assert_eq!(
+            render(&results[..2], OutputFormat::Csv),
+            "number,factors\n4294967291,timed out\n12,2 2 3\n"
+        );
+        assert!(results[0]
+            .to_text()
+            .starts_with("4294967291 timed out [time: "));
+    }
+
+    #[test]
+    fn test_throughput() {
+        assert_eq!(throughput(10, Duration::from_secs(2)), 5.0);
+        assert_eq!(throughput(10, Duration::from_secs(0)), 0.0);
     }
-    (*queue_ref).pop_front()
 }
@@ -1,3 +1,4 @@
+use crossbeam_channel::Receiver;
 use std::{thread, time};
 
 fn parallel_map<T, U, F>(mut input_vec: Vec<T>, num_threads: usize, f: F) -> Vec<U>
@@ -48,12 +49,80 @@ where
     output_vec
 }
 
+/// Like parallel_map, but returns right away with a channel that yields each result as soon as
+/// it is ready, as `(index in input_vec, result)` pairs in whatever order they finish. The
+/// channel disconnects once every result has been sent.
+fn parallel_map_stream<T, U, F>(input_vec: Vec<T>, num_threads: usize, f: F) -> Receiver<(usize, U)>
+where
+    F: FnOnce(T) -> U + Send + Copy + 'static,
+    T: Send + 'static,
+    U: Send + 'static,
+{
+    let (sender_raw, receiver_raw) = crossbeam_channel::unbounded();
+    let (sender_result, receiver_result) = crossbeam_channel::unbounded();
+    for _ in 0..num_threads {
+        let recv_raw = receiver_raw.clone();
+        let send_res = sender_result.clone();
+        thread::spawn(move || {
+            while let Ok((idx, num)) = recv_raw.recv() {
+                // The caller may stop listening early; the rest of the input is just dropped
+                if send_res.send((idx, f(num))).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    for (idx, num) in input_vec.into_iter().enumerate() {
+        sender_raw
+            .send((idx, num))
+            .expect("Tried writing to channel, but there are no receivers!");
+    }
+    receiver_result
+}
+
 fn main() {
     let v = vec![6, 7, 8, 9, 10, 1, 2, 3, 4, 5, 12, 18, 11, 5, 20];
-    let squares = parallel_map(v, 10, |num| {
+    let squares = parallel_map(v.clone(), 10, |num| {
         println!("{} squared is {}", num, num * num);
         thread::sleep(time::Duration::from_millis(500));
         num * num
     });
     println!("squares: {:?}", squares);
+
+    let cubes = parallel_map_stream(v, 10, |num: i32| {
+        thread::sleep(time::Duration::from_millis(50 * num as u64));
+        num * num * num
+    });
+    for (idx, cube) in cubes {
+        println!("cube of v[{}] is {}", idx, cube);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parallel_map_stream_reorders() {
+        let input: Vec<u64> = (0..50).rev().collect();
+        // Bigger numbers take longer, so results come back out of order
+        let results = parallel_map_stream(input.clone(), 8, |num| {
+            thread::sleep(time::Duration::from_millis(num));
+            num * 2
+        });
+        let mut output = vec![None; input.len()];
+        for (idx, doubled) in results {
+            assert!(output[idx].is_none(), "index {} was sent twice", idx);
+            output[idx] = Some(doubled);
+        }
+        let output: Vec<u64> = output.into_iter().map(Option::unwrap).collect();
+        assert_eq!(output, parallel_map(input, 8, |num| num * 2));
+    }
+
+    #[test]
+    fn test_parallel_map_stream_empty() {
+        let results = parallel_map_stream(Vec::<u32>::new(), 4, |num| num + 1);
+        assert_eq!(results.iter().count(), 0);
+    }
 }